@@ -0,0 +1,199 @@
+//! Adapter read-through detection in soft-clipped read tails.
+//!
+//! When a fragment is shorter than the read length the sequencer reads on into the
+//! adapter, which the aligner soft-clips from the 3' end of the read. Those tails are
+//! compared against known adapter sequences to report the read-through rate, and reads
+//! with read-through can optionally be left out of a filtered BAM.
+
+use anyhow::{Context, Result};
+use bio::alphabets::dna;
+use rust_htslib::bam::record::{Cigar, Record};
+use rust_htslib::bam::{Format, Header, Read, Reader, Writer};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::provenance;
+
+/// Nextera/Tn5 (ATAC-seq) and TruSeq adapter starts.
+pub const DEFAULT_ADAPTERS: [&str; 2] = ["CTGTCTCTTATACACATCT", "AGATCGGAAGAGC"];
+
+/// How far into the adapter the aligner may have extended the alignment.
+const MAX_ADAPTER_OFFSET: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct AdapterOptions {
+    pub adapters: Vec<String>,
+    /// Shortest soft-clipped tail that is compared against the adapters
+    pub min_overlap: usize,
+    /// Mismatches allowed per compared base
+    pub max_mismatch_rate: f64,
+    pub threads: usize,
+}
+
+impl Default for AdapterOptions {
+    fn default() -> Self {
+        Self {
+            adapters: DEFAULT_ADAPTERS.iter().map(|a| a.to_string()).collect(),
+            min_overlap: 5,
+            max_mismatch_rate: 0.1,
+            threads: 1,
+        }
+    }
+}
+
+/// The soft-clipped 3' tail of a read in sequencing orientation.
+fn three_prime_clip(record: &Record) -> Option<Vec<u8>> {
+    let cigar = record.cigar();
+    if record.is_reverse() {
+        // Stored reverse complemented, so the 3' end is the start of the record
+        match cigar.first() {
+            Some(Cigar::SoftClip(n)) => Some(dna::revcomp(&record.seq().as_bytes()[..*n as usize])),
+            _ => None,
+        }
+    } else {
+        match cigar.last() {
+            Some(Cigar::SoftClip(n)) => {
+                let seq = record.seq().as_bytes();
+                Some(seq[seq.len() - *n as usize..].to_vec())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether `tail` starts with the adapter (or the adapter minus a few leading bases).
+fn matches_adapter(tail: &[u8], adapter: &[u8], options: &AdapterOptions) -> bool {
+    (0..=MAX_ADAPTER_OFFSET.min(adapter.len())).any(|offset| {
+        let adapter = &adapter[offset..];
+        let length = tail.len().min(adapter.len());
+        if length < options.min_overlap {
+            return false;
+        }
+        let mismatches = tail[..length]
+            .iter()
+            .zip(&adapter[..length])
+            .filter(|(a, b)| !a.eq_ignore_ascii_case(b))
+            .count();
+        mismatches as f64 <= options.max_mismatch_rate * length as f64
+    })
+}
+
+/// Index of the first adapter matching a 3' soft-clipped tail.
+fn find_adapter(tail: &[u8], options: &AdapterOptions) -> Option<usize> {
+    options
+        .adapters
+        .iter()
+        .position(|adapter| matches_adapter(tail, adapter.as_bytes(), options))
+}
+
+/// Scan `bam` for adapter read-through and write a report to `report` (or stdout).
+///
+/// If `filtered_output` is given, every record without adapter read-through is written
+/// there. Records are filtered individually, so the mate of a filtered read is kept.
+pub fn adapter_report<P>(
+    bam: P,
+    report: Option<P>,
+    filtered_output: Option<P>,
+    options: &AdapterOptions,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut reader = Reader::from_path(&bam).context("Could not open BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+
+    let mut writer = match filtered_output {
+        Some(path) => {
+            let mut header = Header::from_template(reader.header());
+            provenance::add_program_record(
+                &mut header,
+                "adapters: removed reads with adapter read-through",
+            );
+            let mut writer = Writer::from_path(path, &header, Format::Bam)?;
+            if options.threads > 1 {
+                writer.set_threads(options.threads)?;
+            }
+            Some(writer)
+        }
+        None => None,
+    };
+
+    let mut n_reads = 0u64;
+    let mut n_clipped = 0u64;
+    let mut per_adapter = vec![0u64; options.adapters.len()];
+
+    for result in reader.records() {
+        let record = result?;
+        let mut adapter = None;
+        if !(record.is_unmapped() || record.is_secondary() || record.is_supplementary()) {
+            n_reads += 1;
+            if let Some(tail) = three_prime_clip(&record) {
+                n_clipped += 1;
+                adapter = find_adapter(&tail, options);
+            }
+            if let Some(i) = adapter {
+                per_adapter[i] += 1;
+            }
+        }
+        if let (Some(writer), None) = (writer.as_mut(), adapter) {
+            writer.write(&record)?;
+        }
+    }
+
+    let rate = |n: u64| match n_reads {
+        0 => 0.0,
+        total => n as f64 / total as f64,
+    };
+    let n_adapter: u64 = per_adapter.iter().sum();
+
+    let mut out: Box<dyn Write> = match report {
+        Some(path) => Box::new(BufWriter::new(File::create(path.as_ref())?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    writeln!(out, "metric\tvalue")?;
+    writeln!(out, "reads\t{}", n_reads)?;
+    writeln!(out, "three_prime_soft_clipped\t{}", n_clipped)?;
+    writeln!(out, "adapter_read_through\t{}", n_adapter)?;
+    writeln!(out, "adapter_read_through_rate\t{:.6}", rate(n_adapter))?;
+    for (adapter, n) in options.adapters.iter().zip(per_adapter) {
+        writeln!(out, "adapter_{}\t{}", adapter, n)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::CigarString;
+
+    #[test]
+    fn finds_adapter_in_three_prime_clip() {
+        let options = AdapterOptions::default();
+        let seq = b"ACGTACGTACCTGTCTCTTA";
+        let qual = vec![30; seq.len()];
+
+        let mut record = Record::new();
+        let cigar = CigarString(vec![Cigar::Match(10), Cigar::SoftClip(10)]);
+        record.set(b"read1", Some(&cigar), seq, &qual);
+        record.unset_unmapped();
+        let tail = three_prime_clip(&record).unwrap();
+        assert_eq!(find_adapter(&tail, &options), Some(0));
+
+        // The same clip on the 5' end of a forward read is not read-through
+        let cigar = CigarString(vec![Cigar::SoftClip(10), Cigar::Match(10)]);
+        record.set(b"read1", Some(&cigar), seq, &qual);
+        assert_eq!(three_prime_clip(&record), None);
+
+        // Alignment extended three bases into the adapter, with one mismatch
+        assert!(matches_adapter(
+            b"TCTCTTATTCACATCT",
+            b"CTGTCTCTTATACACATCT",
+            &options
+        ));
+        assert!(!matches_adapter(b"CTG", b"CTGTCTCTTATACACATCT", &options));
+    }
+}
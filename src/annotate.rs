@@ -0,0 +1,256 @@
+//! Tag reads with the names of the regions they overlap.
+//!
+//! Regions come from BED (name column) or GTF/GFF (a chosen attribute), optionally
+//! gzipped. Each read whose aligned blocks overlap one or more regions gets a string tag
+//! (`XR:Z:peak_1,peak_7` by default) so per-region analyses can group reads directly.
+
+use anyhow::{bail, Context, Result};
+use flate2::read::MultiGzDecoder;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::{Aux, Record};
+use rust_htslib::bam::{Format, Header, HeaderView, Read, Reader, Writer};
+use rust_lapper::{Interval, Lapper};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::provenance;
+
+type Iv = Interval<u64, usize>;
+
+#[derive(Debug, Clone)]
+pub struct AnnotateOptions {
+    /// Two-letter tag to write the region names to
+    pub tag: String,
+    /// GTF/GFF attribute used as the region name
+    pub name_attribute: String,
+    /// Only write reads that overlap at least one region
+    pub only_annotated: bool,
+    pub threads: usize,
+}
+
+impl Default for AnnotateOptions {
+    fn default() -> Self {
+        Self {
+            tag: "XR".to_string(),
+            name_attribute: "gene_id".to_string(),
+            only_annotated: false,
+            threads: 1,
+        }
+    }
+}
+
+/// Named regions per reference id; interval values index into `names`.
+struct Regions {
+    names: Vec<String>,
+    trees: HashMap<u32, Lapper<u64, usize>>,
+}
+
+fn open_regions(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file =
+        File::open(path).with_context(|| format!("Could not open regions `{}`", path.display()))?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => Ok(Box::new(BufReader::new(MultiGzDecoder::new(file)))),
+        _ => Ok(Box::new(BufReader::new(file))),
+    }
+}
+
+fn is_gtf(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    [".gtf", ".gff", ".gff3"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+/// Value of `key` in a GTF (`key "value";`) or GFF3 (`key=value;`) attribute column.
+fn gtf_attribute<'a>(attributes: &'a str, key: &str) -> Option<&'a str> {
+    attributes.split(';').find_map(|field| {
+        let field = field.trim();
+        let (k, v) = field.split_once('=').or_else(|| field.split_once(' '))?;
+        (k == key).then(|| v.trim().trim_matches('"'))
+    })
+}
+
+/// (chrom, 0-based start, end, name) of one BED or GTF line, or `None` for header lines.
+fn parse_region(
+    line: &str,
+    gtf: bool,
+    name_attribute: &str,
+) -> Result<Option<(String, u64, u64, String)>> {
+    if line.trim().is_empty()
+        || line.starts_with('#')
+        || line.starts_with("track")
+        || line.starts_with("browser")
+    {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = line.split('\t').collect();
+    let region = match gtf {
+        true => {
+            if fields.len() < 9 {
+                bail!("Expected 9 columns in GTF line `{}`", line);
+            }
+            let start: u64 = fields[3].parse().context("Invalid GTF start")?;
+            let name = gtf_attribute(fields[8], name_attribute).unwrap_or(fields[2]);
+            (
+                fields[0].to_owned(),
+                start.saturating_sub(1),
+                fields[4].parse().context("Invalid GTF end")?,
+                name.to_owned(),
+            )
+        }
+        false => {
+            if fields.len() < 3 {
+                bail!("Expected at least 3 columns in BED line `{}`", line);
+            }
+            let start = fields[1].parse().context("Invalid BED start")?;
+            let end = fields[2].parse().context("Invalid BED end")?;
+            let name = match fields.get(3) {
+                Some(name) if !name.is_empty() && *name != "." => name.to_string(),
+                _ => format!("{}:{}-{}", fields[0], start, end),
+            };
+            (fields[0].to_owned(), start, end, name)
+        }
+    };
+    Ok(Some(region))
+}
+
+fn read_regions(path: &Path, header: &HeaderView, name_attribute: &str) -> Result<Regions> {
+    let gtf = is_gtf(path);
+    let mut names = Vec::new();
+    let mut intervals: HashMap<u32, Vec<Iv>> = HashMap::new();
+    for line in open_regions(path)?.lines() {
+        let line = line?;
+        if let Some((chrom, start, end, name)) = parse_region(&line, gtf, name_attribute)? {
+            if let Some(tid) = header.tid(chrom.as_bytes()) {
+                intervals.entry(tid).or_default().push(Iv {
+                    start,
+                    stop: end,
+                    val: names.len(),
+                });
+                names.push(name);
+            }
+        }
+    }
+    Ok(Regions {
+        names,
+        trees: intervals
+            .into_iter()
+            .map(|(tid, intervals)| (tid, Lapper::new(intervals)))
+            .collect(),
+    })
+}
+
+/// Names of the regions overlapped by the aligned blocks of `record`, in region order and
+/// without repeats.
+fn overlapping_names(record: &Record, regions: &Regions) -> Vec<String> {
+    let tree = match regions.trees.get(&(record.tid() as u32)) {
+        Some(tree) if !record.is_unmapped() && record.tid() >= 0 => tree,
+        _ => return Vec::new(),
+    };
+    let mut hits: Vec<usize> = record
+        .aligned_blocks()
+        .flat_map(|[start, end]| {
+            tree.find(start as u64, end as u64)
+                .map(|iv| iv.val)
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    hits.sort_unstable();
+    hits.dedup();
+    let mut names: Vec<String> = Vec::with_capacity(hits.len());
+    for i in hits {
+        if !names.contains(&regions.names[i]) {
+            names.push(regions.names[i].clone());
+        }
+    }
+    names
+}
+
+/// Copy `bam` to `output`, tagging each read with the names of the regions in `regions`
+/// (BED or GTF/GFF) it overlaps.
+pub fn annotate_reads<P>(bam: P, regions: P, output: P, options: &AnnotateOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    if options.tag.len() != 2 {
+        bail!("Tag must be two characters, not `{}`", options.tag);
+    }
+    let tag = options.tag.as_bytes();
+
+    let mut reader = Reader::from_path(&bam).context("Could not open BAM file")?;
+    let regions = read_regions(regions.as_ref(), reader.header(), &options.name_attribute)?;
+
+    let mut header = Header::from_template(reader.header());
+    provenance::add_program_record(
+        &mut header,
+        &format!(
+            "annotate: {} tags with overlapping region names",
+            options.tag
+        ),
+    );
+    let mut writer = Writer::from_path(output, &header, Format::Bam)?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+        writer.set_threads(options.threads)?;
+    }
+
+    let mut n_reads = 0u64;
+    let mut n_annotated = 0u64;
+    for result in reader.records() {
+        let mut record = result?;
+        n_reads += 1;
+        // Stale tags from an earlier run would otherwise be kept on unannotated reads
+        if record.aux(tag).is_ok() {
+            record.remove_aux(tag)?;
+        }
+        let names = overlapping_names(&record, &regions);
+        if names.is_empty() {
+            if !options.only_annotated {
+                writer.write(&record)?;
+            }
+            continue;
+        }
+        record.push_aux(tag, Aux::String(&names.join(",")))?;
+        writer.write(&record)?;
+        n_annotated += 1;
+    }
+
+    println!(
+        "Annotated {} of {} reads with {} regions",
+        n_annotated,
+        n_reads,
+        regions.names.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bed_and_gtf_regions() {
+        let bed = parse_region("chr1\t100\t200\tpeak_1", false, "gene_id").unwrap();
+        assert_eq!(
+            bed,
+            Some(("chr1".to_string(), 100, 200, "peak_1".to_string()))
+        );
+        let unnamed = parse_region("chr1\t100\t200", false, "gene_id").unwrap();
+        assert_eq!(unnamed.unwrap().3, "chr1:100-200");
+        assert_eq!(
+            parse_region("track name=peaks", false, "gene_id").unwrap(),
+            None
+        );
+
+        let gtf = "chr1\tHAVANA\tgene\t11869\t14409\t.\t+\t.\tgene_id \"ENSG00000223972\"; gene_name \"DDX11L1\";";
+        let region = parse_region(gtf, true, "gene_name").unwrap().unwrap();
+        assert_eq!((region.1, region.2), (11868, 14409));
+        assert_eq!(region.3, "DDX11L1");
+        assert_eq!(gtf_attribute("ID=gene1;Name=abc", "Name"), Some("abc"));
+        assert!(is_gtf(Path::new("genes.gtf.gz")));
+        assert!(!is_gtf(Path::new("peaks.bed")));
+    }
+}
@@ -1,9 +1,24 @@
-//use rust_htslib::bam::record::Cigar;
-use log::{info, warn};
-use rust_htslib::bam::{Format, Header, Read};
-use std::collections::HashMap;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::{Aux, Cigar, CigarString, Record};
+use rust_htslib::bam::{
+    FetchDefinition, Format, Header, HeaderView, IndexedReader, Read, Reader, Writer,
+};
+use rust_lapper::Lapper;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::path::Path;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use crate::depthsummary::DepthSweep;
+use crate::provenance;
+use crate::shuffle::{self, reg2bin};
+use crate::slice;
+use crate::sort::{self, ExternalSorter};
 
 // Copying from this:
 // def shiftRead(b, chromDict, args):
@@ -114,89 +129,1023 @@ fn set_up_chromsizes(
     Ok(tids)
 }
 
-pub fn atac_shift_bam<P>(bam_input: P, bam_output: P) -> Result<(), rust_htslib::errors::Error>
-where
-    P: AsRef<Path>,
-{
-    let mut reader = rust_htslib::bam::Reader::from_path(bam_input)?;
-    let header = Header::from_template(reader.header());
-    let mut writer = rust_htslib::bam::Writer::from_path(bam_output, &header, Format::Bam)?;
-    let chrom_dict = set_up_chromsizes(reader.header()).expect("Couldn't read chromsizes");
-    let shift = vec![4, -5, 5, -4];
+/// Replace the alignment of `record` with a single match over `[start, end)`, as
+/// deeptools does. SEQ and QUAL are dropped as they no longer fit the new CIGAR.
+fn set_shifted_alignment(record: &mut Record, start: i64, end: i64) {
+    let qname = record.qname().to_owned();
+    let cigar = CigarString(vec![Cigar::Match((end - start) as u32)]);
+    record.set(&qname, Some(&cigar), &[], &[]);
+    record.set_pos(start);
+    record.set_bin(reg2bin(start, end));
+}
 
-    let mut read_counter = 0;
-    for result in reader.records() {
-        let mut record = result?;
+/// ATAC-seq (Tn5) offsets, as deeptools' `--shift`.
+pub const ATAC_SHIFT: [i64; 4] = [4, -5, 5, -4];
+
+/// Assays with known shift offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Preset {
+    /// Tn5 insertion: the 9 bp target duplication is centred with +4/-5
+    Atac,
+    /// pA-Tn5 tagmentation, so the same offsets as ATAC-seq
+    Cutntag,
+    /// MNase cleavage leaves no duplication to correct: no shift
+    Cutrun,
+    /// No shift
+    None,
+}
 
-        if record.is_proper_pair() {
+impl Preset {
+    pub fn shift(self) -> [i64; 4] {
+        match self {
+            Preset::Atac | Preset::Cutntag => ATAC_SHIFT,
+            Preset::Cutrun | Preset::None => [0; 4],
+        }
+    }
+
+    pub fn min_mapq(self) -> u8 {
+        match self {
+            Preset::Atac => 30,
+            Preset::Cutntag | Preset::Cutrun => 20,
+            Preset::None => 0,
+        }
+    }
+
+    /// SAM flags excluded by default: unmapped reads and mates, secondary and QC-failed
+    /// alignments, and duplicates for ATAC-seq only (CUT&Tag/CUT&RUN duplicates are often
+    /// genuine at high occupancy sites)
+    pub fn exclude_flags(self) -> u16 {
+        match self {
+            Preset::Atac => 1804,
+            Preset::Cutntag | Preset::Cutrun => 780,
+            Preset::None => 0,
+        }
+    }
+}
+
+/// Format of the shifted output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Bam,
+    Cram,
+    Sam,
+    /// Both shifted mates of each pair, one line per pair
+    Bedpe,
+    /// Shifted fragments (reads in single-end mode), one line each
+    Bed,
+    /// Coverage of the shifted fragments
+    Bedgraph,
+}
+
+impl OutputFormat {
+    /// Format implied by the extension of `path`, BAM if it is not recognised.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("cram") => OutputFormat::Cram,
+            Some("sam") => OutputFormat::Sam,
+            Some("bedpe") => OutputFormat::Bedpe,
+            Some("bed") => OutputFormat::Bed,
+            Some("bedgraph" | "bg") => OutputFormat::Bedgraph,
+            _ => OutputFormat::Bam,
+        }
+    }
+
+    /// htslib format for alignment output, `None` for interval output.
+    fn htslib_format(self) -> Option<Format> {
+        match self {
+            OutputFormat::Bam => Some(Format::Bam),
+            OutputFormat::Cram => Some(Format::Cram),
+            OutputFormat::Sam => Some(Format::Sam),
+            OutputFormat::Bedpe | OutputFormat::Bed | OutputFormat::Bedgraph => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShiftOptions {
+    /// Offsets (deeptools order) for the forward read 1 start, reverse read 1 end, and the
+    /// equivalents for read 2
+    pub shift: [i64; 4],
+    /// Shift every mapped primary read by strand alone (forward start by `shift[0]`,
+    /// reverse end by `shift[1]`), without proper-pair flags or TLEN adjustments
+    pub single_end: bool,
+    /// Output format; taken from the output extension if not given
+    pub output_format: Option<OutputFormat>,
+    /// Reference FASTA for CRAM input or output
+    pub reference: Option<PathBuf>,
+    /// Coordinate sort the output (shifting can reorder nearby reads)
+    pub sort: bool,
+    /// Index the sorted output (.bai, or .csi for very long references)
+    pub write_index: bool,
+    /// Only shift reads overlapping these regions (BED), read via the index
+    pub regions: Option<PathBuf>,
+    /// Write reads that are not shifted (e.g. not in proper pairs) through unmodified,
+    /// tagged `sh:i:0`, instead of discarding them
+    pub keep_unshifted: bool,
+    /// Wait for both mates of a pair to be shifted and set PNEXT and TLEN from the shifted
+    /// mate, instead of estimating them from the shift offsets
+    pub pair_aware: bool,
+    /// In pair-aware mode, mark reads whose mate was dropped or never seen as having an
+    /// unmapped mate
+    pub fixmate: bool,
+    /// Reads below this mapping quality are removed before shifting
+    pub min_mapq: u8,
+    /// Reads with any of these SAM flags set are removed before shifting
+    pub exclude_flags: u16,
+    /// Reads overlapping these regions (BED) are removed before shifting
+    pub blacklist: Option<PathBuf>,
+    /// Write reads discarded by the proper-pair or coordinate checks, unmodified, to this BAM
+    pub discarded: Option<PathBuf>,
+    /// Collapse each shifted read to its 5' base, the Tn5 insertion site
+    pub cut_sites: bool,
+    /// Count what would happen to each read and print a report instead of writing output
+    pub dry_run: bool,
+    pub threads: usize,
+}
+
+impl Default for ShiftOptions {
+    fn default() -> Self {
+        Self {
+            shift: ATAC_SHIFT,
+            single_end: false,
+            output_format: None,
+            reference: None,
+            sort: false,
+            write_index: false,
+            regions: None,
+            keep_unshifted: false,
+            pair_aware: false,
+            fixmate: false,
+            min_mapq: 0,
+            exclude_flags: 0,
+            blacklist: None,
+            discarded: None,
+            cut_sites: false,
+            dry_run: false,
+            threads: 1,
+        }
+    }
+}
+
+/// Writes shifted records as intervals rather than alignments.
+struct IntervalWriter {
+    format: OutputFormat,
+    /// Each record is an interval of its own (single-end reads or cut sites)
+    per_read: bool,
+    chroms: Vec<String>,
+    writer: Box<dyn Write>,
+    /// Fragments per reference id, collected for bedGraph output
+    fragments: Vec<Vec<(i64, i64)>>,
+}
+
+fn strand(reverse: bool) -> char {
+    match reverse {
+        true => '-',
+        false => '+',
+    }
+}
+
+impl IntervalWriter {
+    /// Span of the fragment a shifted record stands for: the read itself in single-end
+    /// or cut-site mode, otherwise the whole template, reported from its leftmost read only.
+    fn fragment(&self, record: &Record) -> Option<(i64, i64)> {
+        match self.per_read {
+            true => Some((record.pos(), record.reference_end())),
+            false if record.insert_size() > 0 => {
+                Some((record.pos(), record.pos() + record.insert_size()))
+            }
+            false => None,
+        }
+    }
+
+    fn write(&mut self, record: &Record) -> Result<()> {
+        let (start, end) = match self.fragment(record) {
+            Some(fragment) => fragment,
+            None => return Ok(()),
+        };
+        let tid = record.tid() as usize;
+        let chrom = &self.chroms[tid];
+        let name = String::from_utf8_lossy(record.qname());
+        match self.format {
+            OutputFormat::Bedpe => writeln!(
+                self.writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t0\t{}\t{}",
+                chrom,
+                record.pos(),
+                record.reference_end(),
+                chrom,
+                record.mpos(),
+                end,
+                name,
+                strand(record.is_reverse()),
+                strand(record.is_mate_reverse()),
+            )?,
+            OutputFormat::Bed => {
+                let strand = match self.per_read {
+                    true => strand(record.is_reverse()),
+                    false => '.',
+                };
+                writeln!(
+                    self.writer,
+                    "{}\t{}\t{}\t{}\t0\t{}",
+                    chrom, start, end, name, strand
+                )?
+            }
+            _ => self.fragments[tid].push((start, end)),
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        for (tid, mut fragments) in std::mem::take(&mut self.fragments).into_iter().enumerate() {
+            fragments.sort_unstable();
+            let mut runs: Vec<(i64, i64, u32)> = Vec::new();
+            let mut sweep = DepthSweep::new(|start, end, depth| match runs.last_mut() {
+                Some(last) if last.1 == start && last.2 == depth => last.1 = end,
+                _ => runs.push((start, end, depth)),
+            });
+            for &(start, end) in &fragments {
+                sweep.add_read(start, [[start, end]]);
+            }
+            let length = fragments.iter().map(|f| f.1).max().unwrap_or(0);
+            sweep.finish(length as u64);
+            for (start, end, depth) in runs.into_iter().filter(|run| run.2 > 0) {
+                writeln!(
+                    self.writer,
+                    "{}\t{}\t{}\t{}",
+                    self.chroms[tid], start, end, depth
+                )?;
+            }
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Where shifted records go: straight to an alignment writer, through an external sort
+/// first, or out as intervals.
+enum ShiftedOutput {
+    Alignments {
+        writer: Writer,
+        sorter: Option<ExternalSorter>,
+    },
+    Intervals(IntervalWriter),
+    /// Nothing is written (`--dry-run`)
+    Discard,
+}
+
+impl ShiftedOutput {
+    fn write(&mut self, record: Record) -> Result<()> {
+        match self {
+            ShiftedOutput::Alignments {
+                sorter: Some(sorter),
+                ..
+            } => sorter.push(record),
+            ShiftedOutput::Alignments { writer, .. } => Ok(writer.write(&record)?),
+            ShiftedOutput::Intervals(intervals) => intervals.write(&record),
+            ShiftedOutput::Discard => Ok(()),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            ShiftedOutput::Alignments {
+                mut writer,
+                sorter: Some(sorter),
+            } => sorter.finish(&mut writer),
+            ShiftedOutput::Alignments { .. } => Ok(()),
+            ShiftedOutput::Intervals(intervals) => intervals.finish(),
+            ShiftedOutput::Discard => Ok(()),
+        }
+    }
+}
+
+/// What happened to a record passed through the shift.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShiftOutcome {
+    /// Removed by the MAPQ, flag or blacklist filters
+    Filtered,
+    /// Not eligible for shifting (e.g. not a proper pair)
+    Skipped,
+    /// Removed by the coordinate sanity check
+    Dropped,
+    Shifted,
+    /// Shifted, but cut back at a chromosome end
+    Clipped,
+}
+
+/// Reads per shift outcome, reported by `--dry-run`.
+#[derive(Debug, Clone, Copy, Default)]
+struct ShiftCounts {
+    filtered: u64,
+    skipped: u64,
+    dropped: u64,
+    shifted: u64,
+    clipped: u64,
+}
+
+impl ShiftCounts {
+    fn add(&mut self, outcome: ShiftOutcome) {
+        match outcome {
+            ShiftOutcome::Filtered => self.filtered += 1,
+            ShiftOutcome::Skipped => self.skipped += 1,
+            ShiftOutcome::Dropped => self.dropped += 1,
+            ShiftOutcome::Shifted => self.shifted += 1,
+            ShiftOutcome::Clipped => self.clipped += 1,
+        }
+    }
+
+    fn report(&self) {
+        println!("filtered\t{}", self.filtered);
+        println!("not_shifted\t{}", self.skipped);
+        println!("shifted\t{}", self.shifted + self.clipped);
+        println!("clipped_at_chromosome_end\t{}", self.clipped);
+        println!("dropped_by_sanity_check\t{}", self.dropped);
+    }
+}
+
+/// Outcome of a shift to the sanity-checked `[start, end)`: reads touching a chromosome
+/// end are reported as clipped there.
+fn shifted_outcome(start: i64, end: i64, chromsize: i64) -> ShiftOutcome {
+    match start == 0 || end == chromsize {
+        true => ShiftOutcome::Clipped,
+        false => ShiftOutcome::Shifted,
+    }
+}
+
+/// Applies the shift offsets to single records.
+struct Shifter {
+    shift: [i64; 4],
+    single_end: bool,
+    chrom_dict: HashMap<u32, u64>,
+    min_mapq: u8,
+    exclude_flags: u16,
+    blacklist: HashMap<u32, Lapper<u64, u64>>,
+    cut_sites: bool,
+}
+
+impl Shifter {
+    fn new(header: &HeaderView, options: &ShiftOptions) -> Result<Self> {
+        let blacklist = match &options.blacklist {
+            Some(path) => shuffle::read_blacklist(path, header)?,
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            shift: options.shift,
+            single_end: options.single_end,
+            chrom_dict: set_up_chromsizes(header).expect("Couldn't read chromsizes"),
+            min_mapq: options.min_mapq,
+            exclude_flags: options.exclude_flags,
+            blacklist,
+            cut_sites: options.cut_sites,
+        })
+    }
+
+    /// Write the shifted `[start, end)` to `record`, or just its 5' base for cut sites.
+    fn set_alignment(&self, record: &mut Record, start: i64, end: i64, reverse: bool) {
+        match (self.cut_sites, reverse) {
+            (true, true) => set_shifted_alignment(record, end - 1, end),
+            (true, false) => set_shifted_alignment(record, start, start + 1),
+            (false, _) => set_shifted_alignment(record, start, end),
+        }
+    }
+
+    /// Whether `record` fails the MAPQ or flag filters or overlaps the blacklist.
+    fn is_filtered(&self, record: &Record) -> bool {
+        if record.flags() & self.exclude_flags != 0 || record.mapq() < self.min_mapq {
+            return true;
+        }
+        match self.blacklist.get(&(record.tid() as u32)) {
+            Some(tree) if record.tid() >= 0 && !record.is_unmapped() => tree
+                .find(record.pos() as u64, record.reference_end() as u64)
+                .next()
+                .is_some(),
+            _ => false,
+        }
+    }
+
+    fn chromsize(&self, record: &Record) -> i64 {
+        *self
+            .chrom_dict
+            .get(&(record.tid() as u32))
+            .expect("Missing chromsize") as i64
+    }
+
+    /// Shift `record` in place.
+    fn shift(&self, record: &mut Record) -> ShiftOutcome {
+        if self.is_filtered(record) {
+            return ShiftOutcome::Filtered;
+        }
+        let shift = self.shift;
+        if self.single_end {
+            if record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
+                return ShiftOutcome::Skipped;
+            }
+            // The aligned span, so soft clips and indels don't move the shifted end
+            let mut start = record.pos();
+            let mut end = record.reference_end();
+            let reverse = record.is_reverse();
+
+            match reverse {
+                true => end += shift[1],
+                false => start += shift[0],
+            }
+            let chromsize = self.chromsize(record);
+            match sanity_check_coordinates(start, end, reverse, chromsize) {
+                Some((start, end)) => {
+                    self.set_alignment(record, start, end, reverse);
+                    shifted_outcome(start, end, chromsize)
+                }
+                None => ShiftOutcome::Dropped,
+            }
+        } else if record.is_proper_pair() {
             let mut tlen = record.insert_size();
             let mut start = record.pos();
-            let mut end = (start as usize + record.seq_len()) as i64;
+            let mut end = record.reference_end();
             let reverse = record.is_reverse();
             let first_in_template = record.is_first_in_template();
-            let chromsize = chrom_dict
-                .get(&(record.tid() as u32))
-                .expect("Missing chromsize");
 
             let dtlen = match (reverse, first_in_template) {
                 (true, true) => {
-                    end += shift[1];
-                    shift[1] - shift[0]
-                }
-                (true, false) => {
                     end -= shift[2];
                     shift[3] - shift[2]
                 }
-                (false, true) => {
-                    start -= shift[3];
-                    shift[3] - shift[2]
+                (true, false) => {
+                    end += shift[1];
+                    shift[1] - shift[0]
                 }
-                (false, false) => {
+                (false, true) => {
                     start += shift[0];
                     shift[1] - shift[0]
                 }
+                (false, false) => {
+                    start -= shift[3];
+                    shift[3] - shift[2]
+                }
             };
 
-            if let Some((start, _end)) =
-                sanity_check_coordinates(start, end, reverse, *chromsize as i64)
-            {
-                // Edit the record
-                record.set_pos(start);
+            let chromsize = self.chromsize(record);
+            let (start, end) = match sanity_check_coordinates(start, end, reverse, chromsize) {
+                Some(coordinates) => coordinates,
+                None => return ShiftOutcome::Dropped,
+            };
+            // Edit the record
+            self.set_alignment(record, start, end, reverse);
 
-                if tlen > 0 {
-                    tlen += dtlen;
-                } else {
-                    tlen -= dtlen;
+            if tlen > 0 {
+                tlen += dtlen;
+            } else {
+                tlen -= dtlen;
+            }
+            record.set_insert_size(tlen);
+
+            // The mate of a reverse read is forward, so its start moved
+            match (reverse, first_in_template) {
+                (true, true) => {
+                    let mpos = record.mpos() - shift[3];
+                    record.set_mpos(mpos)
+                }
+                (true, false) => {
+                    let mpos = record.mpos() + shift[0];
+                    record.set_mpos(mpos)
                 }
-                record.set_insert_size(tlen);
+                _ => {}
+            };
+            shifted_outcome(start, end, chromsize)
+        } else {
+            ShiftOutcome::Skipped
+        }
+    }
+}
+
+/// Point `record` at its shifted `mate`: PNEXT, mate strand, MC and a TLEN spanning both
+/// shifted reads (positive for the leftmost read).
+fn set_mate_fields(record: &mut Record, mate: &Record) -> Result<()> {
+    record.set_mtid(mate.tid());
+    record.set_mpos(mate.pos());
+    match mate.is_reverse() {
+        true => record.set_mate_reverse(),
+        false => record.unset_mate_reverse(),
+    }
+    let start = record.pos().min(mate.pos());
+    let end = record.reference_end().max(mate.reference_end());
+    let leftmost =
+        (record.pos(), !record.is_first_in_template()) < (mate.pos(), !mate.is_first_in_template());
+    record.set_insert_size(match leftmost {
+        true => end - start,
+        false => start - end,
+    });
+    if record.aux(b"MC").is_ok() {
+        record.remove_aux(b"MC")?;
+        record.push_aux(b"MC", Aux::String(&mate.cigar().to_string()))?;
+    }
+    Ok(())
+}
 
-                match (reverse, first_in_template) {
-                    (true, true) => {
-                        let mpos = record.mpos() + shift[0];
-                        record.set_mpos(mpos)
+/// Mark `record` as having an unmapped mate, as `samtools fixmate` does.
+fn set_mate_missing(record: &mut Record) -> Result<()> {
+    record.unset_proper_pair();
+    record.set_mate_unmapped();
+    record.set_mtid(record.tid());
+    record.set_mpos(record.pos());
+    record.set_insert_size(0);
+    if record.aux(b"MC").is_ok() {
+        record.remove_aux(b"MC")?;
+    }
+    Ok(())
+}
+
+/// Holds shifted primary reads until their mate has been shifted too, so both mates' PNEXT
+/// and TLEN describe the shifted pair. Reads whose mate never arrives (or is dropped) are
+/// released as they are, or with the mate marked unmapped when `fixmate` is set.
+struct MateBuffer {
+    pending: HashMap<Vec<u8>, Record>,
+    dropped: HashSet<Vec<u8>>,
+    fixmate: bool,
+}
+
+impl MateBuffer {
+    fn new(fixmate: bool) -> Self {
+        Self {
+            pending: HashMap::new(),
+            dropped: HashSet::new(),
+            fixmate,
+        }
+    }
+
+    fn orphan(&self, mut record: Record) -> Result<Record> {
+        if self.fixmate {
+            set_mate_missing(&mut record)?;
+        }
+        Ok(record)
+    }
+
+    /// Add a shifted read, returning the reads that are ready to write.
+    fn add(&mut self, mut record: Record) -> Result<Vec<Record>> {
+        if !record.is_paired() || record.is_secondary() || record.is_supplementary() {
+            return Ok(vec![record]);
+        }
+        let name = record.qname().to_vec();
+        if self.dropped.remove(&name) {
+            return Ok(vec![self.orphan(record)?]);
+        }
+        match self.pending.remove(&name) {
+            Some(mut mate) => {
+                set_mate_fields(&mut mate, &record)?;
+                set_mate_fields(&mut record, &mate)?;
+                Ok(vec![mate, record])
+            }
+            None => {
+                self.pending.insert(name, record);
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Note a read dropped by the shift, returning its mate if that was waiting for it.
+    fn drop_read(&mut self, record: &Record) -> Result<Option<Record>> {
+        if record.is_secondary() || record.is_supplementary() {
+            return Ok(None);
+        }
+        match self.pending.remove(record.qname()) {
+            Some(mate) => Ok(Some(self.orphan(mate)?)),
+            None => {
+                self.dropped.insert(record.qname().to_vec());
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reads whose mate never arrived.
+    fn finish(mut self) -> Result<Vec<Record>> {
+        let mut orphans: Vec<Record> = std::mem::take(&mut self.pending).into_values().collect();
+        orphans.sort_by_key(|r| (r.tid() as u32, r.pos()));
+        orphans
+            .into_iter()
+            .map(|record| self.orphan(record))
+            .collect()
+    }
+}
+
+/// Shifts records into an output, reporting progress.
+struct ShiftRun {
+    shifter: Arc<Shifter>,
+    output: ShiftedOutput,
+    /// Set in pair-aware mode
+    mates: Option<MateBuffer>,
+    /// Unmodified reads that failed the proper-pair or coordinate checks
+    discarded: Option<Writer>,
+    counts: ShiftCounts,
+    read_counter: i64,
+    keep_unshifted: bool,
+    quiet: bool,
+}
+
+impl ShiftRun {
+    fn new(
+        header: &HeaderView,
+        bam_output: &Path,
+        format: OutputFormat,
+        options: &ShiftOptions,
+    ) -> Result<Self> {
+        let to_stdout = is_stdio(bam_output);
+        Ok(Self {
+            shifter: Arc::new(Shifter::new(header, options)?),
+            output: match options.dry_run {
+                true => ShiftedOutput::Discard,
+                false => open_output(header, bam_output, format, options)?,
+            },
+            mates: (options.pair_aware && !options.dry_run)
+                .then(|| MateBuffer::new(options.fixmate)),
+            discarded: match (&options.discarded, options.dry_run) {
+                (Some(path), false) => Some(open_discarded(header, path, options)?),
+                _ => None,
+            },
+            counts: ShiftCounts::default(),
+            read_counter: 0,
+            keep_unshifted: options.keep_unshifted,
+            quiet: to_stdout || options.dry_run,
+        })
+    }
+
+    fn process(&mut self, mut record: Record) -> Result<()> {
+        let outcome = self.shifter.shift(&mut record);
+        self.handle(record, outcome)
+    }
+
+    /// Write (or hold back) a record the shifter has already been applied to.
+    fn handle(&mut self, mut record: Record, outcome: ShiftOutcome) -> Result<()> {
+        self.counts.add(outcome);
+        match outcome {
+            ShiftOutcome::Skipped if self.keep_unshifted => {
+                record.push_aux(b"sh", Aux::I32(0))?;
+                return self.output.write(record);
+            }
+            ShiftOutcome::Skipped => {
+                if let Some(discarded) = &mut self.discarded {
+                    discarded.write(&record)?;
+                }
+                return Ok(());
+            }
+            ShiftOutcome::Filtered => {
+                if let Some(mates) = &mut self.mates {
+                    if let Some(orphan) = mates.drop_read(&record)? {
+                        self.output.write(orphan)?;
                     }
-                    (true, false) => {
-                        let mpos = record.mpos() - shift[3];
-                        record.set_mpos(mpos)
+                }
+                return Ok(());
+            }
+            ShiftOutcome::Dropped => {
+                if let Some(mates) = &mut self.mates {
+                    if let Some(orphan) = mates.drop_read(&record)? {
+                        self.output.write(orphan)?;
                     }
-                    _ => {}
-                };
+                }
+                if let Some(discarded) = &mut self.discarded {
+                    discarded.write(&record)?;
+                }
+            }
+            ShiftOutcome::Shifted | ShiftOutcome::Clipped => match &mut self.mates {
+                Some(mates) => {
+                    for ready in mates.add(record)? {
+                        self.output.write(ready)?;
+                    }
+                }
+                None => self.output.write(record)?,
+            },
+        }
+        self.read_counter += 1;
+        if self.read_counter % 100000 == 0 && !self.quiet {
+            println!("Shifted {} reads", self.read_counter);
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<ShiftCounts> {
+        if let Some(mates) = self.mates.take() {
+            for orphan in mates.finish()? {
+                self.output.write(orphan)?;
+            }
+        }
+        self.output.finish()?;
+        Ok(self.counts)
+    }
+}
+
+fn open_discarded(header: &HeaderView, path: &Path, options: &ShiftOptions) -> Result<Writer> {
+    let mut header = Header::from_template(header);
+    provenance::add_program_record(
+        &mut header,
+        "shift: reads discarded by the proper-pair or coordinate checks",
+    );
+    let mut writer = Writer::from_path(path, &header, Format::Bam)?;
+    if options.threads > 1 {
+        writer.set_threads(options.threads)?;
+    }
+    Ok(writer)
+}
+
+/// @PG description recording the offsets and filters applied.
+fn program_description(options: &ShiftOptions) -> String {
+    let offsets: Vec<String> = options.shift.iter().map(|o| o.to_string()).collect();
+    let mut description = format!(
+        "shift: Tn5 offset correction, {} offsets {}",
+        match options.single_end {
+            true => "single-end",
+            false => "paired-end",
+        },
+        offsets.join(",")
+    );
+    if options.min_mapq > 0 {
+        description.push_str(&format!(", min MAPQ {}", options.min_mapq));
+    }
+    if options.exclude_flags > 0 {
+        description.push_str(&format!(", excluded flags {}", options.exclude_flags));
+    }
+    if let Some(blacklist) = &options.blacklist {
+        description.push_str(&format!(", blacklist {}", blacklist.display()));
+    }
+    if options.cut_sites {
+        description.push_str(", collapsed to cut sites");
+    }
+    description
+}
+
+/// `-` reads from stdin or writes to stdout.
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+fn open_output(
+    header: &HeaderView,
+    bam_output: &Path,
+    format: OutputFormat,
+    options: &ShiftOptions,
+) -> Result<ShiftedOutput> {
+    let to_stdout = is_stdio(bam_output);
+    let output = match format.htslib_format() {
+        Some(htslib_format) => {
+            let mut header = match options.sort {
+                true => Header::from_template(&sort::with_sort_order(header, "coordinate")),
+                false => Header::from_template(header),
+            };
+            provenance::add_program_record(&mut header, &program_description(options));
+            let mut writer = match to_stdout {
+                true => Writer::from_stdout(&header, htslib_format)?,
+                false => Writer::from_path(bam_output, &header, htslib_format)?,
+            };
+            if let (OutputFormat::Cram, Some(reference)) = (format, &options.reference) {
+                writer.set_reference(reference)?;
+            }
+            if options.threads > 1 {
+                writer.set_threads(options.threads)?;
+            }
+            let sorter = match options.sort {
+                true => Some(ExternalSorter::new(&header, sort::DEFAULT_CHUNK_SIZE)?),
+                false => None,
+            };
+            ShiftedOutput::Alignments { writer, sorter }
+        }
+        None => {
+            let writer: Box<dyn Write> = match to_stdout {
+                true => Box::new(BufWriter::new(io::stdout())),
+                false => Box::new(BufWriter::new(File::create(bam_output)?)),
+            };
+            let chroms: Vec<String> = header
+                .target_names()
+                .iter()
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .collect();
+            ShiftedOutput::Intervals(IntervalWriter {
+                format,
+                per_read: options.single_end || options.cut_sites,
+                fragments: vec![Vec::new(); chroms.len()],
+                chroms,
+                writer,
+            })
+        }
+    };
+    Ok(output)
+}
+
+/// Records read per batch by the per-chromosome workers.
+const BATCH_SIZE: usize = 100_000;
+
+type ShiftedBatch = Vec<(Record, ShiftOutcome)>;
+
+/// Shift the chromosomes of the indexed `bam_input` on `options.threads` workers, each
+/// with its own reader. Batches are handed to `run` in chromosome order (unplaced reads
+/// last), so the output matches a single-threaded run; batches of chromosomes finished
+/// ahead of the one being written are held in memory until their turn.
+fn shift_chromosomes_parallel(
+    bam_input: &Path,
+    header: &HeaderView,
+    run: &mut ShiftRun,
+    options: &ShiftOptions,
+) -> Result<()> {
+    let targets: Vec<Option<u32>> = (0..header.target_count())
+        .map(Some)
+        .chain(std::iter::once(None))
+        .collect();
+    let (target_sender, target_recv) = crossbeam::channel::unbounded::<(usize, Option<u32>)>();
+    let (batch_sender, batch_recv) =
+        crossbeam::channel::unbounded::<(usize, Option<ShiftedBatch>)>();
+
+    let mut handles = Vec::new();
+    for _ in 0..options.threads {
+        let target_recv = target_recv.clone();
+        let batch_sender = batch_sender.clone();
+        let shifter = Arc::clone(&run.shifter);
+        let bam = bam_input.to_path_buf();
+        let reference = options.reference.clone();
+
+        handles.push(thread::spawn(move || -> Result<()> {
+            let mut reader = IndexedReader::from_path(&bam)?;
+            if let Some(reference) = &reference {
+                reader.set_reference(reference)?;
+            }
+            let send = |message| {
+                batch_sender
+                    .send(message)
+                    .map_err(|_| anyhow!("Shifted records could not be handed to the writer"))
+            };
+            for (i, target) in target_recv {
+                match target {
+                    Some(tid) => reader.fetch(tid)?,
+                    None => reader.fetch(FetchDefinition::Unmapped)?,
+                }
+                let mut batch = Vec::with_capacity(BATCH_SIZE);
+                for result in reader.records() {
+                    let mut record = result?;
+                    let outcome = shifter.shift(&mut record);
+                    batch.push((record, outcome));
+                    if batch.len() == BATCH_SIZE {
+                        send((i, Some(std::mem::take(&mut batch))))?;
+                    }
+                }
+                send((i, Some(batch)))?;
+                // Marks the end of target i
+                send((i, None))?;
+            }
+            Ok(())
+        }));
+    }
+    for target in targets.into_iter().enumerate() {
+        target_sender.send(target)?;
+    }
+    drop(target_sender);
+    drop(batch_sender);
 
-                writer.write(&record)?;
+    let mut next = 0;
+    let mut pending: HashMap<usize, Vec<ShiftedBatch>> = HashMap::new();
+    let mut finished: HashSet<usize> = HashSet::new();
+    for (i, batch) in batch_recv {
+        match batch {
+            Some(batch) => pending.entry(i).or_default().push(batch),
+            None => {
+                finished.insert(i);
+            }
+        }
+        loop {
+            for batch in pending.remove(&next).unwrap_or_default() {
+                for (record, outcome) in batch {
+                    run.handle(record, outcome)?;
+                }
             }
-            // Update counter
-            read_counter += 1;
-            if read_counter % 100000 == 0 {
-                println!("Shifted {} reads", read_counter);
+            match finished.remove(&next) {
+                true => next += 1,
+                false => break,
             }
         }
     }
 
+    for handle in handles {
+        handle.join().expect("Failed to join shift thread")?;
+    }
+    Ok(())
+}
+
+/// Shift every read of `bam_input` (a file or `-`) in file order.
+fn shift_stream(
+    bam_input: &Path,
+    bam_output: &Path,
+    format: OutputFormat,
+    options: &ShiftOptions,
+) -> Result<ShiftCounts> {
+    let mut reader = match is_stdio(bam_input) {
+        true => Reader::from_stdin()?,
+        false => Reader::from_path(bam_input)?,
+    };
+    if let Some(reference) = &options.reference {
+        reader.set_reference(reference)?;
+    }
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+    let mut run = ShiftRun::new(reader.header(), bam_output, format, options)?;
+    for result in reader.records() {
+        run.process(result?)?;
+    }
+    run.finish()
+}
+
+/// Shift the reads of `bam_input` (BAM or CRAM) and write them to `bam_output` as
+/// alignments or intervals, either of which may be `-` for use in a pipe. Progress is not
+/// reported when writing to stdout. With `regions`, only reads overlapping them are read
+/// (via the index) and shifted.
+pub fn atac_shift_bam<P>(bam_input: P, bam_output: P, options: &ShiftOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let format = options
+        .output_format
+        .unwrap_or_else(|| OutputFormat::from_path(bam_output.as_ref()));
+    if format == OutputFormat::Cram && options.reference.is_none() {
+        bail!("CRAM output requires a reference FASTA (--reference)");
+    }
+
+    let to_stdout = is_stdio(bam_output.as_ref());
+    if options.write_index && (to_stdout || !options.sort) {
+        bail!("--write-index needs sorted output written to a file (--sort)");
+    }
+    if format.htslib_format().is_none() && options.sort {
+        bail!("--sort and --write-index only apply to BAM, CRAM or SAM output");
+    }
+    if format.htslib_format().is_none() && options.keep_unshifted {
+        bail!("--keep-unshifted only applies to BAM, CRAM or SAM output");
+    }
+    if options.pair_aware && options.single_end {
+        bail!("Pair-aware shifting needs paired-end reads");
+    }
+    if format == OutputFormat::Bedpe && options.single_end {
+        bail!("BEDPE output needs paired-end reads");
+    }
+    if format == OutputFormat::Bedpe && options.cut_sites {
+        bail!("Cut sites are single positions, so cannot be written as BEDPE");
+    }
+
+    let counts = match &options.regions {
+        Some(regions) => {
+            if is_stdio(bam_input.as_ref()) {
+                bail!("--regions needs an indexed file rather than stdin");
+            }
+            let mut reader = IndexedReader::from_path(&bam_input)
+                .context("Could not open indexed BAM file (--regions needs an index)")?;
+            if let Some(reference) = &options.reference {
+                reader.set_reference(reference)?;
+            }
+            if options.threads > 1 {
+                reader.set_threads(options.threads)?;
+            }
+            let header = reader.header().clone();
+            let regions = slice::read_regions(regions, &header)?;
+            let mut run = ShiftRun::new(&header, bam_output.as_ref(), format, options)?;
+
+            let mut previous: Option<(u32, i64)> = None;
+            for &(tid, start, end) in &regions {
+                reader.fetch((tid, start, end))?;
+                for result in reader.records() {
+                    let record = result?;
+                    // Reads spanning two regions were already taken from the previous one
+                    if let Some((previous_tid, previous_end)) = previous {
+                        if previous_tid == tid && record.pos() < previous_end {
+                            continue;
+                        }
+                    }
+                    run.process(record)?;
+                }
+                previous = Some((tid, end));
+            }
+            run.finish()?
+        }
+        // With an index, chromosomes are decoded and shifted in parallel
+        None if options.threads > 1 && !is_stdio(bam_input.as_ref()) => {
+            match IndexedReader::from_path(&bam_input) {
+                Ok(reader) => {
+                    let header = reader.header().clone();
+                    let mut run = ShiftRun::new(&header, bam_output.as_ref(), format, options)?;
+                    shift_chromosomes_parallel(bam_input.as_ref(), &header, &mut run, options)?;
+                    run.finish()?
+                }
+                Err(_) => shift_stream(bam_input.as_ref(), bam_output.as_ref(), format, options)?,
+            }
+        }
+        None => shift_stream(bam_input.as_ref(), bam_output.as_ref(), format, options)?,
+    };
+
+    if options.dry_run {
+        counts.report();
+    } else if options.write_index {
+        sort::index_bam(bam_output, options.threads)?;
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use rust_htslib::bam::ext::BamRecordExtensions;
+    use rust_htslib::bam::record::{Aux, Cigar, CigarString, Record};
+    use rust_htslib::bam::HeaderView;
     use tempdir::TempDir;
 
     use crate::atac_shift_bam;
@@ -213,10 +1162,201 @@ mod tests {
             let result = atac_shift_bam::atac_shift_bam(
                 bam,
                 out.as_path().to_str().expect("Cannot convert"),
+                &atac_shift_bam::ShiftOptions::default(),
             );
             let out_path = out.exists();
-            assert_eq!(result.is_ok(), true);
-            assert_eq!(out_path, true);
+            assert!(result.is_ok());
+            assert!(out_path);
+        }
+    }
+
+    #[test]
+    fn shift_bam_single_end() {
+        let bam = "test/test.bam";
+        let tmp = TempDir::new("shift_bam_single_end").expect("Failed to make tmpdir");
+        let out = tmp.path().join("test.bam");
+
+        let options = atac_shift_bam::ShiftOptions {
+            single_end: true,
+            ..Default::default()
+        };
+        let result = atac_shift_bam::atac_shift_bam(
+            bam,
+            out.as_path().to_str().expect("Cannot convert"),
+            &options,
+        );
+        assert!(result.is_ok());
+        assert!(out.exists());
+    }
+
+    #[test]
+    fn shifted_alignment_is_consistent() {
+        let cigar = CigarString(vec![Cigar::SoftClip(5), Cigar::Match(45)]);
+        let mut record = Record::new();
+        record.set(b"read", Some(&cigar), &[b'A'; 50], &[30; 50]);
+        record.push_aux(b"NM", Aux::I32(1)).unwrap();
+        record.unset_unmapped();
+        record.set_pos(100);
+
+        atac_shift_bam::set_shifted_alignment(&mut record, 104, 150);
+        assert_eq!(record.pos(), 104);
+        assert_eq!(record.reference_end(), 150);
+        assert_eq!(record.cigar().to_string(), "46M");
+        assert_eq!(record.seq_len(), 0);
+        assert_eq!(record.qname(), b"read");
+        assert!(record.aux(b"NM").is_ok());
+    }
+
+    #[test]
+    fn shift_uses_aligned_end() {
+        let header = HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:1000\n");
+        let options = atac_shift_bam::ShiftOptions {
+            single_end: true,
+            shift: atac_shift_bam::ATAC_SHIFT,
+            ..Default::default()
+        };
+        let shifter = atac_shift_bam::Shifter::new(&header, &options).unwrap();
+
+        // 50 bases of sequence: 10 soft clipped, 40 aligned around a 5 bp deletion
+        let cigar = CigarString(vec![
+            Cigar::SoftClip(10),
+            Cigar::Match(20),
+            Cigar::Del(5),
+            Cigar::Match(20),
+        ]);
+        let mut record = Record::new();
+        record.set(b"read", Some(&cigar), &[b'A'; 50], &[30; 50]);
+        record.unset_unmapped();
+        record.set_tid(0);
+        record.set_pos(100);
+        record.set_reverse();
+
+        // The read spans 100-145 on the reference, not 100-150
+        shifter.shift(&mut record);
+        assert_eq!((record.pos(), record.reference_end()), (100, 140));
+    }
+
+    #[test]
+    fn paired_offsets_follow_deeptools_order() {
+        let header = HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:1000\n");
+        let options = atac_shift_bam::ShiftOptions {
+            shift: [1, -2, 3, -4],
+            ..Default::default()
+        };
+        let shifter = atac_shift_bam::Shifter::new(&header, &options).unwrap();
+
+        let read = |first: bool, reverse: bool, pos: i64, mpos: i64, tlen: i64| {
+            let cigar = CigarString(vec![Cigar::Match(50)]);
+            let mut record = Record::new();
+            record.set(b"pair", Some(&cigar), &[b'A'; 50], &[30; 50]);
+            record.unset_unmapped();
+            record.set_paired();
+            record.set_proper_pair();
+            record.set_tid(0);
+            record.set_mtid(0);
+            record.set_pos(pos);
+            record.set_mpos(mpos);
+            record.set_insert_size(tlen);
+            match first {
+                true => record.set_first_in_template(),
+                false => record.set_last_in_template(),
+            }
+            if reverse {
+                record.set_reverse();
+            }
+            shifter.shift(&mut record);
+            (
+                record.pos(),
+                record.reference_end(),
+                record.mpos(),
+                record.insert_size(),
+            )
+        };
+
+        // Forward read 1 and reverse read 2: the fragment becomes 101-348
+        assert_eq!(read(true, false, 100, 300, 250), (101, 150, 300, 247));
+        assert_eq!(read(false, true, 300, 100, -250), (300, 348, 101, -247));
+        // Reverse read 1 and forward read 2: the fragment becomes 104-347
+        assert_eq!(read(true, true, 300, 100, -250), (300, 347, 104, -243));
+        assert_eq!(read(false, false, 100, 300, 250), (104, 150, 300, 243));
+    }
+
+    #[test]
+    fn cut_sites_are_five_prime_ends() {
+        let header = HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:1000\n");
+        let options = atac_shift_bam::ShiftOptions {
+            single_end: true,
+            cut_sites: true,
+            ..Default::default()
+        };
+        let shifter = atac_shift_bam::Shifter::new(&header, &options).unwrap();
+
+        let cigar = CigarString(vec![Cigar::Match(50)]);
+        for (reverse, site) in [(false, 104), (true, 144)] {
+            let mut record = Record::new();
+            record.set(b"read", Some(&cigar), &[b'A'; 50], &[30; 50]);
+            record.unset_unmapped();
+            record.set_tid(0);
+            record.set_pos(100);
+            if reverse {
+                record.set_reverse();
+            }
+            shifter.shift(&mut record);
+            assert_eq!((record.pos(), record.reference_end()), (site, site + 1));
         }
     }
+
+    #[test]
+    fn shift_bam_to_bedpe() {
+        let tmp = TempDir::new("shift_bam_bedpe").expect("Failed to make tmpdir");
+        let out = tmp.path().join("test.bedpe");
+        assert_eq!(
+            atac_shift_bam::OutputFormat::from_path(&out),
+            atac_shift_bam::OutputFormat::Bedpe
+        );
+
+        atac_shift_bam::atac_shift_bam(
+            std::path::Path::new("test/test.bam"),
+            &out,
+            &atac_shift_bam::ShiftOptions::default(),
+        )
+        .unwrap();
+        let text = std::fs::read_to_string(&out).unwrap();
+        let first: Vec<&str> = text.lines().next().unwrap().split('\t').collect();
+        assert_eq!(first.len(), 10);
+        let (start, mate_end): (i64, i64) = (first[1].parse().unwrap(), first[5].parse().unwrap());
+        assert!(start < mate_end);
+    }
+
+    #[test]
+    fn pair_aware_mates_agree() {
+        let read = |first: bool, pos: i64, length: u32| {
+            let cigar = CigarString(vec![Cigar::Match(length)]);
+            let mut record = Record::new();
+            record.set(b"pair", Some(&cigar), &[], &[]);
+            record.unset_unmapped();
+            record.set_paired();
+            record.set_proper_pair();
+            match first {
+                true => record.set_first_in_template(),
+                false => record.set_reverse(),
+            }
+            record.set_pos(pos);
+            record
+        };
+
+        let mut mates = atac_shift_bam::MateBuffer::new(false);
+        assert!(mates.add(read(true, 104, 46)).unwrap().is_empty());
+        let pair = mates.add(read(false, 300, 45)).unwrap();
+        assert_eq!(pair.len(), 2);
+        assert_eq!((pair[0].mpos(), pair[0].insert_size()), (300, 241));
+        assert_eq!((pair[1].mpos(), pair[1].insert_size()), (104, -241));
+        assert!(pair[0].is_mate_reverse());
+
+        let mut mates = atac_shift_bam::MateBuffer::new(true);
+        assert!(mates.add(read(true, 104, 46)).unwrap().is_empty());
+        let orphan = mates.drop_read(&read(false, 300, 45)).unwrap().unwrap();
+        assert!(orphan.is_mate_unmapped() && !orphan.is_proper_pair());
+        assert!(mates.finish().unwrap().is_empty());
+    }
 }
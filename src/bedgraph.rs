@@ -0,0 +1,144 @@
+//! bedGraph to bigWig conversion.
+
+use anyhow::{bail, Context, Result};
+use flate2::read::MultiGzDecoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::bigwig::BigWigWriter;
+
+fn open_bedgraph(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open bedGraph `{}`", path.display()))?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => Ok(Box::new(BufReader::new(MultiGzDecoder::new(file)))),
+        _ => Ok(Box::new(BufReader::new(file))),
+    }
+}
+
+/// Whether a line carries no data (blank, comment or UCSC track/browser line).
+fn is_header_line(line: &str) -> bool {
+    line.trim().is_empty()
+        || line.starts_with('#')
+        || line.starts_with("track")
+        || line.starts_with("browser")
+}
+
+fn parse_line(line: &str) -> Result<(&str, u64, u64, f32)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 4 {
+        bail!("Malformed bedGraph line: {}", line);
+    }
+    let parse = || -> Result<(u64, u64, f32)> {
+        Ok((fields[1].parse()?, fields[2].parse()?, fields[3].parse()?))
+    };
+    let (start, end, value) =
+        parse().with_context(|| format!("Malformed bedGraph line: {}", line))?;
+    Ok((fields[0], start, end, value))
+}
+
+/// Reorder `chroms` so that those present in the bedGraph come first, in the order they
+/// appear there. The bigWig writer needs intervals in chromosome order, so this lets
+/// files sorted with either `sort -k1,1` or the BAM header order convert unchanged.
+fn chrom_order(path: &Path, chroms: Vec<(String, u64)>) -> Result<Vec<(String, u64)>> {
+    let mut seen: Vec<String> = Vec::new();
+    for line in open_bedgraph(path)?.lines() {
+        let line = line?;
+        if is_header_line(&line) {
+            continue;
+        }
+        let (chrom, ..) = parse_line(&line)?;
+        if seen.last().map(|last| last != chrom).unwrap_or(true) {
+            if seen.iter().any(|c| c == chrom) {
+                bail!(
+                    "bedGraph is not sorted: {} appears in more than one block",
+                    chrom
+                );
+            }
+            seen.push(chrom.to_owned());
+        }
+    }
+
+    let mut lengths: HashMap<String, u64> = chroms.iter().cloned().collect();
+    let mut ordered = Vec::with_capacity(chroms.len());
+    for chrom in seen {
+        match lengths.remove(&chrom) {
+            Some(length) => ordered.push((chrom, length)),
+            None => bail!("Chromosome {} is missing from the chrom sizes", chrom),
+        }
+    }
+    ordered.extend(
+        chroms
+            .into_iter()
+            .filter(|(chrom, _)| lengths.contains_key(chrom)),
+    );
+    Ok(ordered)
+}
+
+/// Convert the bedGraph at `input` (optionally gzipped) into a bigWig at `output`.
+///
+/// Intervals must be sorted by start within each chromosome and every chromosome must be
+/// listed in `chroms`.
+pub fn bedgraph_to_bigwig<P>(input: P, output: P, chroms: Vec<(String, u64)>) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let input = input.as_ref();
+    let chroms = chrom_order(input, chroms)?;
+    let lengths: HashMap<String, u64> = chroms.iter().cloned().collect();
+    let mut writer = BigWigWriter::create(output, chroms)?;
+
+    let mut n_intervals = 0u64;
+    for line in open_bedgraph(input)?.lines() {
+        let line = line?;
+        if is_header_line(&line) {
+            continue;
+        }
+        let (chrom, start, end, value) = parse_line(&line)?;
+        if end > lengths[chrom] {
+            bail!(
+                "Interval {}:{}-{} extends past the end of the chromosome ({})",
+                chrom,
+                start,
+                end,
+                lengths[chrom]
+            );
+        }
+        writer.add(chrom, start, end, value)?;
+        n_intervals += 1;
+    }
+    writer.finish()?;
+
+    println!("Converted {} intervals", n_intervals);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn chromosomes_follow_bedgraph_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bedgraph");
+        let mut file = File::create(&path).unwrap();
+        writeln!(file, "track type=bedGraph").unwrap();
+        writeln!(file, "chr10\t0\t10\t1.5").unwrap();
+        writeln!(file, "chr2\t5\t10\t2").unwrap();
+        drop(file);
+
+        let chroms = vec![
+            ("chr1".to_string(), 100),
+            ("chr2".to_string(), 100),
+            ("chr10".to_string(), 100),
+        ];
+        let ordered = chrom_order(&path, chroms).unwrap();
+        let names: Vec<&str> = ordered.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["chr10", "chr2", "chr1"]);
+
+        assert!(parse_line("chr1\t0\tten\t1").is_err());
+    }
+}
@@ -0,0 +1,443 @@
+//! Tn5 sequence-bias model.
+//!
+//! Learns hexamer insertion preferences by comparing the sequence context of observed cut
+//! sites (ideally from naked DNA, or from background regions) with the hexamer composition
+//! of the same regions. The model is written as a TSV of per-hexamer weights and used to
+//! emit a per-base expected-insertion track and a bias-corrected cut-site track. `coverage`
+//! applies a model to its read, fragment and cut-site counts through [`BiasCorrection`].
+
+use anyhow::{bail, Context, Result};
+use bio::io::bed;
+use rust_htslib::bam::{IndexedReader, Read};
+use rust_htslib::faidx;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::fragments;
+use crate::signal::{self, TrackWriter};
+
+const K: usize = 6;
+const N_KMERS: usize = 1 << (2 * K);
+
+/// Index of the hexamer starting at `seq[0]`, or `None` if it contains anything but ACGT.
+fn kmer_index(seq: &[u8]) -> Option<usize> {
+    if seq.len() < K {
+        return None;
+    }
+    seq[..K].iter().try_fold(0usize, |index, base| {
+        let code = match base.to_ascii_uppercase() {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => return None,
+        };
+        Some(index << 2 | code)
+    })
+}
+
+fn reverse_complement_index(index: usize) -> usize {
+    let mut rc = 0;
+    for i in 0..K {
+        let code = (index >> (2 * i)) & 3;
+        rc = rc << 2 | (3 - code);
+    }
+    rc
+}
+
+fn kmer_string(index: usize) -> String {
+    (0..K)
+        .rev()
+        .map(|i| b"ACGT"[(index >> (2 * i)) & 3] as char)
+        .collect()
+}
+
+/// Relative insertion propensity of each hexamer centred on a cut site.
+#[derive(Debug, Clone)]
+pub struct BiasModel {
+    weights: Vec<f64>,
+}
+
+impl BiasModel {
+    /// Weights are observed cut-site hexamer frequencies over background frequencies.
+    /// Hexamers never seen in the background get a neutral weight of 1.
+    fn learn(observed: &[u64], background: &[u64]) -> Self {
+        let total_observed = observed.iter().sum::<u64>().max(1) as f64;
+        let total_background = background.iter().sum::<u64>().max(1) as f64;
+
+        let weights = observed
+            .iter()
+            .zip(background.iter())
+            .map(|(&o, &b)| match b {
+                0 => 1.0,
+                b => (o as f64 / total_observed) / (b as f64 / total_background),
+            })
+            .collect();
+        Self { weights }
+    }
+
+    /// Weight of the hexamer centred on the cut site between `seq[2]` and `seq[3]`.
+    pub fn weight(&self, seq: &[u8]) -> Option<f64> {
+        kmer_index(seq).map(|index| self.weights[index])
+    }
+
+    /// Weight of a cut on the given strand with its context starting at `seq[0]`. Reverse
+    /// strand cuts were learned from the reverse complement of their context.
+    pub fn stranded_weight(&self, seq: &[u8], reverse: bool) -> Option<f64> {
+        kmer_index(seq).map(|index| match reverse {
+            true => self.weights[reverse_complement_index(index)],
+            false => self.weights[index],
+        })
+    }
+
+    /// Factor a cut on the given strand with its context starting at `seq[0]` is counted
+    /// with: the inverse of its weight, 0 for hexamers never cut, and 1 where there is no
+    /// full hexamer.
+    fn stranded_correction(&self, seq: &[u8], reverse: bool) -> f32 {
+        match self.stranded_weight(seq, reverse) {
+            Some(weight) if weight > 0.0 => (1.0 / weight) as f32,
+            Some(_) => 0.0,
+            None => 1.0,
+        }
+    }
+
+    pub fn write_tsv<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "kmer\tweight")?;
+        for (index, weight) in self.weights.iter().enumerate() {
+            writeln!(writer, "{}\t{:.6}", kmer_string(index), weight)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn from_tsv<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let reader = BufReader::new(File::open(path).context("Could not open bias model")?);
+        let mut weights = vec![1.0; N_KMERS];
+        for line in reader.lines().skip(1) {
+            let line = line?;
+            let mut fields = line.split('\t');
+            let (kmer, weight) = match (fields.next(), fields.next()) {
+                (Some(kmer), Some(weight)) => (kmer, weight),
+                _ => bail!("Malformed bias model line: {}", line),
+            };
+            let index = kmer_index(kmer.as_bytes())
+                .with_context(|| format!("Invalid k-mer in bias model: {}", kmer))?;
+            weights[index] = weight.parse()?;
+        }
+        Ok(Self { weights })
+    }
+}
+
+/// Corrects individual Tn5 insertions for sequence bias, holding the reference sequence of
+/// one chromosome at a time (so reads should come in coordinate order).
+pub struct BiasCorrection {
+    model: BiasModel,
+    fasta: faidx::Reader,
+    names: HashSet<String>,
+    /// Name and sequence of the chromosome last looked up
+    sequence: Option<(String, Vec<u8>)>,
+}
+
+impl BiasCorrection {
+    pub fn open<P: AsRef<Path>>(model: P, fasta: P) -> Result<Self> {
+        let model = BiasModel::from_tsv(model)?;
+        let fasta =
+            faidx::Reader::from_path(&fasta).context("Could not open indexed FASTA file")?;
+        let names = fasta.seq_names()?.into_iter().collect();
+        Ok(Self {
+            model,
+            fasta,
+            names,
+            sequence: None,
+        })
+    }
+
+    /// Load the sequence of `chrom` unless it is the one already held.
+    fn load(&mut self, chrom: &str) -> Result<()> {
+        if matches!(&self.sequence, Some((name, _)) if name == chrom) {
+            return Ok(());
+        }
+        if !self.names.contains(chrom) {
+            bail!("Chromosome `{}` is not in the reference FASTA", chrom);
+        }
+        let length = self.fasta.fetch_seq_len(chrom) as usize;
+        let sequence = self.fasta.fetch_seq(chrom, 0, length.saturating_sub(1))?;
+        self.sequence = Some((chrom.to_string(), sequence));
+        Ok(())
+    }
+
+    /// Factor an insertion at `position` on `chrom` is counted with: the inverse of its
+    /// expected propensity, 0 for hexamers never cut, and 1 where there is no full hexamer
+    /// (Ns or chromosome ends).
+    pub fn correction(&mut self, chrom: &str, position: i64, reverse: bool) -> Result<f32> {
+        self.load(chrom)?;
+        let sequence = match &self.sequence {
+            Some((_, sequence)) => sequence.as_slice(),
+            None => &[],
+        };
+
+        let start = position - (K / 2) as i64;
+        let context = match start >= 0 {
+            true => sequence.get(start as usize..).unwrap_or_default(),
+            false => &[],
+        };
+        Ok(self.model.stranded_correction(context, reverse))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BiasOptions {
+    /// Previously learned model to apply instead of learning one from the regions
+    pub model: Option<PathBuf>,
+    pub min_mapq: u8,
+    pub threads: usize,
+}
+
+/// Sorted, merged regions as (tid, chrom, start, end).
+fn read_regions(
+    path: &Path,
+    header: &rust_htslib::bam::HeaderView,
+) -> Result<Vec<(u32, String, i64, i64)>> {
+    let mut regions = Vec::new();
+    let mut reader = bed::Reader::from_file(path)?;
+    for record in reader.records() {
+        let record = record.context("Error reading BED record")?;
+        if let Some(tid) = header.tid(record.chrom().as_bytes()) {
+            regions.push((
+                tid,
+                record.chrom().to_string(),
+                record.start() as i64,
+                record.end() as i64,
+            ));
+        }
+    }
+    regions.sort_by_key(|r| (r.0, r.2));
+
+    let mut merged: Vec<(u32, String, i64, i64)> = Vec::new();
+    for region in regions {
+        match merged.last_mut() {
+            Some(last) if last.0 == region.0 && region.2 <= last.3 => last.3 = last.3.max(region.3),
+            _ => merged.push(region),
+        }
+    }
+    Ok(merged)
+}
+
+/// Sequence and observed cut sites over one region.
+struct RegionData {
+    /// Sequence from `start - K / 2` to `end + K / 2`, so every position has full context
+    sequence: Vec<u8>,
+    /// Cuts per position (and strand) within the region
+    forward_cuts: Vec<u32>,
+    reverse_cuts: Vec<u32>,
+}
+
+fn load_region(
+    reader: &mut IndexedReader,
+    fasta: &faidx::Reader,
+    region: &(u32, String, i64, i64),
+    min_mapq: u8,
+) -> Result<RegionData> {
+    let (tid, chrom, start, end) = region;
+    let flank = (K / 2) as i64;
+    let seq_start = (start - flank).max(0);
+    let mut sequence = fasta
+        .fetch_seq(chrom, seq_start as usize, (end + flank - 1) as usize)?
+        .to_vec();
+    // Pad the start of chromosomes so indices line up
+    let mut padded = vec![b'N'; (seq_start - (start - flank)) as usize];
+    padded.append(&mut sequence);
+
+    let length = (end - start) as usize;
+    let mut forward_cuts = vec![0; length];
+    let mut reverse_cuts = vec![0; length];
+
+    reader.fetch((*tid, *start, *end))?;
+    for result in reader.records() {
+        let record = result?;
+        if let Some(cut) = fragments::cut_site(&record, min_mapq) {
+            if cut >= *start && cut < *end {
+                let i = (cut - start) as usize;
+                match record.is_reverse() {
+                    true => reverse_cuts[i] += 1,
+                    false => forward_cuts[i] += 1,
+                }
+            }
+        }
+    }
+
+    Ok(RegionData {
+        sequence: padded,
+        forward_cuts,
+        reverse_cuts,
+    })
+}
+
+/// Count hexamers at cut sites and across the regions on both strands.
+fn learn_model(
+    reader: &mut IndexedReader,
+    fasta: &faidx::Reader,
+    regions: &[(u32, String, i64, i64)],
+    min_mapq: u8,
+) -> Result<BiasModel> {
+    let mut observed = vec![0u64; N_KMERS];
+    let mut background = vec![0u64; N_KMERS];
+    for region in regions {
+        let data = load_region(reader, fasta, region, min_mapq)?;
+        for i in 0..data.forward_cuts.len() {
+            if let Some(index) = kmer_index(&data.sequence[i..]) {
+                let rc = reverse_complement_index(index);
+                background[index] += 1;
+                background[rc] += 1;
+                observed[index] += data.forward_cuts[i] as u64;
+                observed[rc] += data.reverse_cuts[i] as u64;
+            }
+        }
+    }
+    Ok(BiasModel::learn(&observed, &background))
+}
+
+/// Expected insertion propensity at each position of a region, averaged over both strands,
+/// and its cuts with each strand corrected by its own weight.
+fn correct_region(model: &BiasModel, data: &RegionData) -> (Vec<f32>, Vec<f32>) {
+    (0..data.forward_cuts.len())
+        .map(|i| {
+            let context = &data.sequence[i..];
+            let expected = match (
+                model.stranded_weight(context, false),
+                model.stranded_weight(context, true),
+            ) {
+                (Some(forward), Some(reverse)) => ((forward + reverse) / 2.0) as f32,
+                _ => 1.0,
+            };
+            let corrected = data.forward_cuts[i] as f32 * model.stranded_correction(context, false)
+                + data.reverse_cuts[i] as f32 * model.stranded_correction(context, true);
+            (expected, corrected)
+        })
+        .unzip()
+}
+
+/// Learn a bias model from `bam` over `regions` (unless one is supplied) and write:
+/// - `<prefix>.bias.tsv`: hexamer weights
+/// - `<prefix>.expected.bw`: per-base expected insertion propensity over the regions
+/// - `<prefix>.corrected.bw`: cut sites on each strand divided by their expected propensity
+pub fn tn5_bias<P>(
+    bam: P,
+    fasta: P,
+    regions: P,
+    output_prefix: P,
+    options: &BiasOptions,
+) -> Result<BiasModel>
+where
+    P: AsRef<Path>,
+{
+    let prefix = output_prefix.as_ref().display().to_string();
+    let mut reader = IndexedReader::from_path(&bam).context("Could not open indexed BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+    let fasta = faidx::Reader::from_path(&fasta).context("Could not open indexed FASTA file")?;
+    let regions = read_regions(regions.as_ref(), reader.header())?;
+
+    let model = match &options.model {
+        Some(path) => BiasModel::from_tsv(path)?,
+        None => learn_model(&mut reader, &fasta, &regions, options.min_mapq)?,
+    };
+    model.write_tsv(format!("{}.bias.tsv", prefix))?;
+
+    // Emit: expected and corrected tracks over the same regions
+    let chroms = signal::chrom_sizes(reader.header());
+    let mut expected = TrackWriter::create(format!("{}.expected.bw", prefix), chroms.clone())?;
+    let mut corrected = TrackWriter::create(format!("{}.corrected.bw", prefix), chroms)?;
+
+    for region in &regions {
+        let data = load_region(&mut reader, &fasta, region, options.min_mapq)?;
+        let (weights, cuts) = correct_region(&model, &data);
+
+        expected.write_values(&region.1, region.2 as u64, &weights)?;
+        corrected.write_values(&region.1, region.2 as u64, &cuts)?;
+    }
+
+    expected.finish()?;
+    corrected.finish()?;
+    Ok(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kmer_indexing() {
+        assert_eq!(kmer_index(b"AAAAAA"), Some(0));
+        assert_eq!(kmer_index(b"TTTTTT"), Some(N_KMERS - 1));
+        assert_eq!(kmer_index(b"acgtac"), kmer_index(b"ACGTAC"));
+        assert_eq!(kmer_index(b"ACGNAC"), None);
+        assert_eq!(kmer_index(b"ACG"), None);
+
+        let index = kmer_index(b"AACGTG").unwrap();
+        assert_eq!(kmer_string(index), "AACGTG");
+        assert_eq!(kmer_string(reverse_complement_index(index)), "CACGTT");
+    }
+
+    #[test]
+    fn weights_are_enrichment_over_background() {
+        let mut observed = vec![0; N_KMERS];
+        let mut background = vec![0; N_KMERS];
+        observed[0] = 3;
+        observed[1] = 1;
+        background[0] = 1;
+        background[1] = 1;
+
+        let model = BiasModel::learn(&observed, &background);
+        assert_eq!(model.weight(b"AAAAAA"), Some(1.5));
+        assert_eq!(model.weight(b"AAAAAC"), Some(0.5));
+        assert_eq!(model.weight(b"GGGGGG"), Some(1.0));
+        assert_eq!(model.stranded_weight(b"TTTTTT", true), Some(1.5));
+        assert_eq!(model.stranded_weight(b"TTTTTT", false), Some(1.0));
+    }
+
+    #[test]
+    fn insertions_are_corrected_by_their_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let fasta = dir.path().join("ref.fa");
+        std::fs::write(&fasta, ">chr1\nAAAAAACCCCCCNNNNNN\n").unwrap();
+        faidx::build(&fasta).unwrap();
+
+        let mut weights = vec![1.0; N_KMERS];
+        weights[kmer_index(b"AAAAAA").unwrap()] = 2.0;
+        weights[kmer_index(b"GGGGGG").unwrap()] = 0.0;
+        let model = dir.path().join("model.bias.tsv");
+        BiasModel { weights }.write_tsv(&model).unwrap();
+
+        let mut correction = BiasCorrection::open(&model, &fasta).unwrap();
+        assert_eq!(correction.correction("chr1", 3, false).unwrap(), 0.5);
+        assert_eq!(correction.correction("chr1", 3, true).unwrap(), 1.0);
+        assert_eq!(correction.correction("chr1", 9, true).unwrap(), 0.0);
+        // No full hexamer at the start of the chromosome or over Ns
+        assert_eq!(correction.correction("chr1", 1, false).unwrap(), 1.0);
+        assert_eq!(correction.correction("chr1", 14, false).unwrap(), 1.0);
+        assert!(correction.correction("chr2", 3, false).is_err());
+    }
+
+    #[test]
+    fn each_strand_is_corrected_by_its_own_weight() {
+        let mut weights = vec![1.0; N_KMERS];
+        weights[kmer_index(b"AAAAAA").unwrap()] = 2.0;
+        weights[kmer_index(b"TTTTTT").unwrap()] = 0.5;
+        let model = BiasModel { weights };
+        let data = RegionData {
+            sequence: b"AAAAAANNNNNN".to_vec(),
+            forward_cuts: vec![4, 4],
+            reverse_cuts: vec![2, 2],
+        };
+
+        let (expected, corrected) = correct_region(&model, &data);
+        // Reverse cuts over AAAAAA were learned under TTTTTT
+        assert_eq!(expected, vec![1.25, 1.0]);
+        assert_eq!(corrected, vec![4.0 / 2.0 + 2.0 / 0.5, 6.0]);
+    }
+}
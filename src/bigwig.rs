@@ -0,0 +1,607 @@
+//! Minimal bigWig writer.
+//!
+//! Writes zlib-compressed bedGraph sections, a chromosome B+ tree, an R-tree index and a
+//! fixed ladder of zoom levels following the UCSC bbi file format, so tracks can be
+//! produced without the UCSC binaries. Intervals must be added sorted by chromosome (in
+//! the order the chromosomes were given) and then by start.
+
+use ahash::HashMap;
+use anyhow::{bail, Context, Result};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+const CHROM_TREE_MAGIC: u32 = 0x78CA_8C91;
+const INDEX_MAGIC: u32 = 0x2468_ACE0;
+const VERSION: u16 = 4;
+const HEADER_SIZE: u64 = 64;
+const ZOOM_HEADER_SIZE: u64 = 24;
+const SUMMARY_SIZE: u64 = 40;
+const ITEMS_PER_SLOT: usize = 1024;
+const BLOCK_SIZE: usize = 256;
+const BEDGRAPH_SECTION: u8 = 1;
+
+/// Zoom level bin sizes. Starting at 2.5 kb keeps the summaries small enough to hold in
+/// memory for a whole genome while still covering browser-scale views.
+const ZOOM_REDUCTIONS: [u32; 8] = [
+    2_560, 10_240, 40_960, 163_840, 655_360, 2_621_440, 10_485_760, 41_943_040,
+];
+
+/// Location of one compressed block, as stored in the R-tree leaves.
+#[derive(Debug, Clone, Copy)]
+struct BlockEntry {
+    chrom: u32,
+    start: u32,
+    end: u32,
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ZoomRecord {
+    chrom: u32,
+    start: u32,
+    end: u32,
+    valid_count: u32,
+    min: f32,
+    max: f32,
+    sum: f32,
+    sum_squares: f32,
+}
+
+struct ZoomLevel {
+    reduction: u32,
+    records: Vec<ZoomRecord>,
+    current: Option<ZoomRecord>,
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    bases_covered: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    sum_squares: f64,
+}
+
+pub struct BigWigWriter {
+    file: BufWriter<File>,
+    position: u64,
+    chroms: Vec<(String, u32)>,
+    chrom_ids: HashMap<String, u32>,
+    current_chrom: Option<u32>,
+    last_start: u32,
+    items: Vec<(u32, u32, f32)>,
+    blocks: Vec<BlockEntry>,
+    zooms: Vec<ZoomLevel>,
+    summary: Summary,
+    max_uncompressed: usize,
+    data_offset: u64,
+}
+
+impl BigWigWriter {
+    /// Create a bigWig at `path` for the given chromosomes and lengths.
+    pub fn create<P: AsRef<Path>>(path: P, chroms: Vec<(String, u64)>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|| format!("Could not create bigWig `{}`", path.display()))?;
+
+        let chroms: Vec<(String, u32)> = chroms
+            .into_iter()
+            .map(|(name, length)| (name, length.min(u32::MAX as u64) as u32))
+            .collect();
+        let chrom_ids = chroms
+            .iter()
+            .enumerate()
+            .map(|(id, (name, _))| (name.clone(), id as u32))
+            .collect();
+
+        let mut writer = Self {
+            file: BufWriter::new(file),
+            position: 0,
+            chroms,
+            chrom_ids,
+            current_chrom: None,
+            last_start: 0,
+            items: Vec::with_capacity(ITEMS_PER_SLOT),
+            blocks: Vec::new(),
+            zooms: ZOOM_REDUCTIONS
+                .iter()
+                .map(|&reduction| ZoomLevel {
+                    reduction,
+                    records: Vec::new(),
+                    current: None,
+                })
+                .collect(),
+            summary: Summary {
+                min: f64::INFINITY,
+                max: f64::NEG_INFINITY,
+                ..Default::default()
+            },
+            max_uncompressed: 0,
+            data_offset: 0,
+        };
+
+        // Header, zoom headers and summary are filled in by `finish`
+        let reserved = HEADER_SIZE + ZOOM_HEADER_SIZE * ZOOM_REDUCTIONS.len() as u64 + SUMMARY_SIZE;
+        writer.write_bytes(&vec![0; reserved as usize])?;
+        writer.write_chrom_tree()?;
+
+        // Number of data blocks, also filled in by `finish`
+        writer.data_offset = writer.position;
+        writer.write_bytes(&0u64.to_le_bytes())?;
+        Ok(writer)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.file.write_all(bytes)?;
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Add the interval `[start, end)` on `chrom` with `value`.
+    pub fn add(&mut self, chrom: &str, start: u64, end: u64, value: f32) -> Result<()> {
+        let chrom_id = *self
+            .chrom_ids
+            .get(chrom)
+            .with_context(|| format!("Chromosome `{}` is not in the bigWig header", chrom))?;
+        let (start, end) = (start as u32, end as u32);
+        if end <= start {
+            return Ok(());
+        }
+
+        match self.current_chrom {
+            Some(current) if current == chrom_id => {
+                if start < self.last_start {
+                    bail!(
+                        "bigWig intervals must be sorted by start ({}:{})",
+                        chrom,
+                        start
+                    );
+                }
+            }
+            Some(current) if current > chrom_id => {
+                bail!("bigWig intervals must follow the chromosome order of the header");
+            }
+            _ => {
+                self.flush_block()?;
+                self.current_chrom = Some(chrom_id);
+            }
+        }
+        self.last_start = start;
+
+        self.items.push((start, end, value));
+        if self.items.len() == ITEMS_PER_SLOT {
+            self.flush_block()?;
+        }
+
+        let span = (end - start) as f64;
+        let value = value as f64;
+        self.summary.bases_covered += (end - start) as u64;
+        self.summary.min = self.summary.min.min(value);
+        self.summary.max = self.summary.max.max(value);
+        self.summary.sum += value * span;
+        self.summary.sum_squares += value * value * span;
+
+        let chrom_length = self.chroms[chrom_id as usize].1;
+        for zoom in self.zooms.iter_mut() {
+            zoom.add(chrom_id, start, end, value as f32, chrom_length);
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, uncompressed: &[u8]) -> Result<(u64, u64)> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(uncompressed)?;
+        let compressed = encoder.finish()?;
+
+        self.max_uncompressed = self.max_uncompressed.max(uncompressed.len());
+        let offset = self.position;
+        self.write_bytes(&compressed)?;
+        Ok((offset, compressed.len() as u64))
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        let chrom = match self.current_chrom {
+            Some(chrom) if !self.items.is_empty() => chrom,
+            _ => return Ok(()),
+        };
+        let start = self.items[0].0;
+        let end = self.items.iter().map(|item| item.1).max().unwrap_or(start);
+
+        let mut buffer = Vec::with_capacity(24 + self.items.len() * 12);
+        buffer.extend_from_slice(&chrom.to_le_bytes());
+        buffer.extend_from_slice(&start.to_le_bytes());
+        buffer.extend_from_slice(&end.to_le_bytes());
+        buffer.extend_from_slice(&0u32.to_le_bytes()); // item step
+        buffer.extend_from_slice(&0u32.to_le_bytes()); // item span
+        buffer.push(BEDGRAPH_SECTION);
+        buffer.push(0);
+        buffer.extend_from_slice(&(self.items.len() as u16).to_le_bytes());
+        for (item_start, item_end, value) in self.items.drain(..) {
+            buffer.extend_from_slice(&item_start.to_le_bytes());
+            buffer.extend_from_slice(&item_end.to_le_bytes());
+            buffer.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let (offset, size) = self.write_block(&buffer)?;
+        self.blocks.push(BlockEntry {
+            chrom,
+            start,
+            end,
+            offset,
+            size,
+        });
+        Ok(())
+    }
+
+    fn write_chrom_tree(&mut self) -> Result<()> {
+        let mut sorted: Vec<(&str, u32, u32)> = self
+            .chroms
+            .iter()
+            .enumerate()
+            .map(|(id, (name, length))| (name.as_str(), id as u32, *length))
+            .collect();
+        sorted.sort_by(|a, b| a.0.cmp(b.0));
+
+        let key_size = sorted.iter().map(|c| c.0.len()).max().unwrap_or(1).max(1);
+        let key = |name: &str| {
+            let mut key = name.as_bytes().to_vec();
+            key.resize(key_size, 0);
+            key
+        };
+
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&CHROM_TREE_MAGIC.to_le_bytes());
+        buffer.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        buffer.extend_from_slice(&(key_size as u32).to_le_bytes());
+        buffer.extend_from_slice(&8u32.to_le_bytes()); // value size
+        buffer.extend_from_slice(&(sorted.len() as u64).to_le_bytes());
+        buffer.extend_from_slice(&0u64.to_le_bytes());
+
+        let levels = tree_levels(sorted.len());
+
+        // Index (into `sorted`) of the first key below every node
+        let mut first_keys: Vec<Vec<usize>> = Vec::new();
+        for (height, level) in levels.iter().enumerate() {
+            let keys = level
+                .iter()
+                .map(|&(start, _)| match height {
+                    0 => start,
+                    _ => first_keys[height - 1][start],
+                })
+                .collect();
+            first_keys.push(keys);
+        }
+
+        // Every item, leaf or branch, is a key plus 8 bytes
+        let offsets = node_offsets(&levels, self.position + buffer.len() as u64, |_| {
+            key_size as u64 + 8
+        });
+
+        for height in (0..levels.len()).rev() {
+            for &(start, end) in &levels[height] {
+                buffer.push(u8::from(height == 0));
+                buffer.push(0);
+                buffer.extend_from_slice(&((end - start) as u16).to_le_bytes());
+                for i in start..end {
+                    if height == 0 {
+                        let (name, id, length) = sorted[i];
+                        buffer.extend_from_slice(&key(name));
+                        buffer.extend_from_slice(&id.to_le_bytes());
+                        buffer.extend_from_slice(&length.to_le_bytes());
+                    } else {
+                        buffer.extend_from_slice(&key(sorted[first_keys[height - 1][i]].0));
+                        buffer.extend_from_slice(&offsets[height - 1][i].to_le_bytes());
+                    }
+                }
+            }
+        }
+
+        self.write_bytes(&buffer)
+    }
+
+    /// Write an R-tree over `entries`, returning its offset.
+    fn write_index(&mut self, entries: &[BlockEntry], end_file_offset: u64) -> Result<u64> {
+        let index_offset = self.position;
+        let first = entries.first();
+        let last = entries.iter().max_by_key(|e| (e.chrom, e.end));
+
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&INDEX_MAGIC.to_le_bytes());
+        buffer.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        buffer.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+        buffer.extend_from_slice(&first.map_or(0, |e| e.chrom).to_le_bytes());
+        buffer.extend_from_slice(&first.map_or(0, |e| e.start).to_le_bytes());
+        buffer.extend_from_slice(&last.map_or(0, |e| e.chrom).to_le_bytes());
+        buffer.extend_from_slice(&last.map_or(0, |e| e.end).to_le_bytes());
+        buffer.extend_from_slice(&end_file_offset.to_le_bytes());
+        buffer.extend_from_slice(&(ITEMS_PER_SLOT as u32).to_le_bytes());
+        buffer.extend_from_slice(&0u32.to_le_bytes());
+
+        let levels = tree_levels(entries.len());
+
+        // Bounds of every node, computed bottom-up
+        let mut bounds: Vec<Vec<(u32, u32, u32, u32)>> = Vec::new();
+        for (height, level) in levels.iter().enumerate() {
+            let level_bounds = level
+                .iter()
+                .map(|&(start, end)| {
+                    if height == 0 {
+                        let items = &entries[start..end];
+                        let lo = items.first().map_or((0, 0), |e| (e.chrom, e.start));
+                        let hi = items
+                            .iter()
+                            .map(|e| (e.chrom, e.end))
+                            .max()
+                            .unwrap_or((0, 0));
+                        (lo.0, lo.1, hi.0, hi.1)
+                    } else {
+                        let children = &bounds[height - 1][start..end];
+                        let lo = (children[0].0, children[0].1);
+                        let hi = children.iter().map(|b| (b.2, b.3)).max().unwrap_or(lo);
+                        (lo.0, lo.1, hi.0, hi.1)
+                    }
+                })
+                .collect();
+            bounds.push(level_bounds);
+        }
+
+        // Leaf items carry the block offset and size, branch items a child offset
+        let offsets = node_offsets(&levels, index_offset + buffer.len() as u64, |height| {
+            if height == 0 {
+                32
+            } else {
+                24
+            }
+        });
+
+        for height in (0..levels.len()).rev() {
+            for &(start, end) in &levels[height] {
+                buffer.push(u8::from(height == 0));
+                buffer.push(0);
+                buffer.extend_from_slice(&((end - start) as u16).to_le_bytes());
+                for i in start..end {
+                    if height == 0 {
+                        let e = &entries[i];
+                        for v in [e.chrom, e.start, e.chrom, e.end] {
+                            buffer.extend_from_slice(&v.to_le_bytes());
+                        }
+                        buffer.extend_from_slice(&e.offset.to_le_bytes());
+                        buffer.extend_from_slice(&e.size.to_le_bytes());
+                    } else {
+                        let b = bounds[height - 1][i];
+                        for v in [b.0, b.1, b.2, b.3] {
+                            buffer.extend_from_slice(&v.to_le_bytes());
+                        }
+                        buffer.extend_from_slice(&offsets[height - 1][i].to_le_bytes());
+                    }
+                }
+            }
+        }
+
+        self.write_bytes(&buffer)?;
+        Ok(index_offset)
+    }
+
+    fn write_zoom_level(&mut self, records: &[ZoomRecord]) -> Result<(u64, u64)> {
+        let data_offset = self.position;
+        self.write_bytes(&(records.len() as u32).to_le_bytes())?;
+
+        let mut entries = Vec::new();
+        let mut start = 0;
+        while start < records.len() {
+            // Blocks never span chromosomes
+            let chrom = records[start].chrom;
+            let end = (start..records.len().min(start + ITEMS_PER_SLOT))
+                .take_while(|&i| records[i].chrom == chrom)
+                .last()
+                .map_or(start + 1, |i| i + 1);
+
+            let mut buffer = Vec::with_capacity((end - start) * 32);
+            for r in &records[start..end] {
+                for v in [r.chrom, r.start, r.end, r.valid_count] {
+                    buffer.extend_from_slice(&v.to_le_bytes());
+                }
+                for v in [r.min, r.max, r.sum, r.sum_squares] {
+                    buffer.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+            let (offset, size) = self.write_block(&buffer)?;
+            entries.push(BlockEntry {
+                chrom,
+                start: records[start].start,
+                end: records[end - 1].end,
+                offset,
+                size,
+            });
+            start = end;
+        }
+
+        let index_offset = self.write_index(&entries, self.position)?;
+        Ok((data_offset, index_offset))
+    }
+
+    /// Write the index and zoom levels and fill in the header.
+    pub fn finish(mut self) -> Result<()> {
+        self.flush_block()?;
+
+        let data_offset = self.data_offset;
+        let blocks = std::mem::take(&mut self.blocks);
+        let index_offset = self.write_index(&blocks, self.position)?;
+
+        let mut zoom_headers = Vec::new();
+        let zooms = std::mem::take(&mut self.zooms);
+        for mut zoom in zooms {
+            zoom.close();
+            if zoom.records.is_empty() {
+                continue;
+            }
+            let (zoom_data, zoom_index) = self.write_zoom_level(&zoom.records)?;
+            zoom_headers.push((zoom.reduction, zoom_data, zoom_index));
+        }
+
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(&BIGWIG_MAGIC.to_le_bytes());
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&(zoom_headers.len() as u16).to_le_bytes());
+        header.extend_from_slice(
+            &(HEADER_SIZE + ZOOM_HEADER_SIZE * ZOOM_REDUCTIONS.len() as u64 + SUMMARY_SIZE)
+                .to_le_bytes(),
+        );
+        header.extend_from_slice(&data_offset.to_le_bytes());
+        header.extend_from_slice(&index_offset.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // field count
+        header.extend_from_slice(&0u16.to_le_bytes()); // defined field count
+        header.extend_from_slice(&0u64.to_le_bytes()); // autoSql offset
+        header.extend_from_slice(
+            &(HEADER_SIZE + ZOOM_HEADER_SIZE * ZOOM_REDUCTIONS.len() as u64).to_le_bytes(),
+        );
+        header.extend_from_slice(&(self.max_uncompressed as u32).to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes()); // extension offset
+
+        for i in 0..ZOOM_REDUCTIONS.len() {
+            let (reduction, zoom_data, zoom_index) =
+                zoom_headers.get(i).copied().unwrap_or_default();
+            header.extend_from_slice(&reduction.to_le_bytes());
+            header.extend_from_slice(&0u32.to_le_bytes());
+            header.extend_from_slice(&zoom_data.to_le_bytes());
+            header.extend_from_slice(&zoom_index.to_le_bytes());
+        }
+
+        let summary = &self.summary;
+        let (min, max) = if summary.bases_covered == 0 {
+            (0.0, 0.0)
+        } else {
+            (summary.min, summary.max)
+        };
+        header.extend_from_slice(&summary.bases_covered.to_le_bytes());
+        header.extend_from_slice(&min.to_le_bytes());
+        header.extend_from_slice(&max.to_le_bytes());
+        header.extend_from_slice(&summary.sum.to_le_bytes());
+        header.extend_from_slice(&summary.sum_squares.to_le_bytes());
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.seek(SeekFrom::Start(data_offset))?;
+        self.file.write_all(&(blocks.len() as u64).to_le_bytes())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Nodes of a tree over `items` entries, leaves first. Each node is a range of indices
+/// into the level below (or into the items themselves, for leaves).
+fn tree_levels(items: usize) -> Vec<Vec<(usize, usize)>> {
+    let chunk = |n: usize| -> Vec<(usize, usize)> {
+        (0..n.max(1))
+            .step_by(BLOCK_SIZE)
+            .map(|start| (start, (start + BLOCK_SIZE).min(n)))
+            .collect()
+    };
+
+    let mut levels = vec![chunk(items)];
+    while levels[levels.len() - 1].len() > 1 {
+        let below = levels[levels.len() - 1].len();
+        levels.push(chunk(below));
+    }
+    levels
+}
+
+/// File offset of every node when the tree is written root first from `start`.
+fn node_offsets(
+    levels: &[Vec<(usize, usize)>],
+    start: u64,
+    item_size: impl Fn(usize) -> u64,
+) -> Vec<Vec<u64>> {
+    let mut offsets = vec![Vec::new(); levels.len()];
+    let mut cursor = start;
+    for height in (0..levels.len()).rev() {
+        for &(first, last) in &levels[height] {
+            offsets[height].push(cursor);
+            cursor += 4 + (last - first) as u64 * item_size(height);
+        }
+    }
+    offsets
+}
+
+impl ZoomLevel {
+    fn add(&mut self, chrom: u32, start: u32, end: u32, value: f32, chrom_length: u32) {
+        let mut bin_start = start - start % self.reduction;
+        while bin_start < end {
+            let bin_end = bin_start
+                .saturating_add(self.reduction)
+                .min(chrom_length.max(end));
+            let overlap = end.min(bin_end) - start.max(bin_start);
+
+            let same_bin =
+                matches!(self.current, Some(r) if r.chrom == chrom && r.start == bin_start);
+            if !same_bin {
+                self.close();
+                self.current = Some(ZoomRecord {
+                    chrom,
+                    start: bin_start,
+                    end: bin_end,
+                    valid_count: 0,
+                    min: f32::INFINITY,
+                    max: f32::NEG_INFINITY,
+                    sum: 0.0,
+                    sum_squares: 0.0,
+                });
+            }
+
+            if let Some(record) = self.current.as_mut() {
+                record.valid_count += overlap;
+                record.min = record.min.min(value);
+                record.max = record.max.max(value);
+                record.sum += value * overlap as f32;
+                record.sum_squares += value * value * overlap as f32;
+            }
+            bin_start = bin_end;
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(record) = self.current.take() {
+            self.records.push(record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn write_small_bigwig() {
+        let tmp = TempDir::new("bigwig_test").expect("Failed to make tmpdir");
+        let path = tmp.path().join("test.bw");
+        let chroms = vec![("chr1".to_string(), 1000), ("chr2".to_string(), 500)];
+
+        let mut writer = BigWigWriter::create(&path, chroms).expect("Could not create bigWig");
+        writer
+            .add("chr1", 0, 10, 1.0)
+            .expect("Could not add interval");
+        writer
+            .add("chr1", 10, 20, 2.0)
+            .expect("Could not add interval");
+        writer
+            .add("chr2", 5, 15, 3.0)
+            .expect("Could not add interval");
+        assert!(writer.add("chr1", 30, 40, 1.0).is_err());
+        writer.finish().expect("Could not finish bigWig");
+
+        let bytes = std::fs::read(&path).expect("Could not read bigWig");
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+
+        assert_eq!(u32_at(0), BIGWIG_MAGIC);
+        assert_eq!(u32_at(u64_at(8) as usize), CHROM_TREE_MAGIC);
+        assert_eq!(u64_at(u64_at(16) as usize), 2); // one data block per chromosome
+        assert_eq!(u32_at(u64_at(24) as usize), INDEX_MAGIC);
+    }
+}
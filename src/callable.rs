@@ -0,0 +1,161 @@
+//! Callable regions: intervals whose filtered depth lies within given bounds.
+//!
+//! Uses the same streaming depth sweep as `depth-summary`, so the output can be used to
+//! mask low (or excessive) coverage before footprinting or variant calling.
+
+use anyhow::{Context, Result};
+use rust_htslib::bam::{IndexedReader, Read};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::depthsummary;
+use crate::signal;
+
+#[derive(Debug, Clone)]
+pub struct CallableOptions {
+    pub min_depth: u32,
+    /// Bases above this depth are not callable. No upper bound if not set
+    pub max_depth: Option<u32>,
+    pub min_mapq: u8,
+    pub threads: usize,
+}
+
+impl Default for CallableOptions {
+    fn default() -> Self {
+        Self {
+            min_depth: 10,
+            max_depth: None,
+            min_mapq: 0,
+            threads: 1,
+        }
+    }
+}
+
+impl CallableOptions {
+    fn is_callable(&self, depth: u32) -> bool {
+        depth >= self.min_depth && !matches!(self.max_depth, Some(max) if depth > max)
+    }
+}
+
+/// Add a depth run to `intervals`, extending the last interval if they touch.
+fn push_run(intervals: &mut Vec<(i64, i64)>, start: i64, end: i64) {
+    match intervals.last_mut() {
+        Some(last) if last.1 == start => last.1 = end,
+        _ => intervals.push((start, end)),
+    }
+}
+
+fn callable_intervals(
+    reader: &mut IndexedReader,
+    tid: u32,
+    length: u64,
+    options: &CallableOptions,
+) -> Result<Vec<(i64, i64)>> {
+    let mut intervals = Vec::new();
+    depthsummary::sweep_chromosome(
+        reader,
+        tid,
+        length,
+        options.min_mapq,
+        |start, end, depth| {
+            if options.is_callable(depth) {
+                push_run(&mut intervals, start, end);
+            }
+        },
+    )?;
+    Ok(intervals)
+}
+
+/// Write the callable intervals of every chromosome of `bam` to `output` as BED.
+pub fn callable_regions<P>(bam: P, output: P, options: &CallableOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let bam: PathBuf = bam.as_ref().to_path_buf();
+    let reader = IndexedReader::from_path(&bam).context("Could not open indexed BAM file")?;
+    let chroms = signal::chrom_sizes(reader.header());
+    drop(reader);
+
+    let (chrom_sender, chrom_recv) = crossbeam::channel::unbounded::<(usize, u64)>();
+    let (intervals_sender, intervals_recv) = crossbeam::channel::unbounded();
+
+    let mut handles = Vec::new();
+    for _ in 0..options.threads.max(1) {
+        let chrom_recv = chrom_recv.clone();
+        let intervals_sender = intervals_sender.clone();
+        let bam = bam.clone();
+        let options = options.clone();
+
+        handles.push(thread::spawn(move || -> Result<()> {
+            let mut reader = IndexedReader::from_path(&bam)?;
+            for (tid, length) in chrom_recv {
+                let intervals = callable_intervals(&mut reader, tid as u32, length, &options)?;
+                intervals_sender.send((tid, intervals))?;
+            }
+            Ok(())
+        }));
+    }
+    drop(intervals_sender);
+
+    for (tid, (_, length)) in chroms.iter().enumerate() {
+        chrom_sender.send((tid, *length))?;
+    }
+    drop(chrom_sender);
+
+    let mut per_chrom = vec![Vec::new(); chroms.len()];
+    for (tid, intervals) in intervals_recv {
+        per_chrom[tid] = intervals;
+    }
+    for handle in handles {
+        handle.join().expect("Failed to join callable thread")?;
+    }
+
+    let mut writer = BufWriter::new(File::create(output.as_ref())?);
+    let mut callable_bases = 0;
+    for ((chrom, _), intervals) in chroms.iter().zip(&per_chrom) {
+        for (start, end) in intervals {
+            writeln!(writer, "{}\t{}\t{}", chrom, start, end)?;
+            callable_bases += end - start;
+        }
+    }
+    writer.flush()?;
+
+    let genome_size: u64 = chroms.iter().map(|(_, length)| length).sum();
+    println!(
+        "{} of {} bases callable ({:.2}%)",
+        callable_bases,
+        genome_size,
+        100.0 * callable_bases as f64 / genome_size.max(1) as f64
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_runs_within_bounds() {
+        let options = CallableOptions {
+            min_depth: 2,
+            max_depth: Some(5),
+            ..Default::default()
+        };
+        let runs = [
+            (0, 10, 1),
+            (10, 20, 2),
+            (20, 30, 5),
+            (30, 40, 6),
+            (40, 50, 3),
+        ];
+        let mut intervals = Vec::new();
+        for (start, end, depth) in runs {
+            if options.is_callable(depth) {
+                push_run(&mut intervals, start, end);
+            }
+        }
+        assert_eq!(intervals, vec![(10, 30), (40, 50)]);
+    }
+}
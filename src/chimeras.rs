@@ -0,0 +1,150 @@
+//! Chimeric (split) read extraction.
+//!
+//! Every record with an SA tag (primary and supplementary alignments alike) is copied to
+//! a BAM file, and each primary alignment/SA pair is summarised as a breakpoint pair in
+//! BEDPE. The breakpoint of an alignment is taken to be its clipped end.
+
+use anyhow::{Context, Result};
+use rust_htslib::bam::record::{Aux, CigarString, CigarStringView, Record};
+use rust_htslib::bam::{Format, Header, Read, Reader, Writer};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str;
+
+use crate::provenance;
+
+/// One entry of an SA tag: `rname,pos,strand,CIGAR,mapQ,NM`.
+#[derive(Debug, Clone, PartialEq)]
+struct SupplementaryAlignment {
+    chrom: String,
+    cigar: CigarStringView,
+    reverse: bool,
+    mapq: u8,
+}
+
+fn parse_sa_tag(tag: &str) -> Vec<SupplementaryAlignment> {
+    tag.split(';')
+        .filter_map(|entry| {
+            let fields: Vec<&str> = entry.split(',').collect();
+            if fields.len() < 6 {
+                return None;
+            }
+            let pos: i64 = fields[1].parse().ok()?;
+            let cigar = CigarString::try_from(fields[3]).ok()?;
+            Some(SupplementaryAlignment {
+                chrom: fields[0].to_owned(),
+                // SA positions are 1-based
+                cigar: cigar.into_view(pos - 1),
+                reverse: fields[2] == "-",
+                mapq: fields[4].parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Reference position of the clipped end of an alignment.
+fn breakpoint(cigar: &CigarStringView) -> i64 {
+    let leading = cigar.leading_softclips() + cigar.leading_hardclips();
+    let trailing = cigar.trailing_softclips() + cigar.trailing_hardclips();
+    match leading > trailing {
+        true => cigar.pos(),
+        false => cigar.end_pos() - 1,
+    }
+}
+
+fn strand(reverse: bool) -> char {
+    match reverse {
+        true => '-',
+        false => '+',
+    }
+}
+
+/// Write `<prefix>.bam` with the chimeric records of `bam` and `<prefix>.bedpe` with
+/// their breakpoint pairs.
+pub fn extract_chimeras<P>(bam: P, output_prefix: P, min_mapq: u8, threads: usize) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let prefix = output_prefix.as_ref().display().to_string();
+    let mut reader = Reader::from_path(&bam).context("Could not open BAM file")?;
+
+    let mut header = Header::from_template(reader.header());
+    provenance::add_program_record(&mut header, "chimeras: reads with SA tags");
+    let mut writer = Writer::from_path(format!("{}.bam", prefix), &header, Format::Bam)?;
+    if threads > 1 {
+        reader.set_threads(threads)?;
+        writer.set_threads(threads)?;
+    }
+    let mut bedpe = BufWriter::new(
+        File::create(format!("{}.bedpe", prefix)).context("Could not create BEDPE file")?,
+    );
+
+    let header_view = reader.header().clone();
+    let mut n_records = 0u64;
+    let mut n_pairs = 0u64;
+
+    for result in reader.records() {
+        let record: Record = result?;
+        let tag = match record.aux(b"SA") {
+            Ok(Aux::String(tag)) => tag.to_owned(),
+            _ => continue,
+        };
+        if record.is_unmapped() || record.is_secondary() || record.mapq() < min_mapq {
+            continue;
+        }
+        writer.write(&record)?;
+        n_records += 1;
+
+        // Pairs are reported from the primary alignment only, so each is written once
+        if record.is_supplementary() {
+            continue;
+        }
+        let chrom = str::from_utf8(header_view.tid2name(record.tid() as u32))?;
+        let primary_breakpoint = breakpoint(&record.cigar());
+        for sa in parse_sa_tag(&tag) {
+            let sa_breakpoint = breakpoint(&sa.cigar);
+            writeln!(
+                bedpe,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                chrom,
+                primary_breakpoint,
+                primary_breakpoint + 1,
+                sa.chrom,
+                sa_breakpoint,
+                sa_breakpoint + 1,
+                str::from_utf8(record.qname())?,
+                record.mapq().min(sa.mapq),
+                strand(record.is_reverse()),
+                strand(sa.reverse),
+            )?;
+            n_pairs += 1;
+        }
+    }
+    bedpe.flush()?;
+
+    println!(
+        "Wrote {} chimeric records and {} breakpoint pairs",
+        n_records, n_pairs
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sa_tags_and_breakpoints() {
+        let alignments = parse_sa_tag("chr2,1001,-,30S70M,60,0;chr3,50,+,70M30H,20,1;");
+        assert_eq!(alignments.len(), 2);
+        assert_eq!(alignments[0].chrom, "chr2");
+        assert!(alignments[0].reverse);
+        assert_eq!(alignments[1].mapq, 20);
+
+        // Clipped at the start: breakpoint at the first aligned base
+        assert_eq!(breakpoint(&alignments[0].cigar), 1000);
+        // Clipped at the end: breakpoint at the last aligned base
+        assert_eq!(breakpoint(&alignments[1].cigar), 49 + 69);
+    }
+}
@@ -0,0 +1,86 @@
+//! chrom.sizes files from BAM/CRAM headers.
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::signal;
+
+#[derive(Debug, Clone, Default)]
+pub struct ChromSizesOptions {
+    /// Drop references whose name starts with any of these
+    pub exclude_prefixes: Vec<String>,
+    /// Drop references whose name matches this regular expression
+    pub exclude_pattern: Option<String>,
+    /// Drop references shorter than this
+    pub min_length: u64,
+}
+
+/// References from `chroms` that pass the filters, in their original order.
+fn filter_chroms(
+    chroms: Vec<(String, u64)>,
+    options: &ChromSizesOptions,
+) -> Result<Vec<(String, u64)>> {
+    let pattern = options
+        .exclude_pattern
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .context("Invalid exclude pattern")?;
+
+    Ok(chroms
+        .into_iter()
+        .filter(|(name, length)| {
+            *length >= options.min_length
+                && !options
+                    .exclude_prefixes
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix.as_str()))
+                && !pattern.as_ref().is_some_and(|p| p.is_match(name))
+        })
+        .collect())
+}
+
+/// Write the reference names and lengths of `bam` (BAM or CRAM) that pass the filters to
+/// `output` (or stdout).
+pub fn chrom_sizes<P>(bam: P, output: Option<P>, options: &ChromSizesOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let chroms = filter_chroms(signal::bam_chrom_sizes(&bam)?, options)?;
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path.as_ref())?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    for (name, length) in &chroms {
+        writeln!(writer, "{}\t{}", name, length)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_by_prefix_pattern_and_length() {
+        let chroms = vec![
+            ("chr1".to_string(), 248_956_422),
+            ("chr1_KI270706v1_random".to_string(), 175_055),
+            ("chrUn_GL000195v1".to_string(), 182_896),
+            ("chrM".to_string(), 16_569),
+            ("dm6_chr2L".to_string(), 23_513_712),
+        ];
+        let options = ChromSizesOptions {
+            exclude_prefixes: vec!["dm6_".to_string(), "chrUn".to_string()],
+            exclude_pattern: Some("_random$".to_string()),
+            min_length: 20_000,
+        };
+        let kept = filter_chroms(chroms, &options).unwrap();
+        assert_eq!(kept, vec![("chr1".to_string(), 248_956_422)]);
+    }
+}
@@ -0,0 +1,214 @@
+//! Cross-species contamination screen for composite (multi-species) references.
+//!
+//! Generalises the split classification: each reference sequence belongs to the species
+//! whose name prefix it carries (e.g. `dm6_chr2L`), or to the host if it has none. Reads
+//! that are confidently assigned (good mapping quality, and with a mate on the same
+//! species) are counted per species, and samples where any non-host species exceeds the
+//! contamination threshold are flagged.
+
+use anyhow::{bail, Context, Result};
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{HeaderView, Read, Reader};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Species are a name and the prefix carried by their reference sequence names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Species {
+    pub name: String,
+    pub prefix: String,
+}
+
+impl std::str::FromStr for Species {
+    type Err = anyhow::Error;
+
+    /// Parse `name=prefix`, or just `prefix` (named after the prefix without a trailing `_`).
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, prefix) = match s.split_once('=') {
+            Some((name, prefix)) => (name, prefix),
+            None => (s.trim_end_matches('_'), s),
+        };
+        if name.is_empty() || prefix.is_empty() {
+            bail!(
+                "Species must be given as name=prefix or prefix, not `{}`",
+                s
+            );
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            prefix: prefix.to_owned(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ContamOptions {
+    pub species: Vec<Species>,
+    /// Name reported for references without any species prefix
+    pub host: String,
+    pub min_mapq: u8,
+    /// Flag a sample if any non-host species makes up more than this fraction of reads
+    pub threshold: f64,
+    pub threads: usize,
+}
+
+impl Default for ContamOptions {
+    fn default() -> Self {
+        Self {
+            species: vec![Species {
+                name: "dm6".to_string(),
+                prefix: "dm6_".to_string(),
+            }],
+            host: "host".to_string(),
+            min_mapq: 30,
+            threshold: 0.01,
+            threads: 1,
+        }
+    }
+}
+
+/// Species index (0 is the host, then `options.species` in order) for every reference.
+fn reference_species(header: &HeaderView, species: &[Species]) -> Vec<usize> {
+    (0..header.target_count())
+        .map(|tid| {
+            let name = header.tid2name(tid);
+            species
+                .iter()
+                .position(|s| name.starts_with(s.prefix.as_bytes()))
+                .map(|i| i + 1)
+                .unwrap_or(0)
+        })
+        .collect()
+}
+
+/// Species a read is confidently assigned to, if any.
+fn assign(record: &Record, species: &[usize], min_mapq: u8) -> Option<usize> {
+    if record.is_unmapped()
+        || record.is_secondary()
+        || record.is_supplementary()
+        || record.is_quality_check_failed()
+        || record.is_duplicate()
+        || record.mapq() < min_mapq
+    {
+        return None;
+    }
+    let own = species[record.tid() as usize];
+    // A mate on another species makes the pair ambiguous
+    if record.is_paired()
+        && !record.is_mate_unmapped()
+        && record.mtid() >= 0
+        && species[record.mtid() as usize] != own
+    {
+        return None;
+    }
+    Some(own)
+}
+
+/// Confidently assigned reads per species for one sample.
+fn screen_sample(bam: &Path, options: &ContamOptions) -> Result<Vec<u64>> {
+    let mut reader = Reader::from_path(bam).context("Could not open BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+    let species = reference_species(reader.header(), &options.species);
+
+    let mut counts = vec![0u64; options.species.len() + 1];
+    for result in reader.records() {
+        let record = result?;
+        if let Some(i) = assign(&record, &species, options.min_mapq) {
+            counts[i] += 1;
+        }
+    }
+    Ok(counts)
+}
+
+/// Screen every BAM in `bams`, writing one row per sample and species to `output` (or
+/// stdout). Returns the samples that were flagged as contaminated.
+pub fn contamination_screen(
+    bams: &[PathBuf],
+    output: Option<&PathBuf>,
+    options: &ContamOptions,
+) -> Result<Vec<PathBuf>> {
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    writeln!(writer, "sample\tspecies\treads\tfraction\tflagged")?;
+
+    let names: Vec<&str> = std::iter::once(options.host.as_str())
+        .chain(options.species.iter().map(|s| s.name.as_str()))
+        .collect();
+    let mut flagged = Vec::new();
+
+    for bam in bams {
+        let counts = screen_sample(bam, options)
+            .with_context(|| format!("Screening `{}` failed", bam.display()))?;
+        let total: u64 = counts.iter().sum();
+        let fractions: Vec<f64> = counts
+            .iter()
+            .map(|&n| match total {
+                0 => 0.0,
+                total => n as f64 / total as f64,
+            })
+            .collect();
+
+        let mut sample_flagged = false;
+        for (i, (name, (count, fraction))) in names
+            .iter()
+            .zip(counts.iter().zip(fractions.iter()))
+            .enumerate()
+        {
+            let contaminant = i > 0 && *fraction > options.threshold;
+            sample_flagged |= contaminant;
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{:.6}\t{}",
+                bam.display(),
+                name,
+                count,
+                fraction,
+                contaminant
+            )?;
+        }
+        if sample_flagged {
+            flagged.push(bam.clone());
+        }
+    }
+    writer.flush()?;
+    Ok(flagged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn species_parsing_and_assignment() {
+        let species: Species = "mm10_".parse().unwrap();
+        assert_eq!(species.name, "mm10");
+        let species: Species = "fly=dm6_".parse().unwrap();
+        assert_eq!(
+            (species.name.as_str(), species.prefix.as_str()),
+            ("fly", "dm6_")
+        );
+        assert!("=dm6_".parse::<Species>().is_err());
+
+        // tid 0 host, tid 1 spike-in
+        let reference_species = vec![0, 1];
+        let mut record = Record::new();
+        record.set(b"read1", None, b"ACGT", b"IIII");
+        record.unset_unmapped();
+        record.set_mapq(40);
+        record.set_tid(1);
+        assert_eq!(assign(&record, &reference_species, 30), Some(1));
+
+        // Mate on the other species is not a confident assignment
+        record.set_paired();
+        record.set_mtid(0);
+        assert_eq!(assign(&record, &reference_species, 30), None);
+
+        record.set_mtid(1);
+        assert_eq!(assign(&record, &reference_species, 50), None);
+    }
+}
@@ -0,0 +1,1026 @@
+//! Binned read or fragment coverage tracks, comparisons of treatment and control tracks,
+//! and quantile normalisation of tracks across samples.
+//!
+//! Coverage can be reported as raw counts, scaled by library size (CPM, RPKM, BPM or
+//! RPGC), or as enrichment over a robust genome-wide background: z-scores against the
+//! median/MAD of non-empty bins, or fold over a local background that is never allowed
+//! below the genome-wide median. A further scale factor, e.g. the spike-in factor written
+//! by `split`, can be applied on top. Counts can be corrected for Tn5 sequence bias with a
+//! model learned by `tn5-bias`, each insertion weighted by the inverse of its expected
+//! propensity.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::{Aux, Record};
+use rust_htslib::bam::{Read, Reader};
+use std::path::{Path, PathBuf};
+
+use crate::bias::BiasCorrection;
+use crate::fragments::{self, FragmentFilter};
+use crate::signal::{self, TrackWriter};
+
+/// What is counted in each bin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum CountUnit {
+    /// The aligned span of every read
+    #[default]
+    Reads,
+    /// The span of every proper pair, from the start of one mate to the end of the other,
+    /// counted once
+    Fragments,
+    /// The Tn5 insertion site of every read, 1 bp at +4 from the 5' end of forward reads
+    /// and -5 from the 5' end of reverse reads
+    CutSites,
+}
+
+/// How reads aligned to more than one location are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum MultimapperMode {
+    /// Skip reads with more than one reported hit (NH > 1)
+    #[default]
+    Unique,
+    /// Count every reported hit fully
+    All,
+    /// Spread each read across its reported hits, weighting every hit by 1/NH
+    Fractional,
+}
+
+/// How binned counts are transformed before writing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Normalization {
+    /// Raw (weighted) read counts
+    #[default]
+    None,
+    /// (count - median) / (1.4826 * MAD) of the non-empty bins genome-wide
+    Zscore,
+    /// count / max(mean of the surrounding window, genome-wide median)
+    Fold,
+    /// Counts per million reads (or fragments) counted
+    Cpm,
+    /// Counts per kilobase of bin per million reads counted
+    Rpkm,
+    /// Counts scaled so that the bins sum to one million (bins per million)
+    Bpm,
+    /// Reads per genomic content: scaled to an average depth of 1x over the effective
+    /// genome size
+    Rpgc,
+}
+
+/// Effective (mappable, non-N) genome sizes of common assemblies, as used by deepTools.
+const EFFECTIVE_GENOME_SIZES: &[(&[&str], u64)] = &[
+    (&["hg19", "grch37"], 2_864_785_220),
+    (&["hg38", "grch38"], 2_913_022_398),
+    (&["mm9", "grcm37"], 2_620_345_972),
+    (&["mm10", "grcm38"], 2_652_783_500),
+    (&["dm3"], 162_367_812),
+    (&["dm6"], 142_573_017),
+    (&["danrer10", "grcz10"], 1_369_631_918),
+    (&["danrer11", "grcz11"], 1_368_780_147),
+    (&["ce11", "wbcel235"], 100_286_401),
+    (&["tair10"], 119_482_012),
+];
+
+/// Parse an effective genome size given in bp or as an assembly name (e.g. hg38, mm10).
+pub fn effective_genome_size(value: &str) -> Result<u64, String> {
+    if let Ok(size) = value.parse::<u64>() {
+        return Ok(size);
+    }
+    let name = value.to_lowercase();
+    EFFECTIVE_GENOME_SIZES
+        .iter()
+        .find(|(names, _)| names.contains(&name.as_str()))
+        .map(|(_, size)| *size)
+        .ok_or_else(|| {
+            let names: Vec<&str> = EFFECTIVE_GENOME_SIZES.iter().map(|(n, _)| n[0]).collect();
+            format!(
+                "`{}` is neither a size in bp nor one of {}",
+                value,
+                names.join(", ")
+            )
+        })
+}
+
+/// How a treatment track is compared to its control, bin by bin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Comparison {
+    /// log2((treatment + pseudocount) / (control + pseudocount))
+    #[default]
+    Log2ratio,
+    /// (treatment + pseudocount) / (control + pseudocount)
+    Ratio,
+    /// treatment - control
+    Subtract,
+}
+
+impl Comparison {
+    fn apply(&self, treatment: f32, control: f32, pseudocount: f32) -> f32 {
+        let ratio = || (treatment + pseudocount) / (control + pseudocount);
+        match self {
+            Comparison::Log2ratio => ratio().log2(),
+            Comparison::Ratio => ratio(),
+            Comparison::Subtract => treatment - control,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CoverageOptions {
+    pub bin_size: u64,
+    pub count: CountUnit,
+    /// Longest fragment counted with `CountUnit::Fragments`, in bp
+    pub max_fragment_length: i64,
+    pub multimappers: MultimapperMode,
+    pub normalization: Normalization,
+    /// Window for the local background of fold enrichment, in bp
+    pub background_window: u64,
+    /// Genome size the RPGC normalisation scales to 1x depth over
+    pub effective_genome_size: Option<u64>,
+    /// Factor every bin is multiplied by after normalisation
+    pub scale_factor: f64,
+    /// Apply the ATAC-seq Tn5 offsets (+4 forward, -5 reverse) to reads on the fly
+    pub atac_shift: bool,
+    /// Tn5 bias model (`<prefix>.bias.tsv` from `tn5-bias`) to correct insertions with
+    pub bias_model: Option<PathBuf>,
+    /// Indexed reference FASTA the bias model reads hexamer context from
+    pub fasta: Option<PathBuf>,
+    pub min_mapq: u8,
+    pub threads: usize,
+}
+
+impl Default for CoverageOptions {
+    fn default() -> Self {
+        Self {
+            bin_size: 50,
+            count: CountUnit::default(),
+            max_fragment_length: 1000,
+            multimappers: MultimapperMode::default(),
+            normalization: Normalization::default(),
+            background_window: 10_000,
+            effective_genome_size: None,
+            scale_factor: 1.0,
+            atac_shift: false,
+            bias_model: None,
+            fasta: None,
+            min_mapq: 0,
+            threads: 1,
+        }
+    }
+}
+
+/// Number of reported alignments for the read, from the NH tag (1 if absent).
+fn number_of_hits(record: &Record) -> u32 {
+    match record.aux(b"NH") {
+        Ok(Aux::U8(n)) => n as u32,
+        Ok(Aux::U16(n)) => n as u32,
+        Ok(Aux::U32(n)) => n,
+        Ok(Aux::I8(n)) => n.max(1) as u32,
+        Ok(Aux::I16(n)) => n.max(1) as u32,
+        Ok(Aux::I32(n)) => n.max(1) as u32,
+        _ => 1,
+    }
+}
+
+/// Weight a record contributes to the coverage, or `None` if it is not counted.
+fn read_weight(record: &Record, options: &CoverageOptions) -> Option<f32> {
+    if record.is_unmapped()
+        || record.is_supplementary()
+        || record.is_quality_check_failed()
+        || record.is_duplicate()
+        || record.mapq() < options.min_mapq
+    {
+        return None;
+    }
+
+    let hits = number_of_hits(record).max(1);
+    match options.multimappers {
+        MultimapperMode::Unique if hits > 1 || record.is_secondary() => None,
+        MultimapperMode::Unique => Some(1.0),
+        MultimapperMode::All => Some(1.0),
+        MultimapperMode::Fractional => Some(1.0 / hits as f32),
+    }
+}
+
+/// Reference span counted for a read. With the ATAC shift, forward reads start 4 bp
+/// later and reverse reads end 5 bp earlier, as `shift` would write them.
+fn read_span(record: &Record, atac_shift: bool) -> (i64, i64) {
+    let (start, end) = (record.pos(), record.reference_end());
+    if !atac_shift {
+        return (start, end);
+    }
+    match record.is_reverse() {
+        true => (start, (end - 5).max(start + 1)),
+        false => ((start + 4).min(end - 1), end),
+    }
+}
+
+/// Reference span counted for a fragment, trimmed like its reads with the ATAC shift.
+fn fragment_span(record: &Record, options: &CoverageOptions) -> Option<(i64, i64)> {
+    let filter = FragmentFilter {
+        min_mapq: options.min_mapq,
+        max_length: options.max_fragment_length,
+        ..Default::default()
+    };
+    let fragment = fragments::from_record(record, &filter)?;
+    match options.atac_shift {
+        true => Some((
+            fragment.start + 4,
+            (fragment.end - 5).max(fragment.start + 5),
+        )),
+        false => Some((fragment.start, fragment.end)),
+    }
+}
+
+/// Tn5 insertion site of a read, as `fragments::cut_site` places it.
+fn insertion(record: &Record) -> i64 {
+    match record.is_reverse() {
+        true => record.reference_end() - 5,
+        false => record.pos() + 4,
+    }
+}
+
+/// Span and weight a record adds to the coverage, or `None` if it is not counted.
+fn counted_span(record: &Record, options: &CoverageOptions) -> Option<(i64, i64, f32)> {
+    let weight = read_weight(record, options)?;
+    let (start, end) = match options.count {
+        CountUnit::Reads => read_span(record, options.atac_shift),
+        CountUnit::Fragments => fragment_span(record, options)?,
+        CountUnit::CutSites => (insertion(record), insertion(record) + 1),
+    };
+    Some((start, end, weight))
+}
+
+/// Tn5 insertions behind a counted record, as (position, reverse): the read's own, or
+/// both ends of a fragment.
+fn insertions(record: &Record, options: &CoverageOptions) -> Vec<(i64, bool)> {
+    match options.count {
+        CountUnit::Reads | CountUnit::CutSites => vec![(insertion(record), record.is_reverse())],
+        CountUnit::Fragments => {
+            let end = record.pos() + record.insert_size();
+            vec![(record.pos() + 4, false), (end - 5, true)]
+        }
+    }
+}
+
+/// Open the bias correction `options` ask for, if any.
+fn bias_correction(options: &CoverageOptions) -> Result<Option<BiasCorrection>> {
+    match (&options.bias_model, &options.fasta) {
+        (Some(model), Some(fasta)) => Ok(Some(BiasCorrection::open(model, fasta)?)),
+        (Some(_), None) => bail!("Tn5 bias correction needs an indexed reference FASTA"),
+        (None, _) => Ok(None),
+    }
+}
+
+/// Like `counted_span`, with the weight multiplied by the mean bias correction of the
+/// record's insertions.
+fn corrected_span(
+    record: &Record,
+    chrom: &str,
+    options: &CoverageOptions,
+    correction: Option<&mut BiasCorrection>,
+) -> Result<Option<(i64, i64, f32)>> {
+    let (start, end, weight) = match counted_span(record, options) {
+        Some(span) => span,
+        None => return Ok(None),
+    };
+    let correction = match correction {
+        Some(correction) => correction,
+        None => return Ok(Some((start, end, weight))),
+    };
+    let insertions = insertions(record, options);
+    let mut factor = 0.0;
+    for &(position, reverse) in &insertions {
+        factor += correction.correction(chrom, position, reverse)?;
+    }
+    Ok(Some((
+        start,
+        end,
+        weight * factor / insertions.len() as f32,
+    )))
+}
+
+/// Add `weight` to every bin overlapped by `[start, end)`.
+fn add_read(bins: &mut [f32], bin_size: u64, start: i64, end: i64, weight: f32) {
+    if end <= start || bins.is_empty() {
+        return;
+    }
+    let first = (start.max(0) as u64 / bin_size) as usize;
+    let last = (((end - 1).max(0) as u64 / bin_size) as usize).min(bins.len() - 1);
+    for bin in bins.iter_mut().take(last + 1).skip(first) {
+        *bin += weight;
+    }
+}
+
+/// Multiply every bin by `factor`.
+fn scale(bins: &mut [f32], factor: f64) {
+    if factor != 1.0 {
+        for value in bins.iter_mut() {
+            *value = (*value as f64 * factor) as f32;
+        }
+    }
+}
+
+/// The spike-in scale factor for `bam` from the JSON stats written by `split`.
+///
+/// Stats of several samples are matched to `bam` by file name, that of one of the
+/// sample's outputs (e.g. its endogenous BAM) or of its input.
+pub fn spikein_scale_factor<P: AsRef<Path>>(stats: P, bam: P) -> Result<f64> {
+    let (stats, bam) = (stats.as_ref(), bam.as_ref());
+    let text = std::fs::read_to_string(stats)
+        .with_context(|| format!("Could not read spike-in stats `{}`", stats.display()))?;
+    let json: serde_json::Value = serde_json::from_str(&text)
+        .with_context(|| format!("`{}` is not a JSON stats file", stats.display()))?;
+
+    let samples = match json {
+        serde_json::Value::Array(samples) => samples,
+        sample => vec![sample],
+    };
+    let same_file = |sample: &&serde_json::Value| {
+        let outputs = sample["outputs"].as_array().into_iter().flatten();
+        outputs
+            .chain([&sample["filename"]])
+            .filter_map(serde_json::Value::as_str)
+            .any(|filename| Path::new(filename).file_name() == bam.file_name())
+    };
+    let sample = match samples.iter().find(same_file) {
+        Some(sample) => sample,
+        None if samples.len() == 1 => &samples[0],
+        None => bail!(
+            "No sample in `{}` matches `{}`",
+            stats.display(),
+            bam.display()
+        ),
+    };
+    match &sample["scale_factors"]["spikein_scale_factor"] {
+        serde_json::Value::Number(factor) => Ok(factor.as_f64().unwrap_or(1.0)),
+        serde_json::Value::Null => {
+            bail!("`{}` has no exogenous reads to scale by", stats.display())
+        }
+        _ => bail!("`{}` has no spike-in scale factor", stats.display()),
+    }
+}
+
+/// Write the bins of one chromosome, merging neighbouring bins with equal values.
+fn write_bins(
+    writer: &mut TrackWriter,
+    chrom: &str,
+    length: u64,
+    bin_size: u64,
+    bins: &[f32],
+) -> Result<()> {
+    let mut run_start = 0;
+    for i in 1..=bins.len() {
+        if i == bins.len() || bins[i] != bins[run_start] {
+            writer.write(
+                chrom,
+                run_start as u64 * bin_size,
+                (i as u64 * bin_size).min(length),
+                bins[run_start],
+            )?;
+            run_start = i;
+        }
+    }
+    Ok(())
+}
+
+/// Write a binned read or fragment coverage track of the coordinate-sorted `bam` to `output` (bigWig for
+/// `.bw`/`.bigwig`, bedGraph otherwise).
+pub fn coverage<P>(bam: P, output: P, options: &CoverageOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    if options.normalization == Normalization::Rpgc && options.effective_genome_size.is_none() {
+        bail!("RPGC normalisation needs an effective genome size");
+    }
+    if options.normalization != Normalization::None {
+        return normalized_coverage(bam.as_ref(), output.as_ref(), options);
+    }
+
+    let mut reader = Reader::from_path(&bam).context("Could not open BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+
+    let chroms = signal::chrom_sizes(reader.header());
+    let mut correction = bias_correction(options)?;
+    let mut writer = TrackWriter::create(&output, chroms.clone())?;
+    let n_bins = |tid: usize| chroms[tid].1.div_ceil(options.bin_size) as usize;
+
+    // Chromosomes are written as soon as the reads move on from them
+    let mut current = 0;
+    let mut bins = vec![0.0f32; chroms.first().map(|_| n_bins(0)).unwrap_or(0)];
+
+    for result in reader.records() {
+        let record = result?;
+        if record.tid() < 0 {
+            continue;
+        }
+        let tid = record.tid() as usize;
+        if tid < current {
+            bail!("BAM file must be coordinate sorted");
+        }
+        while tid > current {
+            let (chrom, length) = &chroms[current];
+            scale(&mut bins, options.scale_factor);
+            write_bins(&mut writer, chrom, *length, options.bin_size, &bins)?;
+            current += 1;
+            bins = vec![0.0; n_bins(current)];
+        }
+
+        let chrom = &chroms[tid].0;
+        if let Some((start, end, weight)) =
+            corrected_span(&record, chrom, options, correction.as_mut())?
+        {
+            add_read(&mut bins, options.bin_size, start, end, weight);
+        }
+    }
+
+    while current < chroms.len() {
+        let (chrom, length) = &chroms[current];
+        scale(&mut bins, options.scale_factor);
+        write_bins(&mut writer, chrom, *length, options.bin_size, &bins)?;
+        current += 1;
+        if current < chroms.len() {
+            bins = vec![0.0; n_bins(current)];
+        }
+    }
+    writer.finish()
+}
+
+/// Binned coverage of every chromosome, concatenated in header order.
+struct BinnedCoverage {
+    chroms: Vec<(String, u64)>,
+    bins: Vec<f32>,
+    /// Weighted number of reads (or fragments) counted
+    total: f64,
+    /// Weighted sum of their lengths, in bp
+    total_length: f64,
+}
+
+fn count_bins(bam: &Path, options: &CoverageOptions) -> Result<BinnedCoverage> {
+    let mut reader = Reader::from_path(bam).context("Could not open BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+    let chroms = signal::chrom_sizes(reader.header());
+    let mut correction = bias_correction(options)?;
+
+    let mut offsets = Vec::with_capacity(chroms.len() + 1);
+    offsets.push(0);
+    for (_, length) in &chroms {
+        offsets.push(offsets.last().unwrap() + length.div_ceil(options.bin_size) as usize);
+    }
+    let mut bins = vec![0.0f32; *offsets.last().unwrap()];
+    let (mut total, mut total_length) = (0.0, 0.0);
+
+    for result in reader.records() {
+        let record = result?;
+        if record.tid() < 0 {
+            continue;
+        }
+        let tid = record.tid() as usize;
+        if let Some((start, end, weight)) =
+            corrected_span(&record, &chroms[tid].0, options, correction.as_mut())?
+        {
+            total += weight as f64;
+            total_length += weight as f64 * (end - start) as f64;
+            add_read(
+                &mut bins[offsets[tid]..offsets[tid + 1]],
+                options.bin_size,
+                start,
+                end,
+                weight,
+            );
+        }
+    }
+    Ok(BinnedCoverage {
+        chroms,
+        bins,
+        total,
+        total_length,
+    })
+}
+
+/// Robust location and scale of the non-empty bins.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Background {
+    median: f32,
+    /// 1.4826 * MAD, or the Poisson standard deviation if the MAD is zero
+    scale: f32,
+}
+
+fn median_of(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mid = values.len() / 2;
+    let (_, median, _) = values.select_nth_unstable_by(mid, f32::total_cmp);
+    *median
+}
+
+/// Background estimated from non-empty bins, so unmappable and assembly-gap bins do not
+/// drag it to zero.
+fn background(bins: &[f32]) -> Background {
+    let mut values: Vec<f32> = bins.iter().copied().filter(|&v| v > 0.0).collect();
+    let median = median_of(&mut values);
+    let mut deviations: Vec<f32> = values.iter().map(|v| (v - median).abs()).collect();
+    let mad = median_of(&mut deviations);
+    let scale = match mad > 0.0 {
+        true => 1.4826 * mad,
+        false => median.sqrt(),
+    };
+    Background { median, scale }
+}
+
+/// Fold enrichment of each bin over the mean of the `window` bins around it, floored at
+/// the genome-wide median.
+fn fold_over_local(bins: &mut [f32], window: usize, floor: f32) {
+    let mut prefix = Vec::with_capacity(bins.len() + 1);
+    prefix.push(0.0f64);
+    for &value in bins.iter() {
+        prefix.push(prefix.last().unwrap() + value as f64);
+    }
+    let half = window / 2;
+    for (i, value) in bins.iter_mut().enumerate() {
+        let start = i.saturating_sub(half);
+        let end = (i + half + 1).min(prefix.len() - 1);
+        let local = ((prefix[end] - prefix[start]) / (end - start) as f64) as f32;
+        let lambda = local.max(floor);
+        *value = match lambda > 0.0 {
+            true => *value / lambda,
+            false => 0.0,
+        };
+    }
+}
+
+/// Factor the counts are multiplied by for the library size normalisations, or `None` for
+/// the others.
+fn scale_factor(coverage: &BinnedCoverage, options: &CoverageOptions) -> Option<f64> {
+    let per = |value: f64, total: f64| match total > 0.0 {
+        true => value / total,
+        false => 0.0,
+    };
+    let bin_kb = options.bin_size as f64 / 1000.0;
+    match options.normalization {
+        Normalization::Cpm => Some(per(1e6, coverage.total)),
+        Normalization::Rpkm => Some(per(1e6, coverage.total) / bin_kb),
+        // Bins all have the same length, so per-kb values scale to a million just as the
+        // counts themselves do
+        Normalization::Bpm => {
+            let sum: f64 = coverage.bins.iter().map(|&v| v as f64).sum();
+            Some(per(1e6, sum))
+        }
+        Normalization::Rpgc => options
+            .effective_genome_size
+            .map(|size| per(size as f64, coverage.total_length)),
+        Normalization::None | Normalization::Zscore | Normalization::Fold => None,
+    }
+}
+
+/// Coverage transformed by `options.normalization`, which needs the whole genome in memory
+/// to sum the counts or estimate the background.
+fn normalized_coverage(bam: &Path, output: &Path, options: &CoverageOptions) -> Result<()> {
+    let coverage = count_bins(bam, options)?;
+    let factor = scale_factor(&coverage, options);
+    let BinnedCoverage {
+        chroms, mut bins, ..
+    } = coverage;
+    if let Some(factor) = factor {
+        println!("Scale factor: {:.6}", factor);
+    }
+    scale(&mut bins, factor.unwrap_or(1.0) * options.scale_factor);
+
+    let background = match options.normalization {
+        Normalization::Zscore | Normalization::Fold => {
+            let background = background(&bins);
+            println!(
+                "Background per {} bp bin: median {:.3}, scale {:.3}",
+                options.bin_size, background.median, background.scale
+            );
+            background
+        }
+        _ => Background {
+            median: 0.0,
+            scale: 0.0,
+        },
+    };
+
+    let window = (options.background_window / options.bin_size).max(1) as usize;
+    let mut offset = 0;
+    let mut writer = TrackWriter::create(output, chroms.clone())?;
+    for (chrom, length) in &chroms {
+        let n = length.div_ceil(options.bin_size) as usize;
+        let chrom_bins = &mut bins[offset..offset + n];
+        match options.normalization {
+            Normalization::None
+            | Normalization::Cpm
+            | Normalization::Rpkm
+            | Normalization::Bpm
+            | Normalization::Rpgc => {}
+            Normalization::Zscore if background.scale > 0.0 => {
+                for value in chrom_bins.iter_mut() {
+                    *value = (*value - background.median) / background.scale;
+                }
+            }
+            Normalization::Zscore => chrom_bins.fill(0.0),
+            Normalization::Fold => fold_over_local(chrom_bins, window, background.median),
+        }
+        write_bins(&mut writer, chrom, *length, options.bin_size, chrom_bins)?;
+        offset += n;
+    }
+    writer.finish()
+}
+
+/// Write the bin-by-bin `comparison` of the `treatment` and `control` coverage to
+/// `output`, each normalised on its own by `options.normalization` first.
+///
+/// Only the library size normalisations apply. Bins without a finite value (an empty
+/// control without a pseudocount) are written as 0.
+pub fn compare<P>(
+    treatment: P,
+    control: P,
+    output: P,
+    comparison: Comparison,
+    pseudocount: f32,
+    options: &CoverageOptions,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    if matches!(
+        options.normalization,
+        Normalization::Zscore | Normalization::Fold
+    ) {
+        bail!("Treatment and control can only be compared after a library size normalisation");
+    }
+    if options.normalization == Normalization::Rpgc && options.effective_genome_size.is_none() {
+        bail!("RPGC normalisation needs an effective genome size");
+    }
+
+    let (treatment, control) = (treatment.as_ref(), control.as_ref());
+    let mut samples = Vec::with_capacity(2);
+    for (name, bam) in [("treatment", treatment), ("control", control)] {
+        let mut coverage = count_bins(bam, options)
+            .with_context(|| format!("Counting reads failed for `{}`", bam.display()))?;
+        let factor = scale_factor(&coverage, options).unwrap_or(1.0);
+        println!("Scale factor for the {}: {:.6}", name, factor);
+        scale(&mut coverage.bins, factor * options.scale_factor);
+        samples.push(coverage);
+    }
+    if samples[0].chroms != samples[1].chroms {
+        bail!(
+            "`{}` has different reference sequences to `{}`",
+            control.display(),
+            treatment.display()
+        );
+    }
+
+    let bins: Vec<f32> = samples[0]
+        .bins
+        .iter()
+        .zip(&samples[1].bins)
+        .map(|(&t, &c)| comparison.apply(t, c, pseudocount))
+        .map(|value| if value.is_finite() { value } else { 0.0 })
+        .collect();
+
+    let chroms = &samples[0].chroms;
+    let mut writer = TrackWriter::create(output, chroms.clone())?;
+    let mut offset = 0;
+    for (chrom, length) in chroms {
+        let n = length.div_ceil(options.bin_size) as usize;
+        write_bins(
+            &mut writer,
+            chrom,
+            *length,
+            options.bin_size,
+            &bins[offset..offset + n],
+        )?;
+        offset += n;
+    }
+    writer.finish()
+}
+
+/// Quantile normalise equally sized samples in place.
+///
+/// Every sample is given the same distribution: the mean across samples of the values at
+/// each rank. Tied values (e.g. empty bins) are all given the mean over their ranks.
+fn quantile_normalize(samples: &mut [Vec<f32>]) {
+    let n = match samples.first() {
+        Some(sample) => sample.len(),
+        None => return,
+    };
+    let sorted: Vec<Vec<f32>> = samples
+        .iter()
+        .map(|sample| {
+            let mut sorted = sample.clone();
+            sorted.sort_unstable_by(f32::total_cmp);
+            sorted
+        })
+        .collect();
+    let reference: Vec<f64> = (0..n)
+        .map(|rank| sorted.iter().map(|s| s[rank] as f64).sum::<f64>() / samples.len() as f64)
+        .collect();
+
+    for (sample, sorted) in samples.iter_mut().zip(&sorted) {
+        // (value, normalised value) for each distinct value, in increasing order
+        let mut mapping: Vec<(f32, f32)> = Vec::new();
+        let mut start = 0;
+        while start < n {
+            let mut end = start + 1;
+            while end < n && sorted[end] == sorted[start] {
+                end += 1;
+            }
+            let mean = reference[start..end].iter().sum::<f64>() / (end - start) as f64;
+            mapping.push((sorted[start], mean as f32));
+            start = end;
+        }
+        for value in sample.iter_mut() {
+            let i = mapping.partition_point(|(v, _)| v.total_cmp(value).is_lt());
+            *value = mapping[i].1;
+        }
+    }
+}
+
+/// Quantile normalise the binned coverage of `bams` against each other, writing
+/// `<output_dir>/<bam stem>.quantile.bw` for each.
+///
+/// All samples are held in memory, so larger bins are advisable for many samples.
+pub fn normalize_tracks<P>(bams: &[PathBuf], output_dir: P, options: &CoverageOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    if bams.len() < 2 {
+        bail!("Quantile normalisation needs at least two samples");
+    }
+    let mut chroms = Vec::new();
+    let mut samples = Vec::with_capacity(bams.len());
+    for bam in bams {
+        let coverage = count_bins(bam, options)
+            .with_context(|| format!("Counting reads failed for `{}`", bam.display()))?;
+        if chroms.is_empty() {
+            chroms = coverage.chroms;
+        } else if chroms != coverage.chroms {
+            bail!(
+                "`{}` has different reference sequences to `{}`",
+                bam.display(),
+                bams[0].display()
+            );
+        }
+        samples.push(coverage.bins);
+    }
+
+    quantile_normalize(&mut samples);
+
+    std::fs::create_dir_all(output_dir.as_ref())?;
+    for (bam, bins) in bams.iter().zip(&samples) {
+        let stem = bam.file_stem().unwrap_or_default().to_string_lossy();
+        let output = output_dir.as_ref().join(format!("{}.quantile.bw", stem));
+        let mut writer = TrackWriter::create(&output, chroms.clone())?;
+        let mut offset = 0;
+        for (chrom, length) in &chroms {
+            let n = length.div_ceil(options.bin_size) as usize;
+            write_bins(
+                &mut writer,
+                chrom,
+                *length,
+                options.bin_size,
+                &bins[offset..offset + n],
+            )?;
+            offset += n;
+        }
+        writer.finish()?;
+        println!("Wrote {}", output.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    #[test]
+    fn multimappers_are_weighted_by_hits() {
+        let mut record = Record::new();
+        record.set(b"read1", None, b"ACGT", b"IIII");
+        record.set_tid(0);
+        record.set_pos(10);
+        record.unset_unmapped();
+        record.push_aux(b"NH", Aux::U8(4)).unwrap();
+
+        let mut options = CoverageOptions::default();
+        assert_eq!(read_weight(&record, &options), None);
+        options.multimappers = MultimapperMode::All;
+        assert_eq!(read_weight(&record, &options), Some(1.0));
+        options.multimappers = MultimapperMode::Fractional;
+        assert_eq!(read_weight(&record, &options), Some(0.25));
+
+        let cigar = CigarString(vec![Cigar::Match(4)]);
+        record.set(b"read1", Some(&cigar), b"ACGT", b"IIII");
+        assert_eq!(read_span(&record, false), (10, 14));
+        assert_eq!(read_span(&record, true), (13, 14));
+        record.set_reverse();
+        assert_eq!(read_span(&record, true), (10, 11));
+
+        let mut bins = vec![0.0; 4];
+        add_read(&mut bins, 10, 5, 25, 0.25);
+        add_read(&mut bins, 10, 35, 100, 1.0);
+        assert_eq!(bins, vec![0.25, 0.25, 0.25, 1.0]);
+    }
+
+    #[test]
+    fn fragments_are_counted_once_from_the_leftmost_mate() {
+        let cigar = CigarString(vec![Cigar::Match(4)]);
+        let mut record = Record::new();
+        record.set(b"pair1", Some(&cigar), b"ACGT", b"IIII");
+        record.set_tid(0);
+        record.set_pos(10);
+        record.unset_unmapped();
+        record.set_paired();
+        record.set_proper_pair();
+        record.set_insert_size(30);
+
+        let mut options = CoverageOptions {
+            count: CountUnit::Fragments,
+            ..Default::default()
+        };
+        assert_eq!(counted_span(&record, &options), Some((10, 40, 1.0)));
+        options.atac_shift = true;
+        assert_eq!(counted_span(&record, &options), Some((14, 35, 1.0)));
+
+        // The mate with the negative TLEN adds nothing
+        record.set_insert_size(-30);
+        assert_eq!(counted_span(&record, &options), None);
+        options.count = CountUnit::Reads;
+        assert_eq!(counted_span(&record, &options), Some((13, 14, 1.0)));
+    }
+
+    #[test]
+    fn cut_sites_are_tn5_insertions() {
+        let cigar = CigarString(vec![Cigar::Match(20)]);
+        let mut record = Record::new();
+        record.set(b"pair1", Some(&cigar), &[b'A'; 20], &[b'I'; 20]);
+        record.set_tid(0);
+        record.set_pos(10);
+        record.unset_unmapped();
+        record.set_paired();
+        record.set_proper_pair();
+        record.set_insert_size(50);
+
+        let mut options = CoverageOptions {
+            count: CountUnit::CutSites,
+            ..Default::default()
+        };
+        assert_eq!(counted_span(&record, &options), Some((14, 15, 1.0)));
+        assert_eq!(insertions(&record, &options), vec![(14, false)]);
+        options.count = CountUnit::Fragments;
+        assert_eq!(insertions(&record, &options), vec![(14, false), (55, true)]);
+
+        record.set_reverse();
+        options.count = CountUnit::CutSites;
+        assert_eq!(counted_span(&record, &options), Some((25, 26, 1.0)));
+        assert_eq!(insertions(&record, &options), vec![(25, true)]);
+
+        // Without a bias model records keep their weight
+        assert_eq!(
+            corrected_span(&record, "chr1", &options, None).unwrap(),
+            Some((25, 26, 1.0))
+        );
+        options.bias_model = Some(PathBuf::from("model.bias.tsv"));
+        assert!(bias_correction(&options).is_err());
+    }
+
+    #[test]
+    fn library_size_scale_factors() {
+        let coverage = BinnedCoverage {
+            chroms: vec![("chr1".to_string(), 400)],
+            bins: vec![1.0, 3.0, 0.0, 1.0],
+            total: 4.0,
+            total_length: 400.0,
+        };
+        let mut options = CoverageOptions {
+            bin_size: 100,
+            normalization: Normalization::Cpm,
+            ..Default::default()
+        };
+        assert_eq!(scale_factor(&coverage, &options), Some(250_000.0));
+        options.normalization = Normalization::Rpkm;
+        assert_eq!(scale_factor(&coverage, &options), Some(2_500_000.0));
+        options.normalization = Normalization::Bpm;
+        assert_eq!(scale_factor(&coverage, &options), Some(200_000.0));
+        options.normalization = Normalization::Rpgc;
+        options.effective_genome_size = Some(1000);
+        assert_eq!(scale_factor(&coverage, &options), Some(2.5));
+        options.normalization = Normalization::Fold;
+        assert_eq!(scale_factor(&coverage, &options), None);
+
+        assert_eq!(effective_genome_size("GRCh38"), Ok(2_913_022_398));
+        assert_eq!(effective_genome_size("12345"), Ok(12345));
+        assert!(effective_genome_size("hg37").is_err());
+    }
+
+    #[test]
+    fn spikein_scale_factor_is_read_from_split_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats = dir.path().join("stats.json");
+        std::fs::write(
+            &stats,
+            r#"[{"filename": "data/a.bam", "scale_factors": {"spikein_scale_factor": 2.5}},
+                {"filename": "data/b.bam", "scale_factors": {"spikein_scale_factor": null}}]"#,
+        )
+        .unwrap();
+        let factor = |bam: &str| spikein_scale_factor(stats.as_path(), Path::new(bam));
+        assert_eq!(factor("other/a.bam").unwrap(), 2.5);
+        assert!(factor("b.bam").is_err());
+        assert!(factor("c.bam").is_err());
+    }
+
+    #[test]
+    fn spikein_scale_factor_is_matched_to_split_outputs() {
+        use crate::split_sample_and_spikein::{
+            split_samples, ExogenousContigs, SplitOptions, SplitStats,
+        };
+        use rust_htslib::bam::header::HeaderRecord;
+        use rust_htslib::bam::{Format, Header, Writer};
+
+        // Two samples with one and two spike-in reads
+        let dir = tempfile::tempdir().unwrap();
+        let mut samples = Vec::new();
+        for (name, n_exogenous) in [("a", 1), ("b", 2)] {
+            let bam = dir.path().join(format!("{}.bam", name));
+            let mut header = Header::new();
+            header.push_record(HeaderRecord::new(b"HD").push_tag(b"VN", "1.6"));
+            for chrom in ["chr1", "dm6_chr2L"] {
+                let mut sq = HeaderRecord::new(b"SQ");
+                sq.push_tag(b"SN", chrom).push_tag(b"LN", 1000);
+                header.push_record(&sq);
+            }
+            let mut writer = Writer::from_path(&bam, &header, Format::Bam).unwrap();
+            for i in 0..3 {
+                let tid = if i < n_exogenous { 1 } else { 0 };
+                let mut record = Record::new();
+                let cigar = CigarString(vec![Cigar::Match(4)]);
+                record.set(format!("r{}", i).as_bytes(), Some(&cigar), b"ACGT", b"IIII");
+                record.unset_unmapped();
+                record.set_tid(tid);
+                record.set_pos(100 * i as i64);
+                record.set_mapq(60);
+                writer.write(&record).unwrap();
+            }
+            samples.push((bam, dir.path().join("split").join(name)));
+        }
+        let options = SplitOptions {
+            threads: 1,
+            quiet: true,
+            ..Default::default()
+        };
+        let exogenous = [ExogenousContigs::Prefix("dm6_".to_string())];
+        let results = split_samples(&samples, &exogenous, &options, 1).unwrap();
+        let stats_path = dir.path().join("stats.json");
+        let stats: Vec<SplitStats> = results.into_iter().map(|(stats, _)| stats).collect();
+        SplitStats::write_all(&stats, &stats_path).unwrap();
+
+        let factor = |bam: &str| {
+            let bam = dir.path().join("split").join(bam);
+            spikein_scale_factor(stats_path.as_path(), bam.as_path()).unwrap()
+        };
+        assert_eq!(factor("a.endogenous.bam"), 1e6);
+        assert_eq!(factor("b.endogenous.bam"), 5e5);
+        assert_eq!(factor("b.exogenous.bam"), 5e5);
+    }
+
+    #[test]
+    fn treatment_is_compared_to_control() {
+        assert_eq!(Comparison::Log2ratio.apply(7.0, 1.0, 1.0), 2.0);
+        assert_eq!(Comparison::Ratio.apply(3.0, 0.0, 1.0), 4.0);
+        assert_eq!(Comparison::Subtract.apply(3.0, 5.0, 1.0), -2.0);
+        assert!(!Comparison::Log2ratio.apply(3.0, 0.0, 0.0).is_finite());
+    }
+
+    #[test]
+    fn quantile_normalization_equalises_distributions() {
+        let mut samples = vec![vec![0.0, 2.0, 4.0, 0.0], vec![0.0, 6.0, 0.0, 2.0]];
+        quantile_normalize(&mut samples);
+        // Reference by rank: [0, 0, 2, 5]; the tied zeros share the mean of ranks 0-1
+        assert_eq!(samples[0], vec![0.0, 2.0, 5.0, 0.0]);
+        assert_eq!(samples[1], vec![0.0, 5.0, 0.0, 2.0]);
+    }
+
+    #[test]
+    fn robust_background_and_enrichment() {
+        let bins = vec![0.0, 0.0, 2.0, 4.0, 4.0, 6.0, 40.0];
+        let bg = background(&bins);
+        assert_eq!(bg.median, 4.0);
+        assert!((bg.scale - 1.4826 * 2.0).abs() < 1e-5);
+
+        // A sparse track with no spread falls back to the Poisson scale
+        assert_eq!(background(&[0.0, 1.0, 1.0]).scale, 1.0);
+
+        let mut bins = vec![1.0, 1.0, 10.0, 1.0, 1.0];
+        fold_over_local(&mut bins, 1, 2.0);
+        assert_eq!(bins, vec![0.5, 0.5, 1.0, 0.5, 0.5]);
+        let mut bins = vec![0.0, 2.0, 0.0];
+        fold_over_local(&mut bins, 3, 0.5);
+        assert!((bins[1] - 3.0).abs() < 1e-5);
+    }
+}
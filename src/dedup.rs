@@ -0,0 +1,562 @@
+//! Positional duplicate marking for coordinate-sorted BAM files.
+//!
+//! Reads are duplicates of each other when their unclipped 5' ends fall on the same
+//! position and strand and, for pairs, the unclipped 5' ends of their mates do too. The
+//! mate's end comes from its CIGAR in the MC tag, or from its position without one, and
+//! its base qualities from the ms tag, both as added by `samtools fixmate -m`. A pair is
+//! decided once, when its first mate is seen, and the other mate follows that decision.
+//! One read or pair of each duplicate set is kept: the one with the highest sum of base
+//! qualities of at least 15 (over both mates of a pair), then the smallest read name.
+
+use crate::provenance;
+use anyhow::{bail, Context, Result};
+use rust_htslib::bam::header::Header;
+use rust_htslib::bam::record::{Aux, CigarString, CigarStringView, Record};
+use rust_htslib::bam::{Format, Read, Reader, Writer};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const DUPLICATE_FLAG: u16 = 0x400;
+
+#[derive(Debug, Clone)]
+pub struct DedupOptions {
+    /// Leave duplicates out of the output instead of flagging them
+    pub remove: bool,
+    pub threads: usize,
+}
+
+impl Default for DedupOptions {
+    fn default() -> Self {
+        DedupOptions {
+            remove: false,
+            threads: 1,
+        }
+    }
+}
+
+/// Reference, unclipped 5' position and strand (true for reverse) of a read.
+type End = (i32, i64, bool);
+
+/// Position and orientation shared by duplicate reads.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DuplicateKey {
+    /// The read's end, or the lower of the two ends of a pair
+    end: End,
+    /// The other end of a pair with both mates mapped
+    mate: Option<End>,
+}
+
+/// How a read ranks within its duplicate set; the greatest is kept.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Rank {
+    score: u64,
+    name: std::cmp::Reverse<Vec<u8>>,
+}
+
+/// Where a pending record's duplicate decision comes from.
+#[derive(Debug)]
+enum Member {
+    /// A single read, or the first mate seen of a pair, deciding for its template
+    Set {
+        key: DuplicateKey,
+        rank: Rank,
+        /// Position after which no more of the set can be seen
+        settles: i64,
+    },
+    /// The second mate seen of a pair, following the first
+    Mate,
+}
+
+#[derive(Debug)]
+struct DuplicateSet {
+    best: Rank,
+    size: u64,
+    /// Members not yet written out
+    pending: usize,
+}
+
+/// Unclipped 5' position of an alignment at `pos`: its start when forward and its end
+/// when reverse, with soft and hard clips added back.
+fn five_prime(pos: i64, reverse: bool, cigar: &CigarStringView) -> i64 {
+    if reverse {
+        cigar.end_pos() - 1 + cigar.trailing_softclips() + cigar.trailing_hardclips()
+    } else {
+        pos - cigar.leading_softclips() - cigar.leading_hardclips()
+    }
+}
+
+/// Unclipped 5' position of a read's mate, from its CIGAR in the MC tag, or its position
+/// without one.
+fn mate_five_prime(record: &Record) -> i64 {
+    let cigar = match record.aux(b"MC") {
+        Ok(Aux::String(cigar)) => CigarString::try_from(cigar).ok(),
+        _ => None,
+    };
+    match cigar {
+        Some(cigar) => five_prime(
+            record.mpos(),
+            record.is_mate_reverse(),
+            &cigar.into_view(record.mpos()),
+        ),
+        None => record.mpos(),
+    }
+}
+
+/// Reads that take part in duplicate marking: mapped primary alignments.
+fn duplicate_key(record: &Record) -> Option<DuplicateKey> {
+    if record.is_unmapped() || record.is_secondary() || record.is_supplementary() {
+        return None;
+    }
+    let end = (
+        record.tid(),
+        five_prime(record.pos(), record.is_reverse(), &record.cigar()),
+        record.is_reverse(),
+    );
+    let mate = (record.is_paired() && !record.is_mate_unmapped()).then(|| {
+        (
+            record.mtid(),
+            mate_five_prime(record),
+            record.is_mate_reverse(),
+        )
+    });
+    // Both mates of a pair give the same key, whichever is seen first
+    Some(match mate {
+        Some(mate) if mate < end => DuplicateKey {
+            end: mate,
+            mate: Some(end),
+        },
+        mate => DuplicateKey { end, mate },
+    })
+}
+
+/// Sum of the base qualities of at least 15, as Picard and samtools score reads.
+fn base_score(qual: &[u8]) -> u64 {
+    qual.iter().filter(|&&q| q >= 15).map(|&q| q as u64).sum()
+}
+
+/// Rank of a read, or of a pair from its first mate seen, adding the mate's score from
+/// the ms tag.
+fn rank(record: &Record) -> Rank {
+    let mate_score = match record.aux(b"ms") {
+        Ok(Aux::U8(score)) => score as u64,
+        Ok(Aux::U16(score)) => score as u64,
+        Ok(Aux::U32(score)) => score as u64,
+        Ok(Aux::I8(score)) => score.max(0) as u64,
+        Ok(Aux::I16(score)) => score.max(0) as u64,
+        Ok(Aux::I32(score)) => score.max(0) as u64,
+        _ => 0,
+    };
+    Rank {
+        score: base_score(record.qual()) + mate_score,
+        name: std::cmp::Reverse(record.qname().to_vec()),
+    }
+}
+
+/// Longest stretch a read can span, clips included, from its start to its end.
+fn read_span(record: &Record) -> i64 {
+    let cigar = record.cigar();
+    cigar.end_pos() - record.pos()
+        + cigar.leading_softclips()
+        + cigar.leading_hardclips()
+        + cigar.trailing_softclips()
+        + cigar.trailing_hardclips()
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DedupStats {
+    /// Mapped primary alignments considered
+    pub examined: u64,
+    pub duplicates: u64,
+    /// Single reads and read pairs (counted once, from the first mate seen)
+    pub templates: u64,
+    pub duplicate_templates: u64,
+    /// Duplicate sets of more than one read
+    pub duplicate_sets: u64,
+}
+
+/// Number of distinct molecules in a library from which `total` templates were sequenced
+/// and `unique` found to be distinct, assuming each is sampled with equal probability
+/// (the Lander-Waterman estimate used by Picard).
+fn estimate_library_size(total: u64, unique: u64) -> Option<u64> {
+    if total == 0 || unique == 0 || unique >= total {
+        return None;
+    }
+    let (n, c) = (total as f64, unique as f64);
+    let f = |x: f64| c / x - 1.0 + (-n / x).exp();
+
+    let mut lower = 1.0;
+    let mut upper = 100.0;
+    if f(lower * c) < 0.0 {
+        return None;
+    }
+    while f(upper * c) > 0.0 {
+        upper *= 10.0;
+    }
+    for _ in 0..40 {
+        let mid = (lower + upper) / 2.0;
+        let value = f(mid * c);
+        if value == 0.0 {
+            break;
+        } else if value > 0.0 {
+            lower = mid;
+        } else {
+            upper = mid;
+        }
+    }
+    Some((c * (lower + upper) / 2.0) as u64)
+}
+
+impl DedupStats {
+    pub fn duplication_rate(&self) -> f64 {
+        match self.examined {
+            0 => 0.0,
+            examined => self.duplicates as f64 / examined as f64,
+        }
+    }
+
+    pub fn library_size(&self) -> Option<u64> {
+        estimate_library_size(self.templates, self.templates - self.duplicate_templates)
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, "metric\tvalue")?;
+        writeln!(writer, "examined\t{}", self.examined)?;
+        writeln!(writer, "duplicates\t{}", self.duplicates)?;
+        writeln!(writer, "duplicate_sets\t{}", self.duplicate_sets)?;
+        writeln!(writer, "duplication_rate\t{:.6}", self.duplication_rate())?;
+        writeln!(writer, "templates\t{}", self.templates)?;
+        writeln!(writer, "duplicate_templates\t{}", self.duplicate_templates)?;
+        match self.library_size() {
+            Some(size) => writeln!(writer, "estimated_library_size\t{}", size)?,
+            None => writeln!(writer, "estimated_library_size\tNA")?,
+        }
+        Ok(())
+    }
+}
+
+/// Records waiting for their duplicate sets to be complete before they are written.
+struct Pending {
+    records: VecDeque<(Record, Option<Member>)>,
+    sets: HashMap<DuplicateKey, DuplicateSet>,
+    /// Pairs whose second mate is still to come, with the decision for it once made
+    mates: HashMap<Vec<u8>, Option<bool>>,
+}
+
+impl Pending {
+    fn push(&mut self, record: Record) {
+        let member = duplicate_key(&record).map(|key| {
+            if key.mate.is_some() {
+                match self.mates.entry(record.qname().to_vec()) {
+                    Entry::Occupied(_) => return Member::Mate,
+                    Entry::Vacant(entry) => {
+                        entry.insert(None);
+                    }
+                }
+            }
+            // The set is complete once its lower end is out of reach, or the read's own
+            // end when the other is on an earlier reference
+            let settles = match key.mate {
+                Some(mate) if key.end.0 != record.tid() => mate.1,
+                _ => key.end.1,
+            };
+            let rank = rank(&record);
+            let set = self.sets.entry(key.clone()).or_insert(DuplicateSet {
+                best: rank.clone(),
+                size: 0,
+                pending: 0,
+            });
+            if rank > set.best {
+                set.best = rank.clone();
+            }
+            set.size += 1;
+            set.pending += 1;
+            Member::Set { key, rank, settles }
+        });
+        self.records.push_back((record, member));
+    }
+
+    /// Write out records from the front whose duplicate sets can no longer grow, i.e. all
+    /// of them if `position` is None (end of a reference), or else those whose 5' ends
+    /// lie more than `reach` before it.
+    fn flush(
+        &mut self,
+        position: Option<i64>,
+        reach: i64,
+        writer: &mut Writer,
+        stats: &mut DedupStats,
+        options: &DedupOptions,
+    ) -> Result<()> {
+        while let Some((_, member)) = self.records.front() {
+            if let (Some(position), Some(Member::Set { settles, .. })) = (position, member) {
+                if settles + reach >= position {
+                    break;
+                }
+            }
+            let (mut record, member) = self.records.pop_front().expect("Front record");
+            let duplicate = match member {
+                None => {
+                    writer.write(&record)?;
+                    continue;
+                }
+                // The first mate was ahead in the queue, so has been decided
+                Some(Member::Mate) => self
+                    .mates
+                    .remove(record.qname())
+                    .flatten()
+                    .expect("Decision of the first mate"),
+                Some(Member::Set { key, rank, .. }) => {
+                    let set = self
+                        .sets
+                        .get_mut(&key)
+                        .expect("Duplicate set of pending record");
+                    let duplicate = rank != set.best;
+                    stats.templates += 1;
+                    if set.size > 1 && !duplicate {
+                        stats.duplicate_sets += 1;
+                    }
+                    if duplicate {
+                        stats.duplicate_templates += 1;
+                    }
+                    set.pending -= 1;
+                    if set.pending == 0 {
+                        self.sets.remove(&key);
+                    }
+                    if key.mate.is_some() {
+                        self.mates.insert(record.qname().to_vec(), Some(duplicate));
+                    }
+                    duplicate
+                }
+            };
+            stats.examined += 1;
+            if duplicate {
+                stats.duplicates += 1;
+            }
+
+            if duplicate {
+                if options.remove {
+                    continue;
+                }
+                record.set_flags(record.flags() | DUPLICATE_FLAG);
+            } else {
+                record.set_flags(record.flags() & !DUPLICATE_FLAG);
+            }
+            writer.write(&record)?;
+        }
+        Ok(())
+    }
+}
+
+/// Mark (or, with `options.remove`, drop) positional duplicates in the coordinate-sorted
+/// `bam`, writing the result to `output`.
+///
+/// Secondary and supplementary alignments are written unchanged.
+pub fn dedup<P>(bam: P, output: P, options: &DedupOptions) -> Result<DedupStats>
+where
+    P: AsRef<Path>,
+{
+    let mut reader = Reader::from_path(&bam).context("Could not open BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+    let mut header = Header::from_template(reader.header());
+    let description = match options.remove {
+        true => "dedup: removed duplicate reads",
+        false => "dedup: marked duplicate reads",
+    };
+    provenance::add_program_record(&mut header, description);
+    let mut writer = Writer::from_path(output.as_ref(), &header, Format::Bam)
+        .with_context(|| format!("Could not create `{}`", output.as_ref().display()))?;
+    if options.threads > 1 {
+        writer.set_threads(options.threads)?;
+    }
+
+    let mut pending = Pending {
+        records: VecDeque::new(),
+        sets: HashMap::new(),
+        mates: HashMap::new(),
+    };
+    let mut stats = DedupStats::default();
+    let mut last = (-1, -1);
+    let mut reach = 0;
+
+    for result in reader.records() {
+        let record = result?;
+        let (tid, pos) = (record.tid(), record.pos());
+        if tid != last.0 {
+            pending.flush(None, reach, &mut writer, &mut stats, options)?;
+        } else if pos < last.1 {
+            bail!(
+                "Input is not coordinate-sorted: `{}` comes after position {}",
+                String::from_utf8_lossy(record.qname()),
+                last.1 + 1
+            );
+        }
+        last = (tid, pos);
+
+        if !record.is_unmapped() {
+            reach = reach.max(read_span(&record));
+        }
+        pending.flush(Some(pos), reach, &mut writer, &mut stats, options)?;
+        pending.push(record);
+    }
+    pending.flush(None, reach, &mut writer, &mut stats, options)?;
+    Ok(stats)
+}
+
+/// Write the duplication metrics to `output`, or stdout.
+pub fn write_stats<P: AsRef<Path>>(stats: &DedupStats, output: Option<P>) -> Result<()> {
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path.as_ref())?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    stats.write(&mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    fn read(name: &str, pos: i64, reverse: bool, cigar: Vec<Cigar>, qual: u8) -> Record {
+        let mut record = Record::new();
+        let length = cigar
+            .iter()
+            .filter(|op| matches!(op, Cigar::Match(_) | Cigar::SoftClip(_)))
+            .map(|op| op.len() as usize)
+            .sum();
+        record.set(
+            name.as_bytes(),
+            Some(&CigarString(cigar)),
+            &vec![b'A'; length],
+            &vec![qual; length],
+        );
+        record.set_tid(0);
+        record.set_pos(pos);
+        record.unset_unmapped();
+        if reverse {
+            record.set_reverse();
+        }
+        record
+    }
+
+    #[test]
+    fn test_duplicate_key_uses_unclipped_five_prime_end() {
+        let clipped = read(
+            "a",
+            102,
+            false,
+            vec![Cigar::SoftClip(2), Cigar::Match(8)],
+            30,
+        );
+        let plain = read("b", 100, false, vec![Cigar::Match(10)], 30);
+        assert_eq!(duplicate_key(&clipped), duplicate_key(&plain));
+
+        let reverse = read(
+            "c",
+            100,
+            true,
+            vec![Cigar::Match(8), Cigar::SoftClip(2)],
+            30,
+        );
+        assert_eq!(duplicate_key(&reverse).unwrap().end.1, 109);
+        assert_ne!(duplicate_key(&reverse), duplicate_key(&plain));
+
+        // The read with the better base qualities is kept
+        let better = read("d", 100, false, vec![Cigar::Match(10)], 40);
+        assert!(rank(&better) > rank(&plain));
+    }
+
+    /// `record` as one mate of a pair with `mate`, with the mate's CIGAR and base quality
+    /// score in its MC and ms tags, as from `samtools fixmate -m`.
+    fn pair(mut record: Record, first: bool, mate: &Record) -> Record {
+        let mut flags = record.flags() | 0x1 | if first { 0x40 } else { 0x80 };
+        if mate.is_reverse() {
+            flags |= 0x20;
+        }
+        record.set_flags(flags);
+        record.set_mtid(0);
+        record.set_mpos(mate.pos());
+        record
+            .push_aux(b"MC", Aux::String(&mate.cigar().to_string()))
+            .unwrap();
+        record
+            .push_aux(b"ms", Aux::I32(base_score(mate.qual()) as i32))
+            .unwrap();
+        record
+    }
+
+    #[test]
+    fn test_pairs_are_decided_once_by_both_ends() {
+        use rust_htslib::bam::header::HeaderRecord;
+
+        // The same fragment twice, clipped differently, with the second's read 2 better
+        let a1 = read("a", 100, false, vec![Cigar::Match(10)], 30);
+        let a2 = read("a", 300, true, vec![Cigar::Match(10)], 30);
+        let b1 = read(
+            "b",
+            102,
+            false,
+            vec![Cigar::SoftClip(2), Cigar::Match(8)],
+            30,
+        );
+        let b2 = read(
+            "b",
+            300,
+            true,
+            vec![Cigar::Match(8), Cigar::SoftClip(2)],
+            40,
+        );
+        let records = [
+            pair(a1.clone(), true, &a2),
+            pair(b1.clone(), true, &b2),
+            pair(a2, false, &a1),
+            pair(b2, false, &b1),
+        ];
+        assert_eq!(duplicate_key(&records[0]), duplicate_key(&records[3]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("in.bam"), dir.path().join("out.bam"));
+        let mut header = Header::new();
+        let mut sq = HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chr1").push_tag(b"LN", 1000);
+        header.push_record(&sq);
+        let mut writer = Writer::from_path(&input, &header, Format::Bam).unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        drop(writer);
+
+        let stats = dedup(&input, &output, &DedupOptions::default()).unwrap();
+        assert_eq!((stats.templates, stats.duplicate_templates), (2, 1));
+        let duplicates: Vec<(Vec<u8>, bool)> = Reader::from_path(&output)
+            .unwrap()
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                (record.qname().to_vec(), record.is_duplicate())
+            })
+            .collect();
+        assert_eq!(
+            duplicates,
+            [
+                (b"a".to_vec(), true),
+                (b"b".to_vec(), false),
+                (b"a".to_vec(), true),
+                (b"b".to_vec(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_library_size_estimate() {
+        assert_eq!(estimate_library_size(100, 100), None);
+        let size = estimate_library_size(1_000_000, 500_000).unwrap();
+        // Half the reads being unique means the library was sampled about 1.6 times over
+        assert!((620_000..640_000).contains(&size), "{}", size);
+    }
+}
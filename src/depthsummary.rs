@@ -0,0 +1,315 @@
+//! Genome-wide depth and breadth summary.
+//!
+//! Each chromosome is streamed through the index and swept with a heap of active aligned
+//! blocks, so depth is never held per base. Reports mean depth and the fraction of bases
+//! covered at each threshold, per chromosome and for the whole genome.
+
+use anyhow::{Context, Result};
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{IndexedReader, Read};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::signal;
+
+#[derive(Debug, Clone)]
+pub struct DepthSummaryOptions {
+    /// Report the fraction of bases with at least this depth, for each threshold
+    pub thresholds: Vec<u32>,
+    pub min_mapq: u8,
+    pub threads: usize,
+}
+
+impl Default for DepthSummaryOptions {
+    fn default() -> Self {
+        Self {
+            thresholds: vec![1, 10, 30],
+            min_mapq: 0,
+            threads: 1,
+        }
+    }
+}
+
+/// Bases and depth totals for one chromosome (or the genome).
+#[derive(Debug, Clone, Default, PartialEq)]
+struct DepthTotals {
+    length: u64,
+    /// Sum of depth over all bases
+    depth_sum: u64,
+    /// Bases at or above each threshold
+    above: Vec<u64>,
+}
+
+impl DepthTotals {
+    fn add(&mut self, other: &DepthTotals) {
+        self.length += other.length;
+        self.depth_sum += other.depth_sum;
+        self.above.resize(other.above.len(), 0);
+        for (a, b) in self.above.iter_mut().zip(&other.above) {
+            *a += b;
+        }
+    }
+
+    fn add_run(&mut self, bases: i64, depth: u32, thresholds: &[u32]) {
+        self.depth_sum += depth as u64 * bases as u64;
+        for (above, &threshold) in self.above.iter_mut().zip(thresholds) {
+            if depth >= threshold {
+                *above += bases as u64;
+            }
+        }
+    }
+
+    fn mean(&self) -> f64 {
+        match self.length {
+            0 => 0.0,
+            length => self.depth_sum as f64 / length as f64,
+        }
+    }
+
+    fn breadth(&self, i: usize) -> f64 {
+        match self.length {
+            0 => 0.0,
+            length => self.above[i] as f64 / length as f64,
+        }
+    }
+}
+
+/// Sweep over aligned blocks in order of read start, reporting runs of constant depth.
+///
+/// `on_run(start, end, depth)` is called for consecutive runs covering the chromosome from
+/// 0; neighbouring runs may have the same depth.
+pub struct DepthSweep<F: FnMut(i64, i64, u32)> {
+    /// Ends of the blocks covering the cursor
+    active: BinaryHeap<Reverse<i64>>,
+    /// Blocks that start ahead of the current read (e.g. after a splice)
+    pending: BinaryHeap<Reverse<(i64, i64)>>,
+    cursor: i64,
+    on_run: F,
+}
+
+impl<F: FnMut(i64, i64, u32)> DepthSweep<F> {
+    pub fn new(on_run: F) -> Self {
+        Self {
+            active: BinaryHeap::new(),
+            pending: BinaryHeap::new(),
+            cursor: 0,
+            on_run,
+        }
+    }
+
+    /// Move the cursor to `position`, closing blocks that end on the way.
+    fn advance(&mut self, position: i64) {
+        while let Some(&Reverse(end)) = self.active.peek() {
+            if end > position {
+                break;
+            }
+            if end > self.cursor {
+                (self.on_run)(self.cursor, end, self.active.len() as u32);
+                self.cursor = end;
+            }
+            self.active.pop();
+        }
+        if position > self.cursor {
+            (self.on_run)(self.cursor, position, self.active.len() as u32);
+            self.cursor = position;
+        }
+    }
+
+    /// Open pending blocks starting at or before `position`.
+    fn open_until(&mut self, position: i64) {
+        while let Some(&Reverse((start, end))) = self.pending.peek() {
+            if start > position {
+                break;
+            }
+            self.advance(start);
+            self.active.push(Reverse(end));
+            self.pending.pop();
+        }
+    }
+
+    /// Add the aligned blocks of a read starting at `position`. Reads must be added in
+    /// order of position.
+    pub fn add_read(&mut self, position: i64, blocks: impl IntoIterator<Item = [i64; 2]>) {
+        self.open_until(position);
+        for [start, end] in blocks {
+            if start < end {
+                self.pending.push(Reverse((start, end)));
+            }
+        }
+    }
+
+    /// Report the remaining runs up to the end of a chromosome of `length` bases.
+    pub fn finish(mut self, length: u64) {
+        self.open_until(i64::MAX);
+        self.advance(length as i64);
+    }
+}
+
+/// Whether a read counts towards depth.
+pub fn counts_towards_depth(record: &Record, min_mapq: u8) -> bool {
+    !(record.is_unmapped()
+        || record.is_secondary()
+        || record.is_supplementary()
+        || record.is_quality_check_failed()
+        || record.is_duplicate()
+        || record.mapq() < min_mapq)
+}
+
+/// Sweep the reads of chromosome `tid` (of `length` bases) that pass the filters.
+pub fn sweep_chromosome<F: FnMut(i64, i64, u32)>(
+    reader: &mut IndexedReader,
+    tid: u32,
+    length: u64,
+    min_mapq: u8,
+    on_run: F,
+) -> Result<()> {
+    let mut sweep = DepthSweep::new(on_run);
+    reader.fetch(tid)?;
+    for result in reader.records() {
+        let record = result?;
+        if !counts_towards_depth(&record, min_mapq) {
+            continue;
+        }
+        // Blocks running off the end of the chromosome are clipped to it
+        let blocks = record
+            .aligned_blocks()
+            .map(|[start, end]| [start, end.min(length as i64)]);
+        sweep.add_read(record.pos(), blocks);
+    }
+    sweep.finish(length);
+    Ok(())
+}
+
+fn chromosome_depth(
+    reader: &mut IndexedReader,
+    tid: u32,
+    length: u64,
+    options: &DepthSummaryOptions,
+) -> Result<DepthTotals> {
+    let mut totals = DepthTotals {
+        length,
+        above: vec![0; options.thresholds.len()],
+        ..Default::default()
+    };
+    sweep_chromosome(
+        reader,
+        tid,
+        length,
+        options.min_mapq,
+        |start, end, depth| totals.add_run(end - start, depth, &options.thresholds),
+    )?;
+    Ok(totals)
+}
+
+/// Write mean depth and breadth at each threshold for every chromosome of `bam`, followed
+/// by a genome-wide row, to `output` (or stdout).
+pub fn depth_summary<P>(bam: P, output: Option<P>, options: &DepthSummaryOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let bam: PathBuf = bam.as_ref().to_path_buf();
+    let reader = IndexedReader::from_path(&bam).context("Could not open indexed BAM file")?;
+    let chroms = signal::chrom_sizes(reader.header());
+    drop(reader);
+
+    let (chrom_sender, chrom_recv) = crossbeam::channel::unbounded::<(usize, u64)>();
+    let (totals_sender, totals_recv) = crossbeam::channel::unbounded();
+
+    let mut handles = Vec::new();
+    for _ in 0..options.threads.max(1) {
+        let chrom_recv = chrom_recv.clone();
+        let totals_sender = totals_sender.clone();
+        let bam = bam.clone();
+        let options = options.clone();
+
+        handles.push(thread::spawn(move || -> Result<()> {
+            let mut reader = IndexedReader::from_path(&bam)?;
+            for (tid, length) in chrom_recv {
+                let totals = chromosome_depth(&mut reader, tid as u32, length, &options)?;
+                totals_sender.send((tid, totals))?;
+            }
+            Ok(())
+        }));
+    }
+    drop(totals_sender);
+
+    for (tid, (_, length)) in chroms.iter().enumerate() {
+        chrom_sender.send((tid, *length))?;
+    }
+    drop(chrom_sender);
+
+    let mut per_chrom = vec![DepthTotals::default(); chroms.len()];
+    for (tid, totals) in totals_recv {
+        per_chrom[tid] = totals;
+    }
+    for handle in handles {
+        handle.join().expect("Failed to join depth thread")?;
+    }
+
+    let mut genome = DepthTotals::default();
+    for totals in &per_chrom {
+        genome.add(totals);
+    }
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path.as_ref())?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    write!(writer, "chrom\tlength\tmean")?;
+    for threshold in &options.thresholds {
+        write!(writer, "\tfrac_ge_{}x", threshold)?;
+    }
+    writeln!(writer)?;
+
+    let rows = chroms
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .zip(per_chrom.iter())
+        .chain(std::iter::once(("genome", &genome)));
+    for (name, totals) in rows {
+        write!(writer, "{}\t{}\t{:.3}", name, totals.length, totals.mean())?;
+        for i in 0..options.thresholds.len() {
+            write!(writer, "\t{:.4}", totals.breadth(i))?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_counts_depth_and_breadth() {
+        let thresholds = [1, 2];
+        let mut totals = DepthTotals {
+            length: 100,
+            above: vec![0; 2],
+            ..Default::default()
+        };
+        let mut runs = Vec::new();
+        let mut sweep = DepthSweep::new(|start, end, depth| {
+            runs.push((start, end, depth));
+            totals.add_run(end - start, depth, &thresholds);
+        });
+        // A spliced read whose second block starts after the next read
+        sweep.add_read(0, [[0, 10], [50, 60]]);
+        sweep.add_read(5, [[5, 15]]);
+        sweep.add_read(55, [[55, 65]]);
+        sweep.finish(100);
+
+        assert_eq!(runs.first(), Some(&(0, 5, 1)));
+        assert_eq!(runs.last(), Some(&(65, 100, 0)));
+        assert_eq!(totals.depth_sum, 40);
+        // Covered: 0-15 and 50-65; depth 2 over 5-10 and 55-60
+        assert_eq!(totals.above, vec![30, 10]);
+        assert!((totals.mean() - 0.4).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,234 @@
+//! Alignment flag statistics (like `samtools flagstat`), optionally per read group.
+
+use anyhow::{Context, Result};
+use rust_htslib::bam::record::{Aux, Record};
+use rust_htslib::bam::{Read, Reader};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Column for records without an RG tag when breaking down by read group.
+const NO_READ_GROUP: &str = "no_read_group";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlagStats {
+    pub total: u64,
+    pub qc_failed: u64,
+    pub primary: u64,
+    pub secondary: u64,
+    pub supplementary: u64,
+    pub duplicates: u64,
+    pub mapped: u64,
+    pub primary_mapped: u64,
+    pub paired: u64,
+    pub read1: u64,
+    pub read2: u64,
+    pub properly_paired: u64,
+    pub both_mapped: u64,
+    pub singletons: u64,
+    pub mate_other_chrom: u64,
+    pub mate_other_chrom_mapq5: u64,
+}
+
+impl FlagStats {
+    pub fn add(&mut self, record: &Record) {
+        self.total += 1;
+        if record.is_quality_check_failed() {
+            self.qc_failed += 1;
+        }
+        if record.is_duplicate() {
+            self.duplicates += 1;
+        }
+        if !record.is_unmapped() {
+            self.mapped += 1;
+        }
+
+        if record.is_secondary() {
+            self.secondary += 1;
+            return;
+        }
+        if record.is_supplementary() {
+            self.supplementary += 1;
+            return;
+        }
+
+        self.primary += 1;
+        if !record.is_unmapped() {
+            self.primary_mapped += 1;
+        }
+        if !record.is_paired() {
+            return;
+        }
+
+        self.paired += 1;
+        if record.is_first_in_template() {
+            self.read1 += 1;
+        }
+        if record.is_last_in_template() {
+            self.read2 += 1;
+        }
+        if record.is_unmapped() {
+            return;
+        }
+        if record.is_proper_pair() {
+            self.properly_paired += 1;
+        }
+        if record.is_mate_unmapped() {
+            self.singletons += 1;
+        } else {
+            self.both_mapped += 1;
+            if record.tid() != record.mtid() {
+                self.mate_other_chrom += 1;
+                if record.mapq() >= 5 {
+                    self.mate_other_chrom_mapq5 += 1;
+                }
+            }
+        }
+    }
+
+    /// Metric names and values in report order.
+    pub fn metrics(&self) -> [(&'static str, u64); 16] {
+        [
+            ("total", self.total),
+            ("qc_failed", self.qc_failed),
+            ("primary", self.primary),
+            ("secondary", self.secondary),
+            ("supplementary", self.supplementary),
+            ("duplicates", self.duplicates),
+            ("mapped", self.mapped),
+            ("primary_mapped", self.primary_mapped),
+            ("paired", self.paired),
+            ("read1", self.read1),
+            ("read2", self.read2),
+            ("properly_paired", self.properly_paired),
+            ("both_mapped", self.both_mapped),
+            ("singletons", self.singletons),
+            ("mate_other_chrom", self.mate_other_chrom),
+            ("mate_other_chrom_mapq5", self.mate_other_chrom_mapq5),
+        ]
+    }
+}
+
+/// Read group IDs in header order.
+fn header_read_groups(header: &rust_htslib::bam::HeaderView) -> Vec<String> {
+    let text = String::from_utf8_lossy(header.as_bytes()).to_string();
+    text.lines()
+        .filter(|line| line.starts_with("@RG"))
+        .filter_map(|line| {
+            line.split('\t')
+                .find_map(|field| field.strip_prefix("ID:"))
+                .map(str::to_owned)
+        })
+        .collect()
+}
+
+fn read_group(record: &Record) -> Option<String> {
+    match record.aux(b"RG") {
+        Ok(Aux::String(rg)) => Some(rg.to_owned()),
+        _ => None,
+    }
+}
+
+/// Write flag statistics for `bam` as a TSV with one row per metric, to `output` or stdout.
+///
+/// The `all` column covers every record. With `by_read_group`, a further column is added
+/// for each @RG in the header (and for records without an RG tag, if there are any).
+pub fn flagstat<P>(bam: P, output: Option<P>, by_read_group: bool, threads: usize) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut reader = Reader::from_path(&bam).context("Could not open BAM file")?;
+    if threads > 1 {
+        reader.set_threads(threads)?;
+    }
+
+    let mut read_groups = header_read_groups(reader.header());
+    let mut all = FlagStats::default();
+    let mut by_group: HashMap<String, FlagStats> = HashMap::new();
+
+    for result in reader.records() {
+        let record = result?;
+        all.add(&record);
+        if by_read_group {
+            let group = read_group(&record).unwrap_or_else(|| NO_READ_GROUP.to_string());
+            by_group.entry(group).or_default().add(&record);
+        }
+    }
+
+    // Read groups used by records but missing from the header still get a column
+    let mut extra: Vec<String> = by_group
+        .keys()
+        .filter(|group| !read_groups.contains(group) && group.as_str() != NO_READ_GROUP)
+        .cloned()
+        .collect();
+    extra.sort();
+    read_groups.extend(extra);
+    if by_group.contains_key(NO_READ_GROUP) {
+        read_groups.push(NO_READ_GROUP.to_string());
+    }
+
+    let mut columns = vec![("all".to_string(), all)];
+    if by_read_group {
+        columns.extend(read_groups.into_iter().map(|group| {
+            let stats = by_group.get(&group).copied().unwrap_or_default();
+            (group, stats)
+        }));
+    }
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path.as_ref())?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    write!(writer, "metric")?;
+    for (name, _) in &columns {
+        write!(writer, "\t{}", name)?;
+    }
+    writeln!(writer)?;
+
+    let metrics: Vec<_> = columns.iter().map(|(_, stats)| stats.metrics()).collect();
+    for (row, (metric, _)) in all.metrics().iter().enumerate() {
+        write!(writer, "{}", metric)?;
+        for values in &metrics {
+            write!(writer, "\t{}", values[row].1)?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_follow_flags() {
+        let mut stats = FlagStats::default();
+
+        let mut record = Record::new();
+        record.set(b"read1", None, b"ACGT", b"IIII");
+        record.set_tid(0);
+        record.set_mtid(1);
+        record.set_mapq(10);
+        // paired, proper pair, first in template
+        record.set_flags(0x1 | 0x2 | 0x40);
+        stats.add(&record);
+
+        record.set_flags(0x1 | 0x80 | 0x8 | 0x400);
+        stats.add(&record);
+
+        record.set_flags(0x100);
+        stats.add(&record);
+
+        assert_eq!(stats.total, 3);
+        assert_eq!(stats.primary, 2);
+        assert_eq!(stats.secondary, 1);
+        assert_eq!(stats.mapped, 3);
+        assert_eq!((stats.read1, stats.read2), (1, 1));
+        assert_eq!(stats.properly_paired, 1);
+        assert_eq!(stats.singletons, 1);
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.mate_other_chrom_mapq5, 1);
+    }
+}
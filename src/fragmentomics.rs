@@ -0,0 +1,189 @@
+//! Short/long fragment ratios in fixed genomic bins (DELFI-style fragmentation profiles).
+
+use anyhow::{Context, Result};
+use rust_htslib::bam::{Read, Reader};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::fragments::{self, FragmentFilter};
+use crate::signal::{self, TrackWriter};
+
+#[derive(Debug, Clone)]
+pub struct FragmentomicsOptions {
+    pub bin_size: u64,
+    pub short: RangeInclusive<i64>,
+    pub long: RangeInclusive<i64>,
+    pub min_mapq: u8,
+    pub threads: usize,
+}
+
+impl Default for FragmentomicsOptions {
+    fn default() -> Self {
+        Self {
+            bin_size: 100_000,
+            short: 100..=150,
+            long: 151..=220,
+            min_mapq: 0,
+            threads: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct BinCounts {
+    short: u64,
+    long: u64,
+}
+
+impl BinCounts {
+    fn ratio(&self) -> Option<f64> {
+        match self.long {
+            0 => None,
+            long => Some(self.short as f64 / long as f64),
+        }
+    }
+}
+
+/// Per-chromosome bin counts of short and long fragments.
+struct FragmentProfile {
+    chroms: Vec<(String, u64)>,
+    bins: Vec<Vec<BinCounts>>,
+    bin_size: u64,
+}
+
+impl FragmentProfile {
+    fn new(chroms: Vec<(String, u64)>, bin_size: u64) -> Self {
+        let bins = chroms
+            .iter()
+            .map(|(_, length)| vec![BinCounts::default(); length.div_ceil(bin_size) as usize])
+            .collect();
+        Self {
+            chroms,
+            bins,
+            bin_size,
+        }
+    }
+
+    /// Count a fragment in the bin holding its midpoint.
+    fn add(&mut self, tid: usize, midpoint: u64, length: i64, options: &FragmentomicsOptions) {
+        let bin = match self.bins[tid].get_mut((midpoint / self.bin_size) as usize) {
+            Some(bin) => bin,
+            None => return,
+        };
+        if options.short.contains(&length) {
+            bin.short += 1;
+        } else if options.long.contains(&length) {
+            bin.long += 1;
+        }
+    }
+
+    fn write_tsv<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "chrom\tstart\tend\tshort\tlong\tratio")?;
+
+        for ((chrom, length), bins) in self.chroms.iter().zip(self.bins.iter()) {
+            for (i, counts) in bins.iter().enumerate() {
+                let start = i as u64 * self.bin_size;
+                let end = (start + self.bin_size).min(*length);
+                let ratio = counts
+                    .ratio()
+                    .map(|r| format!("{:.4}", r))
+                    .unwrap_or_else(|| "NA".to_string());
+                writeln!(
+                    writer,
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    chrom, start, end, counts.short, counts.long, ratio
+                )?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Bins without any long fragments have no ratio and are left out of the track.
+    fn write_track<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = TrackWriter::create(path, self.chroms.clone())?;
+        for ((chrom, length), bins) in self.chroms.iter().zip(self.bins.iter()) {
+            for (i, counts) in bins.iter().enumerate() {
+                if let Some(ratio) = counts.ratio() {
+                    let start = i as u64 * self.bin_size;
+                    let end = (start + self.bin_size).min(*length);
+                    writer.write(chrom, start, end, ratio as f32)?;
+                }
+            }
+        }
+        writer.finish()
+    }
+}
+
+/// Write the fragmentation profile of `bam` to `output`.
+///
+/// Outputs ending in `.bw`/`.bigwig`/`.bedgraph`/`.bg` get the ratio track only;
+/// anything else gets a TSV with the counts behind each ratio.
+pub fn fragmentomics<P>(bam: P, output: P, options: &FragmentomicsOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut reader = Reader::from_path(&bam).context("Could not open BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+
+    let chroms = signal::chrom_sizes(reader.header());
+    let mut profile = FragmentProfile::new(chroms, options.bin_size);
+
+    let filter = FragmentFilter {
+        min_mapq: options.min_mapq,
+        min_length: *options.short.start().min(options.long.start()),
+        max_length: *options.short.end().max(options.long.end()),
+    };
+
+    for result in reader.records() {
+        let record = result?;
+        if let Some(fragment) = fragments::from_record(&record, &filter) {
+            profile.add(
+                fragment.tid as usize,
+                fragment.midpoint() as u64,
+                fragment.len(),
+                options,
+            );
+        }
+    }
+
+    let extension = output
+        .as_ref()
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+    match extension.as_deref() {
+        Some("bw" | "bigwig" | "bedgraph" | "bg") => profile.write_track(&output),
+        _ => profile.write_tsv(&output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragments_are_binned_by_midpoint() {
+        let options = FragmentomicsOptions {
+            bin_size: 100,
+            ..Default::default()
+        };
+        let mut profile = FragmentProfile::new(vec![("chr1".to_string(), 250)], 100);
+
+        profile.add(0, 50, 120, &options);
+        profile.add(0, 99, 200, &options);
+        profile.add(0, 150, 120, &options);
+        profile.add(0, 150, 300, &options); // neither short nor long
+        profile.add(0, 400, 120, &options); // off the end of the chromosome
+
+        assert_eq!(profile.bins[0].len(), 3);
+        assert_eq!(profile.bins[0][0], BinCounts { short: 1, long: 1 });
+        assert_eq!(profile.bins[0][0].ratio(), Some(1.0));
+        assert_eq!(profile.bins[0][1].ratio(), None);
+    }
+}
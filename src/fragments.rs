@@ -0,0 +1,93 @@
+//! Paired-end fragments reconstructed from BAM records.
+
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::Record;
+
+/// A sequenced fragment spanning `[start, end)` on reference `tid`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment {
+    pub tid: i32,
+    pub start: i64,
+    pub end: i64,
+}
+
+impl Fragment {
+    pub fn len(&self) -> i64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() <= 0
+    }
+
+    pub fn midpoint(&self) -> i64 {
+        self.start + self.len() / 2
+    }
+}
+
+/// Which records contribute fragments.
+#[derive(Debug, Clone, Copy)]
+pub struct FragmentFilter {
+    pub min_mapq: u8,
+    pub min_length: i64,
+    pub max_length: i64,
+}
+
+impl Default for FragmentFilter {
+    fn default() -> Self {
+        Self {
+            min_mapq: 0,
+            min_length: 1,
+            max_length: 1000,
+        }
+    }
+}
+
+/// The fragment a record belongs to.
+///
+/// Each fragment is only reported once, from the leftmost mate of a proper pair (the one
+/// with a positive TLEN), so iterating over all records yields every fragment exactly once.
+pub fn from_record(record: &Record, filter: &FragmentFilter) -> Option<Fragment> {
+    if record.is_unmapped()
+        || record.is_secondary()
+        || record.is_supplementary()
+        || record.is_quality_check_failed()
+        || record.is_duplicate()
+        || !record.is_proper_pair()
+        || record.mapq() < filter.min_mapq
+    {
+        return None;
+    }
+
+    let tlen = record.insert_size();
+    if tlen <= 0 || tlen < filter.min_length || tlen > filter.max_length {
+        return None;
+    }
+
+    Some(Fragment {
+        tid: record.tid(),
+        start: record.pos(),
+        end: record.pos() + tlen,
+    })
+}
+
+/// The Tn5 insertion site of a read.
+///
+/// Uses the standard ATAC-seq offsets: +4 from the 5' end of forward reads and -5 from the
+/// 5' end of reverse reads. Every mapped primary read contributes a cut site.
+pub fn cut_site(record: &Record, min_mapq: u8) -> Option<i64> {
+    if record.is_unmapped()
+        || record.is_secondary()
+        || record.is_supplementary()
+        || record.is_quality_check_failed()
+        || record.is_duplicate()
+        || record.mapq() < min_mapq
+    {
+        return None;
+    }
+
+    match record.is_reverse() {
+        true => Some(record.reference_end() - 5),
+        false => Some(record.pos() + 4),
+    }
+}
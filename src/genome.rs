@@ -0,0 +1,140 @@
+//! Known reference assemblies and detecting them from reference names and lengths.
+
+use std::collections::HashMap;
+
+/// A reference assembly and the lengths of a few contigs that identify it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assembly {
+    pub name: &'static str,
+    /// Contig names (without any `chr` prefix) and lengths
+    pub fingerprint: &'static [(&'static str, u64)],
+    /// ENCODE exclusion list (Amemiya et al. 2019), if one is published for the assembly
+    pub blacklist_url: Option<&'static str>,
+}
+
+pub const ASSEMBLIES: [Assembly; 5] = [
+    Assembly {
+        name: "hg19",
+        fingerprint: &[
+            ("1", 249_250_621),
+            ("2", 243_199_373),
+            ("3", 198_022_430),
+            ("X", 155_270_560),
+            ("Y", 59_373_566),
+        ],
+        blacklist_url: Some("https://raw.githubusercontent.com/Boyle-Lab/Blacklist/master/lists/hg19-blacklist.v2.bed.gz"),
+    },
+    Assembly {
+        name: "hg38",
+        fingerprint: &[
+            ("1", 248_956_422),
+            ("2", 242_193_529),
+            ("3", 198_295_559),
+            ("X", 156_040_895),
+            ("Y", 57_227_415),
+        ],
+        blacklist_url: Some("https://raw.githubusercontent.com/Boyle-Lab/Blacklist/master/lists/hg38-blacklist.v2.bed.gz"),
+    },
+    Assembly {
+        name: "mm10",
+        fingerprint: &[
+            ("1", 195_471_971),
+            ("2", 182_113_224),
+            ("3", 160_039_680),
+            ("X", 171_031_299),
+            ("Y", 91_744_698),
+        ],
+        blacklist_url: Some("https://raw.githubusercontent.com/Boyle-Lab/Blacklist/master/lists/mm10-blacklist.v2.bed.gz"),
+    },
+    Assembly {
+        name: "mm39",
+        fingerprint: &[
+            ("1", 195_154_279),
+            ("2", 181_755_017),
+            ("3", 159_745_316),
+            ("X", 169_476_592),
+            ("Y", 91_455_967),
+        ],
+        blacklist_url: None,
+    },
+    Assembly {
+        name: "dm6",
+        fingerprint: &[
+            ("2L", 23_513_712),
+            ("2R", 25_286_936),
+            ("3L", 28_110_227),
+            ("3R", 32_079_331),
+            ("X", 23_542_271),
+        ],
+        blacklist_url: Some("https://raw.githubusercontent.com/Boyle-Lab/Blacklist/master/lists/dm6-blacklist.v2.bed.gz"),
+    },
+];
+
+/// The assembly a set of references was matched to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildMatch {
+    pub assembly: Assembly,
+    /// Whether the references use UCSC-style `chr` names
+    pub chr_prefix: bool,
+    /// Fingerprint contigs found with the expected length
+    pub matched: usize,
+}
+
+/// Match reference names and lengths against the known assemblies.
+///
+/// The assembly with the most fingerprint contigs present at the expected length wins,
+/// provided more than half of its fingerprint matches.
+pub fn detect_build(chroms: &[(String, u64)]) -> Option<BuildMatch> {
+    let chr_prefix = chroms.iter().any(|(name, _)| name.starts_with("chr"));
+    let lengths: HashMap<&str, u64> = chroms
+        .iter()
+        .map(|(name, length)| (name.strip_prefix("chr").unwrap_or(name), *length))
+        .collect();
+
+    ASSEMBLIES
+        .iter()
+        .map(|assembly| {
+            let matched = assembly
+                .fingerprint
+                .iter()
+                .filter(|(name, length)| lengths.get(name) == Some(length))
+                .count();
+            BuildMatch {
+                assembly: *assembly,
+                chr_prefix,
+                matched,
+            }
+        })
+        .filter(|m| 2 * m.matched > m.assembly.fingerprint.len())
+        .max_by_key(|m| m.matched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_assemblies_with_and_without_prefix() {
+        let hg38 = vec![
+            ("chr1".to_string(), 248_956_422),
+            ("chr2".to_string(), 242_193_529),
+            ("chr3".to_string(), 198_295_559),
+            ("chrM".to_string(), 16_569),
+        ];
+        let found = detect_build(&hg38).unwrap();
+        assert_eq!(found.assembly.name, "hg38");
+        assert!(found.chr_prefix);
+        assert_eq!(found.matched, 3);
+
+        let grch37 = vec![
+            ("1".to_string(), 249_250_621),
+            ("2".to_string(), 243_199_373),
+            ("X".to_string(), 155_270_560),
+        ];
+        let found = detect_build(&grch37).unwrap();
+        assert_eq!(found.assembly.name, "hg19");
+        assert!(!found.chr_prefix);
+
+        assert_eq!(detect_build(&[("contig1".to_string(), 1000)]), None);
+    }
+}
@@ -2,9 +2,42 @@ use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use std::path::{PathBuf};
 
+pub mod adapters;
+pub mod annotate;
 pub mod atac_shift_bam;
+pub mod bedgraph;
+pub mod bias;
+pub mod bigwig;
+pub mod callable;
+pub mod chimeras;
+pub mod chromsizes;
+pub mod contam;
+pub mod coverage;
+pub mod dedup;
+pub mod depthsummary;
+pub mod flagstat;
+pub mod fragmentomics;
+pub mod fragments;
+pub mod genome;
+pub mod jaccard;
+pub mod multimap;
+pub mod nucleosome;
+pub mod pairorientation;
+pub mod peaks;
+pub mod provenance;
+pub mod qualprofile;
+pub mod regiondepth;
+pub mod shuffle;
+pub mod signal;
+pub mod slice;
+pub mod sort;
+pub mod split_by;
 pub mod subtract_regions;
 pub mod split_sample_and_spikein;
+pub mod vplot;
+pub mod wasp;
+pub mod wps;
+pub mod xenograft;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -16,88 +49,1377 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Shift {
+        /// Bam or cram file for processing
+        #[arg(short, long)]
+        bam: Option<PathBuf>,
+
+        /// Output file name
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Assay preset for the shift offsets and read filters; --shift, --min-mapq and
+        /// --exclude-flags override it. Without a preset the ATAC-seq offsets are used and
+        /// no reads are filtered
+        #[arg(long, value_enum)]
+        preset: Option<atac_shift_bam::Preset>,
+
+        /// Shift offsets as in deeptools alignmentSieve: forward read 1 start, reverse read 1
+        /// end, then the same for read 2. Defaults to the offsets of --preset
+        #[arg(long, num_args = 4, allow_negative_numbers = true)]
+        shift: Option<Vec<i64>>,
+
+        /// Single-end library: shift every mapped read by strand (the first two --shift
+        /// offsets) instead of only proper pairs
+        #[arg(long)]
+        single_end: bool,
+
+        /// Output format, inferred from the output extension (.bam, .cram, .sam, .bedpe, .bed,
+        /// .bedgraph) if not given. Interval formats write the shifted fragments directly
+        #[arg(long, value_enum)]
+        output_format: Option<atac_shift_bam::OutputFormat>,
+
+        /// Reference FASTA, required for CRAM output and CRAM input without embedded references
+        #[arg(long)]
+        reference: Option<PathBuf>,
+
+        /// Coordinate sort the shifted output
+        #[arg(long)]
+        sort: bool,
+
+        /// Write a .bai (or .csi) index next to the sorted output
+        #[arg(long, requires = "sort")]
+        write_index: bool,
+
+        /// Only shift reads overlapping the regions in this BED file (needs an indexed input)
+        #[arg(short, long)]
+        regions: Option<PathBuf>,
+
+        /// Write reads that are not shifted (e.g. not in proper pairs) through unmodified,
+        /// tagged sh:i:0, instead of discarding them
+        #[arg(long)]
+        keep_unshifted: bool,
+
+        /// Hold reads until their mate is shifted and set PNEXT/TLEN from the shifted mate, so
+        /// both records of a pair agree
+        #[arg(long)]
+        pair_aware: bool,
+
+        /// With --pair-aware, mark reads whose mate was dropped or not found as mate-unmapped
+        #[arg(long, requires = "pair_aware")]
+        fixmate: bool,
+
+        /// Minimum mapping quality of the reads shifted
+        #[arg(long)]
+        min_mapq: Option<u8>,
+
+        /// Remove reads with any of these SAM flags set (e.g. 1804)
+        #[arg(long)]
+        exclude_flags: Option<u16>,
+
+        /// Remove reads overlapping the regions in this BED file
+        #[arg(long)]
+        blacklist: Option<PathBuf>,
+
+        /// Write reads discarded by the proper-pair or coordinate checks to this BAM
+        #[arg(long)]
+        discarded: Option<PathBuf>,
+
+        /// Collapse each shifted read to its 1 bp Tn5 insertion site: 1 bp alignments, or
+        /// cut-site counts with --output-format bedgraph
+        #[arg(long)]
+        cut_sites: bool,
+
+        /// Report how many reads would be shifted, clipped at chromosome ends or dropped,
+        /// without writing any output
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Number of threads to use for BAM compression/decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    Subtract {
+        /// Regions to subtract: BED, narrowPeak, broadPeak, GFF3 or GTF (by extension), optionally
+        /// gzipped. Repeat or separate with commas to subtract several files at once
+        #[arg(short='r', long="regions", value_delimiter = ',')]
+        regions: Vec<PathBuf>,
+
+        /// Indexed BAM or CRAM file for processing (`-` for stdin, read with --stream)
+        #[arg(short='b', long="bam")]
+        bam: Option<PathBuf>,
+
+        /// Output file name (`-` for stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Keep only the reads overlapping the regions instead of removing them
+        #[arg(long)]
+        invert: bool,
+
+        /// Only count reads overlapping the regions by at least this many bases
+        #[arg(long, default_value_t = 1)]
+        min_overlap_bp: u64,
+
+        /// Only count reads with at least this fraction of their aligned span in the regions
+        #[arg(long, default_value_t = 0.0)]
+        min_overlap_frac: f64,
+
+        /// Remove (or with --invert keep) both mates of a pair when either overlaps a region
+        #[arg(long)]
+        pairs: bool,
+
+        /// Index the output BAM
+        #[arg(long)]
+        write_index: bool,
+
+        /// Only use GTF/GFF features of this type (e.g. exon)
+        #[arg(long)]
+        feature_type: Option<String>,
+
+        /// Widen each region by this many bases on both sides
+        #[arg(long, default_value_t = 0)]
+        slop: u64,
+
+        /// Also subtract the ENCODE blacklist of this assembly, downloaded on first use and
+        /// cached in ~/.cache/rsbamtk
+        #[arg(long, value_enum)]
+        blacklist_preset: Option<subtract_regions::BlacklistPreset>,
+
+        /// Write the reads that are left out to this BAM instead of discarding them
+        #[arg(long)]
+        removed_output: Option<PathBuf>,
+
+        /// Pass unmapped reads without coordinates through to the output
+        #[arg(long)]
+        keep_unmapped: bool,
+
+        /// Output format; taken from the output extension if not given
+        #[arg(long, value_enum)]
+        output_format: Option<subtract_regions::AlignmentFormat>,
+
+        /// Reference FASTA for CRAM input or output
+        #[arg(long)]
+        reference: Option<PathBuf>,
+
+        /// Read the input in a single pass, unsorted and without an index; implied when
+        /// reading from stdin (`-`)
+        #[arg(long)]
+        stream: bool,
+
+        /// Keep overlapping reads with the overlapping part masked: soft-clipped where it
+        /// is at either end of the alignment, or with its base qualities set to 0
+        #[arg(long, value_enum)]
+        mask: Option<subtract_regions::MaskMode>,
+
+        /// Write the regions that captured the most reads, with their share of the placed
+        /// reads, to this TSV (or JSON, for a .json path)
+        #[arg(long)]
+        region_report: Option<PathBuf>,
+
+        /// Number of regions in the --region-report
+        #[arg(long, default_value_t = 10)]
+        top_regions: usize,
+
+        /// Bases of a chromosome each thread filters at a time
+        #[arg(long, default_value_t = 10_000_000, value_parser = clap::value_parser!(u64).range(1..))]
+        window_size: u64,
+
+        /// Measure overlap against the whole span of BED12 regions rather than their
+        /// blocks (e.g. exons)
+        #[arg(long)]
+        bed12_span: bool,
+
+        /// Discard reads below this mapping quality
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Discard reads without all of these SAM flags set (e.g. 2 for proper pairs)
+        #[arg(long, default_value_t = 0)]
+        include_flags: u16,
+
+        /// Discard reads with any of these SAM flags set (e.g. 1804)
+        #[arg(long, default_value_t = 0)]
+        exclude_flags: u16,
+
+        /// Number of threads to use
+        #[arg(short, long)]
+        threads: Option<usize>,
+    },
+
+    Split {
+        /// Bam file for processing. Several can be given to split them in one run
+        #[arg(short, long, num_args = 1.., required = true)]
+        bam: Vec<PathBuf>,
+
+        /// Number of BAMs to split at the same time, when given several
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+
+        /// Prefix to use for exogenous spike-in reads
+        /// If not provided will default to dm6_. Repeat for several spike-in genomes
+        /// (e.g. dm6_ and ecoli_), each written to its own BAM.
+        #[arg(short, long)]
+        exogenous_prefix: Vec<String>,
+
+        /// Recognise exogenous contigs by this suffix instead (e.g. _dm6 for chr2L_dm6).
+        /// Repeat for several spike-in genomes.
+        #[arg(long, conflicts_with_all = ["exogenous_prefix", "exogenous_regex"])]
+        exogenous_suffix: Vec<String>,
+
+        /// Recognise exogenous contigs by a regular expression matching their names
+        #[arg(long, conflicts_with = "exogenous_prefix")]
+        exogenous_regex: Option<String>,
+
+        /// Take the exogenous contigs from a file listing their names, one per line, for
+        /// references whose contigs were not renamed. Repeat for several spike-in genomes,
+        /// each named after its file.
+        #[arg(long, conflicts_with_all = ["exogenous_prefix", "exogenous_suffix", "exogenous_regex"])]
+        exogenous_chroms: Vec<PathBuf>,
+
+        /// Find the prefix or suffix of the exogenous contigs (e.g. dm6_) from the contig
+        /// names in the header of the (first) BAM
+        #[arg(long, conflicts_with_all = ["exogenous_prefix", "exogenous_suffix", "exogenous_regex", "exogenous_chroms"])]
+        auto_detect_prefix: bool,
+
+        /// Output file prefix. The output files will be named as prefix.endogenous.bam,
+        /// prefix.exogenous.bam and so on. Defaults to the input BAM without its extension.
+        /// With several BAMs, a directory for the outputs of each, named after the BAM
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Add an @CO line with a hash of the split stats to every output BAM.
+        /// This requires re-writing the outputs once splitting has finished.
+        #[arg(long)]
+        stats_comment: bool,
+
+        /// Write the split stats to this JSON file, or TSV if it doesn't end in .json
+        #[arg(long)]
+        stats_output: Option<PathBuf>,
+
+        /// Route duplicate-marked reads by genome rather than to the unmapped output
+        #[arg(long)]
+        keep_duplicates: bool,
+
+        /// Route secondary alignments by genome rather than to the unmapped output
+        #[arg(long)]
+        keep_secondary: bool,
+
+        /// Route supplementary alignments by genome rather than to the unmapped output
+        #[arg(long)]
+        keep_supplementary: bool,
+
+        /// Route QC-failed reads by genome rather than to the unmapped output
+        #[arg(long)]
+        keep_qcfail: bool,
+
+        /// Remove the exogenous prefix, suffix or regex match from contig names in the
+        /// exogenous BAM (e.g. dm6_chr2L becomes chr2L)
+        #[arg(long)]
+        strip_prefix: bool,
+
+        /// Number of threads to use for BAM decompression, and for compressing each output
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+
+        /// Don't show a progress bar
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Keep both mates of a pair in the same output when only one passes the filters:
+        /// filter the pair unless both pass, or keep it if either does. Mates are held
+        /// until both are seen, so the outputs of coordinate-sorted input are marked
+        /// unsorted; this is best used on name-sorted or collated input.
+        #[arg(long, value_enum)]
+        pair_policy: Option<split_sample_and_spikein::PairPolicy>,
+
+        /// Count unmapped and filtered reads in the stats without writing them to the
+        /// unmapped BAM
+        #[arg(long)]
+        no_filtered_output: bool,
+
+        /// Where pairs with mates on different genomes go: the both genomes BAM, or the
+        /// genome of R1, of the mate with the higher MAPQ or of the higher AS tag. Ties and
+        /// missing values stay in the both genomes BAM. Other than `both`, mates are held
+        /// until both are seen, so the outputs of coordinate-sorted input are marked unsorted.
+        #[arg(long, value_enum, default_value_t = split_sample_and_spikein::AmbiguousPolicy::Both)]
+        ambiguous_policy: split_sample_and_spikein::AmbiguousPolicy,
+
+        /// Compression level of the output BAMs, from 0 to 9
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=9))]
+        compression_level: Option<u8>,
+
+        /// Write uncompressed BAMs, e.g. for intermediate files in a pipeline
+        #[arg(long, conflicts_with = "compression_level")]
+        uncompressed: bool,
+
+        /// Write the outputs as BAM or as CRAM (prefix.endogenous.cram, ...) against
+        /// --reference
+        #[arg(long, value_enum, default_value_t = subtract_regions::AlignmentFormat::Bam)]
+        output_format: subtract_regions::AlignmentFormat,
+
+        /// Indexed (samtools faidx) reference FASTA, required for CRAM output
+        #[arg(long, required_if_eq("output_format", "cram"))]
+        reference: Option<PathBuf>,
+
+        /// Also write the exogenous reads as FASTQ for realignment: prefix.1.fq.gz and
+        /// prefix.2.fq.gz for pairs, prefix.fq.gz for single reads. Each genome gets its
+        /// own files (prefix.<genome>.1.fq.gz) when there is more than one.
+        #[arg(long)]
+        exogenous_fastq: Option<PathBuf>,
+
+        /// Whether reads with a missing mapping quality (255, given to unique reads by
+        /// some aligners) pass the MAPQ filter or go to the unmapped BAM
+        #[arg(long, value_enum, default_value_t = split_sample_and_spikein::MissingMapq::Keep)]
+        missing_mapq: split_sample_and_spikein::MissingMapq,
+
+        /// Write secondary and supplementary alignments to the same output as their
+        /// primary alignment, instead of the unmapped BAM. Reads the input twice, and
+        /// alignments seen before their primary are written after it, so the outputs of
+        /// coordinate-sorted input are marked unsorted.
+        #[arg(long)]
+        follow_primary: bool,
+
+        /// Write the read name, flag, class, MAPQ and contigs of every record to this TSV
+        /// (gzipped if it ends in .gz), to audit the split or build custom filters. With
+        /// several BAMs, each table's file name starts with its BAM's name.
+        #[arg(long)]
+        assignment_table: Option<PathBuf>,
+
+        /// Warn if the fraction of genome-assigned reads that are exogenous falls outside
+        /// this range (e.g. 0.01 0.2)
+        #[arg(long, num_args = 2, value_names = ["MIN", "MAX"])]
+        expected_spikein_fraction: Option<Vec<f64>>,
+    },
+
+    /// Split a BAM into one file per group of reads, e.g. per chromosome, read group or
+    /// cluster of cells
+    SplitBy {
         /// Bam file for processing
         #[arg(short, long)]
-        bam: Option<PathBuf>,
+        bam: PathBuf,
+
+        /// Output file prefix. The output files will be named as prefix.<group>.bam
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// What to split the reads by: one output per chromosome, read group or group of
+        /// cell barcodes
+        #[arg(long, value_enum, default_value_t = split_by::SplitBy::Chromosome)]
+        by: split_by::SplitBy,
+
+        /// Tag holding the cell barcode, for --by tag
+        #[arg(long, default_value = "CB")]
+        tag: String,
+
+        /// Tab-separated table of cell barcodes and their group (e.g. cluster), for --by tag
+        #[arg(long, required_if_eq("by", "tag"))]
+        groups: Option<PathBuf>,
+
+        /// Most output files open at once; more groups take further passes over the input
+        #[arg(long, default_value_t = 256)]
+        max_open_files: usize,
+
+        /// Compression level of the output BAMs, from 0 to 9
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=9))]
+        compression_level: Option<u8>,
+
+        /// Write uncompressed BAMs, e.g. for intermediate files in a pipeline
+        #[arg(long, conflicts_with = "compression_level")]
+        uncompressed: bool,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Separate host and graft reads of xenograft (PDX) samples, from their alignments
+    /// to each genome
+    Xenograft {
+        /// Reads aligned to the graft genome (e.g. human)
+        #[arg(long)]
+        graft: PathBuf,
+
+        /// The same reads aligned to the host genome (e.g. mouse), in the same order
+        #[arg(long)]
+        host: PathBuf,
+
+        /// Output file prefix. The output files will be named as prefix.graft.bam,
+        /// prefix.host.bam, prefix.ambiguous_graft.bam, prefix.ambiguous_host.bam and
+        /// prefix.unmapped.bam
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Which genome a read aligns to better by: its alignment score (AS tag) or its
+        /// edit distance (NM tag), each breaking ties in the other and then the
+        /// suboptimal alignment score (XS tag)
+        #[arg(long, value_enum, default_value_t = xenograft::XenograftScore::AlignmentScore)]
+        score: xenograft::XenograftScore,
+
+        /// Number of threads to use for BAM compression and decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Mark or remove PCR duplicates by the 5' ends of reads, or of both mates of a pair
+    Dedup {
+        /// Coordinate-sorted bam file for processing, with MC and ms tags from
+        /// `samtools fixmate -m` to compare pairs by their mates' clipping and qualities
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output BAM file name
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Leave duplicates out of the output instead of setting their duplicate flag
+        #[arg(long)]
+        remove: bool,
+
+        /// Output file for the duplication rate and library size estimate. Written to
+        /// stdout if not given
+        #[arg(long)]
+        metrics: Option<PathBuf>,
+
+        /// Number of threads to use for BAM compression/decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Windowed protection score (WPS) track for cell-free DNA
+    Wps {
+        /// Indexed bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output file name. Written as bigWig if it ends in .bw/.bigwig, bedGraph otherwise
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Window size used to score each position
+        #[arg(short, long, default_value_t = 120, value_parser = clap::value_parser!(i64).range(1..))]
+        window: i64,
+
+        /// Minimum fragment length to use
+        #[arg(long, default_value_t = 120)]
+        min_length: i64,
+
+        /// Maximum fragment length to use
+        #[arg(long, default_value_t = 180)]
+        max_length: i64,
+
+        /// Minimum mapping quality of the reads used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Short/long fragment ratios in fixed genomic bins
+    Fragmentomics {
+        /// Bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output file name. Tracks (.bw/.bigwig/.bedgraph) get the ratio only, anything
+        /// else a TSV with per-bin counts
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Size of the genomic bins
+        #[arg(long, default_value_t = 100_000, value_parser = clap::value_parser!(u64).range(1..))]
+        bin_size: u64,
+
+        /// Shortest fragment counted as short
+        #[arg(long, default_value_t = 100)]
+        short_min: i64,
+
+        /// Longest fragment counted as short
+        #[arg(long, default_value_t = 150)]
+        short_max: i64,
+
+        /// Shortest fragment counted as long
+        #[arg(long, default_value_t = 151)]
+        long_min: i64,
+
+        /// Longest fragment counted as long
+        #[arg(long, default_value_t = 220)]
+        long_max: i64,
+
+        /// Minimum mapping quality of the reads used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Nucleosome occupancy track and candidate dyad calls from ATAC fragments
+    Nucleosome {
+        /// Indexed bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output prefix. Writes prefix.occupancy.bw and prefix.dyads.bed
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Longest fragment treated as nucleosome-free
+        #[arg(long, default_value_t = 120)]
+        nfr_max: i64,
+
+        /// Shortest mono-nucleosomal fragment
+        #[arg(long, default_value_t = 147)]
+        mono_min: i64,
+
+        /// Longest mono-nucleosomal fragment
+        #[arg(long, default_value_t = 247)]
+        mono_max: i64,
+
+        /// Standard deviation of the kernel used to smooth dyad positions
+        #[arg(long, default_value_t = 25.0, value_parser = positive_f64)]
+        sigma: f64,
+
+        /// Minimum distance between called dyads
+        #[arg(long, default_value_t = 120)]
+        min_spacing: i64,
+
+        /// Minimum dyad score to report a dyad
+        #[arg(long, default_value_t = 2.0)]
+        min_score: f32,
+
+        /// Minimum mapping quality of the reads used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// V-plot matrix of fragment midpoints against fragment length around anchors
+    Vplot {
+        /// Indexed bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// BED file of anchor regions (e.g. motif sites). Strand is taken from column 6
+        #[arg(short, long)]
+        regions: PathBuf,
+
+        /// Output file name. Written as a numpy archive if it ends in .npz, TSV otherwise
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Distance either side of the anchor centre to include
+        #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(i64).range(0..))]
+        flank: i64,
+
+        /// Minimum fragment length to include
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(i64).range(1..))]
+        min_length: i64,
+
+        /// Maximum fragment length to include
+        #[arg(long, default_value_t = 500)]
+        max_length: i64,
+
+        /// Minimum mapping quality of the reads used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Learn Tn5 hexamer insertion bias and write expected and bias-corrected cut-site tracks
+    #[command(name = "tn5-bias")]
+    Tn5Bias {
+        /// Indexed bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Indexed (samtools faidx) reference FASTA
+        #[arg(short, long)]
+        fasta: PathBuf,
+
+        /// BED file of naked-DNA or background regions to learn from and emit tracks over
+        #[arg(short, long)]
+        regions: PathBuf,
+
+        /// Output prefix. Writes <prefix>.bias.tsv, <prefix>.expected.bw and <prefix>.corrected.bw
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Apply a previously learned <prefix>.bias.tsv instead of learning a new model
+        #[arg(long)]
+        model: Option<PathBuf>,
+
+        /// Minimum mapping quality of the reads used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Quick-look enriched regions from binned coverage (Poisson, local lambda) as narrowPeak
+    Callpeaks {
+        /// Bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output narrowPeak file name
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Bin size in bp
+        #[arg(long, default_value_t = 200, value_parser = clap::value_parser!(u64).range(1..))]
+        bin_size: u64,
+
+        /// P-value threshold for a bin to be called enriched
+        #[arg(short, long, default_value_t = 1e-5)]
+        pvalue: f64,
+
+        /// Windows (bp) used to estimate the local background rate
+        #[arg(long, value_delimiter = ',', default_values_t = [10000])]
+        local_windows: Vec<u64>,
+
+        /// Merge enriched bins that are at most this far apart
+        #[arg(long, default_value_t = 200)]
+        merge_distance: u64,
+
+        /// Do not count proper pairs spanning more than this, in bp (no limit by default)
+        #[arg(long)]
+        max_fragment_length: Option<i64>,
+
+        /// Minimum mapping quality of the reads used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Convert a bedGraph (optionally gzipped) to bigWig
+    Bg2bw {
+        /// bedGraph file for conversion
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Output bigWig file name
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Take chromosome sizes from the header of this bam file
+        #[arg(short, long, required_unless_present = "chrom_sizes")]
+        bam: Option<PathBuf>,
+
+        /// Take chromosome sizes from a chrom.sizes file
+        #[arg(short = 'g', long, conflicts_with = "bam")]
+        chrom_sizes: Option<PathBuf>,
+    },
+
+    /// Shuffled control bam: every fragment randomly re-placed on its own chromosome
+    Shuffle {
+        /// Bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output file name (unsorted)
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// BED file of regions (blacklist, assembly gaps) fragments must not be placed in
+        #[arg(long)]
+        blacklist: Option<PathBuf>,
+
+        /// Seed for the random number generator
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Minimum mapping quality of the reads used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM compression/decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Binned read or fragment coverage as bedGraph or bigWig
+    Coverage {
+        /// Coordinate-sorted bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output file name. Written as bigWig if it ends in .bw/.bigwig, bedGraph otherwise
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Bin size in bp
+        #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u64).range(1..))]
+        bin_size: u64,
+
+        /// Count reads, the fragments spanned by proper pairs, or Tn5 cut sites
+        #[arg(long, value_enum, default_value_t = coverage::CountUnit::Reads)]
+        count: coverage::CountUnit,
+
+        /// Longest fragment counted with --count fragments, in bp
+        #[arg(long, default_value_t = 1000)]
+        max_fragment_length: i64,
+
+        /// How to count reads with more than one reported alignment (NH tag)
+        #[arg(long, value_enum, default_value_t = coverage::MultimapperMode::Unique)]
+        multimappers: coverage::MultimapperMode,
+
+        /// Correct each Tn5 insertion for sequence bias with this model (<prefix>.bias.tsv
+        /// from tn5-bias)
+        #[arg(long, requires = "fasta")]
+        tn5_bias: Option<PathBuf>,
+
+        /// Indexed (samtools faidx) reference FASTA for --tn5-bias
+        #[arg(long)]
+        fasta: Option<PathBuf>,
+
+        /// Scale counts by library size (cpm, rpkm, bpm, rpgc), or report enrichment over
+        /// a robust genome-wide background (zscore, fold), instead of raw counts
+        #[arg(long, value_enum, default_value_t = coverage::Normalization::None)]
+        normalize: coverage::Normalization,
+
+        /// Window for the local background of --normalize fold, in bp
+        #[arg(long, default_value_t = 10_000)]
+        background_window: u64,
+
+        /// Effective genome size for --normalize rpgc, in bp or as an assembly name (hg19,
+        /// hg38, mm9, mm10, dm3, dm6, danrer10, danrer11, ce11, tair10)
+        #[arg(
+            long,
+            value_parser = coverage::effective_genome_size,
+            required_if_eq("normalize", "rpgc")
+        )]
+        effective_genome_size: Option<u64>,
+
+        /// Multiply every bin by this factor, after any normalisation
+        #[arg(long, default_value_t = 1.0)]
+        scale_factor: f64,
+
+        /// Scale by the spike-in factor in this stats.json from `split`, for spike-in
+        /// calibrated tracks
+        #[arg(long, conflicts_with = "scale_factor")]
+        spikein_stats: Option<PathBuf>,
+
+        /// Apply the ATAC-seq Tn5 offsets (+4/-5) to reads on the fly, without a shifted BAM
+        #[arg(long)]
+        atac_shift: bool,
+
+        /// Minimum mapping quality of the reads used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Binned log2 ratio, ratio or difference of treatment over control coverage, as
+    /// bedGraph or bigWig
+    Bamcompare {
+        /// Coordinate-sorted treatment (e.g. ChIP) bam file
+        #[arg(long)]
+        treatment: PathBuf,
+
+        /// Coordinate-sorted control (e.g. input) bam file
+        #[arg(long)]
+        control: PathBuf,
+
+        /// Output file name. Written as bigWig if it ends in .bw/.bigwig, bedGraph otherwise
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// How each bin of the treatment is compared to the control
+        #[arg(long, value_enum, default_value_t = coverage::Comparison::Log2ratio)]
+        operation: coverage::Comparison,
+
+        /// Added to both samples in each bin for --operation log2ratio and ratio
+        #[arg(long, default_value_t = 1.0)]
+        pseudocount: f32,
+
+        /// Bin size in bp
+        #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u64).range(1..))]
+        bin_size: u64,
+
+        /// Count reads, or the fragments spanned by proper pairs
+        #[arg(long, value_enum, default_value_t = coverage::CountUnit::Reads)]
+        count: coverage::CountUnit,
+
+        /// How each sample is scaled by its library size before comparing (none, cpm, rpkm,
+        /// bpm or rpgc)
+        #[arg(long, value_enum, default_value_t = coverage::Normalization::Cpm)]
+        normalize: coverage::Normalization,
+
+        /// Effective genome size for --normalize rpgc, in bp or as an assembly name
+        #[arg(
+            long,
+            value_parser = coverage::effective_genome_size,
+            required_if_eq("normalize", "rpgc")
+        )]
+        effective_genome_size: Option<u64>,
+
+        /// Minimum mapping quality of the reads used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Mean/median depth and fraction of bases above depth thresholds for each BED region
+    Regiondepth {
+        /// Indexed bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// BED file of regions to summarise
+        #[arg(short, long)]
+        regions: PathBuf,
+
+        /// Output TSV file name
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Depth thresholds to report the fraction of bases at or above
+        #[arg(long, value_delimiter = ',', default_values_t = [1, 10, 30])]
+        thresholds: Vec<u32>,
+
+        /// Minimum mapping quality of the reads used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Alignment flag statistics, optionally broken down by read group
+    Flagstat {
+        /// Bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output TSV file name. Written to stdout if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Add a column for every read group (@RG) alongside the totals
+        #[arg(long)]
+        by_read_group: bool,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Secondary/supplementary rates, SA-tag chimeras and the distribution of hits per read
+    Multimappers {
+        /// Bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output TSV file name. Written to stdout if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Per-cycle base-quality profile of (a reservoir sample of) the reads
+    Qualprofile {
+        /// Bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output TSV file name
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Number of reads to sample. 0 profiles every read
+        #[arg(long, default_value_t = 100_000)]
+        sample_size: usize,
+
+        /// Seed for the random number generator
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Adapter read-through in soft-clipped 3' tails, with an optional filtered bam
+    Adapters {
+        /// Bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Report TSV file name. Written to stdout if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Write the reads without adapter read-through to this bam file
+        #[arg(long)]
+        filtered_output: Option<PathBuf>,
+
+        /// Adapter sequences to look for (defaults to Nextera and TruSeq)
+        #[arg(long, value_delimiter = ',')]
+        adapters: Vec<String>,
+
+        /// Shortest soft-clipped tail compared against the adapters
+        #[arg(long, default_value_t = 5)]
+        min_overlap: usize,
+
+        /// Mismatches allowed per compared base
+        #[arg(long, default_value_t = 0.1)]
+        max_mismatch_rate: f64,
+
+        /// Number of threads to use for BAM compression/decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Extract reads with SA tags into a bam plus a breakpoint-pair BEDPE
+    Chimeras {
+        /// Bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output prefix. Writes <prefix>.bam and <prefix>.bedpe
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Minimum mapping quality of the alignments used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM compression/decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Detect the reference assembly (hg19/hg38/mm10/mm39/dm6) from reference names and lengths
+    DetectBuild {
+        /// Take references from the header of this bam file
+        #[arg(short, long, required_unless_present = "chrom_sizes")]
+        bam: Option<PathBuf>,
+
+        /// Take references from a chrom.sizes file
+        #[arg(short = 'g', long, conflicts_with = "bam")]
+        chrom_sizes: Option<PathBuf>,
+    },
+
+    /// Per-species read fractions for composite references, flagging contaminated samples
+    ContamScreen {
+        /// Bam files to screen (one row per sample and species)
+        #[arg(short, long, required = true, num_args = 1..)]
+        bam: Vec<PathBuf>,
+
+        /// Output TSV file name. Written to stdout if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Species in the composite reference, as name=prefix or prefix (e.g. dm6_,mm10=mm_)
+        #[arg(long, value_delimiter = ',', default_values_t = ["dm6_".to_string()])]
+        species: Vec<String>,
+
+        /// Name reported for reference sequences without a species prefix
+        #[arg(long, default_value = "host")]
+        host: String,
+
+        /// Minimum mapping quality for a read to be confidently assigned
+        #[arg(long, default_value_t = 30)]
+        min_mapq: u8,
+
+        /// Flag samples where any non-host species exceeds this fraction of assigned reads
+        #[arg(long, default_value_t = 0.01)]
+        threshold: f64,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Extract the reads overlapping BED regions from an indexed BAM
+    Slice {
+        /// Indexed bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// BED file of regions to extract
+        #[arg(short, long)]
+        regions: PathBuf,
+
+        /// Output BAM file name
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Also fetch mates mapping outside the regions so the output is pair-complete
+        #[arg(long)]
+        fetch_mates: bool,
+
+        /// Number of threads to use for BAM compression/decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Genome-wide and per-chromosome mean depth and breadth of coverage
+    DepthSummary {
+        /// Indexed bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output TSV file name. Written to stdout if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Depths to report breadth of coverage at
+        #[arg(long, value_delimiter = ',', default_values_t = [1, 10, 30])]
+        thresholds: Vec<u32>,
+
+        /// Minimum mapping quality of reads to count
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of chromosomes to process in parallel
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// BED intervals where filtered depth is within --min-depth and --max-depth
+    Callable {
+        /// Indexed bam file for processing
+        #[arg(short, long)]
+        bam: PathBuf,
+
+        /// Output BED file name
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Minimum depth for a base to be callable
+        #[arg(long, default_value_t = 10)]
+        min_depth: u32,
+
+        /// Maximum depth for a base to be callable. Unbounded if not given
+        #[arg(long)]
+        max_depth: Option<u32>,
+
+        /// Minimum mapping quality of reads to count
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of chromosomes to process in parallel
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Quantile-normalize binned coverage across samples, writing one bigWig per sample
+    NormalizeTracks {
+        /// Coordinate-sorted bam files to normalize against each other
+        #[arg(short, long, required = true, num_args = 1..)]
+        bam: Vec<PathBuf>,
+
+        /// Output directory. Writes <bam stem>.quantile.bw for each sample
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Bin size in bp
+        #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u64).range(1..))]
+        bin_size: u64,
+
+        /// How to count reads with more than one reported alignment (NH tag)
+        #[arg(long, value_enum, default_value_t = coverage::MultimapperMode::Unique)]
+        multimappers: coverage::MultimapperMode,
+
+        /// Minimum mapping quality of the reads used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// Write reference names and lengths from a BAM/CRAM header as a chrom.sizes file
+    Chromsizes {
+        /// Bam or cram file to read the header of
+        #[arg(short, long)]
+        bam: PathBuf,
 
-        /// Output file name
+        /// Output chrom.sizes file name. Written to stdout if not given
         #[arg(short, long)]
         output: Option<PathBuf>,
-    },
 
-    Subtract {
-        /// Bed file for processing
-        #[arg(short='r', long="regions")]
-        regions: Option<PathBuf>,
+        /// Leave out references starting with any of these prefixes (e.g. chrUn,dm6_)
+        #[arg(long, value_delimiter = ',')]
+        exclude_prefix: Vec<String>,
 
+        /// Leave out references matching this regular expression (e.g. '_random$|_alt$')
+        #[arg(long)]
+        exclude_pattern: Option<String>,
+
+        /// Leave out references shorter than this
+        #[arg(long, default_value_t = 0)]
+        min_length: u64,
+    },
+
+    /// Tag reads with the names of the BED/GTF regions they overlap (e.g. XR:Z:peak_1)
+    Annotate {
         /// Bam file for processing
-        #[arg(short='b', long="bam")]
-        bam: Option<PathBuf>,
+        #[arg(short, long)]
+        bam: PathBuf,
 
-        /// Output file name
+        /// BED or GTF/GFF file of named regions (optionally gzipped)
         #[arg(short, long)]
-        output: Option<PathBuf>,
+        regions: PathBuf,
 
-        /// Number of threads to use
+        /// Output BAM file name
         #[arg(short, long)]
-        threads: Option<usize>,
+        output: PathBuf,
+
+        /// Tag to store the comma-separated region names in
+        #[arg(long, default_value = "XR")]
+        tag: String,
+
+        /// GTF/GFF attribute to name regions by
+        #[arg(long, default_value = "gene_id")]
+        name_attribute: String,
+
+        /// Only write reads that overlap at least one region
+        #[arg(long)]
+        only_annotated: bool,
+
+        /// Number of threads to use for BAM compression/decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
     },
 
-    Split {
+    /// WASP-style removal of reads whose alignment depends on the allele they carry
+    WaspFilter {
+        /// Bam file for processing (first step, with --snps)
+        #[arg(short, long, required_unless_present = "remapped")]
+        bam: Option<PathBuf>,
+
+        /// VCF of heterozygous SNVs. Writes <prefix>.keep.bam, <prefix>.to_remap.bam and
+        /// allele-swapped FASTQ to remap with the original aligner
+        #[arg(long, required_unless_present = "remapped", conflicts_with = "remapped")]
+        snps: Option<PathBuf>,
+
+        /// Remapped allele-swapped reads. Writes the templates of <prefix>.to_remap.bam that
+        /// mapped back to the same place to <prefix>.remap.keep.bam
+        #[arg(long)]
+        remapped: Option<PathBuf>,
+
+        /// Output prefix, shared by both steps
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Discard templates overlapping more SNVs than this instead of remapping them
+        #[arg(long, default_value_t = 6)]
+        max_snvs: usize,
+
+        /// Minimum mapping quality of remapped reads
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM compression/decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
+
+    /// FR/RF/tandem pair orientation per insert-size bin, and the inter-chromosomal pair rate
+    PairOrientation {
         /// Bam file for processing
         #[arg(short, long)]
         bam: PathBuf,
 
-        /// Prefix to use for exogenous spike-in reads
-        /// If not provided will default to dm6_
+        /// Output TSV file name. Written to stdout if not given
         #[arg(short, long)]
-        exogenous_prefix: Option<String>,
+        output: Option<PathBuf>,
+
+        /// Insert-size bin edges
+        #[arg(long, value_delimiter = ',', default_values_t = [100, 200, 500, 1000, 10_000])]
+        insert_bins: Vec<u64>,
+
+        /// Minimum mapping quality of the read a pair is counted from
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
+    },
 
+    /// Jaccard similarity of the fragment intervals of two BAMs (replicate concordance)
+    Jaccard {
+        /// First indexed bam file
+        #[arg(short = 'a', long)]
+        bam_a: PathBuf,
 
-        /// Output file prefix. The output files will be named as prefix_X.bam
+        /// Second indexed bam file
+        #[arg(short = 'b', long)]
+        bam_b: PathBuf,
+
+        /// Output TSV file name. Written to stdout if not given
         #[arg(short, long)]
         output: Option<PathBuf>,
 
+        /// Compare the bins of this size touched by fragments instead of exact base pairs
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        bin_size: Option<u64>,
+
+        /// Minimum fragment length to use
+        #[arg(long, default_value_t = 1)]
+        min_length: i64,
+
+        /// Maximum fragment length to use
+        #[arg(long, default_value_t = 1000)]
+        max_length: i64,
+
+        /// Minimum mapping quality of the reads used
+        #[arg(long, default_value_t = 0)]
+        min_mapq: u8,
+
+        /// Number of threads to use for BAM decompression
+        #[arg(short, long, default_value_t = 1)]
+        threads: usize,
     },
 }
 
+/// Parse a finite number greater than 0.
+fn positive_f64(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(number) if number > 0.0 && number.is_finite() => Ok(number),
+        Ok(_) => Err(format!("{} is not a finite number greater than 0", value)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Some(Commands::Shift { bam, output }) => match (bam, output) {
-            (Some(bam_file), Some(output_file)) => {
-                atac_shift_bam::atac_shift_bam(bam_file, output_file).with_context(|| {
-                    format!(
-                        "Shifting reads failed for file `{}`",
-                        bam_file.to_string_lossy()
-                    )
-                })?;
-            }
-            (Some(bam_file), None) => {
-                let output_file = &PathBuf::from("shifted.bam");
-                atac_shift_bam::atac_shift_bam(bam_file, output_file).with_context(|| {
-                    format!(
-                        "Shifting reads failed for file `{}`",
-                        bam_file.to_string_lossy()
-                    )
-                })?
-            }
-            _ => {
-                println!("Options not provided, will not run")
+        Some(Commands::Shift {
+            bam,
+            output,
+            preset,
+            shift,
+            single_end,
+            output_format,
+            reference,
+            sort,
+            write_index,
+            regions,
+            keep_unshifted,
+            pair_aware,
+            fixmate,
+            min_mapq,
+            exclude_flags,
+            blacklist,
+            discarded,
+            cut_sites,
+            dry_run,
+            threads,
+        }) => {
+            let options = atac_shift_bam::ShiftOptions {
+                shift: match shift {
+                    Some(shift) => shift
+                        .as_slice()
+                        .try_into()
+                        .context("--shift takes exactly four offsets")?,
+                    None => preset.map_or(atac_shift_bam::ATAC_SHIFT, |p| p.shift()),
+                },
+                single_end: *single_end,
+                output_format: *output_format,
+                reference: reference.clone(),
+                sort: *sort,
+                write_index: *write_index,
+                regions: regions.clone(),
+                keep_unshifted: *keep_unshifted,
+                pair_aware: *pair_aware,
+                fixmate: *fixmate,
+                min_mapq: min_mapq.unwrap_or(preset.map_or(0, |p| p.min_mapq())),
+                exclude_flags: exclude_flags.unwrap_or(preset.map_or(0, |p| p.exclude_flags())),
+                blacklist: blacklist.clone(),
+                discarded: discarded.clone(),
+                cut_sites: *cut_sites,
+                dry_run: *dry_run,
+                threads: *threads,
+            };
+            match (bam, output) {
+                (Some(bam_file), Some(output_file)) => {
+                    atac_shift_bam::atac_shift_bam(bam_file, output_file, &options)
+                        .with_context(|| {
+                            format!(
+                                "Shifting reads failed for file `{}`",
+                                bam_file.to_string_lossy()
+                            )
+                        })?;
+                }
+                (Some(bam_file), None) => {
+                    let output_file = &PathBuf::from("shifted.bam");
+                    atac_shift_bam::atac_shift_bam(bam_file, output_file, &options)
+                        .with_context(|| {
+                            format!(
+                                "Shifting reads failed for file `{}`",
+                                bam_file.to_string_lossy()
+                            )
+                        })?
+                }
+                _ => {
+                    println!("Options not provided, will not run")
+                }
             }
-        },
+        }
 
         Some(Commands::Subtract {
-            regions: bed,
+            regions: beds,
             bam,
             output,
+            invert,
+            min_overlap_bp,
+            min_overlap_frac,
+            pairs,
+            write_index,
+            feature_type,
+            slop,
+            blacklist_preset,
+            removed_output,
+            keep_unmapped,
+            output_format,
+            reference,
+            stream,
+            mask,
+            region_report,
+            top_regions,
+            window_size,
+            bed12_span,
+            min_mapq,
+            include_flags,
+            exclude_flags,
             threads,
         }) => {
-            println!("Running subtract subcommand. Will subtract regions from BAM file.");
+            // Progress goes to stderr so the output can be written to stdout
+            eprintln!("Running subtract subcommand. Will subtract regions from BAM file.");
 
-            match (bed, bam) {
-                (Some(bed_file), Some(bam_file)) => {
+            match (beds.is_empty() && blacklist_preset.is_none(), bam) {
+                (false, Some(bam_file)) => {
                     let output = match output {
                         Some(output) => output.to_owned(),
                         None => PathBuf::from("subtracted.bam"),
@@ -107,16 +1429,42 @@ fn main() -> Result<()> {
                         None => 1,
                     };
 
-                    println!("BED file: {}", bed_file.to_string_lossy());
-                    println!("BAM file: {}", bam_file.to_string_lossy());
-                    println!("Output file: {}", output.to_string_lossy());
-                    println!("Threads: {}", threads);
-                    
+                    for bed_file in beds {
+                        eprintln!("BED file: {}", bed_file.to_string_lossy());
+                    }
+                    eprintln!("BAM file: {}", bam_file.to_string_lossy());
+                    eprintln!("Output file: {}", output.to_string_lossy());
+                    eprintln!("Threads: {}", threads);
+
+                    let options = subtract_regions::SubtractOptions {
+                        invert: *invert,
+                        min_overlap_bp: *min_overlap_bp,
+                        min_overlap_frac: *min_overlap_frac,
+                        pairs: *pairs,
+                        write_index: *write_index,
+                        feature_type: feature_type.clone(),
+                        slop: *slop,
+                        blacklist_preset: *blacklist_preset,
+                        removed_output: removed_output.clone(),
+                        keep_unmapped: *keep_unmapped,
+                        output_format: *output_format,
+                        reference: reference.clone(),
+                        stream: *stream,
+                        mask: *mask,
+                        region_report: region_report.clone(),
+                        top_regions: *top_regions,
+                        window_size: *window_size,
+                        bed12_span: *bed12_span,
+                        min_mapq: *min_mapq,
+                        include_flags: *include_flags,
+                        exclude_flags: *exclude_flags,
+                        threads,
+                    };
                     subtract_regions::remove_regions_from_bam(
-                        bed_file.to_path_buf(),
+                        beds,
                         bam_file.to_path_buf(),
                         output,
-                        threads,
+                        &options,
                     )?;
                 }
                 _ => {
@@ -126,22 +1474,770 @@ fn main() -> Result<()> {
             }
         }
 
-        Some(Commands::Split { bam, exogenous_prefix, output }) => match (bam, output) {
-            (bam_file, Some(output_file)) => {
-                let exogenous_prefix = match exogenous_prefix {
-                    Some(prefix) => prefix.to_owned(),
-                    None => "dm6_".to_string(),
-                };
-                let mut  splitter =  split_sample_and_spikein::SplitBam::new(bam_file.to_path_buf(), output_file.to_path_buf())?;
-                let stats = splitter.split(exogenous_prefix.as_bytes())?;
+        Some(Commands::Split {
+            bam,
+            jobs,
+            exogenous_prefix,
+            exogenous_suffix,
+            exogenous_regex,
+            exogenous_chroms,
+            auto_detect_prefix,
+            output,
+            stats_comment,
+            stats_output,
+            keep_duplicates,
+            keep_secondary,
+            keep_supplementary,
+            keep_qcfail,
+            strip_prefix,
+            threads,
+            quiet,
+            pair_policy,
+            no_filtered_output,
+            ambiguous_policy,
+            compression_level,
+            uncompressed,
+            output_format,
+            reference,
+            exogenous_fastq,
+            missing_mapq,
+            follow_primary,
+            assignment_table,
+            expected_spikein_fraction,
+        }) => {
+            use split_sample_and_spikein::ExogenousContigs;
+
+            if *stats_comment && *output_format == subtract_regions::AlignmentFormat::Cram {
+                anyhow::bail!("--stats-comment rewrites the outputs as BAM, so cannot be used with CRAM output");
+            }
+            if let Some([min, max]) = expected_spikein_fraction.as_deref() {
+                if min > max {
+                    anyhow::bail!("--expected-spikein-fraction needs MIN no greater than MAX");
+                }
+            }
 
+            // sample.bam is split into sample.endogenous.bam, sample.exogenous.bam, ...
+            let prefixes = split_sample_and_spikein::sample_prefixes(bam, output.as_deref())?;
+            let exogenous = match exogenous_regex {
+                _ if *auto_detect_prefix => {
+                    let chroms = signal::bam_chrom_sizes(&bam[0])?;
+                    let detected = split_sample_and_spikein::detect_exogenous(&chroms);
+                    if detected.is_empty() {
+                        anyhow::bail!(
+                            "Could not find exogenous contigs in `{}`; give them with --exogenous-prefix",
+                            bam[0].display()
+                        );
+                    }
+                    for genome in &detected {
+                        println!("{}", genome.summary());
+                    }
+                    detected.into_iter().map(|genome| genome.contigs).collect()
+                }
+                _ if !exogenous_chroms.is_empty() => exogenous_chroms
+                    .iter()
+                    .map(|path| ExogenousContigs::from_list(path))
+                    .collect::<Result<_>>()?,
+                Some(pattern) => vec![ExogenousContigs::Regex(
+                    regex::bytes::Regex::new(pattern)
+                        .with_context(|| format!("Invalid --exogenous-regex `{}`", pattern))?,
+                )],
+                None if !exogenous_suffix.is_empty() => exogenous_suffix
+                    .iter()
+                    .map(|suffix| ExogenousContigs::Suffix(suffix.to_owned()))
+                    .collect(),
+                None if !exogenous_prefix.is_empty() => exogenous_prefix
+                    .iter()
+                    .map(|prefix| ExogenousContigs::Prefix(prefix.to_owned()))
+                    .collect(),
+                None => vec![ExogenousContigs::Prefix("dm6_".to_string())],
+            };
+            let options = split_sample_and_spikein::SplitOptions {
+                keep_duplicates: *keep_duplicates,
+                keep_secondary: *keep_secondary,
+                keep_supplementary: *keep_supplementary,
+                keep_qcfail: *keep_qcfail,
+                strip_prefix: *strip_prefix,
+                threads: *threads,
+                quiet: *quiet,
+                pair_policy: *pair_policy,
+                no_filtered_output: *no_filtered_output,
+                ambiguous_policy: *ambiguous_policy,
+                compression_level: match uncompressed {
+                    true => Some(0),
+                    false => *compression_level,
+                },
+                output_format: *output_format,
+                reference: reference.clone(),
+                exogenous_fastq: exogenous_fastq.clone(),
+                missing_mapq: *missing_mapq,
+                follow_primary: *follow_primary,
+                assignment_table: assignment_table.clone(),
+            };
+            let samples: Vec<_> = bam.iter().cloned().zip(prefixes).collect();
+            let (stats, outputs): (Vec<_>, Vec<_>) =
+                split_sample_and_spikein::split_samples(&samples, &exogenous, &options, *jobs)?
+                    .into_iter()
+                    .unzip();
+
+            for stats in &stats {
                 stats.print();
+                if let Some([min, max]) = expected_spikein_fraction.as_deref() {
+                    if let Some(warning) = stats.spikein_fraction_warning(*min, *max) {
+                        eprintln!("Warning: {}", warning);
+                    }
+                }
+            }
+            if stats.len() > 1 {
+                split_sample_and_spikein::SplitStats::print_comparison(&stats);
+            }
+            if let Some(stats_output) = stats_output {
+                split_sample_and_spikein::SplitStats::write_all(&stats, stats_output)?;
+            }
+
+            if *stats_comment {
+                for (stats, outputs) in stats.iter().zip(&outputs) {
+                    let comment = format!(
+                        "{} split stats sha256:{}",
+                        provenance::PROGRAM_NAME,
+                        provenance::stats_hash(stats)?
+                    );
+                    for output in outputs {
+                        provenance::append_comment(output, &comment)?;
+                    }
+                }
+            }
+        }
+
+        Some(Commands::SplitBy {
+            bam,
+            output,
+            by,
+            tag,
+            groups,
+            max_open_files,
+            compression_level,
+            uncompressed,
+            threads,
+        }) => {
+            let options = split_by::SplitByOptions {
+                by: *by,
+                tag: tag.clone(),
+                groups: groups.clone(),
+                max_open_files: *max_open_files,
+                compression_level: match uncompressed {
+                    true => Some(0),
+                    false => *compression_level,
+                },
+                threads: *threads,
+            };
+            split_by::split_by(bam, output, &options).with_context(|| {
+                format!("Splitting failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Xenograft {
+            graft,
+            host,
+            output,
+            score,
+            threads,
+        }) => {
+            let options = xenograft::XenograftOptions {
+                score: *score,
+                threads: *threads,
+            };
+            let counts = xenograft::xenograft(graft, host, output, &options)?;
+            counts.print();
+        }
+
+        Some(Commands::Dedup {
+            bam,
+            output,
+            remove,
+            metrics,
+            threads,
+        }) => {
+            let options = dedup::DedupOptions {
+                remove: *remove,
+                threads: *threads,
+            };
+            let stats = dedup::dedup(bam, output, &options).with_context(|| {
+                format!("Duplicate marking failed for file `{}`", bam.to_string_lossy())
+            })?;
+            dedup::write_stats(&stats, metrics.as_ref())?;
+        }
+
+        Some(Commands::Wps {
+            bam,
+            output,
+            window,
+            min_length,
+            max_length,
+            min_mapq,
+            threads,
+        }) => {
+            let options = wps::WpsOptions {
+                window: *window,
+                filter: fragments::FragmentFilter {
+                    min_mapq: *min_mapq,
+                    min_length: *min_length,
+                    max_length: *max_length,
+                },
+                threads: *threads,
+            };
+            wps::windowed_protection_scores(bam, output, &options).with_context(|| {
+                format!("Computing WPS failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Fragmentomics {
+            bam,
+            output,
+            bin_size,
+            short_min,
+            short_max,
+            long_min,
+            long_max,
+            min_mapq,
+            threads,
+        }) => {
+            let options = fragmentomics::FragmentomicsOptions {
+                bin_size: *bin_size,
+                short: *short_min..=*short_max,
+                long: *long_min..=*long_max,
+                min_mapq: *min_mapq,
+                threads: *threads,
+            };
+            fragmentomics::fragmentomics(bam, output, &options).with_context(|| {
+                format!(
+                    "Computing fragmentomics profile failed for file `{}`",
+                    bam.to_string_lossy()
+                )
+            })?;
+        }
+
+        Some(Commands::Nucleosome {
+            bam,
+            output,
+            nfr_max,
+            mono_min,
+            mono_max,
+            sigma,
+            min_spacing,
+            min_score,
+            min_mapq,
+            threads,
+        }) => {
+            let options = nucleosome::NucleosomeOptions {
+                nfr_max: *nfr_max,
+                mono_min: *mono_min,
+                mono_max: *mono_max,
+                sigma: *sigma,
+                min_spacing: *min_spacing,
+                min_score: *min_score,
+                min_mapq: *min_mapq,
+                threads: *threads,
+            };
+            nucleosome::nucleosome_signal(bam, output, &options).with_context(|| {
+                format!(
+                    "Computing nucleosome signal failed for file `{}`",
+                    bam.to_string_lossy()
+                )
+            })?;
+        }
+
+        Some(Commands::Vplot {
+            bam,
+            regions,
+            output,
+            flank,
+            min_length,
+            max_length,
+            min_mapq,
+            threads,
+        }) => {
+            let options = vplot::VplotOptions {
+                flank: *flank,
+                min_length: *min_length,
+                max_length: *max_length,
+                min_mapq: *min_mapq,
+                threads: *threads,
+            };
+            vplot::vplot(bam, regions, output, &options).with_context(|| {
+                format!("V-plot failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Tn5Bias {
+            bam,
+            fasta,
+            regions,
+            output,
+            model,
+            min_mapq,
+            threads,
+        }) => {
+            let options = bias::BiasOptions {
+                model: model.clone(),
+                min_mapq: *min_mapq,
+                threads: *threads,
+            };
+            bias::tn5_bias(bam, fasta, regions, output, &options).with_context(|| {
+                format!("Tn5 bias correction failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Callpeaks {
+            bam,
+            output,
+            bin_size,
+            pvalue,
+            local_windows,
+            merge_distance,
+            max_fragment_length,
+            min_mapq,
+            threads,
+        }) => {
+            let options = peaks::PeakOptions {
+                bin_size: *bin_size,
+                pvalue: *pvalue,
+                local_windows: local_windows.clone(),
+                merge_distance: *merge_distance,
+                max_fragment_length: *max_fragment_length,
+                min_mapq: *min_mapq,
+                threads: *threads,
+            };
+            peaks::call_peaks(bam, output, &options).with_context(|| {
+                format!("Peak calling failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Bg2bw {
+            input,
+            output,
+            bam,
+            chrom_sizes,
+        }) => {
+            let chroms = match (bam, chrom_sizes) {
+                (_, Some(chrom_sizes)) => signal::read_chrom_sizes(chrom_sizes)?,
+                (Some(bam), None) => signal::bam_chrom_sizes(bam)?,
+                (None, None) => unreachable!("clap requires --bam or --chrom-sizes"),
+            };
+            bedgraph::bedgraph_to_bigwig(input, output, chroms).with_context(|| {
+                format!("Converting `{}` to bigWig failed", input.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Shuffle {
+            bam,
+            output,
+            blacklist,
+            seed,
+            min_mapq,
+            threads,
+        }) => {
+            let options = shuffle::ShuffleOptions {
+                seed: *seed,
+                min_mapq: *min_mapq,
+                threads: *threads,
+            };
+            shuffle::shuffle_bam(bam, output, blacklist.as_ref(), &options).with_context(|| {
+                format!("Shuffling failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Coverage {
+            bam,
+            output,
+            bin_size,
+            count,
+            max_fragment_length,
+            multimappers,
+            tn5_bias,
+            fasta,
+            normalize,
+            background_window,
+            effective_genome_size,
+            scale_factor,
+            spikein_stats,
+            atac_shift,
+            min_mapq,
+            threads,
+        }) => {
+            let options = coverage::CoverageOptions {
+                bin_size: *bin_size,
+                count: *count,
+                max_fragment_length: *max_fragment_length,
+                multimappers: *multimappers,
+                normalization: *normalize,
+                background_window: *background_window,
+                effective_genome_size: *effective_genome_size,
+                scale_factor: match spikein_stats {
+                    Some(stats) => {
+                        let factor = coverage::spikein_scale_factor(stats, bam)?;
+                        println!("Spike-in scale factor: {:.6}", factor);
+                        factor
+                    }
+                    None => *scale_factor,
+                },
+                atac_shift: *atac_shift,
+                bias_model: tn5_bias.clone(),
+                fasta: fasta.clone(),
+                min_mapq: *min_mapq,
+                threads: *threads,
+            };
+            coverage::coverage(bam, output, &options).with_context(|| {
+                format!("Computing coverage failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Bamcompare {
+            treatment,
+            control,
+            output,
+            operation,
+            pseudocount,
+            bin_size,
+            count,
+            normalize,
+            effective_genome_size,
+            min_mapq,
+            threads,
+        }) => {
+            let options = coverage::CoverageOptions {
+                bin_size: *bin_size,
+                count: *count,
+                normalization: *normalize,
+                effective_genome_size: *effective_genome_size,
+                min_mapq: *min_mapq,
+                threads: *threads,
+                ..Default::default()
+            };
+            coverage::compare(treatment, control, output, *operation, *pseudocount, &options)
+                .context("Comparing treatment and control coverage failed")?;
+        }
+
+        Some(Commands::Regiondepth {
+            bam,
+            regions,
+            output,
+            thresholds,
+            min_mapq,
+            threads,
+        }) => {
+            let options = regiondepth::RegionDepthOptions {
+                thresholds: thresholds.clone(),
+                min_mapq: *min_mapq,
+                threads: *threads,
+            };
+            regiondepth::region_depth(bam, regions, output, &options).with_context(|| {
+                format!("Region depth failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Flagstat {
+            bam,
+            output,
+            by_read_group,
+            threads,
+        }) => {
+            flagstat::flagstat(bam, output.as_ref(), *by_read_group, *threads).with_context(
+                || format!("Flagstat failed for file `{}`", bam.to_string_lossy()),
+            )?;
+        }
+
+        Some(Commands::Multimappers {
+            bam,
+            output,
+            threads,
+        }) => {
+            multimap::multimap_report(bam, output.as_ref(), *threads).with_context(|| {
+                format!("Multi-mapping report failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Qualprofile {
+            bam,
+            output,
+            sample_size,
+            seed,
+            threads,
+        }) => {
+            let options = qualprofile::QualProfileOptions {
+                sample_size: *sample_size,
+                seed: *seed,
+                threads: *threads,
+            };
+            qualprofile::qual_profile(bam, output, &options).with_context(|| {
+                format!("Quality profile failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Adapters {
+            bam,
+            output,
+            filtered_output,
+            adapters,
+            min_overlap,
+            max_mismatch_rate,
+            threads,
+        }) => {
+            let mut options = adapters::AdapterOptions {
+                min_overlap: *min_overlap,
+                max_mismatch_rate: *max_mismatch_rate,
+                threads: *threads,
+                ..Default::default()
+            };
+            if !adapters.is_empty() {
+                options.adapters = adapters.clone();
+            }
+            adapters::adapter_report(bam, output.as_ref(), filtered_output.as_ref(), &options)
+                .with_context(|| {
+                    format!("Adapter scan failed for file `{}`", bam.to_string_lossy())
+                })?;
+        }
+
+        Some(Commands::Chimeras {
+            bam,
+            output,
+            min_mapq,
+            threads,
+        }) => {
+            chimeras::extract_chimeras(bam, output, *min_mapq, *threads).with_context(|| {
+                format!("Chimera extraction failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::DetectBuild { bam, chrom_sizes }) => {
+            let chroms = match (bam, chrom_sizes) {
+                (_, Some(chrom_sizes)) => signal::read_chrom_sizes(chrom_sizes)?,
+                (Some(bam), None) => signal::bam_chrom_sizes(bam)?,
+                (None, None) => unreachable!("clap requires --bam or --chrom-sizes"),
+            };
+            match genome::detect_build(&chroms) {
+                Some(found) => println!(
+                    "{}\t{}\t{}/{} fingerprint contigs",
+                    found.assembly.name,
+                    if found.chr_prefix { "chr" } else { "no-chr" },
+                    found.matched,
+                    found.assembly.fingerprint.len()
+                ),
+                None => println!("unknown"),
+            }
+        }
+
+        Some(Commands::ContamScreen {
+            bam,
+            output,
+            species,
+            host,
+            min_mapq,
+            threshold,
+            threads,
+        }) => {
+            let options = contam::ContamOptions {
+                species: species
+                    .iter()
+                    .map(|s| s.parse())
+                    .collect::<Result<Vec<contam::Species>>>()?,
+                host: host.clone(),
+                min_mapq: *min_mapq,
+                threshold: *threshold,
+                threads: *threads,
+            };
+            let flagged = contam::contamination_screen(bam, output.as_ref(), &options)
+                .context("Contamination screen failed")?;
+            for sample in &flagged {
+                eprintln!(
+                    "Warning: `{}` exceeds the contamination threshold of {}",
+                    sample.to_string_lossy(),
+                    threshold
+                );
             }
+        }
+
+        Some(Commands::Slice {
+            bam,
+            regions,
+            output,
+            fetch_mates,
+            threads,
+        }) => {
+            let options = slice::SliceOptions {
+                fetch_mates: *fetch_mates,
+                threads: *threads,
+            };
+            slice::slice_bam(bam, regions, output, &options).with_context(|| {
+                format!("Slicing failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::DepthSummary {
+            bam,
+            output,
+            thresholds,
+            min_mapq,
+            threads,
+        }) => {
+            let options = depthsummary::DepthSummaryOptions {
+                thresholds: thresholds.clone(),
+                min_mapq: *min_mapq,
+                threads: *threads,
+            };
+            depthsummary::depth_summary(bam, output.as_ref(), &options).with_context(|| {
+                format!("Depth summary failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Callable {
+            bam,
+            output,
+            min_depth,
+            max_depth,
+            min_mapq,
+            threads,
+        }) => {
+            let options = callable::CallableOptions {
+                min_depth: *min_depth,
+                max_depth: *max_depth,
+                min_mapq: *min_mapq,
+                threads: *threads,
+            };
+            callable::callable_regions(bam, output, &options).with_context(|| {
+                format!("Callable regions failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::NormalizeTracks {
+            bam,
+            output,
+            bin_size,
+            multimappers,
+            min_mapq,
+            threads,
+        }) => {
+            let options = coverage::CoverageOptions {
+                bin_size: *bin_size,
+                multimappers: *multimappers,
+                min_mapq: *min_mapq,
+                threads: *threads,
+                ..Default::default()
+            };
+            coverage::normalize_tracks(bam, output, &options)
+                .context("Quantile normalization of coverage tracks failed")?;
+        }
+
+        Some(Commands::Chromsizes {
+            bam,
+            output,
+            exclude_prefix,
+            exclude_pattern,
+            min_length,
+        }) => {
+            let options = chromsizes::ChromSizesOptions {
+                exclude_prefixes: exclude_prefix.clone(),
+                exclude_pattern: exclude_pattern.clone(),
+                min_length: *min_length,
+            };
+            chromsizes::chrom_sizes(bam, output.as_ref(), &options).with_context(|| {
+                format!("Writing chrom sizes failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Annotate {
+            bam,
+            regions,
+            output,
+            tag,
+            name_attribute,
+            only_annotated,
+            threads,
+        }) => {
+            let options = annotate::AnnotateOptions {
+                tag: tag.clone(),
+                name_attribute: name_attribute.clone(),
+                only_annotated: *only_annotated,
+                threads: *threads,
+            };
+            annotate::annotate_reads(bam, regions, output, &options).with_context(|| {
+                format!("Annotating reads failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
 
-            _ => {
-                println!("Options not provided, will not run")
+        Some(Commands::WaspFilter {
+            bam,
+            snps,
+            remapped,
+            output,
+            max_snvs,
+            min_mapq,
+            threads,
+        }) => {
+            let options = wasp::WaspOptions {
+                max_snvs: *max_snvs,
+                min_mapq: *min_mapq,
+                threads: *threads,
+            };
+            match (bam, snps, remapped) {
+                (_, _, Some(remapped)) => wasp::filter_remapped(remapped, output, &options)
+                    .with_context(|| {
+                        format!("WASP filter failed for file `{}`", remapped.to_string_lossy())
+                    })?,
+                (Some(bam), Some(snps), None) => {
+                    wasp::find_intersecting_snvs(bam, snps, output, &options).with_context(
+                        || format!("WASP filter failed for file `{}`", bam.to_string_lossy()),
+                    )?
+                }
+                _ => unreachable!("clap requires --bam and --snps without --remapped"),
             }
-        },
+        }
+
+        Some(Commands::PairOrientation {
+            bam,
+            output,
+            insert_bins,
+            min_mapq,
+            threads,
+        }) => {
+            pairorientation::pair_orientation(
+                bam,
+                output.as_ref(),
+                insert_bins,
+                *min_mapq,
+                *threads,
+            )
+            .with_context(|| {
+                format!("Pair orientation report failed for file `{}`", bam.to_string_lossy())
+            })?;
+        }
+
+        Some(Commands::Jaccard {
+            bam_a,
+            bam_b,
+            output,
+            bin_size,
+            min_length,
+            max_length,
+            min_mapq,
+            threads,
+        }) => {
+            let options = jaccard::JaccardOptions {
+                bin_size: *bin_size,
+                filter: fragments::FragmentFilter {
+                    min_mapq: *min_mapq,
+                    min_length: *min_length,
+                    max_length: *max_length,
+                },
+                threads: *threads,
+            };
+            jaccard::jaccard(bam_a, bam_b, output.as_ref(), &options).with_context(|| {
+                format!(
+                    "Jaccard similarity failed for `{}` and `{}`",
+                    bam_a.to_string_lossy(),
+                    bam_b.to_string_lossy()
+                )
+            })?;
+        }
 
         _ => {
             println!("Subcommand not provided, will not run")
@@ -0,0 +1,235 @@
+//! Report of secondary, supplementary and chimeric alignments and of hit counts per read.
+
+use anyhow::{Context, Result};
+use rust_htslib::bam::record::{Aux, Record};
+use rust_htslib::bam::{Read, Reader};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Default)]
+struct MultimapReport {
+    total: u64,
+    primary: u64,
+    primary_mapped: u64,
+    secondary: u64,
+    supplementary: u64,
+    /// Primary records with an SA tag
+    chimeric: u64,
+    /// Number of primary alignments (read segments) by number of reported hits
+    hits: BTreeMap<u32, u64>,
+}
+
+fn hits_from_tag(record: &Record) -> Option<u32> {
+    match record.aux(b"NH") {
+        Ok(Aux::U8(n)) => Some(n as u32),
+        Ok(Aux::U16(n)) => Some(n as u32),
+        Ok(Aux::U32(n)) => Some(n),
+        Ok(Aux::I8(n)) => Some(n.max(0) as u32),
+        Ok(Aux::I16(n)) => Some(n.max(0) as u32),
+        Ok(Aux::I32(n)) => Some(n.max(0) as u32),
+        _ => None,
+    }
+}
+
+/// Identifies one segment of a template, so read 1 and read 2 are counted separately.
+fn segment_key(record: &Record) -> (Vec<u8>, bool) {
+    (record.qname().to_vec(), record.is_last_in_template())
+}
+
+fn rate(count: u64, total: u64) -> f64 {
+    match total {
+        0 => 0.0,
+        total => count as f64 / total as f64,
+    }
+}
+
+impl MultimapReport {
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, "metric\tvalue")?;
+        writeln!(writer, "total_records\t{}", self.total)?;
+        writeln!(writer, "primary\t{}", self.primary)?;
+        writeln!(writer, "secondary\t{}", self.secondary)?;
+        writeln!(writer, "supplementary\t{}", self.supplementary)?;
+        writeln!(writer, "chimeric\t{}", self.chimeric)?;
+        writeln!(
+            writer,
+            "secondary_per_primary\t{:.6}",
+            rate(self.secondary, self.primary)
+        )?;
+        writeln!(
+            writer,
+            "supplementary_per_primary\t{:.6}",
+            rate(self.supplementary, self.primary)
+        )?;
+        writeln!(
+            writer,
+            "chimeric_rate\t{:.6}",
+            rate(self.chimeric, self.primary_mapped)
+        )?;
+        let multi: u64 = self.hits.range(2..).map(|(_, n)| n).sum();
+        writeln!(
+            writer,
+            "multimapped_rate\t{:.6}",
+            rate(multi, self.primary_mapped)
+        )?;
+        for (hits, n) in &self.hits {
+            writeln!(writer, "hits_{}\t{}", hits, n)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the multi-mapping report for `bam` to `output`, or stdout.
+///
+/// Hit counts come from the NH tag of primary alignments where present, and otherwise
+/// from the number of secondary records sharing the read name and segment.
+pub fn multimap_report<P>(bam: P, output: Option<P>, threads: usize) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut reader = Reader::from_path(&bam).context("Could not open BAM file")?;
+    if threads > 1 {
+        reader.set_threads(threads)?;
+    }
+
+    let mut report = MultimapReport::default();
+    // Without NH tags only the segments with secondary records need remembering; every
+    // other untagged primary alignment is a unique hit
+    let mut secondary_counts: HashMap<(Vec<u8>, bool), u32> = HashMap::new();
+    let mut untagged_primary = 0u64;
+
+    for result in reader.records() {
+        let record = result?;
+        report.total += 1;
+        if record.is_supplementary() {
+            report.supplementary += 1;
+            continue;
+        }
+        if record.is_secondary() {
+            report.secondary += 1;
+            if hits_from_tag(&record).is_none() {
+                *secondary_counts.entry(segment_key(&record)).or_default() += 1;
+            }
+            continue;
+        }
+
+        report.primary += 1;
+        if record.is_unmapped() {
+            continue;
+        }
+        report.primary_mapped += 1;
+        if record.aux(b"SA").is_ok() {
+            report.chimeric += 1;
+        }
+        match hits_from_tag(&record) {
+            Some(hits) => *report.hits.entry(hits.max(1)).or_default() += 1,
+            None => untagged_primary += 1,
+        }
+    }
+
+    let multimapped = secondary_counts.len() as u64;
+    for secondary in secondary_counts.into_values() {
+        *report.hits.entry(secondary + 1).or_default() += 1;
+    }
+    if untagged_primary > multimapped {
+        *report.hits.entry(1).or_default() += untagged_primary - multimapped;
+    }
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path.as_ref())?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    report.write(&mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_rates() {
+        let mut report = MultimapReport {
+            total: 6,
+            primary: 4,
+            primary_mapped: 4,
+            secondary: 2,
+            chimeric: 1,
+            ..Default::default()
+        };
+        report.hits.insert(1, 3);
+        report.hits.insert(3, 1);
+
+        let mut out = Vec::new();
+        report.write(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("secondary_per_primary\t0.500000\n"));
+        assert!(text.contains("chimeric_rate\t0.250000\n"));
+        assert!(text.contains("multimapped_rate\t0.250000\n"));
+        assert!(text.contains("hits_3\t1\n"));
+    }
+
+    #[test]
+    fn report_of_a_bam() {
+        use rust_htslib::bam::header::HeaderRecord;
+        use rust_htslib::bam::{Format, Header, Writer};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = dir.path().join("input.bam");
+        let mut header = Header::new();
+        let mut sq = HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chr1").push_tag(b"LN", 1000);
+        header.push_record(&sq);
+        let mut writer = Writer::from_path(&bam, &header, Format::Bam).unwrap();
+        // Read name, flags, NH tag and whether it has an SA tag: "a" has two hits by its
+        // NH tags, "b" three by its secondary records, "c" is chimeric and "d" unmapped
+        let records: [(&[u8], u16, Option<u8>, bool); 8] = [
+            (b"a", 0, Some(2), false),
+            (b"a", 0x100, Some(2), false),
+            (b"b", 0, None, false),
+            (b"b", 0x100, None, false),
+            (b"b", 0x100, None, false),
+            (b"c", 0, None, true),
+            (b"c", 0x800, None, true),
+            (b"d", 0x4, None, false),
+        ];
+        for (i, &(name, flags, hits, chimeric)) in records.iter().enumerate() {
+            let mut record = Record::new();
+            record.set(name, None, b"ACGT", b"IIII");
+            record.set_flags(flags);
+            if flags & 0x4 == 0 {
+                record.set_tid(0);
+                record.set_pos(10 * i as i64);
+            }
+            if let Some(hits) = hits {
+                record.push_aux(b"NH", Aux::U8(hits)).unwrap();
+            }
+            if chimeric {
+                record
+                    .push_aux(b"SA", Aux::String("chr1,500,+,4M,60,0;"))
+                    .unwrap();
+            }
+            writer.write(&record).unwrap();
+        }
+        drop(writer);
+
+        let output = dir.path().join("multimap.tsv");
+        multimap_report(bam.as_path(), Some(output.as_path()), 1).unwrap();
+        let text = std::fs::read_to_string(&output).unwrap();
+        for line in [
+            "total_records\t8",
+            "primary\t4",
+            "secondary\t3",
+            "supplementary\t1",
+            "chimeric\t1",
+            "hits_1\t1",
+            "hits_2\t1",
+            "hits_3\t1",
+        ] {
+            assert!(text.lines().any(|row| row == line), "{} in\n{}", line, text);
+        }
+    }
+}
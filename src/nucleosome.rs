@@ -0,0 +1,352 @@
+//! Nucleosome occupancy and dyad calls from ATAC-seq fragments.
+//!
+//! A NucleoATAC-like approach: fragments are stratified by size into nucleosome-free (NFR)
+//! and mono-nucleosomal classes. Occupancy at a position is the fraction of covering
+//! fragments that are mono-nucleosomal. NFR and mono-nucleosome coverage are
+//! cross-correlated over a window spanning a nucleosome and its flanks at each position: a positioned
+//! nucleosome shows mono-nucleosome coverage where NFR coverage dips, so anti-correlation
+//! raises the dyad score and correlation lowers it. Mono-nucleosome midpoints are smoothed
+//! with a Gaussian kernel and weighted by occupancy and this cross-correlation to give a
+//! dyad score, whose local maxima are reported as candidate dyads.
+
+use anyhow::{Context, Result};
+use log::info;
+use rust_htslib::bam::{IndexedReader, Read};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::fragments::{self, FragmentFilter};
+use crate::signal::{self, TrackWriter};
+
+const CHUNK_SIZE: i64 = 1_000_000;
+
+#[derive(Debug, Clone)]
+pub struct NucleosomeOptions {
+    /// Longest fragment treated as nucleosome-free
+    pub nfr_max: i64,
+    /// Shortest mono-nucleosomal fragment
+    pub mono_min: i64,
+    /// Longest mono-nucleosomal fragment
+    pub mono_max: i64,
+    /// Standard deviation of the kernel used to smooth dyad positions
+    pub sigma: f64,
+    /// Minimum distance between called dyads
+    pub min_spacing: i64,
+    /// Minimum dyad score to report a dyad
+    pub min_score: f32,
+    pub min_mapq: u8,
+    pub threads: usize,
+}
+
+impl Default for NucleosomeOptions {
+    fn default() -> Self {
+        Self {
+            nfr_max: 120,
+            mono_min: 147,
+            mono_max: 247,
+            sigma: 25.0,
+            min_spacing: 120,
+            min_score: 2.0,
+            min_mapq: 0,
+            threads: 1,
+        }
+    }
+}
+
+/// Signals over one stretch of a chromosome.
+struct NucleosomeSignal {
+    offset: i64,
+    nfr_coverage: Vec<i32>,
+    mono_coverage: Vec<i32>,
+    dyad_density: Vec<f32>,
+}
+
+impl NucleosomeSignal {
+    fn new(offset: i64, length: usize) -> Self {
+        Self {
+            offset,
+            nfr_coverage: vec![0; length + 1],
+            mono_coverage: vec![0; length + 1],
+            dyad_density: vec![0.0; length],
+        }
+    }
+
+    fn add_coverage(diff: &mut [i32], offset: i64, start: i64, end: i64) {
+        let first = (start - offset).clamp(0, diff.len() as i64 - 1) as usize;
+        let last = (end - offset).clamp(0, diff.len() as i64 - 1) as usize;
+        if first < last {
+            diff[first] += 1;
+            diff[last] -= 1;
+        }
+    }
+
+    fn add_nfr(&mut self, start: i64, end: i64) {
+        Self::add_coverage(&mut self.nfr_coverage, self.offset, start, end);
+    }
+
+    fn add_mono(&mut self, start: i64, end: i64, kernel: &[f32]) {
+        Self::add_coverage(&mut self.mono_coverage, self.offset, start, end);
+
+        let half = (kernel.len() / 2) as i64;
+        let midpoint = start + (end - start) / 2 - self.offset;
+        for (i, weight) in kernel.iter().enumerate() {
+            let position = midpoint - half + i as i64;
+            if position >= 0 && (position as usize) < self.dyad_density.len() {
+                self.dyad_density[position as usize] += weight;
+            }
+        }
+    }
+
+    /// Per-base depth from a difference array.
+    fn depth(diff: &[i32], length: usize) -> Vec<i32> {
+        diff[..length]
+            .iter()
+            .scan(0, |depth, d| {
+                *depth += d;
+                Some(*depth)
+            })
+            .collect()
+    }
+
+    fn nfr_depth(&self) -> Vec<i32> {
+        Self::depth(&self.nfr_coverage, self.dyad_density.len())
+    }
+
+    fn mono_depth(&self) -> Vec<i32> {
+        Self::depth(&self.mono_coverage, self.dyad_density.len())
+    }
+
+    /// Fraction of covering fragments that are mono-nucleosomal, per base.
+    fn occupancy(nfr: &[i32], mono: &[i32]) -> Vec<f32> {
+        nfr.iter()
+            .zip(mono.iter())
+            .map(|(&nfr, &mono)| match nfr + mono {
+                0 => 0.0,
+                total => mono as f32 / total as f32,
+            })
+            .collect()
+    }
+
+    /// Pearson correlation of NFR and mono-nucleosome depth within `half_window` of each
+    /// position. Windows where either signal is flat have no defined correlation and get 0.
+    fn cross_correlation(nfr: &[i32], mono: &[i32], half_window: usize) -> Vec<f32> {
+        let mut sums = vec![[0.0f64; 5]; nfr.len() + 1];
+        for (i, (&a, &b)) in nfr.iter().zip(mono.iter()).enumerate() {
+            let (a, b) = (a as f64, b as f64);
+            let previous = sums[i];
+            sums[i + 1] = [
+                previous[0] + a,
+                previous[1] + b,
+                previous[2] + a * a,
+                previous[3] + b * b,
+                previous[4] + a * b,
+            ];
+        }
+
+        (0..nfr.len())
+            .map(|i| {
+                let lo = i.saturating_sub(half_window);
+                let hi = (i + half_window + 1).min(nfr.len());
+                let n = (hi - lo) as f64;
+                let [sa, sb, saa, sbb, sab] = {
+                    let (upper, lower) = (sums[hi], sums[lo]);
+                    [0, 1, 2, 3, 4].map(|k| upper[k] - lower[k])
+                };
+                let var_a = saa / n - (sa / n).powi(2);
+                let var_b = sbb / n - (sb / n).powi(2);
+                if var_a <= f64::EPSILON || var_b <= f64::EPSILON {
+                    return 0.0;
+                }
+                let covariance = sab / n - (sa / n) * (sb / n);
+                (covariance / (var_a * var_b).sqrt()).clamp(-1.0, 1.0) as f32
+            })
+            .collect()
+    }
+
+    /// Dyad score: smoothed mono-nucleosome midpoints weighted by occupancy, scaled from
+    /// 0 (NFR and mono-nucleosome signal perfectly correlated) to 1 (anti-correlated).
+    fn dyad_scores(&self, occupancy: &[f32], correlation: &[f32]) -> Vec<f32> {
+        self.dyad_density
+            .iter()
+            .zip(occupancy.iter())
+            .zip(correlation.iter())
+            .map(|((density, occupancy), r)| density * occupancy * (1.0 - r) / 2.0)
+            .collect()
+    }
+
+    /// Positions (relative to `offset`) that are the highest dyad score within
+    /// `min_spacing` either side and score at least `min_score`.
+    fn call_dyads(scores: &[f32], min_spacing: i64, min_score: f32) -> Vec<(usize, f32)> {
+        let spacing = min_spacing.max(1) as usize;
+        let mut dyads = Vec::new();
+        for (i, &score) in scores.iter().enumerate() {
+            if score < min_score {
+                continue;
+            }
+            let lo = i.saturating_sub(spacing);
+            let hi = (i + spacing + 1).min(scores.len());
+            // Ties go to the leftmost position
+            let is_peak = scores[lo..i].iter().all(|&s| s < score)
+                && scores[i + 1..hi].iter().all(|&s| s <= score);
+            if is_peak {
+                dyads.push((i, score));
+            }
+        }
+        dyads
+    }
+}
+
+fn gaussian_kernel(sigma: f64) -> Vec<f32> {
+    let half = (3.0 * sigma).ceil() as i64;
+    (-half..=half)
+        .map(|x| (-(x * x) as f64 / (2.0 * sigma * sigma)).exp() as f32)
+        .collect()
+}
+
+/// Write `<prefix>.occupancy.bw` and `<prefix>.dyads.bed` for `bam`.
+pub fn nucleosome_signal<P>(bam: P, output_prefix: P, options: &NucleosomeOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let prefix = output_prefix.as_ref().display().to_string();
+    let mut reader = IndexedReader::from_path(&bam).context("Could not open indexed BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+
+    let chroms = signal::chrom_sizes(reader.header());
+    let mut occupancy_writer =
+        TrackWriter::create(format!("{}.occupancy.bw", prefix), chroms.clone())?;
+    let mut dyad_writer = BufWriter::new(
+        File::create(format!("{}.dyads.bed", prefix)).context("Could not create dyad BED file")?,
+    );
+
+    let filter = FragmentFilter {
+        min_mapq: options.min_mapq,
+        min_length: 1,
+        max_length: options.mono_max,
+    };
+    let kernel = gaussian_kernel(options.sigma);
+    // A window reaching one nucleosome footprint either side of a dyad covers the
+    // nucleosome and its flanking linker DNA.
+    let half_window = options.mono_min.max(1) as usize;
+
+    // Chunks are extended on both sides so that smoothing and peak calling near chunk
+    // edges see the same data they would for a whole chromosome.
+    let padding = options.mono_max + half_window as i64 + kernel.len() as i64 + options.min_spacing;
+    let mut n_dyads = 0;
+
+    for (tid, (chrom, length)) in chroms.iter().enumerate() {
+        let length = *length as i64;
+        info!("Computing nucleosome signal for {}", chrom);
+
+        let mut chunk_start = 0;
+        while chunk_start < length {
+            let chunk_end = (chunk_start + CHUNK_SIZE).min(length);
+            let start = (chunk_start - padding).max(0);
+            let end = (chunk_end + padding).min(length);
+            let mut signal = NucleosomeSignal::new(start, (end - start) as usize);
+
+            reader.fetch((tid as i32, (start - options.mono_max).max(0), end))?;
+            for result in reader.records() {
+                let record = result?;
+                if let Some(fragment) = fragments::from_record(&record, &filter) {
+                    let len = fragment.len();
+                    if len <= options.nfr_max {
+                        signal.add_nfr(fragment.start, fragment.end);
+                    } else if len >= options.mono_min && len <= options.mono_max {
+                        signal.add_mono(fragment.start, fragment.end, &kernel);
+                    }
+                }
+            }
+
+            let nfr = signal.nfr_depth();
+            let mono = signal.mono_depth();
+            let occupancy = NucleosomeSignal::occupancy(&nfr, &mono);
+            let correlation = NucleosomeSignal::cross_correlation(&nfr, &mono, half_window);
+            let scores = signal.dyad_scores(&occupancy, &correlation);
+
+            let within = (chunk_start - start) as usize..(chunk_end - start) as usize;
+            occupancy_writer.write_values(chrom, chunk_start as u64, &occupancy[within])?;
+
+            for (i, score) in
+                NucleosomeSignal::call_dyads(&scores, options.min_spacing, options.min_score)
+            {
+                let position = start + i as i64;
+                if position >= chunk_start && position < chunk_end {
+                    n_dyads += 1;
+                    writeln!(
+                        dyad_writer,
+                        "{}\t{}\t{}\tdyad_{}\t{:.3}",
+                        chrom,
+                        position,
+                        position + 1,
+                        n_dyads,
+                        score
+                    )?;
+                }
+            }
+
+            chunk_start = chunk_end;
+        }
+    }
+
+    occupancy_writer.finish()?;
+    dyad_writer.flush()?;
+    println!("Called {} candidate dyads", n_dyads);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn occupancy_and_dyads() {
+        let kernel = gaussian_kernel(5.0);
+        let mut signal = NucleosomeSignal::new(0, 400);
+        signal.add_nfr(0, 100);
+        signal.add_nfr(250, 350);
+        for _ in 0..3 {
+            signal.add_mono(100, 250, &kernel);
+        }
+
+        let nfr = signal.nfr_depth();
+        let mono = signal.mono_depth();
+        let occupancy = NucleosomeSignal::occupancy(&nfr, &mono);
+        assert_eq!(occupancy[50], 0.0);
+        assert_eq!(occupancy[175], 1.0);
+        assert_eq!(occupancy[300], 0.0);
+
+        let correlation = NucleosomeSignal::cross_correlation(&nfr, &mono, 147);
+        let scores = signal.dyad_scores(&occupancy, &correlation);
+        let dyads = NucleosomeSignal::call_dyads(&scores, 50, 1.0);
+        assert_eq!(dyads.len(), 1);
+        assert_eq!(dyads[0].0, 175);
+    }
+
+    #[test]
+    fn cross_correlation_of_nfr_and_mono() {
+        let nfr = [4, 4, 4, 0, 0, 0, 4, 4, 4];
+        let mono = [0, 0, 0, 3, 3, 3, 0, 0, 0];
+        let correlation = NucleosomeSignal::cross_correlation(&nfr, &mono, 4);
+        assert!((correlation[4] + 1.0).abs() < 1e-6);
+
+        let correlation = NucleosomeSignal::cross_correlation(&nfr, &nfr, 4);
+        assert!((correlation[4] - 1.0).abs() < 1e-6);
+
+        // A flat NFR signal has no defined correlation
+        let correlation = NucleosomeSignal::cross_correlation(&[0; 9], &mono, 4);
+        assert_eq!(correlation[4], 0.0);
+
+        // Flanking NFR signal around a nucleosome raises its dyad score
+        let kernel = gaussian_kernel(2.0);
+        let mut signal = NucleosomeSignal::new(0, 9);
+        signal.add_mono(3, 6, &kernel);
+        let occupancy = vec![1.0; 9];
+        let flanked = signal.dyad_scores(&occupancy, &[-1.0; 9]);
+        let alone = signal.dyad_scores(&occupancy, &[0.0; 9]);
+        assert!(flanked[4] > alone[4]);
+    }
+}
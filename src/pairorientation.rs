@@ -0,0 +1,214 @@
+//! Read-pair orientation report.
+//!
+//! Pairs are classed as FR (pointing inward, as in most libraries), RF (pointing outward,
+//! e.g. mate-pair libraries or tandem duplications) or tandem (both mates on the same
+//! strand), per insert-size bin, alongside the rate of pairs with mates on different
+//! chromosomes. Each pair is counted once: from its reverse-strand read if the strands
+//! differ (its 5' end is known without the mate CIGAR), from read 1 otherwise.
+
+use anyhow::{Context, Result};
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{Read, Reader};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Orientation {
+    Fr,
+    Rf,
+    Tandem,
+}
+
+/// Orientation of the pair `record` belongs to, if the pair is counted from this record.
+fn orientation(record: &Record) -> Option<Orientation> {
+    match (record.is_reverse(), record.is_mate_reverse()) {
+        (true, false) => {
+            // The forward mate's 5' end is its start
+            let forward_five_prime = record.mpos();
+            let reverse_five_prime = record.reference_end();
+            match forward_five_prime < reverse_five_prime {
+                true => Some(Orientation::Fr),
+                false => Some(Orientation::Rf),
+            }
+        }
+        (false, true) => None,
+        _ if record.is_first_in_template() => Some(Orientation::Tandem),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct OrientationCounts {
+    fr: u64,
+    rf: u64,
+    tandem: u64,
+}
+
+impl OrientationCounts {
+    fn add(&mut self, orientation: Orientation) {
+        match orientation {
+            Orientation::Fr => self.fr += 1,
+            Orientation::Rf => self.rf += 1,
+            Orientation::Tandem => self.tandem += 1,
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.fr + self.rf + self.tandem
+    }
+}
+
+/// Index of the insert-size bin, where bin `i` is `[edges[i - 1], edges[i])`.
+fn insert_bin(insert_size: u64, edges: &[u64]) -> usize {
+    edges.partition_point(|&edge| edge <= insert_size)
+}
+
+fn bin_label(i: usize, edges: &[u64]) -> String {
+    let start = match i {
+        0 => 0,
+        i => edges[i - 1],
+    };
+    match edges.get(i) {
+        Some(end) => format!("{}-{}", start, end),
+        None => format!("{}+", start),
+    }
+}
+
+/// Write pair orientation proportions per insert-size bin (bin edges in `insert_bins`) to
+/// `output` (or stdout).
+pub fn pair_orientation<P>(
+    bam: P,
+    output: Option<P>,
+    insert_bins: &[u64],
+    min_mapq: u8,
+    threads: usize,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut reader = Reader::from_path(&bam).context("Could not open BAM file")?;
+    if threads > 1 {
+        reader.set_threads(threads)?;
+    }
+    let mut edges = insert_bins.to_vec();
+    edges.sort_unstable();
+    edges.dedup();
+
+    let mut per_bin = vec![OrientationCounts::default(); edges.len() + 1];
+    let mut same_chromosome = 0u64;
+    let mut inter_chromosomal = 0u64;
+
+    for result in reader.records() {
+        let record = result?;
+        if !record.is_paired()
+            || record.is_unmapped()
+            || record.is_mate_unmapped()
+            || record.is_secondary()
+            || record.is_supplementary()
+            || record.is_quality_check_failed()
+            || record.is_duplicate()
+            || record.mapq() < min_mapq
+        {
+            continue;
+        }
+        let orientation = match orientation(&record) {
+            Some(orientation) => orientation,
+            None => continue,
+        };
+        if record.tid() != record.mtid() {
+            inter_chromosomal += 1;
+            continue;
+        }
+        same_chromosome += 1;
+        per_bin[insert_bin(record.insert_size().unsigned_abs(), &edges)].add(orientation);
+    }
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path.as_ref())?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    writeln!(
+        writer,
+        "insert_size\tpairs\tfr\trf\ttandem\tfrac_fr\tfrac_rf\tfrac_tandem"
+    )?;
+    let mut all = OrientationCounts::default();
+    for counts in &per_bin {
+        all.fr += counts.fr;
+        all.rf += counts.rf;
+        all.tandem += counts.tandem;
+    }
+    let rows = per_bin
+        .iter()
+        .enumerate()
+        .map(|(i, counts)| (bin_label(i, &edges), counts))
+        .chain(std::iter::once(("all".to_string(), &all)));
+    for (label, counts) in rows {
+        let fraction = |n: u64| match counts.total() {
+            0 => 0.0,
+            total => n as f64 / total as f64,
+        };
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}",
+            label,
+            counts.total(),
+            counts.fr,
+            counts.rf,
+            counts.tandem,
+            fraction(counts.fr),
+            fraction(counts.rf),
+            fraction(counts.tandem),
+        )?;
+    }
+    let total_pairs = same_chromosome + inter_chromosomal;
+    writeln!(
+        writer,
+        "inter_chromosomal\t{}\t.\t.\t.\t{:.6}\t.\t.",
+        inter_chromosomal,
+        match total_pairs {
+            0 => 0.0,
+            total => inter_chromosomal as f64 / total as f64,
+        }
+    )?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    #[test]
+    fn classifies_orientation_and_bins() {
+        let cigar = CigarString(vec![Cigar::Match(50)]);
+        let mut record = Record::new();
+        record.set(b"pair", Some(&cigar), &[b'A'; 50], &[30; 50]);
+        record.unset_unmapped();
+        record.set_paired();
+        record.set_first_in_template();
+        record.set_pos(300);
+
+        // Reverse read at 300-350 with its forward mate upstream: inward facing
+        record.set_reverse();
+        record.set_mpos(100);
+        assert_eq!(orientation(&record), Some(Orientation::Fr));
+        // Forward mate beyond the reverse read's 5' end: outward facing
+        record.set_mpos(400);
+        assert_eq!(orientation(&record), Some(Orientation::Rf));
+        // Same strand, counted from read 1 only
+        record.set_mate_reverse();
+        assert_eq!(orientation(&record), Some(Orientation::Tandem));
+        record.unset_reverse();
+        assert_eq!(orientation(&record), None);
+
+        let edges = [100, 500];
+        assert_eq!(insert_bin(50, &edges), 0);
+        assert_eq!(insert_bin(100, &edges), 1);
+        assert_eq!(insert_bin(10_000, &edges), 2);
+        assert_eq!(bin_label(1, &edges), "100-500");
+        assert_eq!(bin_label(2, &edges), "500+");
+    }
+}
@@ -0,0 +1,154 @@
+//! Provenance records for BAM files written by rsbamtk.
+//!
+//! Every command that writes a BAM appends an @PG record chained (via PP) to the last
+//! program already present in the header, so the steps applied to a file can be
+//! reconstructed. Commands that produce statistics can also stamp an @CO line carrying a
+//! hash of the stats summary.
+
+use anyhow::{Context, Result};
+use noodles::sam;
+use rust_htslib::bam::header::{Header, HeaderRecord};
+use rust_htslib::bam::{Format, Read};
+use sam::header::record::value::map::{program, Program};
+use sam::header::record::value::Map;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+pub const PROGRAM_NAME: &str = "rsbamtk";
+pub const PROGRAM_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The command line rsbamtk was invoked with.
+pub fn command_line() -> String {
+    std::env::args().collect::<Vec<_>>().join(" ")
+}
+
+/// First of `rsbamtk`, `rsbamtk.1`, `rsbamtk.2`, ... not already used as an @PG ID.
+fn unique_program_id(existing: &[String]) -> String {
+    let mut id = PROGRAM_NAME.to_string();
+    let mut suffix = 0;
+    while existing.contains(&id) {
+        suffix += 1;
+        id = format!("{}.{}", PROGRAM_NAME, suffix);
+    }
+    id
+}
+
+/// The last @PG in the header that no other @PG points to, i.e. the end of the chain.
+fn last_program_id(programs: &[(String, Option<String>)]) -> Option<String> {
+    programs
+        .iter()
+        .rev()
+        .find(|(id, _)| !programs.iter().any(|(_, pp)| pp.as_ref() == Some(id)))
+        .map(|(id, _)| id.clone())
+}
+
+/// Append an @PG record for this invocation to a rust-htslib header.
+///
+/// `description` is written to the DS field and should say what was done to the file.
+pub fn add_program_record(header: &mut Header, description: &str) {
+    let programs: Vec<(String, Option<String>)> = header
+        .to_hashmap()
+        .remove("PG")
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|pg| Some((pg.get("ID")?.clone(), pg.get("PP").cloned())))
+        .collect();
+    let ids: Vec<String> = programs.iter().map(|(id, _)| id.clone()).collect();
+
+    let mut record = HeaderRecord::new(b"PG");
+    record
+        .push_tag(b"ID", unique_program_id(&ids))
+        .push_tag(b"PN", PROGRAM_NAME);
+    if let Some(previous) = last_program_id(&programs) {
+        record.push_tag(b"PP", previous);
+    }
+    record
+        .push_tag(b"VN", PROGRAM_VERSION)
+        .push_tag(b"DS", description)
+        .push_tag(b"CL", command_line());
+
+    header.push_record(&record);
+}
+
+/// Append an @PG record for this invocation to a noodles header.
+///
+/// noodles chains the new program onto the existing @PG records itself.
+pub fn add_program_record_sam(header: &mut sam::Header, description: &str) -> Result<()> {
+    let ids: Vec<String> = header
+        .programs()
+        .as_ref()
+        .keys()
+        .map(|id| id.to_string())
+        .collect();
+
+    let record = Map::<Program>::builder()
+        .insert(program::tag::NAME, PROGRAM_NAME)
+        .insert(program::tag::VERSION, PROGRAM_VERSION)
+        .insert(program::tag::DESCRIPTION, description)
+        .insert(program::tag::COMMAND_LINE, command_line())
+        .build()?;
+
+    header
+        .programs_mut()
+        .add(unique_program_id(&ids), record)
+        .context("Could not add @PG record to header")?;
+    Ok(())
+}
+
+/// SHA-256 of the JSON serialisation of a stats summary, as a hex string.
+pub fn stats_hash<S: Serialize>(stats: &S) -> Result<String> {
+    let json = serde_json::to_vec(stats)?;
+    let digest = Sha256::digest(&json);
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Rewrite `bam` in place with an extra @CO line.
+///
+/// BAM headers are written before any records, so stats that are only known once a
+/// command has finished have to be added by re-writing the file.
+pub fn append_comment<P: AsRef<Path>>(bam: P, comment: &str) -> Result<()> {
+    let bam = bam.as_ref();
+    let tmp = bam.with_extension("reheader.tmp.bam");
+
+    {
+        let mut reader = rust_htslib::bam::Reader::from_path(bam)
+            .with_context(|| format!("Could not open `{}`", bam.display()))?;
+        let mut header = Header::from_template(reader.header());
+        header.push_comment(comment.as_bytes());
+        let mut writer = rust_htslib::bam::Writer::from_path(&tmp, &header, Format::Bam)?;
+
+        for record in reader.records() {
+            writer.write(&record?)?;
+        }
+    }
+
+    std::fs::rename(&tmp, bam).with_context(|| format!("Could not replace `{}`", bam.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_records_chain_onto_existing_header() {
+        let reader = rust_htslib::bam::Reader::from_path("test/test.bam").expect("Open test.bam");
+        let mut header = Header::from_template(reader.header());
+
+        add_program_record(&mut header, "test");
+        add_program_record(&mut header, "test again");
+
+        let programs = header.to_hashmap().remove("PG").expect("No @PG records");
+        let ours: Vec<_> = programs
+            .iter()
+            .filter(|pg| pg.get("PN").map(|pn| pn.as_str()) == Some(PROGRAM_NAME))
+            .collect();
+
+        assert_eq!(ours.len(), 2);
+        assert_eq!(ours[0]["ID"], "rsbamtk");
+        assert_eq!(ours[0]["PP"], "samtools.2");
+        assert_eq!(ours[1]["ID"], "rsbamtk.1");
+        assert_eq!(ours[1]["PP"], "rsbamtk");
+    }
+}
@@ -0,0 +1,266 @@
+//! Per-cycle base-quality profiles from aligned records.
+//!
+//! Qualities are put back into sequencing order (reversed for reverse-strand alignments)
+//! and read 1 and read 2 are profiled separately. To keep huge BAMs fast, a fixed-size
+//! uniform sample of reads is drawn by reservoir sampling and only those are profiled.
+//! Profiling every read instead adds each to the profiles as it is read.
+
+use anyhow::{Context, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{Read, Reader};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Highest Phred score tracked; anything above is counted here.
+const MAX_QUALITY: usize = 93;
+
+#[derive(Debug, Clone)]
+pub struct QualProfileOptions {
+    /// Number of reads to sample. Zero profiles every read
+    pub sample_size: usize,
+    pub seed: u64,
+    pub threads: usize,
+}
+
+impl Default for QualProfileOptions {
+    fn default() -> Self {
+        Self {
+            sample_size: 100_000,
+            seed: 0,
+            threads: 1,
+        }
+    }
+}
+
+/// Uniform sample of up to `capacity` items from a stream of unknown length (Algorithm R).
+struct Reservoir<T> {
+    items: Vec<T>,
+    capacity: usize,
+    seen: u64,
+    rng: StdRng,
+}
+
+impl<T> Reservoir<T> {
+    fn new(capacity: usize, seed: u64) -> Self {
+        Self {
+            items: Vec::new(),
+            capacity,
+            seen: 0,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Offer an item; `make` is only called if the item is kept.
+    fn offer<F: FnOnce() -> T>(&mut self, make: F) {
+        self.seen += 1;
+        if self.items.len() < self.capacity {
+            self.items.push(make());
+            return;
+        }
+        let j = self.rng.gen_range(0..self.seen);
+        if (j as usize) < self.capacity {
+            self.items[j as usize] = make();
+        }
+    }
+}
+
+/// Quality histograms per sequencing cycle.
+#[derive(Debug, Default)]
+struct CycleProfile {
+    histograms: Vec<[u64; MAX_QUALITY + 1]>,
+}
+
+impl CycleProfile {
+    fn add(&mut self, qualities: &[u8]) {
+        if self.histograms.len() < qualities.len() {
+            self.histograms
+                .resize(qualities.len(), [0; MAX_QUALITY + 1]);
+        }
+        for (histogram, &q) in self.histograms.iter_mut().zip(qualities) {
+            histogram[(q as usize).min(MAX_QUALITY)] += 1;
+        }
+    }
+
+    /// Smallest quality at or below which at least `fraction` of the bases fall.
+    fn quantile(histogram: &[u64], fraction: f64) -> usize {
+        let total: u64 = histogram.iter().sum();
+        let target = (fraction * total as f64).ceil().max(1.0) as u64;
+        let mut cumulative = 0;
+        for (q, &n) in histogram.iter().enumerate() {
+            cumulative += n;
+            if cumulative >= target {
+                return q;
+            }
+        }
+        MAX_QUALITY
+    }
+
+    fn write<W: Write>(&self, writer: &mut W, read: &str) -> Result<()> {
+        for (cycle, histogram) in self.histograms.iter().enumerate() {
+            let bases: u64 = histogram.iter().sum();
+            if bases == 0 {
+                continue;
+            }
+            let mean = histogram
+                .iter()
+                .enumerate()
+                .map(|(q, &n)| q as u64 * n)
+                .sum::<u64>() as f64
+                / bases as f64;
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{:.2}\t{}\t{}\t{}\t{}\t{}",
+                read,
+                cycle + 1,
+                bases,
+                mean,
+                Self::quantile(histogram, 0.1),
+                Self::quantile(histogram, 0.25),
+                Self::quantile(histogram, 0.5),
+                Self::quantile(histogram, 0.75),
+                Self::quantile(histogram, 0.9),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn has_qualities(record: &Record) -> bool {
+    record.qual().first().map(|&q| q != 255).unwrap_or(false)
+}
+
+/// Qualities of a record in sequencing order.
+fn sequencing_qualities(record: &Record) -> Vec<u8> {
+    let mut qualities = record.qual().to_vec();
+    if record.is_reverse() {
+        qualities.reverse();
+    }
+    qualities
+}
+
+/// Write the per-cycle quality profile of `bam` to `output` as a TSV.
+pub fn qual_profile<P>(bam: P, output: P, options: &QualProfileOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut reader = Reader::from_path(&bam).context("Could not open BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+
+    let mut read1 = CycleProfile::default();
+    let mut read2 = CycleProfile::default();
+    // (is read 2, qualities) of the sampled reads, with no sample when profiling them all
+    let mut reservoir: Option<Reservoir<(bool, Vec<u8>)>> =
+        (options.sample_size > 0).then(|| Reservoir::new(options.sample_size, options.seed));
+    let mut n_reads = 0u64;
+    for result in reader.records() {
+        let record = result?;
+        if record.is_secondary() || record.is_supplementary() || !has_qualities(&record) {
+            continue;
+        }
+        n_reads += 1;
+        let is_read2 = record.is_paired() && record.is_last_in_template();
+        match &mut reservoir {
+            Some(reservoir) => reservoir.offer(|| (is_read2, sequencing_qualities(&record))),
+            None => match is_read2 {
+                true => read2.add(&sequencing_qualities(&record)),
+                false => read1.add(&sequencing_qualities(&record)),
+            },
+        }
+    }
+
+    let n_profiled = match &reservoir {
+        Some(reservoir) => {
+            for (is_read2, qualities) in &reservoir.items {
+                match is_read2 {
+                    true => read2.add(qualities),
+                    false => read1.add(qualities),
+                }
+            }
+            reservoir.items.len() as u64
+        }
+        None => n_reads,
+    };
+
+    let mut writer = BufWriter::new(File::create(output.as_ref())?);
+    writeln!(
+        writer,
+        "read\tcycle\tbases\tmean\tp10\tp25\tmedian\tp75\tp90"
+    )?;
+    read1.write(&mut writer, "1")?;
+    read2.write(&mut writer, "2")?;
+    writer.flush()?;
+
+    println!("Profiled {} of {} reads", n_profiled, n_reads);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservoir_and_quantiles() {
+        let mut reservoir = Reservoir::new(10, 1);
+        for i in 0..1000 {
+            reservoir.offer(|| i);
+        }
+        assert_eq!(reservoir.items.len(), 10);
+        assert_eq!(reservoir.seen, 1000);
+        assert!(reservoir.items.iter().any(|&i| i >= 10));
+
+        let mut profile = CycleProfile::default();
+        profile.add(&[30, 20]);
+        profile.add(&[40]);
+        profile.add(&[10]);
+        profile.add(&[35]);
+        assert_eq!(profile.histograms.len(), 2);
+        assert_eq!(CycleProfile::quantile(&profile.histograms[0], 0.5), 30);
+        assert_eq!(CycleProfile::quantile(&profile.histograms[0], 0.1), 10);
+        assert_eq!(CycleProfile::quantile(&profile.histograms[1], 0.9), 20);
+    }
+
+    #[test]
+    fn profiling_every_read_matches_a_sample_of_all_of_them() {
+        use rust_htslib::bam::header::HeaderRecord;
+        use rust_htslib::bam::{Format, Header, Writer};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = dir.path().join("input.bam");
+        let mut header = Header::new();
+        let mut sq = HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chr1").push_tag(b"LN", 1000);
+        header.push_record(&sq);
+        let mut writer = Writer::from_path(&bam, &header, Format::Bam).unwrap();
+        for (i, qual) in [[30, 20, 10], [40, 30, 20], [10, 10, 35]]
+            .iter()
+            .enumerate()
+        {
+            let mut record = Record::new();
+            record.set(format!("r{}", i).as_bytes(), None, b"ACG", qual);
+            if i == 1 {
+                record.set_reverse();
+            }
+            writer.write(&record).unwrap();
+        }
+        drop(writer);
+
+        let profile = |sample_size| {
+            let output = dir.path().join(format!("profile{}.tsv", sample_size));
+            let options = QualProfileOptions {
+                sample_size,
+                ..Default::default()
+            };
+            qual_profile(bam.as_path(), output.as_path(), &options).unwrap();
+            std::fs::read_to_string(output).unwrap()
+        };
+        let all = profile(0);
+        assert_eq!(all, profile(10));
+        // The reverse read's qualities are counted in sequencing order
+        assert!(all.contains("1\t1\t3\t20.00\t"), "{}", all);
+    }
+}
@@ -0,0 +1,250 @@
+//! Per-region depth summaries (like `mosdepth --by`).
+
+use anyhow::{Context, Result};
+use bio::io::bed;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::{IndexedReader, Read};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+#[derive(Debug, Clone)]
+pub struct RegionDepthOptions {
+    /// Report the fraction of bases with at least this depth, for each threshold
+    pub thresholds: Vec<u32>,
+    pub min_mapq: u8,
+    pub threads: usize,
+}
+
+impl Default for RegionDepthOptions {
+    fn default() -> Self {
+        Self {
+            thresholds: vec![1, 10, 30],
+            min_mapq: 0,
+            threads: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Region {
+    chrom: String,
+    start: u64,
+    end: u64,
+    name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct DepthSummary {
+    mean: f64,
+    median: f64,
+    /// Fraction of bases at or above each threshold
+    above: Vec<f64>,
+}
+
+impl DepthSummary {
+    fn from_depths(mut depths: Vec<u32>, thresholds: &[u32]) -> Self {
+        if depths.is_empty() {
+            return Self {
+                mean: 0.0,
+                median: 0.0,
+                above: vec![0.0; thresholds.len()],
+            };
+        }
+        let n = depths.len();
+        let mean = depths.iter().map(|&d| d as f64).sum::<f64>() / n as f64;
+        let above = thresholds
+            .iter()
+            .map(|&t| depths.iter().filter(|&&d| d >= t).count() as f64 / n as f64)
+            .collect();
+
+        depths.sort_unstable();
+        let median = match n % 2 {
+            0 => (depths[n / 2 - 1] + depths[n / 2]) as f64 / 2.0,
+            _ => depths[n / 2] as f64,
+        };
+        Self {
+            mean,
+            median,
+            above,
+        }
+    }
+}
+
+/// Per-base depth over `region` from aligned blocks of the reads that pass the filters.
+fn region_depths(reader: &mut IndexedReader, region: &Region, min_mapq: u8) -> Result<Vec<u32>> {
+    let length = (region.end - region.start) as usize;
+    let mut diff = vec![0i64; length + 1];
+
+    reader.fetch((
+        region.chrom.as_str(),
+        region.start as i64,
+        region.end as i64,
+    ))?;
+    for result in reader.records() {
+        let record = result?;
+        if record.is_unmapped()
+            || record.is_secondary()
+            || record.is_supplementary()
+            || record.is_quality_check_failed()
+            || record.is_duplicate()
+            || record.mapq() < min_mapq
+        {
+            continue;
+        }
+        for [start, end] in record.aligned_blocks() {
+            let start = (start - region.start as i64).clamp(0, length as i64) as usize;
+            let end = (end - region.start as i64).clamp(0, length as i64) as usize;
+            if start < end {
+                diff[start] += 1;
+                diff[end] -= 1;
+            }
+        }
+    }
+
+    let mut depth = 0;
+    Ok(diff[..length]
+        .iter()
+        .map(|d| {
+            depth += d;
+            depth as u32
+        })
+        .collect())
+}
+
+fn read_regions(path: &Path) -> Result<Vec<Region>> {
+    let mut reader = bed::Reader::from_file(path)?;
+    let mut regions = Vec::new();
+    for record in reader.records() {
+        let record = record.context("Error reading BED record")?;
+        regions.push(Region {
+            chrom: record.chrom().to_owned(),
+            start: record.start(),
+            end: record.end(),
+            name: record.name().unwrap_or(".").to_owned(),
+        });
+    }
+    Ok(regions)
+}
+
+/// Summarise the depth of `bam` over every region in `regions`, writing one TSV row per
+/// region in input order. Regions that can't be read (e.g. on a contig missing from the
+/// BAM) are reported and get a row of NA.
+pub fn region_depth<P>(bam: P, regions: P, output: P, options: &RegionDepthOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let regions = read_regions(regions.as_ref())?;
+    let bam: PathBuf = bam.as_ref().to_path_buf();
+    // Check the index up front rather than in every worker
+    IndexedReader::from_path(&bam).context("Could not open indexed BAM file")?;
+
+    let (region_sender, region_recv) = crossbeam::channel::unbounded::<(usize, Region)>();
+    let (summary_sender, summary_recv) = crossbeam::channel::unbounded();
+
+    let mut handles = Vec::new();
+    for _ in 0..options.threads.max(1) {
+        let region_recv = region_recv.clone();
+        let summary_sender = summary_sender.clone();
+        let bam = bam.clone();
+        let options = options.clone();
+
+        handles.push(thread::spawn(move || -> Result<()> {
+            let mut reader = IndexedReader::from_path(&bam)?;
+            for (i, region) in region_recv {
+                let summary = match region_depths(&mut reader, &region, options.min_mapq) {
+                    Ok(depths) => Some(DepthSummary::from_depths(depths, &options.thresholds)),
+                    Err(e) => {
+                        eprintln!(
+                            "Could not summarise {}:{}-{}: {}",
+                            region.chrom, region.start, region.end, e
+                        );
+                        None
+                    }
+                };
+                summary_sender.send((i, summary))?;
+            }
+            Ok(())
+        }));
+    }
+    drop(summary_sender);
+
+    for region in regions.iter().cloned().enumerate() {
+        region_sender.send(region)?;
+    }
+    drop(region_sender);
+
+    let mut summaries = vec![None; regions.len()];
+    for (i, summary) in summary_recv {
+        summaries[i] = summary;
+    }
+    for handle in handles {
+        handle.join().expect("Failed to join depth thread")?;
+    }
+
+    let mut writer = BufWriter::new(File::create(output.as_ref())?);
+    write!(writer, "chrom\tstart\tend\tname\tmean\tmedian")?;
+    for threshold in &options.thresholds {
+        write!(writer, "\tfrac_ge_{}x", threshold)?;
+    }
+    writeln!(writer)?;
+
+    for (region, summary) in regions.iter().zip(summaries) {
+        write!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            region.chrom, region.start, region.end, region.name
+        )?;
+        match summary {
+            Some(summary) => {
+                write!(writer, "\t{:.3}\t{:.1}", summary.mean, summary.median)?;
+                for fraction in summary.above {
+                    write!(writer, "\t{:.4}", fraction)?;
+                }
+            }
+            None => {
+                for _ in 0..2 + options.thresholds.len() {
+                    write!(writer, "\tNA")?;
+                }
+            }
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_of_depths() {
+        let summary = DepthSummary::from_depths(vec![0, 10, 40, 2], &[1, 10, 30]);
+        assert_eq!(summary.mean, 13.0);
+        assert_eq!(summary.median, 6.0);
+        assert_eq!(summary.above, vec![0.75, 0.5, 0.25]);
+
+        let empty = DepthSummary::from_depths(vec![], &[1]);
+        assert_eq!(empty.above, vec![0.0]);
+    }
+
+    #[test]
+    fn unreadable_regions_get_a_row_of_na() {
+        let dir = tempfile::tempdir().unwrap();
+        let bam = dir.path().join("test.bam");
+        std::fs::copy("test/test.bam", &bam).unwrap();
+        crate::sort::index_bam(&bam, 1).unwrap();
+        let regions = dir.path().join("regions.bed");
+        std::fs::write(&regions, "chr1\t0\t1000\tknown\nchrNone\t0\t10\tmissing\n").unwrap();
+        let output = dir.path().join("depth.tsv");
+
+        region_depth(&bam, &regions, &output, &RegionDepthOptions::default()).unwrap();
+        let text = std::fs::read_to_string(&output).unwrap();
+        let rows: Vec<&str> = text.lines().collect();
+        assert_eq!(rows.len(), 3);
+        assert!(rows[1].starts_with("chr1\t0\t1000\tknown\t"));
+        assert_eq!(rows[2], "chrNone\t0\t10\tmissing\tNA\tNA\tNA\tNA\tNA");
+    }
+}
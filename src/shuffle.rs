@@ -0,0 +1,244 @@
+//! Shuffled control BAMs.
+//!
+//! Each fragment is moved to a uniformly random position on its own chromosome, keeping
+//! its length (so the fragment length distribution is preserved) and optionally avoiding
+//! blacklisted regions. Both mates of a pair are moved by the same offset. The random
+//! number generator is seeded so that controls are reproducible.
+
+use anyhow::{Context, Result};
+use bio::io::bed;
+use log::info;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{Format, Header, HeaderView, Read, Reader, Writer};
+use rust_lapper::{Interval, Lapper};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::provenance;
+use crate::signal;
+
+type Iv = Interval<u64, u64>;
+
+/// Placement attempts before a fragment that keeps landing in the blacklist is dropped.
+const MAX_ATTEMPTS: usize = 100;
+
+#[derive(Debug, Clone, Default)]
+pub struct ShuffleOptions {
+    pub seed: u64,
+    pub min_mapq: u8,
+    pub threads: usize,
+}
+
+/// Blacklisted intervals per reference id.
+pub fn read_blacklist(path: &Path, header: &HeaderView) -> Result<HashMap<u32, Lapper<u64, u64>>> {
+    let mut intervals: HashMap<u32, Vec<Iv>> = HashMap::new();
+    let mut reader = bed::Reader::from_file(path)?;
+    for record in reader.records() {
+        let record = record.context("Error reading BED record")?;
+        if let Some(tid) = header.tid(record.chrom().as_bytes()) {
+            intervals.entry(tid).or_default().push(Iv {
+                start: record.start(),
+                stop: record.end(),
+                val: 0,
+            });
+        }
+    }
+    Ok(intervals
+        .into_iter()
+        .map(|(tid, intervals)| (tid, Lapper::new(intervals)))
+        .collect())
+}
+
+/// BAM bin for a record covering `[beg, end)`, as in the SAM specification.
+pub fn reg2bin(beg: i64, end: i64) -> u16 {
+    let end = end.max(beg + 1) - 1;
+    for (shift, offset) in [(14, 4681), (17, 585), (20, 73), (23, 9), (26, 1)] {
+        if beg >> shift == end >> shift {
+            return (offset + (beg >> shift)) as u16;
+        }
+    }
+    0
+}
+
+/// Span of the fragment a record belongs to, if it can be shuffled: mapped primary
+/// alignments, in proper pairs if paired.
+fn fragment_span(record: &Record, min_mapq: u8) -> Option<(i64, i64)> {
+    if record.is_unmapped()
+        || record.is_secondary()
+        || record.is_supplementary()
+        || record.mapq() < min_mapq
+    {
+        return None;
+    }
+
+    if !record.is_paired() {
+        return Some((record.pos(), record.reference_end()));
+    }
+    if !record.is_proper_pair() || record.tid() != record.mtid() || record.insert_size() == 0 {
+        return None;
+    }
+    let start = record.pos().min(record.mpos());
+    Some((start, start + record.insert_size().abs()))
+}
+
+/// A random start for a fragment of `length` on a chromosome of `chrom_length` that does
+/// not overlap the blacklist.
+fn random_start<R: Rng>(
+    rng: &mut R,
+    length: i64,
+    chrom_length: i64,
+    blacklist: Option<&Lapper<u64, u64>>,
+) -> Option<i64> {
+    if length > chrom_length {
+        return None;
+    }
+    for _ in 0..MAX_ATTEMPTS {
+        let start = rng.gen_range(0..=chrom_length - length);
+        let blocked = blacklist
+            .map(|lapper| {
+                lapper
+                    .find(start as u64, (start + length) as u64)
+                    .next()
+                    .is_some()
+            })
+            .unwrap_or(false);
+        if !blocked {
+            return Some(start);
+        }
+    }
+    None
+}
+
+/// Write a shuffled copy of `bam` to `output`.
+///
+/// Records that cannot be shuffled (unmapped, secondary, supplementary, improperly paired
+/// or below `min_mapq`) are left out. The output is unsorted.
+pub fn shuffle_bam<P>(
+    bam: P,
+    output: P,
+    blacklist: Option<P>,
+    options: &ShuffleOptions,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut reader = Reader::from_path(&bam).context("Could not open BAM file")?;
+    let blacklist = match blacklist {
+        Some(path) => read_blacklist(path.as_ref(), reader.header())?,
+        None => HashMap::new(),
+    };
+
+    // The records no longer follow the input sort order
+    let text = String::from_utf8_lossy(reader.header().as_bytes())
+        .replace("SO:coordinate", "SO:unsorted")
+        .replace("SO:queryname", "SO:unsorted");
+    let mut header = Header::from_template(&HeaderView::from_bytes(text.as_bytes()));
+    provenance::add_program_record(
+        &mut header,
+        &format!(
+            "shuffle: randomly re-placed fragments (seed {})",
+            options.seed
+        ),
+    );
+
+    let mut writer = Writer::from_path(&output, &header, Format::Bam)?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+        writer.set_threads(options.threads)?;
+    }
+
+    let chrom_lengths: Vec<i64> = signal::chrom_sizes(reader.header())
+        .into_iter()
+        .map(|(_, length)| length as i64)
+        .collect();
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    // New fragment starts for pairs whose first mate has been written
+    let mut pending: HashMap<Vec<u8>, i64> = HashMap::new();
+    let mut n_written = 0u64;
+    let mut n_dropped = 0u64;
+
+    for result in reader.records() {
+        let mut record = result?;
+        let (start, end) = match fragment_span(&record, options.min_mapq) {
+            Some(span) => span,
+            None => {
+                n_dropped += 1;
+                continue;
+            }
+        };
+
+        let new_start = match pending.remove(record.qname()) {
+            Some(new_start) => new_start,
+            None => {
+                let tid = record.tid() as u32;
+                let chrom_length = chrom_lengths[tid as usize];
+                match random_start(&mut rng, end - start, chrom_length, blacklist.get(&tid)) {
+                    Some(new_start) => {
+                        if record.is_paired() {
+                            pending.insert(record.qname().to_vec(), new_start);
+                        }
+                        new_start
+                    }
+                    None => {
+                        n_dropped += 1;
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let delta = new_start - start;
+        let aligned_length = record.reference_end() - record.pos();
+        record.set_pos(record.pos() + delta);
+        record.set_bin(reg2bin(record.pos(), record.pos() + aligned_length));
+        if record.is_paired() {
+            record.set_mpos(record.mpos() + delta);
+        }
+        writer.write(&record)?;
+        n_written += 1;
+    }
+
+    // Mates of these were dropped or missing from the input
+    if !pending.is_empty() {
+        info!(
+            "{} fragments were written without their mate",
+            pending.len()
+        );
+    }
+    println!("Shuffled {} records ({} dropped)", n_written, n_dropped);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placements_are_seeded_and_avoid_the_blacklist() {
+        let blacklist = Lapper::new(vec![Iv {
+            start: 0,
+            stop: 500,
+            val: 0,
+        }]);
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let starts: Vec<i64> = (0..100)
+            .map(|_| random_start(&mut rng, 50, 1000, Some(&blacklist)).unwrap())
+            .collect();
+        assert!(starts.iter().all(|&s| s >= 500 && s + 50 <= 1000));
+
+        let mut rng = StdRng::seed_from_u64(7);
+        assert_eq!(
+            random_start(&mut rng, 50, 1000, Some(&blacklist)),
+            Some(starts[0])
+        );
+        assert_eq!(random_start(&mut rng, 2000, 1000, None), None);
+
+        assert_eq!(reg2bin(0, 100), 4681);
+        assert_eq!(reg2bin(16_384, 16_500), 4682);
+        assert_eq!(reg2bin(16_000, 17_000), 585);
+    }
+}
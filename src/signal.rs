@@ -0,0 +1,115 @@
+//! Writing genome-wide signal tracks as bedGraph or bigWig.
+
+use anyhow::{bail, Context, Result};
+use rust_htslib::bam::{Read, Reader};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str;
+
+use crate::bigwig::BigWigWriter;
+
+/// Reference names and lengths from a BAM header, in header order.
+pub fn chrom_sizes(header: &rust_htslib::bam::HeaderView) -> Vec<(String, u64)> {
+    (0..header.target_count())
+        .map(|tid| {
+            let name = str::from_utf8(header.tid2name(tid))
+                .expect("Could not convert chrom name to str")
+                .to_owned();
+            let length = header.target_len(tid).unwrap_or(0);
+            (name, length)
+        })
+        .collect()
+}
+
+/// Reference names and lengths from the header of the BAM file at `path`.
+pub fn bam_chrom_sizes<P: AsRef<Path>>(path: P) -> Result<Vec<(String, u64)>> {
+    let reader = Reader::from_path(path).context("Could not open BAM file")?;
+    Ok(chrom_sizes(reader.header()))
+}
+
+/// Reference names and lengths from a UCSC-style `chrom.sizes` file, in file order.
+pub fn read_chrom_sizes<P: AsRef<Path>>(path: P) -> Result<Vec<(String, u64)>> {
+    let path = path.as_ref();
+    let reader = BufReader::new(
+        File::open(path)
+            .with_context(|| format!("Could not open chrom sizes `{}`", path.display()))?,
+    );
+
+    let mut chroms = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next().map(str::parse::<u64>)) {
+            (Some(name), Some(Ok(length))) => chroms.push((name.to_owned(), length)),
+            _ => bail!("Malformed chrom sizes line: {}", line),
+        }
+    }
+    Ok(chroms)
+}
+
+pub enum TrackWriter {
+    BedGraph(BufWriter<File>),
+    BigWig(Box<BigWigWriter>),
+}
+
+impl TrackWriter {
+    /// Open a track for writing. Paths ending in `.bw`/`.bigwig` are written as bigWig,
+    /// anything else as bedGraph.
+    pub fn create<P: AsRef<Path>>(path: P, chroms: Vec<(String, u64)>) -> Result<Self> {
+        let path = path.as_ref();
+        let is_bigwig = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| matches!(ext.to_lowercase().as_str(), "bw" | "bigwig"))
+            .unwrap_or(false);
+
+        if is_bigwig {
+            Ok(Self::BigWig(Box::new(BigWigWriter::create(path, chroms)?)))
+        } else {
+            let file = File::create(path)
+                .with_context(|| format!("Could not create bedGraph `{}`", path.display()))?;
+            Ok(Self::BedGraph(BufWriter::new(file)))
+        }
+    }
+
+    /// Write a single interval.
+    pub fn write(&mut self, chrom: &str, start: u64, end: u64, value: f32) -> Result<()> {
+        match self {
+            Self::BedGraph(writer) => {
+                writeln!(writer, "{}\t{}\t{}\t{}", chrom, start, end, value)?;
+            }
+            Self::BigWig(writer) => writer.add(chrom, start, end, value)?,
+        }
+        Ok(())
+    }
+
+    /// Write per-base `values` starting at `offset`, merging runs of equal values into a
+    /// single interval.
+    pub fn write_values(&mut self, chrom: &str, offset: u64, values: &[f32]) -> Result<()> {
+        let mut run_start = 0;
+        for i in 1..=values.len() {
+            if i == values.len() || values[i] != values[run_start] {
+                self.write(
+                    chrom,
+                    offset + run_start as u64,
+                    offset + i as u64,
+                    values[run_start],
+                )?;
+                run_start = i;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self {
+            Self::BedGraph(mut writer) => writer.flush()?,
+            Self::BigWig(writer) => writer.finish()?,
+        }
+        Ok(())
+    }
+}
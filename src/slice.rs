@@ -0,0 +1,202 @@
+//! Extract the reads overlapping a set of regions from an indexed BAM.
+//!
+//! With mate fetching enabled, a second pass uses the index to pull in the mates of
+//! sliced reads that map outside the regions, so the sliced BAM stays pair-complete
+//! (e.g. for realignment or fragment-level tools).
+
+use anyhow::{Context, Result};
+use bio::io::bed;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{Format, Header, HeaderView, IndexedReader, Read, Writer};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::provenance;
+
+#[derive(Debug, Clone)]
+pub struct SliceOptions {
+    /// Also fetch mates mapping outside the regions
+    pub fetch_mates: bool,
+    pub threads: usize,
+}
+
+impl Default for SliceOptions {
+    fn default() -> Self {
+        Self {
+            fetch_mates: false,
+            threads: 1,
+        }
+    }
+}
+
+/// Sorted, merged (tid, start, end) regions from a BED file. Unknown chromosomes are skipped.
+pub fn read_regions(path: &Path, header: &HeaderView) -> Result<Vec<(u32, i64, i64)>> {
+    let mut regions = Vec::new();
+    let mut reader = bed::Reader::from_file(path)?;
+    for record in reader.records() {
+        let record = record.context("Error reading BED record")?;
+        if let Some(tid) = header.tid(record.chrom().as_bytes()) {
+            regions.push((tid, record.start() as i64, record.end() as i64));
+        }
+    }
+    regions.sort_unstable();
+
+    let mut merged: Vec<(u32, i64, i64)> = Vec::new();
+    for region in regions {
+        match merged.last_mut() {
+            Some(last) if last.0 == region.0 && region.1 <= last.2 => last.2 = last.2.max(region.2),
+            _ => merged.push(region),
+        }
+    }
+    Ok(merged)
+}
+
+/// Identifies one segment of a template: (read name, is first segment).
+type SegmentKey = (Vec<u8>, bool);
+
+fn segment_key(record: &Record) -> SegmentKey {
+    (record.qname().to_vec(), record.is_first_in_template())
+}
+
+/// Mates of primary records that are not in `records`, grouped by (mate tid, mate position).
+fn missing_mates(records: &[Record]) -> HashMap<(i32, i64), HashSet<SegmentKey>> {
+    let present: HashSet<SegmentKey> = records
+        .iter()
+        .filter(|r| !r.is_secondary() && !r.is_supplementary())
+        .map(segment_key)
+        .collect();
+
+    let mut missing: HashMap<(i32, i64), HashSet<SegmentKey>> = HashMap::new();
+    for record in records {
+        if !record.is_paired()
+            || record.is_mate_unmapped()
+            || record.is_secondary()
+            || record.is_supplementary()
+            || record.mtid() < 0
+        {
+            continue;
+        }
+        let mate = (record.qname().to_vec(), !record.is_first_in_template());
+        if !present.contains(&mate) {
+            missing
+                .entry((record.mtid(), record.mpos()))
+                .or_default()
+                .insert(mate);
+        }
+    }
+    missing
+}
+
+/// Write the records of `bam` overlapping the regions in `regions` (BED) to `output`,
+/// coordinate sorted.
+pub fn slice_bam<P>(bam: P, regions: P, output: P, options: &SliceOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut reader = IndexedReader::from_path(&bam).context("Could not open indexed BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+    let header_view = reader.header().clone();
+    let regions = read_regions(regions.as_ref(), &header_view)?;
+
+    let mut records = Vec::new();
+    let mut previous: Option<(u32, i64)> = None;
+    for &(tid, start, end) in &regions {
+        reader.fetch((tid, start, end))?;
+        for result in reader.records() {
+            let record = result?;
+            // Reads spanning two regions were already taken from the previous one
+            if let Some((previous_tid, previous_end)) = previous {
+                if previous_tid == tid && record.pos() < previous_end {
+                    continue;
+                }
+            }
+            records.push(record);
+        }
+        previous = Some((tid, end));
+    }
+    let n_sliced = records.len();
+
+    if options.fetch_mates {
+        let mut missing: Vec<_> = missing_mates(&records).into_iter().collect();
+        missing.sort_unstable_by_key(|(position, _)| *position);
+        for ((tid, pos), mut wanted) in missing {
+            reader.fetch((tid, pos, pos + 1))?;
+            for result in reader.records() {
+                let record = result?;
+                if record.pos() != pos || record.is_secondary() || record.is_supplementary() {
+                    continue;
+                }
+                if wanted.remove(&segment_key(&record)) {
+                    records.push(record);
+                }
+            }
+        }
+    }
+    let n_mates = records.len() - n_sliced;
+
+    // Unplaced records (tid -1) sort last
+    records.sort_by_key(|r| (r.tid() as u32, r.pos()));
+
+    let mut header = Header::from_template(&header_view);
+    provenance::add_program_record(
+        &mut header,
+        match options.fetch_mates {
+            true => "slice: reads overlapping regions, with mates",
+            false => "slice: reads overlapping regions",
+        },
+    );
+    let mut writer = Writer::from_path(output, &header, Format::Bam)?;
+    if options.threads > 1 {
+        writer.set_threads(options.threads)?;
+    }
+    for record in &records {
+        writer.write(record)?;
+    }
+
+    println!(
+        "Wrote {} reads from {} regions and {} rescued mates",
+        n_sliced,
+        regions.len(),
+        n_mates
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paired_record(qname: &[u8], first: bool, mtid: i32, mpos: i64) -> Record {
+        let mut record = Record::new();
+        record.set(qname, None, b"ACGT", b"IIII");
+        record.unset_unmapped();
+        record.set_paired();
+        match first {
+            true => record.set_first_in_template(),
+            false => record.set_last_in_template(),
+        }
+        record.set_mtid(mtid);
+        record.set_mpos(mpos);
+        record
+    }
+
+    #[test]
+    fn finds_mates_outside_the_slice() {
+        let records = vec![
+            // Both mates sliced
+            paired_record(b"a", true, 0, 100),
+            paired_record(b"a", false, 0, 50),
+            // Mate elsewhere
+            paired_record(b"b", true, 1, 5000),
+            paired_record(b"c", false, 1, 5000),
+        ];
+        let missing = missing_mates(&records);
+        assert_eq!(missing.len(), 1);
+        let wanted = &missing[&(1, 5000)];
+        assert_eq!(wanted.len(), 2);
+        assert!(wanted.contains(&(b"b".to_vec(), false)));
+        assert!(wanted.contains(&(b"c".to_vec(), true)));
+    }
+}
@@ -0,0 +1,189 @@
+//! External coordinate sorting and indexing of BAM output.
+//!
+//! Records are sorted in memory in chunks; once a chunk fills it is spilled to a temporary
+//! BAM, and the spills are k-way merged at the end, so outputs larger than memory can be
+//! sorted. Ties are kept in the order the records were pushed.
+
+use anyhow::{Context, Result};
+use rust_htslib::bam::index::{self, Type};
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{CompressionLevel, Format, Header, HeaderView, Read, Reader, Writer};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Records held in memory before spilling a sorted chunk to disk.
+pub const DEFAULT_CHUNK_SIZE: usize = 500_000;
+
+/// Coordinate order of a record; unplaced reads (tid -1) sort last.
+fn sort_key(record: &Record) -> (u32, i64) {
+    (record.tid() as u32, record.pos())
+}
+
+/// `header` with its `@HD` sort order set to `order`, adding an `@HD` line if needed.
+pub fn with_sort_order(header: &HeaderView, order: &str) -> HeaderView {
+    let text = String::from_utf8_lossy(header.as_bytes());
+    let mut lines: Vec<String> = text.lines().map(|line| line.to_owned()).collect();
+    match lines.first_mut() {
+        Some(hd) if hd.starts_with("@HD") => {
+            let mut fields: Vec<String> = hd
+                .split('\t')
+                .filter(|field| !field.starts_with("SO:"))
+                .map(|field| field.to_owned())
+                .collect();
+            fields.push(format!("SO:{}", order));
+            *hd = fields.join("\t");
+        }
+        _ => lines.insert(0, format!("@HD\tVN:1.6\tSO:{}", order)),
+    }
+    HeaderView::from_bytes(format!("{}\n", lines.join("\n")).as_bytes())
+}
+
+/// Coordinate sorts records pushed in any order, spilling to temporary BAMs.
+pub struct ExternalSorter {
+    header: Header,
+    chunk: Vec<Record>,
+    chunk_size: usize,
+    spills: Vec<PathBuf>,
+    dir: TempDir,
+}
+
+impl ExternalSorter {
+    pub fn new(header: &Header, chunk_size: usize) -> Result<Self> {
+        Ok(Self {
+            header: header.clone(),
+            chunk: Vec::with_capacity(chunk_size),
+            chunk_size: chunk_size.max(1),
+            spills: Vec::new(),
+            dir: tempfile::tempdir().context("Could not create a directory for sorting")?,
+        })
+    }
+
+    pub fn push(&mut self, record: Record) -> Result<()> {
+        self.chunk.push(record);
+        if self.chunk.len() >= self.chunk_size {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        self.chunk.sort_by_key(sort_key);
+        let path = self
+            .dir
+            .path()
+            .join(format!("chunk_{}.bam", self.spills.len()));
+        let mut writer = Writer::from_path(&path, &self.header, Format::Bam)?;
+        writer.set_compression_level(CompressionLevel::Fastest)?;
+        for record in self.chunk.drain(..) {
+            writer.write(&record)?;
+        }
+        self.spills.push(path);
+        Ok(())
+    }
+
+    /// Write every pushed record to `writer` in coordinate order.
+    pub fn finish(mut self, writer: &mut Writer) -> Result<()> {
+        if self.spills.is_empty() {
+            self.chunk.sort_by_key(sort_key);
+            for record in &self.chunk {
+                writer.write(record)?;
+            }
+            return Ok(());
+        }
+        if !self.chunk.is_empty() {
+            self.spill()?;
+        }
+
+        let mut readers = Vec::with_capacity(self.spills.len());
+        let mut current = Vec::with_capacity(self.spills.len());
+        let mut heap = BinaryHeap::new();
+        for (i, path) in self.spills.iter().enumerate() {
+            let mut reader = Reader::from_path(path)?;
+            let mut record = Record::new();
+            if let Some(result) = reader.read(&mut record) {
+                result?;
+                heap.push(Reverse((sort_key(&record), i)));
+            }
+            readers.push(reader);
+            current.push(record);
+        }
+        while let Some(Reverse((_, i))) = heap.pop() {
+            writer.write(&current[i])?;
+            if let Some(result) = readers[i].read(&mut current[i]) {
+                result?;
+                heap.push(Reverse((sort_key(&current[i]), i)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Index the coordinate-sorted `bam`, as CSI if any reference is too long for BAI.
+pub fn index_bam<P: AsRef<Path>>(bam: P, threads: usize) -> Result<()> {
+    let reader = Reader::from_path(&bam)?;
+    let header = reader.header();
+    let longest = (0..header.target_count())
+        .filter_map(|tid| header.target_len(tid))
+        .max()
+        .unwrap_or(0);
+    let index_type = match longest >= 1 << 29 {
+        true => Type::Csi(14),
+        false => Type::Bai,
+    };
+    index::build(bam.as_ref(), None, index_type, threads as u32)
+        .with_context(|| format!("Could not index `{}`", bam.as_ref().display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::header::HeaderRecord;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    #[test]
+    fn sorts_across_spilled_chunks() {
+        let mut header = Header::new();
+        for (name, length) in [("chr1", "1000"), ("chr2", "1000")] {
+            let mut sq = HeaderRecord::new(b"SQ");
+            sq.push_tag(b"SN", name).push_tag(b"LN", length);
+            header.push_record(&sq);
+        }
+        let view = with_sort_order(&HeaderView::from_header(&header), "coordinate");
+        let text = String::from_utf8_lossy(view.as_bytes()).into_owned();
+        assert!(text.starts_with("@HD\tVN:1.6\tSO:coordinate"));
+
+        let mut sorter = ExternalSorter::new(&header, 2).unwrap();
+        let cigar = CigarString(vec![Cigar::Match(10)]);
+        let placements = [(1, 50), (0, 300), (-1, -1), (0, 20), (1, 10)];
+        for (i, &(tid, pos)) in placements.iter().enumerate() {
+            let mut record = Record::new();
+            record.set(
+                format!("r{}", i).as_bytes(),
+                Some(&cigar),
+                &[b'A'; 10],
+                &[30; 10],
+            );
+            record.set_tid(tid);
+            record.set_pos(pos);
+            sorter.push(record).unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sorted.bam");
+        {
+            let mut writer = Writer::from_path(&path, &header, Format::Bam).unwrap();
+            sorter.finish(&mut writer).unwrap();
+        }
+        index_bam(&path, 1).unwrap();
+        assert!(dir.path().join("sorted.bam.bai").exists());
+
+        let mut reader = Reader::from_path(&path).unwrap();
+        let order: Vec<(i32, i64)> = reader
+            .records()
+            .map(|r| r.map(|r| (r.tid(), r.pos())).unwrap())
+            .collect();
+        assert_eq!(order, vec![(0, 20), (0, 300), (1, 10), (1, 50), (-1, -1)]);
+    }
+}
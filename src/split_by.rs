@@ -0,0 +1,563 @@
+//! Split a BAM into one file per group of reads: per reference sequence, for
+//! per-chromosome processing, per read group, to demultiplex merged runs, or per group
+//! of cell barcodes (e.g. clusters of single-cell ATAC data).
+//!
+//! Each output's header keeps only its own @SQ (or @RG) line. Split by chromosome,
+//! records are renumbered to reference 0, and mates on another chromosome are not in that
+//! header so become unplaced (RNEXT `*`). Reads without a position or read group are
+//! written to a separate `unplaced` or `no_read_group` output; reads whose barcode is not
+//! in any group are dropped.
+//!
+//! Each output is a BGZF writer with its own buffers, so split by chromosome or barcode
+//! groups at most `max_open_files` are open at once and more groups take further passes
+//! over the input.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use rust_htslib::bam::record::{Aux, Record};
+use rust_htslib::bam::{CompressionLevel, Format, Header, HeaderView, Read, Reader, Writer};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::provenance;
+
+/// What records are grouped by, one output per group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SplitBy {
+    /// One output per reference sequence
+    Chromosome,
+    /// One output per read group (RG tag)
+    ReadGroup,
+    /// One output per group of cell barcodes (CB tag), from a barcode to group table
+    Tag,
+}
+
+impl SplitBy {
+    /// Header record type and the tag naming each group in it, for groups with one.
+    fn header_record(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            SplitBy::Chromosome => Some(("@SQ", "SN")),
+            SplitBy::ReadGroup => Some(("@RG", "ID")),
+            SplitBy::Tag => None,
+        }
+    }
+
+    /// File name part for reads not in any group.
+    fn ungrouped(&self) -> &'static str {
+        match self {
+            SplitBy::Chromosome => "unplaced",
+            SplitBy::ReadGroup => "no_read_group",
+            SplitBy::Tag => "no_group",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SplitByOptions {
+    pub by: SplitBy,
+    /// Cell barcode tag, for `SplitBy::Tag`
+    pub tag: String,
+    /// Barcode to group table, for `SplitBy::Tag`
+    pub groups: Option<PathBuf>,
+    /// Most outputs open at once when splitting by chromosome or barcode groups
+    pub max_open_files: usize,
+    /// Compression level of the outputs, from 0 (uncompressed) to 9
+    pub compression_level: Option<u8>,
+    pub threads: usize,
+}
+
+impl Default for SplitByOptions {
+    fn default() -> Self {
+        Self {
+            by: SplitBy::Chromosome,
+            tag: "CB".to_string(),
+            groups: None,
+            max_open_files: 256,
+            compression_level: None,
+            threads: 1,
+        }
+    }
+}
+
+/// Read a table of barcodes and their groups, one tab-separated pair per line.
+pub fn read_barcode_groups(path: &Path) -> Result<HashMap<String, String>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read `{}`", path.display()))?;
+    let mut groups: HashMap<String, String> = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut fields = line.split('\t').map(str::trim);
+        let (barcode, group) = match (fields.next(), fields.next()) {
+            (Some(barcode), Some(group)) if !barcode.is_empty() && !group.is_empty() => {
+                (barcode, group)
+            }
+            _ => bail!(
+                "Line {} of `{}` is not a barcode and group separated by a tab",
+                i + 1,
+                path.display()
+            ),
+        };
+        if let Some(other) = groups.insert(barcode.to_string(), group.to_string()) {
+            if other != group {
+                bail!(
+                    "Barcode {} is in both group {} and {}",
+                    barcode,
+                    other,
+                    group
+                );
+            }
+        }
+    }
+    Ok(groups)
+}
+
+/// How records are assigned to groups.
+struct Grouping {
+    by: SplitBy,
+    /// Reference sequence names, by tid
+    names: Vec<String>,
+    tag: String,
+    /// Group of each cell barcode
+    barcodes: HashMap<String, String>,
+}
+
+impl Grouping {
+    /// The group `record` belongs to, None for unplaced reads, reads without a read group
+    /// or reads whose barcode is not in any group.
+    fn group_of(&self, record: &Record) -> Option<String> {
+        match self.by {
+            SplitBy::Chromosome => {
+                (record.tid() >= 0).then(|| self.names[record.tid() as usize].clone())
+            }
+            SplitBy::ReadGroup => match record.aux(b"RG") {
+                Ok(Aux::String(read_group)) => Some(read_group.to_string()),
+                _ => None,
+            },
+            SplitBy::Tag => match record.aux(self.tag.as_bytes()) {
+                Ok(Aux::String(barcode)) => self.barcodes.get(barcode).cloned(),
+                _ => None,
+            },
+        }
+    }
+
+    fn describe(&self, group: Option<&str>) -> String {
+        match (self.by, group) {
+            (SplitBy::Chromosome, Some(name)) => format!("split-by: reads on {}", name),
+            (SplitBy::Chromosome, None) => "split-by: unplaced reads".to_string(),
+            (SplitBy::ReadGroup, Some(id)) => format!("split-by: reads in read group {}", id),
+            (SplitBy::ReadGroup, None) => "split-by: reads without a read group".to_string(),
+            (SplitBy::Tag, group) => format!(
+                "split-by: reads with {} barcodes in group {}",
+                self.tag,
+                group.unwrap_or("none")
+            ),
+        }
+    }
+}
+
+/// `name` with characters that are awkward in file names replaced by `_`.
+fn file_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.is_ascii_alphanumeric() || "._-".contains(c) {
+            true => c,
+            false => '_',
+        })
+        .collect()
+}
+
+/// Value of `tag` in a tab-separated header line, e.g. `SN` in an @SQ line.
+fn header_field<'a>(line: &'a str, tag: &str) -> Option<&'a str> {
+    line.split('\t')
+        .skip(1)
+        .find_map(|field| field.strip_prefix(tag)?.strip_prefix(':'))
+}
+
+/// `header` with only the lines for which `keep` is true.
+fn filter_header(header: &HeaderView, keep: impl Fn(&str) -> bool) -> Header {
+    let text = String::from_utf8_lossy(header.as_bytes());
+    let text: String = text
+        .lines()
+        .filter(|line| keep(line))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    Header::from_template(&HeaderView::from_bytes(text.as_bytes()))
+}
+
+/// Move a record onto reference 0 of a header holding only its own reference sequence,
+/// clearing the mate position when the mate is elsewhere.
+fn renumber(record: &mut Record) {
+    let tid = record.tid();
+    if record.mtid() >= 0 && record.mtid() != tid {
+        record.set_mtid(-1);
+        record.set_mpos(-1);
+    }
+    if tid >= 0 {
+        record.set_tid(0);
+        if record.mtid() >= 0 {
+            record.set_mtid(0);
+        }
+    }
+}
+
+struct Output {
+    path: PathBuf,
+    writer: Writer,
+    count: u64,
+}
+
+/// One pass over `bam`, writing the records of the `wanted` groups (all groups if None),
+/// where a `None` group is the reads in no group.
+/// Returns the path and record count of each output, and the number of reads in no group.
+fn split_pass(
+    bam: &Path,
+    prefix: &str,
+    grouping: &Grouping,
+    wanted: Option<&HashSet<Option<String>>>,
+    file_names: &mut HashMap<String, Option<String>>,
+    options: &SplitByOptions,
+) -> Result<(Vec<(PathBuf, u64)>, u64)> {
+    let mut reader = Reader::from_path(bam).context("Could not open BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+    let header_view = reader.header().clone();
+    let by = grouping.by;
+
+    // Outputs are opened, in order, when their group's first record is seen
+    let mut outputs: Vec<Output> = Vec::new();
+    let mut groups: HashMap<Option<String>, usize> = HashMap::new();
+    let mut n_ungrouped = 0;
+    let mut record = Record::new();
+    while let Some(result) = reader.read(&mut record) {
+        result.context("Error reading BAM record")?;
+        let group = match (grouping.group_of(&record), by) {
+            (None, SplitBy::Tag) => {
+                n_ungrouped += 1;
+                continue;
+            }
+            (group, _) => group,
+        };
+        if let Some(wanted) = wanted {
+            if !wanted.contains(&group) {
+                continue;
+            }
+        }
+
+        let index = match groups.entry(group) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => {
+                let group = entry.key().as_deref();
+                let file_name = file_safe(group.unwrap_or(by.ungrouped()));
+                if let Some(other) = file_names.insert(file_name.clone(), entry.key().clone()) {
+                    bail!(
+                        "`{}` and `{}` would both be written to {}",
+                        group.unwrap_or(by.ungrouped()),
+                        other.as_deref().unwrap_or(by.ungrouped()),
+                        file_name
+                    );
+                }
+
+                let mut header = match by.header_record() {
+                    Some((record_type, tag)) => filter_header(&header_view, |line| {
+                        !line.starts_with(record_type)
+                            || group.is_some_and(|group| header_field(line, tag) == Some(group))
+                    }),
+                    None => Header::from_template(&header_view),
+                };
+                provenance::add_program_record(&mut header, &grouping.describe(group));
+                let path = PathBuf::from(format!("{}.{}.bam", prefix, file_name));
+                let mut writer = Writer::from_path(&path, &header, Format::Bam)
+                    .with_context(|| format!("Could not create `{}`", path.display()))?;
+                match options.compression_level {
+                    Some(0) => writer.set_compression_level(CompressionLevel::Uncompressed)?,
+                    Some(level) => {
+                        writer.set_compression_level(CompressionLevel::Level(level as u32))?
+                    }
+                    None => {}
+                }
+                outputs.push(Output {
+                    path,
+                    writer,
+                    count: 0,
+                });
+                *entry.insert(outputs.len() - 1)
+            }
+        };
+
+        if by == SplitBy::Chromosome {
+            renumber(&mut record);
+        }
+        let output = &mut outputs[index];
+        output.writer.write(&record)?;
+        output.count += 1;
+    }
+
+    let counts = outputs
+        .into_iter()
+        .map(|output| (output.path, output.count))
+        .collect();
+    Ok((counts, n_ungrouped))
+}
+
+/// Write the records of `bam` to `<output_prefix>.<group>.bam`, one file per group.
+pub fn split_by<P>(bam: P, output_prefix: P, options: &SplitByOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let bam = bam.as_ref();
+    let prefix = output_prefix.as_ref().display().to_string();
+    let names: Vec<String> = Reader::from_path(bam)
+        .context("Could not open BAM file")?
+        .header()
+        .target_names()
+        .iter()
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect();
+
+    let barcodes = match (options.by, &options.groups) {
+        (SplitBy::Tag, _) if options.tag.len() != 2 => {
+            bail!("`{}` is not a two character tag", options.tag)
+        }
+        (SplitBy::Tag, Some(path)) => read_barcode_groups(path)?,
+        (SplitBy::Tag, None) => bail!("Splitting by tag needs a barcode to group table"),
+        _ => HashMap::new(),
+    };
+
+    // Chromosomes, with the unplaced reads last, and barcode groups are written a batch of
+    // at most max_open_files per pass
+    let all_groups: Vec<Option<String>> = match options.by {
+        SplitBy::Chromosome => names.iter().cloned().map(Some).chain([None]).collect(),
+        SplitBy::ReadGroup => Vec::new(),
+        SplitBy::Tag => barcodes
+            .values()
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(Some)
+            .collect(),
+    };
+    let passes: Vec<Option<HashSet<Option<String>>>> = match options.by {
+        SplitBy::ReadGroup => vec![None],
+        _ => all_groups
+            .chunks(options.max_open_files.max(1))
+            .map(|groups| Some(groups.iter().cloned().collect()))
+            .collect(),
+    };
+    if passes.len() > 1 {
+        println!(
+            "Writing {} groups at most {} at a time, in {} passes",
+            all_groups.len(),
+            options.max_open_files,
+            passes.len()
+        );
+    }
+
+    let grouping = Grouping {
+        by: options.by,
+        names,
+        tag: options.tag.clone(),
+        barcodes,
+    };
+    let mut file_names = HashMap::new();
+    let mut n_ungrouped = 0;
+    for wanted in &passes {
+        let (counts, ungrouped) = split_pass(
+            bam,
+            &prefix,
+            &grouping,
+            wanted.as_ref(),
+            &mut file_names,
+            options,
+        )?;
+        for (path, count) in counts {
+            println!("{}\t{}", path.display(), count);
+        }
+        n_ungrouped = ungrouped;
+    }
+    if options.by == SplitBy::Tag {
+        println!(
+            "{} reads without a {} barcode in any group were not written",
+            n_ungrouped, options.tag
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_split_by_chromosome_subsets_headers() {
+    let dir = tempfile::tempdir().expect("Create temp dir");
+    let prefix = dir.path().join("out");
+    split_by(
+        Path::new("test/test.bam"),
+        prefix.as_path(),
+        &SplitByOptions::default(),
+    )
+    .expect("Split");
+
+    let mut n_input = 0;
+    let mut input = Reader::from_path("test/test.bam").expect("Open test.bam");
+    for record in input.records() {
+        record.expect("Read record");
+        n_input += 1;
+    }
+
+    let mut n_output = 0;
+    let chr1 = dir.path().join("out.chr1.bam");
+    assert!(chr1.exists());
+    for entry in std::fs::read_dir(dir.path()).expect("List outputs") {
+        let mut reader = Reader::from_path(entry.expect("Output").path()).expect("Open output");
+        assert!(reader.header().target_count() <= 1);
+        for record in reader.records() {
+            let record = record.expect("Read output record");
+            assert!(record.tid() <= 0 && record.mtid() <= 0);
+            n_output += 1;
+        }
+    }
+    assert_eq!(n_input, n_output);
+}
+
+#[cfg(test)]
+#[test]
+fn test_split_by_chromosome_caps_open_files() {
+    let dir = tempfile::tempdir().expect("Create temp dir");
+    let all_at_once = dir.path().join("all");
+    std::fs::create_dir(&all_at_once).expect("Create output dir");
+    split_by(
+        Path::new("test/test.bam"),
+        all_at_once.join("out").as_path(),
+        &SplitByOptions::default(),
+    )
+    .expect("Split");
+
+    // test.bam has ~200 reference sequences, so a cap of 64 takes four passes
+    let capped = dir.path().join("capped");
+    std::fs::create_dir(&capped).expect("Create output dir");
+    let options = SplitByOptions {
+        max_open_files: 64,
+        ..Default::default()
+    };
+    split_by(
+        Path::new("test/test.bam"),
+        capped.join("out").as_path(),
+        &options,
+    )
+    .expect("Split");
+
+    let counts = |dir: &Path| -> Vec<(std::ffi::OsString, usize)> {
+        let mut counts: Vec<_> = std::fs::read_dir(dir)
+            .expect("List outputs")
+            .map(|entry| {
+                let path = entry.expect("Output").path();
+                let mut reader = Reader::from_path(&path).expect("Open output");
+                (
+                    path.file_name().expect("File name").to_owned(),
+                    reader.records().count(),
+                )
+            })
+            .collect();
+        counts.sort();
+        counts
+    };
+    assert_eq!(counts(&all_at_once), counts(&capped));
+}
+
+/// A one-chromosome BAM with `@RG` lines for `read_groups` and a record per value, with
+/// `tag` set to the value if there is one.
+#[cfg(test)]
+fn write_tagged_bam(path: &Path, read_groups: &[&str], tag: &[u8], values: &[Option<&str>]) {
+    use rust_htslib::bam::header::HeaderRecord;
+
+    let mut header = Header::new();
+    let mut sq = HeaderRecord::new(b"SQ");
+    sq.push_tag(b"SN", "chr1").push_tag(b"LN", 1000);
+    header.push_record(&sq);
+    for id in read_groups {
+        let mut rg = HeaderRecord::new(b"RG");
+        rg.push_tag(b"ID", id).push_tag(b"SM", "sample");
+        header.push_record(&rg);
+    }
+
+    let mut writer = Writer::from_path(path, &header, Format::Bam).expect("Create BAM");
+    for (i, value) in values.iter().enumerate() {
+        let mut record = Record::new();
+        record.set(format!("r{}", i).as_bytes(), None, b"ACGT", b"IIII");
+        record.set_tid(0);
+        record.set_pos(10 * i as i64);
+        if let Some(value) = value {
+            record.push_aux(tag, Aux::String(value)).expect("Add tag");
+        }
+        writer.write(&record).expect("Write record");
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_split_by_read_group_keeps_matching_rg_lines() {
+    let dir = tempfile::tempdir().expect("Create temp dir");
+    let input = dir.path().join("input.bam");
+    write_tagged_bam(
+        &input,
+        &["run1", "run2"],
+        b"RG",
+        &[Some("run1"), Some("run2"), Some("run1"), None],
+    );
+
+    let options = SplitByOptions {
+        by: SplitBy::ReadGroup,
+        ..Default::default()
+    };
+    let prefix = dir.path().join("out");
+    split_by(input.as_path(), prefix.as_path(), &options).expect("Split");
+
+    for (name, n_records, read_groups) in [
+        ("out.run1.bam", 2, vec!["run1"]),
+        ("out.run2.bam", 1, vec!["run2"]),
+        ("out.no_read_group.bam", 1, vec![]),
+    ] {
+        let mut reader = Reader::from_path(dir.path().join(name)).expect("Open output");
+        let text = String::from_utf8_lossy(reader.header().as_bytes()).into_owned();
+        let ids: Vec<&str> = text
+            .lines()
+            .filter(|line| line.starts_with("@RG"))
+            .filter_map(|line| header_field(line, "ID"))
+            .collect();
+        assert_eq!(ids, read_groups);
+        assert_eq!(reader.records().count(), n_records);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_split_by_barcode_groups_over_several_passes() {
+    let dir = tempfile::tempdir().expect("Create temp dir");
+    let input = dir.path().join("input.bam");
+    write_tagged_bam(
+        &input,
+        &[],
+        b"CB",
+        &[Some("AAAC"), Some("CCCT"), Some("GGGA"), Some("TTTT"), None],
+    );
+    let groups = dir.path().join("groups.tsv");
+    std::fs::write(&groups, "AAAC\tcluster1\nGGGA\tcluster1\nCCCT\tcluster2\n")
+        .expect("Write groups");
+
+    // One output open at a time, so each cluster is written in its own pass
+    let options = SplitByOptions {
+        by: SplitBy::Tag,
+        groups: Some(groups),
+        max_open_files: 1,
+        ..Default::default()
+    };
+    let prefix = dir.path().join("out");
+    split_by(input.as_path(), prefix.as_path(), &options).expect("Split");
+
+    for (name, n_records) in [("out.cluster1.bam", 2), ("out.cluster2.bam", 1)] {
+        let mut reader = Reader::from_path(dir.path().join(name)).expect("Open output");
+        assert_eq!(reader.records().count(), n_records);
+    }
+    assert!(!dir.path().join("out.no_group.bam").exists());
+}
@@ -1,43 +1,121 @@
 use ahash::HashMap;
-use anyhow::{Context, Result};
-use bstr::ByteSlice;
-use noodles::bam::io::Writer;
-use noodles::bed::record;
-use noodles::{bam, bgzf, sam};
-use std::fmt::format;
+use anyhow::{bail, Context, Result};
+use bio::alphabets::dna;
+use bstr::BString;
+use flate2::write::GzEncoder;
+use itertools::Itertools;
+use clap::ValueEnum;
+use noodles::{bam, bgzf, cram, fasta, sam};
+use noodles::sam::alignment::io::Write as _;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::prelude::v1::*;
 use serde::{Serialize, Deserialize};
-use indicatif::{ProgressBar, ProgressIterator};
-use sam::header::record::value::{map::ReferenceSequence, Map};
+use indicatif::{ProgressBar, ProgressStyle};
+use regex::bytes::Regex;
+
+use crate::provenance;
+use crate::subtract_regions::AlignmentFormat;
 
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SplitStats {
     filename: String,
+    /// Files the reads were written to, to find a sample's stats from one of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    outputs: Vec<String>,
     n_unmapped_reads: u64,
     n_qcfail_reads: u64,
     n_duplicate_reads: u64,
     n_secondary_reads: u64,
+    n_supplementary_reads: u64,
     n_low_maq: u64,
     n_both_genomes: u64,
     n_exogenous: u64,
     n_endogenous: u64,
+    /// Reads filtered along with their mate, keeping pairs together
+    #[serde(default)]
+    n_mate_filtered: u64,
+    /// Exogenous reads from each genome, when splitting out more than one
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    n_exogenous_by_genome: BTreeMap<String, u64>,
+    /// Endogenous reads on each reference sequence
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    n_endogenous_by_contig: BTreeMap<String, u64>,
+    /// Endogenous reads by input reference sequence ID, until they are named
+    #[serde(skip)]
+    endogenous_by_id: Vec<u64>,
+    /// Primary mapped reads on each side of the split, whatever their route, and how many
+    /// of them are marked as duplicates
+    #[serde(default)]
+    n_endogenous_primary: u64,
+    #[serde(default)]
+    n_endogenous_primary_duplicates: u64,
+    #[serde(default)]
+    n_exogenous_primary: u64,
+    #[serde(default)]
+    n_exogenous_primary_duplicates: u64,
+}
+
+/// Spike-in normalisation factors, from record counts. None without exogenous reads.
+#[derive(Debug, Serialize)]
+pub struct ScaleFactors {
+    /// Endogenous reads per exogenous read
+    endogenous_exogenous_ratio: Option<f64>,
+    /// Fraction of the genome-assigned reads that are exogenous
+    exogenous_fraction: Option<f64>,
+    /// Reads per spike-in million: multiply endogenous coverage by this to normalise
+    spikein_scale_factor: Option<f64>,
+}
+
+/// The stats of one sample as written to JSON.
+#[derive(Serialize)]
+struct StatsReport<'a> {
+    #[serde(flatten)]
+    stats: &'a SplitStats,
+    n_total: u64,
+    percentages: BTreeMap<&'static str, f64>,
+    scale_factors: ScaleFactors,
+    qc: SpikeinQc,
+}
+
+/// Spike-in QC metrics, as asked for when reporting calibrated ChIP or CUT&RUN.
+#[derive(Debug, Serialize)]
+pub struct SpikeinQc {
+    /// Percentage of the genome-assigned reads that are exogenous
+    percent_spikein: Option<f64>,
+    /// Fraction of the primary mapped reads marked as duplicates, in each genome
+    endogenous_duplicate_rate: Option<f64>,
+    exogenous_duplicate_rate: Option<f64>,
+    /// Endogenous reads on each chromosome per exogenous read
+    endogenous_exogenous_ratio_by_contig: BTreeMap<String, f64>,
 }
 
 impl SplitStats{
     fn new(filename: String) -> Self {
         Self {
             filename,
+            outputs: Vec::new(),
             n_unmapped_reads: 0,
             n_qcfail_reads: 0,
             n_duplicate_reads: 0,
             n_secondary_reads: 0,
+            n_supplementary_reads: 0,
             n_low_maq: 0,
             n_both_genomes: 0,
             n_exogenous: 0,
             n_endogenous: 0,
+            n_mate_filtered: 0,
+            n_exogenous_by_genome: BTreeMap::new(),
+            n_endogenous_by_contig: BTreeMap::new(),
+            endogenous_by_id: Vec::new(),
+            n_endogenous_primary: 0,
+            n_endogenous_primary_duplicates: 0,
+            n_exogenous_primary: 0,
+            n_exogenous_primary_duplicates: 0,
         }
     }
 
@@ -57,6 +135,10 @@ impl SplitStats{
         self.n_secondary_reads += 1;
     }
 
+    fn add_supplementary(&mut self) {
+        self.n_supplementary_reads += 1;
+    }
+
     fn add_low_maq(&mut self) {
         self.n_low_maq += 1;
     }
@@ -65,12 +147,63 @@ impl SplitStats{
         self.n_both_genomes += 1;
     }
 
-    fn add_exogenous(&mut self) {
+    fn add_exogenous(&mut self, genome: Option<&str>) {
         self.n_exogenous += 1;
+        if let Some(genome) = genome {
+            *self.n_exogenous_by_genome.entry(genome.to_string()).or_insert(0) += 1;
+        }
     }
 
-    fn add_endogenous(&mut self) {
+    fn add_endogenous(&mut self, reference_id: Option<usize>) {
         self.n_endogenous += 1;
+        if let Some(id) = reference_id {
+            if id >= self.endogenous_by_id.len() {
+                self.endogenous_by_id.resize(id + 1, 0);
+            }
+            self.endogenous_by_id[id] += 1;
+        }
+    }
+
+    /// Count a primary mapped read towards the duplicate rate of its genome.
+    fn add_primary(&mut self, exogenous: bool, duplicate: bool) {
+        let (primary, duplicates) = match exogenous {
+            true => (&mut self.n_exogenous_primary, &mut self.n_exogenous_primary_duplicates),
+            false => (&mut self.n_endogenous_primary, &mut self.n_endogenous_primary_duplicates),
+        };
+        *primary += 1;
+        *duplicates += duplicate as u64;
+    }
+
+    /// Name the per contig endogenous counts, given the input reference sequence names.
+    fn name_contigs<'a>(&mut self, names: impl IntoIterator<Item = &'a [u8]>) {
+        for (name, &count) in names.into_iter().zip(&self.endogenous_by_id) {
+            if count > 0 {
+                self.n_endogenous_by_contig
+                    .insert(String::from_utf8_lossy(name).into_owned(), count);
+            }
+        }
+        self.endogenous_by_id.clear();
+    }
+
+    fn add_mate_filtered(&mut self) {
+        self.n_mate_filtered += 1;
+    }
+
+    /// Count a record, on reference sequence `reference_id`, in the category of its route.
+    /// `genome_labels` break the exogenous count down by genome where given.
+    fn add(&mut self, route: Route, reference_id: Option<usize>, genome_labels: &[Option<String>]) {
+        match route {
+            Route::Unmapped => self.add_unmapped(),
+            Route::QcFail => self.add_qcfail(),
+            Route::Duplicate => self.add_duplicate(),
+            Route::Secondary => self.add_secondary(),
+            Route::Supplementary => self.add_supplementary(),
+            Route::LowMapq => self.add_low_maq(),
+            Route::MateFiltered => self.add_mate_filtered(),
+            Route::BothGenomes => self.add_both_genomes(),
+            Route::Genome(0) => self.add_endogenous(reference_id),
+            Route::Genome(genome) => self.add_exogenous(genome_labels[genome - 1].as_deref()),
+        }
     }
 
     pub fn print(&self) {
@@ -79,81 +212,1369 @@ impl SplitStats{
         println!("QC fail reads: {}", self.n_qcfail_reads);
         println!("Duplicate reads: {}", self.n_duplicate_reads);
         println!("Secondary reads: {}", self.n_secondary_reads);
+        println!("Supplementary reads: {}", self.n_supplementary_reads);
         println!("Low mapping quality reads: {}", self.n_low_maq);
         println!("Both genomes reads: {}", self.n_both_genomes);
         println!("Exogenous reads: {}", self.n_exogenous);
+        for (genome, count) in &self.n_exogenous_by_genome {
+            println!("  {}: {}", genome, count);
+        }
         println!("Endogenous reads: {}", self.n_endogenous);
+        println!("Reads filtered with their mate: {}", self.n_mate_filtered);
+
+        let factors = self.scale_factors();
+        let show = |factor: Option<f64>| factor.map_or("NA".to_string(), |f| format!("{:.6}", f));
+        println!(
+            "Endogenous/exogenous ratio: {}",
+            show(factors.endogenous_exogenous_ratio)
+        );
+        println!("Exogenous fraction: {}", show(factors.exogenous_fraction));
+        println!(
+            "Spike-in scale factor (per million exogenous reads): {}",
+            show(factors.spikein_scale_factor)
+        );
+
+        let qc = self.qc();
+        println!("Spike-in: {}%", show(qc.percent_spikein));
+        println!(
+            "Duplicate rate (endogenous, exogenous): {}, {}",
+            show(qc.endogenous_duplicate_rate),
+            show(qc.exogenous_duplicate_rate)
+        );
+    }
+
+    /// QC metrics for the spike-in beyond the raw counts.
+    pub fn qc(&self) -> SpikeinQc {
+        let rate = |count: u64, total: u64| (total > 0).then(|| count as f64 / total as f64);
+        SpikeinQc {
+            percent_spikein: self.scale_factors().exogenous_fraction.map(|f| 100.0 * f),
+            endogenous_duplicate_rate: rate(
+                self.n_endogenous_primary_duplicates,
+                self.n_endogenous_primary,
+            ),
+            exogenous_duplicate_rate: rate(
+                self.n_exogenous_primary_duplicates,
+                self.n_exogenous_primary,
+            ),
+            endogenous_exogenous_ratio_by_contig: self
+                .n_endogenous_by_contig
+                .iter()
+                .filter_map(|(contig, &count)| {
+                    rate(count, self.n_exogenous).map(|ratio| (contig.clone(), ratio))
+                })
+                .collect(),
+        }
+    }
+
+    /// A warning if the exogenous fraction is outside `min..=max`, e.g. from too little
+    /// or too much spike-in added to the sample.
+    pub fn spikein_fraction_warning(&self, min: f64, max: f64) -> Option<String> {
+        let fraction = self.scale_factors().exogenous_fraction.unwrap_or(0.0);
+        (!(min..=max).contains(&fraction)).then(|| {
+            format!(
+                "{}: spike-in fraction {:.4} is outside the expected range {} to {}",
+                self.filename, fraction, min, max
+            )
+        })
+    }
+
+    /// Normalisation factors for the spike-in, as used to scale endogenous coverage
+    /// (e.g. bigWig --scale-factor).
+    pub fn scale_factors(&self) -> ScaleFactors {
+        let exogenous = self.n_exogenous as f64;
+        let endogenous = self.n_endogenous as f64;
+        let per_exogenous = |value: f64| (self.n_exogenous > 0).then(|| value / exogenous);
+        ScaleFactors {
+            endogenous_exogenous_ratio: per_exogenous(endogenous),
+            exogenous_fraction: (self.n_exogenous > 0).then(|| exogenous / (exogenous + endogenous)),
+            spikein_scale_factor: per_exogenous(1e6),
+        }
+    }
+
+    /// Reads in each category; every input record falls in exactly one.
+    fn categories(&self) -> [(&'static str, u64); 10] {
+        [
+            ("unmapped", self.n_unmapped_reads),
+            ("qcfail", self.n_qcfail_reads),
+            ("duplicate", self.n_duplicate_reads),
+            ("secondary", self.n_secondary_reads),
+            ("supplementary", self.n_supplementary_reads),
+            ("low_mapq", self.n_low_maq),
+            ("both_genomes", self.n_both_genomes),
+            ("exogenous", self.n_exogenous),
+            ("endogenous", self.n_endogenous),
+            ("mate_filtered", self.n_mate_filtered),
+        ]
+    }
+
+    fn total(&self) -> u64 {
+        self.n_unmapped_reads
+            + self.n_qcfail_reads
+            + self.n_duplicate_reads
+            + self.n_secondary_reads
+            + self.n_supplementary_reads
+            + self.n_low_maq
+            + self.n_both_genomes
+            + self.n_exogenous
+            + self.n_endogenous
+            + self.n_mate_filtered
+    }
+
+    /// Write the counts and their percentage of all reads, with the scale factors, as JSON
+    /// for a `.json` path and TSV (one row per metric) otherwise.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        Self::write_all(std::slice::from_ref(self), path)
+    }
+
+    /// Write the stats of several samples to one table (or a JSON array), to compare
+    /// them across an experiment.
+    pub fn write_all(stats: &[SplitStats], path: &Path) -> Result<()> {
+        let mut file = std::io::BufWriter::new(
+            std::fs::File::create(path)
+                .with_context(|| format!("Could not create `{}`", path.display()))?,
+        );
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => {
+                let reports: Vec<StatsReport> = stats.iter().map(SplitStats::report).collect();
+                match reports.as_slice() {
+                    [report] => serde_json::to_writer_pretty(&mut file, report)?,
+                    _ => serde_json::to_writer_pretty(&mut file, &reports)?,
+                }
+            }
+            _ => {
+                writeln!(file, "filename\tmetric\tvalue\tpercentage")?;
+                for stats in stats {
+                    stats.write_rows(&mut file)?;
+                }
+            }
+        }
+        file.flush()?;
+        Ok(())
     }
 
+    fn percentage(&self, count: u64) -> f64 {
+        100.0 * count as f64 / self.total().max(1) as f64
+    }
+
+    fn report(&self) -> StatsReport<'_> {
+        StatsReport {
+            stats: self,
+            n_total: self.total(),
+            scale_factors: self.scale_factors(),
+            qc: self.qc(),
+            percentages: self
+                .categories()
+                .iter()
+                .map(|&(category, count)| (category, self.percentage(count)))
+                .collect(),
+        }
+    }
+
+    /// TSV rows of the counts, scale factors and QC metrics.
+    fn write_rows<W: Write>(&self, file: &mut W) -> Result<()> {
+        let total_row = ("total", self.total());
+        let by_genome = self
+            .n_exogenous_by_genome
+            .iter()
+            .map(|(genome, &count)| (format!("exogenous_{}", genome), count));
+        let rows = self
+            .categories()
+            .into_iter()
+            .chain([total_row])
+            .map(|(category, count)| (category.to_string(), count))
+            .chain(by_genome);
+        for (category, count) in rows {
+            writeln!(
+                file,
+                "{}\t{}\t{}\t{:.4}",
+                self.filename,
+                category,
+                count,
+                self.percentage(count)
+            )?;
+        }
+        let factors = self.scale_factors();
+        for (name, factor) in [
+            ("endogenous_exogenous_ratio", factors.endogenous_exogenous_ratio),
+            ("exogenous_fraction", factors.exogenous_fraction),
+            ("spikein_scale_factor", factors.spikein_scale_factor),
+        ] {
+            let value = factor.map_or("NA".to_string(), |f| f.to_string());
+            writeln!(file, "{}\t{}\t{}\tNA", self.filename, name, value)?;
+        }
+        let qc = self.qc();
+        let by_contig = qc
+            .endogenous_exogenous_ratio_by_contig
+            .iter()
+            .map(|(contig, &ratio)| {
+                (format!("endogenous_exogenous_ratio_{}", contig), Some(ratio))
+            });
+        let rows = [
+            ("percent_spikein".to_string(), qc.percent_spikein),
+            ("endogenous_duplicate_rate".to_string(), qc.endogenous_duplicate_rate),
+            ("exogenous_duplicate_rate".to_string(), qc.exogenous_duplicate_rate),
+        ]
+        .into_iter()
+        .chain(by_contig);
+        for (name, metric) in rows {
+            let value = metric.map_or("NA".to_string(), |f| f.to_string());
+            writeln!(file, "{}\t{}\t{}\tNA", self.filename, name, value)?;
+        }
+        Ok(())
+    }
+
+    /// Print the spike-in of each sample side by side.
+    pub fn print_comparison(stats: &[SplitStats]) {
+        println!("filename\tendogenous\texogenous\tpercent_spikein\tspikein_scale_factor");
+        for stats in stats {
+            let show = |value: Option<f64>| value.map_or("NA".to_string(), |v| format!("{:.4}", v));
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                stats.filename,
+                stats.n_endogenous,
+                stats.n_exogenous,
+                show(stats.qc().percent_spikein),
+                show(stats.scale_factors().spikein_scale_factor)
+            );
+        }
+    }
+
+}
+
+
+/// Which reference sequences belong to the exogenous (spike-in) genome.
+#[derive(Debug, Clone)]
+pub enum ExogenousContigs {
+    /// Names starting with this, e.g. `dm6_` for dm6_chr2L
+    Prefix(String),
+    /// Names ending with this, e.g. `_dm6` for chr2L_dm6
+    Suffix(String),
+    /// Names containing a match for this pattern
+    Regex(Regex),
+    /// Names in a list, for references whose contigs were not renamed. The label names
+    /// the genome's outputs.
+    Listed {
+        label: String,
+        names: ahash::HashSet<Vec<u8>>,
+    },
+}
+
+impl ExogenousContigs {
+    /// Contigs listed one per line in `path` (a chrom.sizes file works too), labelled by
+    /// the file name without its extension.
+    pub fn from_list(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read `{}`", path.display()))?;
+        let names: ahash::HashSet<Vec<u8>> = text
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .map(|name| name.as_bytes().to_vec())
+            .collect();
+        if names.is_empty() {
+            bail!("No contig names in `{}`", path.display());
+        }
+        let label = path
+            .file_stem()
+            .map_or("exogenous".into(), |stem| stem.to_string_lossy());
+        Ok(ExogenousContigs::Listed {
+            label: label.into_owned(),
+            names,
+        })
+    }
+
+    pub fn matches(&self, name: &[u8]) -> bool {
+        match self {
+            ExogenousContigs::Prefix(prefix) => name.starts_with(prefix.as_bytes()),
+            ExogenousContigs::Suffix(suffix) => name.ends_with(suffix.as_bytes()),
+            ExogenousContigs::Regex(regex) => regex.is_match(name),
+            ExogenousContigs::Listed { names, .. } => names.contains(name),
+        }
+    }
+
+    /// The name with the prefix, suffix or first pattern match removed. Listed names are
+    /// kept as they are.
+    pub fn strip(&self, name: &[u8]) -> Vec<u8> {
+        match self {
+            ExogenousContigs::Prefix(prefix) => {
+                name.strip_prefix(prefix.as_bytes()).unwrap_or(name).to_vec()
+            }
+            ExogenousContigs::Suffix(suffix) => {
+                name.strip_suffix(suffix.as_bytes()).unwrap_or(name).to_vec()
+            }
+            ExogenousContigs::Regex(regex) => regex.replace(name, &b""[..]).into_owned(),
+            ExogenousContigs::Listed { .. } => name.to_vec(),
+        }
+    }
+
+    /// Short name for the genome, used for its output file and stats: the prefix or
+    /// suffix without separators, or the pattern itself.
+    pub fn label(&self) -> String {
+        let separators: &[char] = &['_', '.', '-'];
+        match self {
+            ExogenousContigs::Prefix(prefix) => prefix.trim_matches(separators).to_string(),
+            ExogenousContigs::Suffix(suffix) => suffix.trim_matches(separators).to_string(),
+            ExogenousContigs::Regex(regex) => regex.as_str().to_string(),
+            ExogenousContigs::Listed { label, .. } => label.clone(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            ExogenousContigs::Prefix(prefix) => format!("the {} prefix", prefix),
+            ExogenousContigs::Suffix(suffix) => format!("the {} suffix", suffix),
+            ExogenousContigs::Regex(regex) => format!("matches of /{}/", regex.as_str()),
+            ExogenousContigs::Listed { label, names } => {
+                format!("the {} list of {} contigs", label, names.len())
+            }
+        }
+    }
 }
 
+/// A spike-in genome found by `detect_exogenous`.
+#[derive(Debug)]
+pub struct DetectedGenome {
+    pub contigs: ExogenousContigs,
+    pub n_contigs: usize,
+    pub length: u64,
+}
+
+impl DetectedGenome {
+    pub fn summary(&self) -> String {
+        format!(
+            "Detected exogenous contigs by {}: {} contigs, {} bp",
+            self.contigs.describe(),
+            self.n_contigs,
+            self.length
+        )
+    }
+}
+
+/// Find the spike-in genomes of a combined reference from its contig names and lengths:
+/// prefixes or suffixes, up to a `_`, `.` or `-`, that leave chromosome names once removed
+/// (dm6_chr2L, chr2L_dm6) and whose contigs are shorter in total than the rest.
+/// Several are returned when the reference combines more than one spike-in genome.
+pub fn detect_exogenous(chroms: &[(String, u64)]) -> Vec<DetectedGenome> {
+    let chromosome =
+        Regex::new(r"^(chr)?([0-9]+[LR]?|[IVXYZWM]+|MT)$").expect("Valid chromosome pattern");
+    let separators: &[char] = &['_', '.', '-'];
+    let total: u64 = chroms.iter().map(|(_, length)| length).sum();
+
+    let candidates: std::collections::BTreeSet<(bool, &str)> = chroms
+        .iter()
+        .flat_map(|(name, _)| {
+            let prefix = name.find(separators).map(|i| (true, &name[..=i]));
+            let suffix = name.rfind(separators).map(|i| (false, &name[i..]));
+            prefix.into_iter().chain(suffix)
+        })
+        .collect();
+
+    candidates
+        .into_iter()
+        .filter_map(|(is_prefix, affix)| {
+            let contigs = match is_prefix {
+                true => ExogenousContigs::Prefix(affix.to_string()),
+                false => ExogenousContigs::Suffix(affix.to_string()),
+            };
+            let members: Vec<&(String, u64)> = chroms
+                .iter()
+                .filter(|(name, _)| contigs.matches(name.as_bytes()))
+                .collect();
+            let length: u64 = members.iter().map(|(_, length)| length).sum();
+            let has_chromosomes = members
+                .iter()
+                .any(|(name, _)| chromosome.is_match(&contigs.strip(name.as_bytes())));
+            // Alternate and unplaced contigs of the main genome look alike (chr1_alt)
+            let is_patch = ["alt", "fix", "random", "decoy"].contains(&contigs.label().as_str());
+            (has_chromosomes && !is_patch && 2 * length < total).then_some(DetectedGenome {
+                contigs,
+                n_contigs: members.len(),
+                length,
+            })
+        })
+        .collect()
+}
+
+/// How `split` routes and writes records.
+#[derive(Debug, Clone, Default)]
+pub struct SplitOptions {
+    /// Route flagged records by genome rather than diverting them to the unmapped output
+    pub keep_duplicates: bool,
+    pub keep_secondary: bool,
+    pub keep_supplementary: bool,
+    pub keep_qcfail: bool,
+    /// Remove the exogenous prefix, suffix or pattern match from contig names in the
+    /// exogenous output
+    pub strip_prefix: bool,
+    /// BGZF worker threads for the input and for each output
+    pub threads: usize,
+    /// Don't show a progress bar
+    pub quiet: bool,
+    /// Keep the mates of each pair in the same output, under this policy
+    pub pair_policy: Option<PairPolicy>,
+    /// Count filtered reads without writing them to the unmapped output
+    pub no_filtered_output: bool,
+    /// How pairs with mates on different genomes are assigned
+    pub ambiguous_policy: AmbiguousPolicy,
+    /// BGZF compression level of BAM outputs, from 0 (uncompressed) to 9
+    pub compression_level: Option<u8>,
+    /// Write the outputs as BAM or as CRAM
+    pub output_format: AlignmentFormat,
+    /// Indexed reference FASTA, needed for CRAM output
+    pub reference: Option<PathBuf>,
+    /// Also write the exogenous reads as FASTQ, to files starting with this prefix
+    pub exogenous_fastq: Option<PathBuf>,
+    /// Whether reads without a mapping quality (255) pass the MAPQ filter
+    pub missing_mapq: MissingMapq,
+    /// Send secondary and supplementary alignments wherever their primary alignment goes
+    pub follow_primary: bool,
+    /// Write where each record went to this table, gzipped for a `.gz` path
+    pub assignment_table: Option<PathBuf>,
+}
+
+impl SplitOptions {
+    /// Whether records can be held back and written after later ones: mates waiting for
+    /// each other to apply the pair policy or to resolve pairs across genomes, and
+    /// secondary or supplementary alignments waiting for their primary.
+    fn reorders_records(&self) -> bool {
+        self.pair_policy.is_some()
+            || self.ambiguous_policy != AmbiguousPolicy::Both
+            || self.follow_primary
+    }
+}
+
+/// What happens to reads whose mapping quality is missing (255), as some aligners give
+/// uniquely mapped reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum MissingMapq {
+    /// Treat them as passing the MAPQ filter, as samtools does
+    #[default]
+    Keep,
+    /// Send them to the unmapped output with the low MAPQ reads
+    Filter,
+}
+
+impl MissingMapq {
+    /// Whether a read with mapping quality `mapq` fails the MAPQ filter.
+    fn is_low(self, mapq: Option<u8>) -> bool {
+        match mapq {
+            Some(mapq) => mapq < MIN_MAPQ,
+            None => self == MissingMapq::Filter,
+        }
+    }
+}
+
+/// Where pairs with mates on different genomes go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum AmbiguousPolicy {
+    /// The both genomes output
+    #[default]
+    Both,
+    /// The genome of the first read of the pair
+    R1,
+    /// The genome of the mate with the higher mapping quality
+    HigherMapq,
+    /// The genome of the mate with the higher alignment score (AS tag)
+    AlignmentScore,
+}
+
+/// What an ambiguous policy compares between the mates of a pair.
+#[derive(Debug, Clone, Copy)]
+struct MateInfo {
+    genome: usize,
+    is_first: bool,
+    mapq: Option<u8>,
+    alignment_score: Option<i64>,
+}
+
+/// Genome for a pair with mates on different genomes, or None to keep it in the both
+/// genomes output (including ties and missing values).
+fn resolve_ambiguous(policy: AmbiguousPolicy, first: &MateInfo, second: &MateInfo) -> Option<usize> {
+    let higher = |a: Option<i64>, b: Option<i64>| match (a, b) {
+        (Some(a), Some(b)) if a > b => Some(first.genome),
+        (Some(a), Some(b)) if b > a => Some(second.genome),
+        _ => None,
+    };
+    match policy {
+        AmbiguousPolicy::Both => None,
+        AmbiguousPolicy::R1 => [first, second]
+            .iter()
+            .find(|mate| mate.is_first)
+            .map(|mate| mate.genome),
+        AmbiguousPolicy::HigherMapq => {
+            higher(first.mapq.map(i64::from), second.mapq.map(i64::from))
+        }
+        AmbiguousPolicy::AlignmentScore => higher(first.alignment_score, second.alignment_score),
+    }
+}
+
+/// How a pair is routed when only one mate passes the filters, so both mates end up in
+/// the same output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PairPolicy {
+    /// Filter the pair unless both mates pass
+    BothPass,
+    /// Keep the pair, routed by the passing mate, if either mate passes
+    EitherPass,
+}
+
+/// Reads with a lower mapping quality are filtered.
+const MIN_MAPQ: u8 = 30;
+
+/// The classification settings, for the @PG description of each output.
+fn describe_options(exogenous: &[ExogenousContigs], options: &SplitOptions) -> String {
+    let mut description = format!(
+        "exogenous contigs by {}, min MAPQ {}",
+        exogenous.iter().map(ExogenousContigs::describe).join(" or "),
+        MIN_MAPQ
+    );
+    let kept: Vec<&str> = [
+        (options.keep_duplicates, "duplicate"),
+        (options.keep_secondary, "secondary"),
+        (options.keep_supplementary, "supplementary"),
+        (options.keep_qcfail, "QC-failed"),
+    ]
+    .iter()
+    .filter(|(keep, _)| *keep)
+    .map(|(_, flag)| *flag)
+    .collect();
+    if !kept.is_empty() {
+        description.push_str(&format!(", keeping {} reads", kept.join(", ")));
+    }
+    if let Some(policy) = options.pair_policy.and_then(|policy| policy.to_possible_value()) {
+        description.push_str(&format!(", pair policy {}", policy.get_name()));
+    }
+    if options.follow_primary {
+        description.push_str(", secondary and supplementary alignments with their primary");
+    }
+    if options.missing_mapq == MissingMapq::Filter {
+        description.push_str(", filtering reads without MAPQ");
+    }
+    if options.ambiguous_policy != AmbiguousPolicy::Both {
+        if let Some(policy) = options.ambiguous_policy.to_possible_value() {
+            description.push_str(&format!(", ambiguous pairs by {}", policy.get_name()));
+        }
+    }
+    description
+}
+
+/// Where `split` writes a record, which is also the category it is counted in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    Unmapped,
+    QcFail,
+    Duplicate,
+    Secondary,
+    Supplementary,
+    LowMapq,
+    /// Passed the filters, but its mate did not
+    MateFiltered,
+    BothGenomes,
+    /// The endogenous genome (0) or an exogenous genome
+    Genome(usize),
+}
+
+impl Route {
+    /// Whether the record goes to the unmapped (filtered) output.
+    fn is_filtered(&self) -> bool {
+        !matches!(self, Route::BothGenomes | Route::Genome(_))
+    }
+
+    /// Name of the route's category, as in the stats. Exogenous genomes are named by
+    /// their label where given.
+    fn class(&self, genome_labels: &[Option<String>]) -> String {
+        match self {
+            Route::Unmapped => "unmapped".to_string(),
+            Route::QcFail => "qcfail".to_string(),
+            Route::Duplicate => "duplicate".to_string(),
+            Route::Secondary => "secondary".to_string(),
+            Route::Supplementary => "supplementary".to_string(),
+            Route::LowMapq => "low_mapq".to_string(),
+            Route::MateFiltered => "mate_filtered".to_string(),
+            Route::BothGenomes => "both_genomes".to_string(),
+            Route::Genome(0) => "endogenous".to_string(),
+            Route::Genome(genome) => match &genome_labels[genome - 1] {
+                Some(label) => format!("exogenous_{}", label),
+                None => "exogenous".to_string(),
+            },
+        }
+    }
+}
+
+/// Labels breaking the exogenous counts down by genome, only when there is more than one.
+fn genome_labels(exogenous: &[ExogenousContigs]) -> Vec<Option<String>> {
+    match exogenous {
+        [_] => vec![None],
+        _ => exogenous.iter().map(|genome| Some(genome.label())).collect(),
+    }
+}
+
+/// Where each record went, with what it was classified by, to audit the split or filter
+/// reads downstream.
+struct AssignmentTable {
+    writer: Box<dyn Write>,
+    labels: Vec<Option<String>>,
+}
+
+impl AssignmentTable {
+    fn new(path: &Path, exogenous: &[ExogenousContigs]) -> Result<Self> {
+        let file = BufWriter::new(
+            File::create(path).with_context(|| format!("Could not create `{}`", path.display()))?,
+        );
+        let mut writer: Box<dyn Write> = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Box::new(GzEncoder::new(file, flate2::Compression::default())),
+            _ => Box::new(file),
+        };
+        writeln!(writer, "read_name\tflag\tclass\tmapq\tcontig\tmate_contig")?;
+        Ok(Self {
+            writer,
+            labels: genome_labels(exogenous),
+        })
+    }
+
+    fn write(&mut self, headers: &BamHeaders, record: &bam::Record, route: Route) -> Result<()> {
+        let contig = |id: Option<usize>| {
+            id.and_then(|id| headers.header_input.reference_sequences().get_index(id))
+                .map_or("*".to_string(), |(name, _)| name.to_string())
+        };
+        let mate_id = record.mate_reference_sequence_id().and_then(|id| id.ok());
+        writeln!(
+            self.writer,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            record.name().map_or("*".to_string(), |name| String::from_utf8_lossy(name.as_bytes()).into_owned()),
+            u16::from(record.flags()),
+            route.class(&self.labels),
+            record.mapping_quality().map_or(255, |mapq| mapq.get()),
+            contig(reference_id_of(record)),
+            contig(mate_id)
+        )?;
+        Ok(())
+    }
+}
+
+/// Route of a secondary or supplementary alignment following its primary: the primary's
+/// output, the both genomes output if the alignment (or its mate) is on another genome,
+/// or the unmapped output as its own category if the primary was filtered.
+fn follower_route(primary: Route, in_primary_genome: bool, is_secondary: bool) -> Route {
+    match primary {
+        Route::Genome(_) if !in_primary_genome => Route::BothGenomes,
+        primary if !primary.is_filtered() => primary,
+        _ if is_secondary => Route::Secondary,
+        _ => Route::Supplementary,
+    }
+}
+
+/// Read name and whether it is the first segment, shared by all alignments of a read.
+type ReadKey = (Vec<u8>, bool);
+
+fn read_key(record: &bam::Record) -> ReadKey {
+    (
+        record.name().map(|name| name.as_bytes().to_vec()).unwrap_or_default(),
+        record.flags().is_first_segment(),
+    )
+}
+
+/// Secondary and supplementary alignments held until their primary alignment is routed,
+/// for `follow_primary`.
+#[derive(Default)]
+struct Followers {
+    /// Reads with secondary or supplementary alignments, found in a first pass so that
+    /// only their primaries' routes are kept
+    reads: ahash::HashSet<ReadKey>,
+    primary_routes: HashMap<ReadKey, Route>,
+    waiting: HashMap<ReadKey, Vec<bam::Record>>,
+}
+
+impl Followers {
+    /// Note the route of a primary alignment, returning any alignments waiting for it.
+    fn primary_routed(&mut self, record: &bam::Record, route: Route) -> Vec<bam::Record> {
+        let flags = record.flags();
+        if flags.is_secondary() || flags.is_supplementary() {
+            return Vec::new();
+        }
+        let key = read_key(record);
+        if !self.reads.contains(&key) {
+            return Vec::new();
+        }
+        let waiting = self.waiting.remove(&key).unwrap_or_default();
+        self.primary_routes.insert(key, route);
+        waiting
+    }
+}
+
+/// Write `record` to the output of `route` and count it, along with any secondary or
+/// supplementary alignments that were waiting for it as their primary.
+fn emit(
+    outputs: &mut SplitOutputs,
+    stats: &mut SplitStats,
+    followers: &mut Followers,
+    headers: &BamHeaders,
+    labels: &[Option<String>],
+    record: &bam::Record,
+    route: Route,
+) -> Result<()> {
+    outputs.write(headers, record, route)?;
+    stats.add(route, reference_id_of(record), labels);
+    for follower in followers.primary_routed(record, route) {
+        let route = headers.follower_route(route, &follower);
+        outputs.write(headers, &follower, route)?;
+        stats.add(route, reference_id_of(&follower), labels);
+    }
+    Ok(())
+}
+
+/// Routes for the two mates of a pair, so they end up in the same output.
+fn pair_routes(first: Route, second: Route, policy: PairPolicy) -> (Route, Route) {
+    match (first.is_filtered(), second.is_filtered(), policy) {
+        (false, true, PairPolicy::BothPass) => (Route::MateFiltered, second),
+        (true, false, PairPolicy::BothPass) => (first, Route::MateFiltered),
+        (false, true, PairPolicy::EitherPass) => (first, first),
+        (true, false, PairPolicy::EitherPass) => (second, second),
+        _ => (first, second),
+    }
+}
+
+/// Number of records in `bam` according to its index, if it has one.
+fn indexed_record_count(bam: &Path) -> Option<u64> {
+    let has_index = ["bai", "csi"]
+        .iter()
+        .any(|ext| PathBuf::from(format!("{}.{}", bam.display(), ext)).exists());
+    if !has_index {
+        return None;
+    }
+    let mut reader = rust_htslib::bam::IndexedReader::from_path(bam).ok()?;
+    let stats = reader.index_stats().ok()?;
+    Some(stats.iter().map(|&(_, _, mapped, unmapped)| mapped + unmapped).sum())
+}
+
+/// Progress of `split` through `total` records, or just the record rate when the total
+/// isn't known.
+fn progress_bar(total: Option<u64>, quiet: bool) -> ProgressBar {
+    if quiet {
+        return ProgressBar::hidden();
+    }
+    match total {
+        Some(total) => ProgressBar::new(total).with_style(
+            ProgressStyle::with_template(
+                "{bar:40} {human_pos}/{human_len} reads ({per_sec}, ETA {eta})",
+            )
+            .expect("Valid progress template"),
+        ),
+        None => ProgressBar::new_spinner().with_style(
+            ProgressStyle::with_template("{spinner} {human_pos} reads ({per_sec})")
+                .expect("Valid progress template"),
+        ),
+    }
+}
+
+type BamWriter = bam::io::Writer<bgzf::MultithreadedWriter<std::fs::File>>;
+
+/// BAM writer compressing with `threads` worker threads, at the BGZF default level
+/// unless `compression_level` (0, uncompressed, to 9) is given.
+fn bam_writer(path: &Path, threads: NonZeroUsize, compression_level: Option<u8>) -> Result<BamWriter> {
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Could not create `{}`", path.display()))?;
+    let mut builder = bgzf::multithreaded_writer::Builder::default().set_worker_count(threads);
+    if let Some(level) = compression_level {
+        let level = bgzf::writer::CompressionLevel::new(level)
+            .with_context(|| format!("Invalid compression level {}", level))?;
+        builder = builder.set_compression_level(level);
+    }
+    Ok(bam::io::Writer::from(builder.build_from_writer(file)))
+}
+
+/// Reference sequences for CRAM outputs, read from an indexed (samtools faidx) FASTA.
+fn reference_repository(reference: &Path) -> Result<fasta::Repository> {
+    let reader = fasta::io::indexed_reader::Builder::default()
+        .build_from_path(reference)
+        .with_context(|| format!("Could not open indexed FASTA `{}`", reference.display()))?;
+    Ok(fasta::Repository::new(
+        fasta::repository::adapters::IndexedReader::new(reader),
+    ))
+}
+
+/// Write a read as FASTQ, in sequencing orientation. Missing quality scores are written
+/// as Q1, as samtools does.
+fn write_fastq<W: Write>(
+    writer: &mut W,
+    name: &[u8],
+    sequence: &[u8],
+    quality_scores: &[u8],
+    is_reverse: bool,
+) -> Result<()> {
+    let mut quality_scores: Vec<u8> = quality_scores
+        .iter()
+        .map(|&score| match score {
+            255 => b'"',
+            score => score + 33,
+        })
+        .collect();
+    let sequence = match is_reverse {
+        true => {
+            quality_scores.reverse();
+            dna::revcomp(sequence)
+        }
+        false => sequence.to_vec(),
+    };
+    writer.write_all(b"@")?;
+    writer.write_all(name)?;
+    writer.write_all(b"\n")?;
+    writer.write_all(&sequence)?;
+    writer.write_all(b"\n+\n")?;
+    writer.write_all(&quality_scores)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+fn fastq_writer(path: PathBuf) -> Result<GzEncoder<BufWriter<File>>> {
+    let file = File::create(&path)
+        .with_context(|| format!("Could not create `{}`", path.display()))?;
+    Ok(GzEncoder::new(BufWriter::new(file), flate2::Compression::default()))
+}
+
+/// FASTQ of one exogenous genome's primary reads, for realigning them to the genome's
+/// own reference: `prefix.1.fq.gz` and `prefix.2.fq.gz` for pairs, and `prefix.fq.gz`
+/// for single reads and reads whose mate went elsewhere.
+struct FastqExport {
+    single: GzEncoder<BufWriter<File>>,
+    read1: GzEncoder<BufWriter<File>>,
+    read2: GzEncoder<BufWriter<File>>,
+    /// Mates wait here for their partner so that both files list pairs in the same order
+    mates: HashMap<Vec<u8>, bam::Record>,
+}
+
+impl FastqExport {
+    fn new(prefix: &Path) -> Result<Self> {
+        let path = |ext: &str| {
+            let mut path = prefix.as_os_str().to_owned();
+            path.push(ext);
+            PathBuf::from(path)
+        };
+        Ok(Self {
+            single: fastq_writer(path(".fq.gz"))?,
+            read1: fastq_writer(path(".1.fq.gz"))?,
+            read2: fastq_writer(path(".2.fq.gz"))?,
+            mates: HashMap::default(),
+        })
+    }
+
+    fn write(&mut self, record: &bam::Record) -> Result<()> {
+        let flags = record.flags();
+        if flags.is_secondary() || flags.is_supplementary() {
+            return Ok(());
+        }
+        if !flags.is_segmented() {
+            return write_bam_fastq(&mut self.single, record);
+        }
+        let name = record.name().map(|name| name.as_bytes().to_vec()).unwrap_or_default();
+        match self.mates.remove(&name) {
+            Some(mate) => {
+                for record in [&mate, record] {
+                    match record.flags().is_first_segment() {
+                        true => write_bam_fastq(&mut self.read1, record)?,
+                        false => write_bam_fastq(&mut self.read2, record)?,
+                    }
+                }
+            }
+            None => {
+                self.mates.insert(name, record.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        for (_, record) in self.mates.drain() {
+            write_bam_fastq(&mut self.single, &record)?;
+        }
+        self.single.try_finish()?;
+        self.read1.try_finish()?;
+        self.read2.try_finish()?;
+        Ok(())
+    }
+}
+
+fn write_bam_fastq<W: Write>(writer: &mut W, record: &bam::Record) -> Result<()> {
+    let name = record.name().map(|name| name.as_bytes().to_vec()).unwrap_or_default();
+    let sequence: Vec<u8> = record.sequence().iter().collect();
+    write_fastq(
+        writer,
+        &name,
+        &sequence,
+        record.quality_scores().as_ref(),
+        record.flags().is_reverse_complemented(),
+    )
+}
+
+/// A split output, written as BAM or as CRAM against the reference.
+enum SplitWriter {
+    Bam(BamWriter),
+    Cram(cram::io::Writer<std::fs::File>),
+}
+
+impl SplitWriter {
+    fn write_header(&mut self, header: &sam::Header) -> Result<()> {
+        match self {
+            SplitWriter::Bam(writer) => writer.write_header(header)?,
+            SplitWriter::Cram(writer) => writer.write_header(header)?,
+        }
+        Ok(())
+    }
+
+    fn write_alignment_record(
+        &mut self,
+        header: &sam::Header,
+        record: &dyn sam::alignment::Record,
+    ) -> Result<()> {
+        match self {
+            SplitWriter::Bam(writer) => writer.write_alignment_record(header, record)?,
+            SplitWriter::Cram(writer) => writer.write_alignment_record(header, record)?,
+        }
+        Ok(())
+    }
+
+    /// Input records are copied into BAM outputs without decoding them.
+    fn write_record(&mut self, header: &sam::Header, record: &bam::Record) -> Result<()> {
+        match self {
+            SplitWriter::Bam(writer) => writer.write_record(header, record)?,
+            SplitWriter::Cram(writer) => writer.write_alignment_record(header, record)?,
+        }
+        Ok(())
+    }
+
+    /// CRAM writers hold records until a container is full, so the last container is
+    /// only written on finishing. BAM writers finish when dropped.
+    fn finish(&mut self, header: &sam::Header) -> Result<()> {
+        if let SplitWriter::Cram(writer) = self {
+            writer.try_finish(header)?;
+        }
+        Ok(())
+    }
+}
+
+fn open_input(
+    path: &Path,
+    threads: NonZeroUsize,
+) -> Result<bam::io::Reader<bgzf::MultithreadedReader<std::fs::File>>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Could not open `{}`", path.display()))?;
+    Ok(bam::io::Reader::from(bgzf::MultithreadedReader::with_worker_count(threads, file)))
+}
 
 pub struct SplitBam {
-    bam_input: bam::io::Reader<noodles::bgzf::Reader<std::fs::File>>,
-    bam_endogenous: bam::io::Writer<noodles::bgzf::Writer<std::fs::File>>,
-    bam_exogenous: bam::io::Writer<noodles::bgzf::Writer<std::fs::File>>,
-    bam_both_genomes: bam::io::Writer<noodles::bgzf::Writer<std::fs::File>>,
-    bam_unmapped: bam::io::Writer<noodles::bgzf::Writer<std::fs::File>>,
+    bam_input: bam::io::Reader<bgzf::MultithreadedReader<std::fs::File>>,
+    outputs: SplitOutputs,
+    exogenous: Vec<ExogenousContigs>,
+    input_path: PathBuf,
+    input_name: String,
+    /// Records in the input, from its index
+    n_records: Option<u64>,
+    output_prefix: PathBuf,
+    options: SplitOptions,
+}
+
+/// Kept apart from the input so records can be written while iterating over it.
+struct SplitOutputs {
+    /// The endogenous output, then one per exogenous genome
+    genomes: Vec<SplitWriter>,
+    both_genomes: SplitWriter,
+    /// None when filtered reads are only counted
+    unmapped: Option<SplitWriter>,
+    /// FASTQ for each exogenous genome, when requested
+    exogenous_fastq: Vec<FastqExport>,
+    assignments: Option<AssignmentTable>,
+}
+
+impl SplitOutputs {
+    fn write_headers(&mut self, headers: &BamHeaders) -> Result<()> {
+        for (writer, header) in self.genomes.iter_mut().zip(&headers.header_genomes) {
+            writer.write_header(header)?;
+        }
+        self.both_genomes.write_header(&headers.header_both_genomes)?;
+        if let Some(unmapped) = &mut self.unmapped {
+            unmapped.write_header(&headers.header_unmapped)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, headers: &BamHeaders) -> Result<()> {
+        for (writer, header) in self.genomes.iter_mut().zip(&headers.header_genomes) {
+            writer.finish(header)?;
+        }
+        self.both_genomes.finish(&headers.header_both_genomes)?;
+        if let Some(unmapped) = &mut self.unmapped {
+            unmapped.finish(&headers.header_unmapped)?;
+        }
+        for fastq in &mut self.exogenous_fastq {
+            fastq.finish()?;
+        }
+        if let Some(assignments) = &mut self.assignments {
+            assignments.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Each genome's header only holds its own reference sequences, so records are
+    /// renumbered to match before writing.
+    fn write(&mut self, headers: &BamHeaders, record: &bam::Record, route: Route) -> Result<()> {
+        if let Some(assignments) = &mut self.assignments {
+            assignments.write(headers, record, route)?;
+        }
+        match route {
+            Route::Genome(genome) => {
+                if genome > 0 {
+                    if let Some(fastq) = self.exogenous_fastq.get_mut(genome - 1) {
+                        fastq.write(record)?;
+                    }
+                }
+                let record = remap_reference_ids(
+                    record,
+                    &headers.header_input,
+                    &headers.ids_genomes[genome],
+                )?;
+                self.genomes[genome]
+                    .write_alignment_record(&headers.header_genomes[genome], &record)?;
+            }
+            Route::BothGenomes => self
+                .both_genomes
+                .write_record(&headers.header_both_genomes, record)?,
+            _ => {
+                if let Some(unmapped) = &mut self.unmapped {
+                    unmapped.write_record(&headers.header_unmapped, record)?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 struct BamHeaders {
     header_input: sam::Header,
-    header_endogenous: sam::Header,
-    header_exogenous: sam::Header,
+    /// Endogenous first, then each exogenous genome, as for `SplitOutputs::genomes`
+    header_genomes: Vec<sam::Header>,
     header_both_genomes: sam::Header,
     header_unmapped: sam::Header,
+    /// Index of each input reference sequence in each genome's header
+    ids_genomes: Vec<Vec<Option<usize>>>,
+    /// Genome of each input reference sequence: 0 for endogenous, i + 1 for exogenous genome i
+    genome_of: Vec<usize>,
+}
+
+impl BamHeaders {
+    /// Where `record` goes: the unmapped output if it is filtered, else the output of
+    /// its genome, or the both genomes output if its mate is on another genome.
+    fn route(&self, record: &bam::Record, options: &SplitOptions) -> Route {
+        let flags = record.flags();
+        if flags.is_unmapped() {
+            return Route::Unmapped;
+        } else if flags.is_qc_fail() && !options.keep_qcfail {
+            return Route::QcFail;
+        } else if flags.is_duplicate() && !options.keep_duplicates {
+            return Route::Duplicate;
+        } else if flags.is_secondary() && !options.keep_secondary {
+            return Route::Secondary;
+        } else if flags.is_supplementary() && !options.keep_supplementary {
+            return Route::Supplementary;
+        } else if options
+            .missing_mapq
+            .is_low(record.mapping_quality().map(|mapq| mapq.get()))
+        {
+            return Route::LowMapq;
+        }
+
+        // Flagged as mapped but without a reference, so it can't be placed in a genome
+        let genome = match reference_id_of(record) {
+            Some(id) => self.genome_of[id],
+            None => return Route::Unmapped,
+        };
+
+        // Single-end reads and reads with an unmapped mate go by their own genome
+        let mate_id = record.mate_reference_sequence_id().and_then(|id| id.ok());
+        if let (true, false, Some(mate_id)) = (flags.is_segmented(), flags.is_mate_unmapped(), mate_id) {
+            if self.genome_of[mate_id] != genome {
+                return Route::BothGenomes;
+            }
+        }
+        Route::Genome(genome)
+    }
+
+    /// Route of `record`, a secondary or supplementary alignment, given the route of its
+    /// primary alignment.
+    fn follower_route(&self, primary: Route, record: &bam::Record) -> Route {
+        let in_genome = |genome: usize| {
+            let mate_id = record.mate_reference_sequence_id().and_then(|id| id.ok());
+            reference_id_of(record).map(|id| self.genome_of[id]) == Some(genome)
+                && (record.flags().is_mate_unmapped()
+                    || mate_id.is_none_or(|id| self.genome_of[id] == genome))
+        };
+        let in_primary_genome = match primary {
+            Route::Genome(genome) => in_genome(genome),
+            _ => true,
+        };
+        follower_route(primary, in_primary_genome, record.flags().is_secondary())
+    }
+
+    fn mate_info(&self, record: &bam::Record) -> MateInfo {
+        let alignment_score = record
+            .data()
+            .get(&sam::alignment::record::data::field::Tag::ALIGNMENT_SCORE)
+            .and_then(|value| value.ok())
+            .and_then(|value| value.as_int());
+        MateInfo {
+            genome: record
+                .reference_sequence_id()
+                .and_then(|id| id.ok())
+                .map_or(0, |id| self.genome_of[id]),
+            is_first: record.flags().is_first_segment(),
+            mapq: record.mapping_quality().map(|mapq| mapq.get()),
+            alignment_score,
+        }
+    }
+}
+
+fn reference_id_of(record: &bam::Record) -> Option<usize> {
+    record.reference_sequence_id().and_then(|id| id.ok())
+}
+
+/// Change a header's sort order from coordinate to unsorted.
+fn mark_unsorted(header: &mut sam::Header) {
+    use sam::header::record::value::map::header::{sort_order, tag};
+    if let Some(map) = header.header_mut() {
+        let fields = map.other_fields_mut();
+        if fields.get(&tag::SORT_ORDER).is_some_and(|order| order.as_slice() == sort_order::COORDINATE) {
+            fields.insert(tag::SORT_ORDER, BString::from(sort_order::UNSORTED));
+        }
+    }
+}
+
+/// Output extensions for the endogenous and each exogenous genome's BAM. A single
+/// exogenous genome keeps the plain `exogenous.bam` name.
+fn genome_extensions(exogenous: &[ExogenousContigs]) -> Vec<String> {
+    let mut extensions = vec!["endogenous.bam".to_string()];
+    match exogenous {
+        [_] => extensions.push("exogenous.bam".to_string()),
+        _ => extensions.extend(
+            exogenous
+                .iter()
+                .map(|genome| format!("exogenous.{}.bam", genome.label())),
+        ),
+    }
+    extensions
+}
+
+/// FASTQ prefix for each exogenous genome, named by genome when there is more than one
+/// as for the BAM outputs.
+fn fastq_prefixes(prefix: &Path, exogenous: &[ExogenousContigs]) -> Vec<PathBuf> {
+    match exogenous {
+        [_] => vec![prefix.to_path_buf()],
+        _ => exogenous
+            .iter()
+            .map(|genome| {
+                let mut path = prefix.as_os_str().to_owned();
+                path.push(format!(".{}", genome.label()));
+                PathBuf::from(path)
+            })
+            .collect(),
+    }
+}
+
+/// Path of the output with extension `ext`, appended to `prefix` so that prefixes with a
+/// dot (`sample.rep1`) are kept whole. A trailing `.bam` on the prefix is dropped, and
+/// CRAM outputs end in `.cram` rather than `.bam`.
+fn output_path(prefix: &Path, ext: &str, format: AlignmentFormat) -> PathBuf {
+    let prefix = match prefix.extension() {
+        Some(bam) if bam == "bam" => prefix.with_extension(""),
+        _ => prefix.to_path_buf(),
+    };
+    let mut path = prefix.into_os_string();
+    path.push(".");
+    path.push(ext);
+    let path = PathBuf::from(path);
+    match format {
+        AlignmentFormat::Bam => path,
+        AlignmentFormat::Cram => path.with_extension("cram"),
+    }
+}
+
+/// Copy `record` with its reference sequence IDs (from the input header) moved to their
+/// index in a header with a subset of the reference sequences, given by `ids`.
+///
+/// A pair assigned to one genome by the ambiguous or pair policy can have a mate on a
+/// reference outside the subset. That mate is written unmapped at its partner's position,
+/// without RNEXT/PNEXT, and its partner is flagged as having an unmapped mate.
+fn remap_reference_ids(
+    record: &bam::Record,
+    header_input: &sam::Header,
+    ids: &[Option<usize>],
+) -> Result<sam::alignment::RecordBuf> {
+    use sam::alignment::record::{Flags, MappingQuality};
+
+    let mut record = sam::alignment::RecordBuf::try_from_alignment_record(header_input, record)?;
+    let remap = |id: Option<usize>| id.and_then(|id| ids.get(id).copied().flatten());
+    let reference_sequence_id = remap(record.reference_sequence_id());
+    let mate_reference_sequence_id = remap(record.mate_reference_sequence_id());
+    let mate_off_genome =
+        record.mate_reference_sequence_id().is_some() && mate_reference_sequence_id.is_none();
+    *record.reference_sequence_id_mut() = reference_sequence_id;
+    *record.mate_reference_sequence_id_mut() = mate_reference_sequence_id;
+
+    let flags = record.flags();
+    if !flags.is_unmapped() && reference_sequence_id.is_none() {
+        *record.flags_mut() = (flags | Flags::UNMAPPED).difference(Flags::PROPERLY_SEGMENTED);
+        *record.reference_sequence_id_mut() = mate_reference_sequence_id;
+        *record.alignment_start_mut() =
+            mate_reference_sequence_id.and(record.mate_alignment_start());
+        *record.mapping_quality_mut() = Some(MappingQuality::MIN);
+        *record.cigar_mut() = Default::default();
+        *record.mate_reference_sequence_id_mut() = None;
+        *record.mate_alignment_start_mut() = None;
+        *record.template_length_mut() = 0;
+    } else if flags.is_segmented() && !flags.is_mate_unmapped() && mate_off_genome {
+        *record.flags_mut() =
+            (flags | Flags::MATE_UNMAPPED).difference(Flags::PROPERLY_SEGMENTED);
+        *record.mate_reference_sequence_id_mut() = reference_sequence_id;
+        *record.mate_alignment_start_mut() = record.alignment_start();
+        *record.template_length_mut() = 0;
+    }
+    Ok(record)
 }
 
 impl SplitBam {
-    pub fn new(bam_input: PathBuf, output_prefix: PathBuf) -> Result<Self> {
-        let bam_input = bam::io::reader::Builder::default().build_from_path(bam_input)?;
-        let bam_endogenous = bam::io::writer::Builder::default()
-            .build_from_path(output_prefix.with_extension("endogenous.bam"))?;
-        let bam_exogenous = bam::io::writer::Builder::default()
-            .build_from_path(output_prefix.with_extension("exogenous.bam"))?;
-        let bam_both_genomes = bam::io::writer::Builder::default()
-            .build_from_path(output_prefix.with_extension("both_genomes.bam"))?;
-        let bam_unmapped = bam::io::writer::Builder::default()
-            .build_from_path(output_prefix.with_extension("unmapped.bam"))?;
+    /// Split `bam_input` into endogenous reads and reads from each of the `exogenous`
+    /// genomes, with pairs spanning genomes written to the both genomes output.
+    pub fn new(
+        bam_input: PathBuf,
+        output_prefix: PathBuf,
+        exogenous: Vec<ExogenousContigs>,
+        options: SplitOptions,
+    ) -> Result<Self> {
+        if exogenous.is_empty() {
+            bail!("No exogenous genomes given");
+        }
+        let extensions = genome_extensions(&exogenous);
+        if extensions.iter().unique().count() != extensions.len() {
+            bail!("Exogenous genomes need distinct names for their output files: {:?}", extensions);
+        }
+        let reference = match (options.output_format, &options.reference) {
+            (AlignmentFormat::Cram, None) => {
+                bail!("CRAM output requires a reference FASTA (--reference)")
+            }
+            // Renamed contigs would be looked up under the wrong name in the reference
+            (AlignmentFormat::Cram, Some(_)) if options.strip_prefix => {
+                bail!("CRAM output keeps contig names as in the reference, so cannot be used with --strip-prefix")
+            }
+            (AlignmentFormat::Cram, Some(reference)) => Some(reference_repository(reference)?),
+            (AlignmentFormat::Bam, _) => None,
+        };
+
+        // Each of the reader and writers gets its own BGZF worker threads
+        let threads = NonZeroUsize::new(options.threads).unwrap_or(NonZeroUsize::MIN);
+        let input_name = bam_input.display().to_string();
+        let n_records = indexed_record_count(&bam_input);
+        let input_path = bam_input;
+        let bam_input = open_input(&input_path, threads)?;
+        let writer = |ext: &str| -> Result<SplitWriter> {
+            let path = output_path(&output_prefix, ext, options.output_format);
+            Ok(match &reference {
+                Some(repository) => SplitWriter::Cram(
+                    cram::io::writer::Builder::default()
+                        .set_reference_sequence_repository(repository.clone())
+                        .build_with_path(&path)
+                        .with_context(|| format!("Could not create `{}`", path.display()))?,
+                ),
+                None => SplitWriter::Bam(bam_writer(&path, threads, options.compression_level)?),
+            })
+        };
+        let outputs = SplitOutputs {
+            genomes: extensions
+                .iter()
+                .map(|ext| writer(ext))
+                .collect::<Result<Vec<_>>>()?,
+            both_genomes: writer("both_genomes.bam")?,
+            unmapped: match options.no_filtered_output {
+                true => None,
+                false => Some(writer("unmapped.bam")?),
+            },
+            exogenous_fastq: match &options.exogenous_fastq {
+                Some(prefix) => fastq_prefixes(prefix, &exogenous)
+                    .iter()
+                    .map(|prefix| FastqExport::new(prefix))
+                    .collect::<Result<_>>()?,
+                None => Vec::new(),
+            },
+            assignments: options
+                .assignment_table
+                .as_deref()
+                .map(|path| AssignmentTable::new(path, &exogenous))
+                .transpose()?,
+        };
 
         Ok(Self {
             bam_input,
-            bam_endogenous,
-            bam_exogenous,
-            bam_both_genomes,
-            bam_unmapped,
+            outputs,
+            exogenous,
+            input_path,
+            input_name,
+            n_records,
+            output_prefix,
+            options,
         })
     }
 
-    fn make_headers(&mut self, exogenous_prefix: &[u8]) -> Result<BamHeaders> {
+    /// Paths of the endogenous, exogenous, both genomes and (unless filtered reads are
+    /// discarded) unmapped outputs.
+    pub fn output_paths(&self) -> Vec<PathBuf> {
+        let unmapped = self.outputs.unmapped.is_some().then_some("unmapped.bam");
+        genome_extensions(&self.exogenous)
+            .iter()
+            .map(String::as_str)
+            .chain(["both_genomes.bam"])
+            .chain(unmapped)
+            .map(|ext| output_path(&self.output_prefix, ext, self.options.output_format))
+            .collect()
+    }
+
+    fn make_headers(&mut self) -> Result<BamHeaders> {
         let header_input = self.bam_input.read_header()?;
 
         let reference_seqs = header_input.reference_sequences().clone();
 
-        // Split reference sequences into endogenous and exogenous based on prefixes present.
-        // Endogenous sequences have no prefix, exogenous sequences have a prefix.
-        let mut reference_seqs_endogenous = sam::header::ReferenceSequences::new();
-        let mut reference_seqs_exogenous = sam::header::ReferenceSequences::new();
+        // Assign each reference sequence to the first exogenous genome whose names it
+        // matches, or to the endogenous genome otherwise.
+        let n_genomes = self.exogenous.len() + 1;
+        let mut reference_seqs_genomes = vec![sam::header::ReferenceSequences::new(); n_genomes];
+        let mut ids_genomes = vec![Vec::with_capacity(reference_seqs.len()); n_genomes];
+        let mut genome_of = Vec::with_capacity(reference_seqs.len());
 
         for (name, len) in reference_seqs.iter() {
-            if name.starts_with(&exogenous_prefix) {
-                reference_seqs_exogenous.insert(name.clone(), len.clone());
-            } else {
-                reference_seqs_endogenous.insert(name.clone(), len.clone());
+            let genome = self
+                .exogenous
+                .iter()
+                .position(|exogenous| exogenous.matches(name))
+                .map_or(0, |i| i + 1);
+            for (i, ids) in ids_genomes.iter_mut().enumerate() {
+                ids.push((i == genome).then(|| reference_seqs_genomes[genome].len()));
             }
+            genome_of.push(genome);
+            // Records refer to contigs by index, so only the header needs renaming
+            let name = match (genome, self.options.strip_prefix) {
+                (1.., true) => BString::from(self.exogenous[genome - 1].strip(name)),
+                _ => name.clone(),
+            };
+            reference_seqs_genomes[genome].insert(name, len.clone());
         }
 
-        let header_endogenous = sam::Header::builder()
-            .set_header(header_input.header().expect("No header present").clone())
-            .set_reference_sequences(reference_seqs_endogenous)
-            .build();
-
-        let header_exogenous = sam::Header::builder()
-            .set_header(header_input.header().expect("No header present").clone())
-            .set_reference_sequences(reference_seqs_exogenous)
-            .build();
+        let mut header_genomes = reference_seqs_genomes
+            .into_iter()
+            .map(|reference_seqs| {
+                sam::Header::builder()
+                    .set_header(header_input.header().expect("No header present").clone())
+                    .set_reference_sequences(reference_seqs)
+                    .build()
+            })
+            .collect::<Vec<_>>();
 
-        let header_both_genomes = sam::Header::builder()
+        let mut header_both_genomes = sam::Header::builder()
             .set_header(header_input.header().expect("No header present").clone())
             .set_reference_sequences(reference_seqs.clone())
             .build();
@@ -163,153 +1584,646 @@ impl SplitBam {
         //     .add_reference_sequence("unmapped",  Map::<ReferenceSequence>::new(NonZeroUsize::try_from(1e6 as usize)?)) // Provide a dummy reference sequence argument
         //     .build();
         
-        let header_unmapped = sam::Header::builder()
+        let mut header_unmapped = sam::Header::builder()
             .set_header(header_input.header().expect("No header present").clone())
             .set_reference_sequences(reference_seqs)
             .build();
 
+        // Records held back are written at a later record's position, so coordinate-sorted
+        // input no longer gives coordinate-sorted outputs
+        if self.options.reorders_records() {
+            for header in header_genomes
+                .iter_mut()
+                .chain([&mut header_both_genomes, &mut header_unmapped])
+            {
+                mark_unsorted(header);
+            }
+        }
+
+        // Each output records how reads were classified, as well as what it holds
+        let parameters = describe_options(&self.exogenous, &self.options);
+        provenance::add_program_record_sam(
+            &mut header_genomes[0],
+            &format!("split: endogenous reads ({})", parameters),
+        )?;
+        for (exogenous, header) in self.exogenous.iter().zip(&mut header_genomes[1..]) {
+            let reads = match self.exogenous.len() {
+                1 => "exogenous reads".to_string(),
+                _ => format!("{} exogenous reads", exogenous.label()),
+            };
+            let description = match self.options.strip_prefix {
+                true => format!(
+                    "split: {}, with {} removed from contig names ({})",
+                    reads,
+                    exogenous.describe(),
+                    parameters
+                ),
+                false => format!("split: {} ({})", reads, parameters),
+            };
+            provenance::add_program_record_sam(header, &description)?;
+        }
+        provenance::add_program_record_sam(
+            &mut header_both_genomes,
+            &format!("split: pairs spanning both genomes ({})", parameters),
+        )?;
+        provenance::add_program_record_sam(
+            &mut header_unmapped,
+            &format!("split: unmapped and filtered reads ({})", parameters),
+        )?;
+
         Ok(BamHeaders {
             header_input,
-            header_endogenous,
-            header_exogenous,
+            header_genomes,
             header_both_genomes,
             header_unmapped,
+            ids_genomes,
+            genome_of,
         })
     }
 
-    fn write_headers(&mut self, headers: &BamHeaders) -> Result<()> {
-        self.bam_endogenous.write_header(&headers.header_endogenous)?;
-        self.bam_exogenous.write_header(&headers.header_exogenous)?;
-        self.bam_both_genomes.write_header(&headers.header_both_genomes)?;
-        self.bam_unmapped.write_header(&headers.header_unmapped)?;
-        Ok(())
+    /// First pass over the input, finding the reads with secondary or supplementary
+    /// alignments.
+    fn find_followers(&self) -> Result<Followers> {
+        let threads = NonZeroUsize::new(self.options.threads).unwrap_or(NonZeroUsize::MIN);
+        let mut reader = open_input(&self.input_path, threads)?;
+        reader.read_header()?;
+        let mut followers = Followers::default();
+        for record in reader.records() {
+            let record = record?;
+            let flags = record.flags();
+            if flags.is_secondary() || flags.is_supplementary() {
+                followers.reads.insert(read_key(&record));
+            }
+        }
+        Ok(followers)
     }
 
-    pub fn split(&mut self, exogenous_prefix: &[u8]) -> Result<SplitStats> {
+    pub fn split(&mut self) -> Result<SplitStats> {
         
-        let headers = self.make_headers(exogenous_prefix)?;
-        self.write_headers(&headers)?;
-        let mut stats = SplitStats::new("SplitBam".to_string());
+        let headers = self.make_headers()?;
+        self.outputs.write_headers(&headers)?;
+        let mut stats = SplitStats::new(self.input_name.clone());
+        stats.outputs = self
+            .output_paths()
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        let labels = genome_labels(&self.exogenous);
+
 
+        // With a pair policy, the first mate seen waits here for the second
+        let mut mates: HashMap<Vec<u8>, (bam::Record, Route)> = HashMap::default();
+        let mut followers = match self.options.follow_primary {
+            true => self.find_followers()?,
+            false => Followers::default(),
+        };
 
+        let progress = progress_bar(self.n_records, self.options.quiet);
         for (ii, record) in self.bam_input.records().enumerate() {
-            let record = record.expect(format!("Error reading record {}", ii).as_str());
-            if ii % 1_000_000 == 0 {
-                println!("Processed {} reads", ii);
+            let record = record.with_context(|| format!("Error reading record {}", ii))?;
+            if ii % 10_000 == 0 {
+                progress.set_position(ii as u64);
             }
-    
-            if record.flags().is_unmapped() {
-                self.bam_unmapped
-                    .write_record(&headers.header_unmapped, &record)
-                    .expect("Error writing record");
-                stats.add_unmapped();
-                continue;
-            } else if record.flags().is_qc_fail() {
-                self.bam_unmapped
-                    .write_record(&headers.header_unmapped, &record)
-                    .expect("Error writing record");
-                stats.add_qcfail();
-                continue;
-            } else if record.flags().is_duplicate() {
-                self.bam_unmapped
-                    .write_record(&headers.header_unmapped, &record)
-                    .expect("Error writing record");
-                stats.add_duplicate();
-                continue;
-            } else if record.flags().is_secondary() {
-                self.bam_unmapped
-                    .write_record(&headers.header_unmapped, &record)
-                    .expect("Error writing record");
-                stats.add_secondary();
+
+            let route = headers.route(&record, &self.options);
+            let flags = record.flags();
+            let reference_id = reference_id_of(&record);
+            if !flags.is_unmapped() && !flags.is_secondary() && !flags.is_supplementary() {
+                if let Some(id) = reference_id {
+                    stats.add_primary(headers.genome_of[id] > 0, flags.is_duplicate());
+                }
+            }
+            if self.options.follow_primary && (flags.is_secondary() || flags.is_supplementary()) {
+                let key = read_key(&record);
+                match followers.primary_routes.get(&key) {
+                    Some(&primary) => {
+                        let route = headers.follower_route(primary, &record);
+                        self.outputs.write(&headers, &record, route)?;
+                        stats.add(route, reference_id, &labels);
+                    }
+                    None => followers.waiting.entry(key).or_default().push(record),
+                }
                 continue;
-            } else if record.mapping_quality().expect("No mapping quality").get() < 30 {
-                self.bam_unmapped
-                    .write_record(&headers.header_unmapped, &record)
-                    .expect("Error writing record");
-                stats.add_low_maq();
+            }
+
+            // Mates are paired up to apply the pair policy, or to compare them when they
+            // are on different genomes
+            let is_primary_pair =
+                flags.is_segmented() && !flags.is_secondary() && !flags.is_supplementary();
+            let resolve_ambiguous_pair = route == Route::BothGenomes
+                && self.options.ambiguous_policy != AmbiguousPolicy::Both;
+            if !is_primary_pair || (self.options.pair_policy.is_none() && !resolve_ambiguous_pair) {
+                emit(&mut self.outputs, &mut stats, &mut followers, &headers, &labels, &record, route)?;
                 continue;
-            } else if !record.flags().is_mate_unmapped() {
-                let r1_seq_id = record
-                    .reference_sequence_id()
-                    .expect("No reference sequence ID")
-                    .expect("Failed to get reference sequence ID");
-                let r1_seq_name = headers
-                    .header_input
-                    .reference_sequences()
-                    .get_index(r1_seq_id)
-                    .expect("Failed to get reference sequence name")
-                    .0;
-                let r2_seq_id = record
-                    .mate_reference_sequence_id()
-                    .expect("No mate reference sequence ID")
-                    .expect("Failed to get mate reference sequence ID");
-                let r2_seq_name = headers
-                    .header_input
-                    .reference_sequences()
-                    .get_index(r2_seq_id)
-                    .expect("Failed to get mate reference sequence name")
-                    .0;
-
-                if r1_seq_name.starts_with(exogenous_prefix)
-                    && r2_seq_name.starts_with(exogenous_prefix)
-                {
-                    let res = self.bam_exogenous
-                        .write_record(&headers.header_exogenous, &record);
-
-
-                    match res {
-                        Ok(_) => {},
-                        Err(e) => {
-                            println!("Error writing record: {:?}", e);
+            }
+
+            let name = record.name().map(|name| name.as_bytes().to_vec()).unwrap_or_default();
+            match mates.remove(&name) {
+                Some((mate, mate_route)) => {
+                    let (mut mate_route, mut route) = match self.options.pair_policy {
+                        Some(policy) => pair_routes(mate_route, route, policy),
+                        None => (mate_route, route),
+                    };
+                    if (mate_route, route) == (Route::BothGenomes, Route::BothGenomes) {
+                        if let Some(genome) = resolve_ambiguous(
+                            self.options.ambiguous_policy,
+                            &headers.mate_info(&mate),
+                            &headers.mate_info(&record),
+                        ) {
+                            mate_route = Route::Genome(genome);
+                            route = Route::Genome(genome);
                         }
                     }
-
-                    stats.add_exogenous();
-                    continue;
-                } else if r1_seq_name.starts_with(exogenous_prefix)
-                    || r2_seq_name.starts_with(exogenous_prefix)
-                {
-                    self.bam_both_genomes
-                        .write_record(&headers.header_both_genomes, &record)
-                        .expect("Error writing record");
-
-                    stats.add_both_genomes();
-                    continue;
-                } else {
-                    self.bam_endogenous
-                        .write_record(&headers.header_endogenous, &record)
-                        .expect("Error writing record");
-                    stats.add_endogenous();
-                    continue;
-                };
-            } else if record.flags().is_mate_unmapped() {
-                let r1_seq_id = record
-                    .reference_sequence_id()
-                    .expect("No reference sequence ID")
-                    .expect("Failed to get reference sequence ID");
-                let r1_seq_name = headers
-                    .header_input
-                    .reference_sequences()
-                    .get_index(r1_seq_id)
-                    .expect("Failed to get reference sequence name")
-                    .0;
-
-                if r1_seq_name.starts_with(exogenous_prefix) {
-                    self.bam_exogenous
-                        .write_record(&headers.header_exogenous, &record)
-                        .expect("Error writing record");
-                    stats.add_exogenous();
-                    continue;
-                } else {
-                    self.bam_endogenous
-                        .write_record(&headers.header_endogenous, &record)
-                        .expect("Error writing record");
-                    stats.add_endogenous();
-                    continue;
+                    for (record, route) in [(&mate, mate_route), (&record, route)] {
+                        emit(&mut self.outputs, &mut stats, &mut followers, &headers, &labels, record, route)?;
+                    }
+                }
+                None => {
+                    mates.insert(name, (record, route));
                 }
             }
         }
+
+        // Reads whose mate is missing from the input are routed on their own
+        for (_, (record, route)) in mates.drain() {
+            emit(&mut self.outputs, &mut stats, &mut followers, &headers, &labels, &record, route)?;
+        }
+        // Alignments whose primary is missing from the input are routed on their own
+        for record in followers.waiting.drain().flat_map(|(_, records)| records) {
+            let route = headers.route(&record, &self.options);
+            self.outputs.write(&headers, &record, route)?;
+            stats.add(route, reference_id_of(&record), &labels);
+        }
+        self.outputs.finish(&headers)?;
+        stats.name_contigs(
+            headers
+                .header_input
+                .reference_sequences()
+                .keys()
+                .map(|name| name.as_slice()),
+        );
+        progress.set_position(stats.total());
+        progress.finish();
         Ok(stats)
     }
 
 }
+
+/// Output prefix of each of `bams`: `output` itself for a single BAM, or the name of each
+/// BAM inside the `output` directory for several. Without `output`, each BAM's own path
+/// without its extension.
+pub fn sample_prefixes(bams: &[PathBuf], output: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let prefixes: Vec<PathBuf> = match (bams, output) {
+        ([_], Some(output)) => vec![output.to_path_buf()],
+        (_, Some(output)) => bams
+            .iter()
+            .map(|bam| output.join(bam.file_stem().unwrap_or(bam.as_os_str())))
+            .collect(),
+        (_, None) => bams.iter().map(|bam| bam.with_extension("")).collect(),
+    };
+    if prefixes.iter().unique().count() != prefixes.len() {
+        bail!("Input BAMs need distinct names for their outputs: {:?}", prefixes);
+    }
+    Ok(prefixes)
+}
+
+/// Split each BAM to its output prefix, `jobs` files at a time. Returns the stats and
+/// output paths of each sample, in input order.
+pub fn split_samples(
+    samples: &[(PathBuf, PathBuf)],
+    exogenous: &[ExogenousContigs],
+    options: &SplitOptions,
+    jobs: usize,
+) -> Result<Vec<(SplitStats, Vec<PathBuf>)>> {
+    let (sample_sender, sample_recv) = crossbeam::channel::unbounded::<(usize, PathBuf, PathBuf)>();
+    let (stats_sender, stats_recv) = crossbeam::channel::unbounded();
+
+    let n_samples = samples.len();
+    let mut handles = Vec::new();
+    for _ in 0..jobs.clamp(1, n_samples.max(1)) {
+        let sample_recv = sample_recv.clone();
+        let stats_sender = stats_sender.clone();
+        let exogenous = exogenous.to_vec();
+        let mut options = options.clone();
+        // Progress bars of files split at the same time would draw over each other
+        options.quiet |= jobs > 1 && n_samples > 1;
+
+        handles.push(std::thread::spawn(move || -> Result<()> {
+            for (i, bam, output_prefix) in sample_recv {
+                if let Some(parent) = output_prefix.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut options = options.clone();
+                // Each sample's FASTQ and assignment table are named after its BAM
+                if n_samples > 1 {
+                    options.exogenous_fastq = options.exogenous_fastq.map(|prefix| {
+                        let mut prefix = prefix.into_os_string();
+                        prefix.push(".");
+                        prefix.push(bam.file_stem().unwrap_or_default());
+                        PathBuf::from(prefix)
+                    });
+                    options.assignment_table = options.assignment_table.map(|table| {
+                        let mut name = bam.file_stem().unwrap_or_default().to_owned();
+                        name.push(".");
+                        name.push(table.file_name().unwrap_or_default());
+                        table.with_file_name(name)
+                    });
+                }
+                let mut splitter = SplitBam::new(bam, output_prefix, exogenous.clone(), options)?;
+                let stats = splitter.split()?;
+                stats_sender.send((i, stats, splitter.output_paths()))?;
+            }
+            Ok(())
+        }));
+    }
+    drop(stats_sender);
+
+    for (i, (bam, output_prefix)) in samples.iter().enumerate() {
+        sample_sender.send((i, bam.clone(), output_prefix.clone()))?;
+    }
+    drop(sample_sender);
+
+    let mut results: Vec<Option<(SplitStats, Vec<PathBuf>)>> = (0..n_samples).map(|_| None).collect();
+    for (i, stats, outputs) in stats_recv {
+        results[i] = Some((stats, outputs));
+    }
+    for handle in handles {
+        handle.join().expect("Failed to join split thread")?;
+    }
+    Ok(results.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+#[test]
+fn test_exogenous_contigs_match_and_strip() {
+    let prefix = ExogenousContigs::Prefix("dm6_".to_string());
+    assert!(prefix.matches(b"dm6_chr2L") && !prefix.matches(b"chr2L_dm6"));
+    assert_eq!(prefix.strip(b"dm6_chr2L"), b"chr2L");
+
+    let suffix = ExogenousContigs::Suffix("_dm6".to_string());
+    assert!(suffix.matches(b"chr2L_dm6") && !suffix.matches(b"dm6_chr2L"));
+    assert_eq!(suffix.strip(b"chr2L_dm6"), b"chr2L");
+
+    let regex = ExogenousContigs::Regex(Regex::new("^(dm6|ecoli)_").unwrap());
+    assert!(regex.matches(b"ecoli_chr") && !regex.matches(b"chr1"));
+    assert_eq!(regex.strip(b"ecoli_chr"), b"chr");
+
+    let listed = ExogenousContigs::Listed {
+        label: "dm6".to_string(),
+        names: [b"2L".to_vec(), b"X".to_vec()].into_iter().collect(),
+    };
+    assert!(listed.matches(b"2L") && !listed.matches(b"2"));
+    assert_eq!(listed.strip(b"2L"), b"2L");
+}
+
+#[cfg(test)]
+#[test]
+fn test_genome_extensions_name_each_exogenous_genome() {
+    let dm6 = ExogenousContigs::Prefix("dm6_".to_string());
+    let ecoli = ExogenousContigs::Prefix("ecoli_".to_string());
+    assert_eq!(
+        genome_extensions(std::slice::from_ref(&dm6)),
+        ["endogenous.bam", "exogenous.bam"]
+    );
+    assert_eq!(
+        genome_extensions(&[dm6, ecoli]),
+        ["endogenous.bam", "exogenous.dm6.bam", "exogenous.ecoli.bam"]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_output_path_appends_to_prefix() {
+    let path = |prefix: &str| output_path(Path::new(prefix), "endogenous.bam", AlignmentFormat::Bam);
+    assert_eq!(path("out/sample"), Path::new("out/sample.endogenous.bam"));
+    assert_eq!(path("sample.rep1"), Path::new("sample.rep1.endogenous.bam"));
+    assert_eq!(path("sample.bam"), Path::new("sample.endogenous.bam"));
+    assert_eq!(
+        output_path(Path::new("sample"), "exogenous.dm6.bam", AlignmentFormat::Cram),
+        Path::new("sample.exogenous.dm6.cram")
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_write_fastq_in_sequencing_orientation() {
+    let mut fastq = Vec::new();
+    write_fastq(&mut fastq, b"read1", b"AACG", &[30, 30, 20, 10], false).unwrap();
+    write_fastq(&mut fastq, b"read2", b"AACG", &[30, 30, 20, 255], true).unwrap();
+    assert_eq!(fastq, b"@read1\nAACG\n+\n??5+\n@read2\nCGTT\n+\n\"5??\n");
+}
+
+#[cfg(test)]
+#[test]
+fn test_missing_mapq_policy() {
+    assert!(MissingMapq::Keep.is_low(Some(10)) && !MissingMapq::Keep.is_low(Some(30)));
+    assert!(!MissingMapq::Keep.is_low(None));
+    assert!(MissingMapq::Filter.is_low(None));
+}
+
+#[cfg(test)]
+#[test]
+fn test_spikein_qc_metrics() {
+    let mut stats = SplitStats::new("sample.bam".to_string());
+    for _ in 0..3 {
+        stats.add(Route::Genome(0), Some(0), &[None]);
+    }
+    stats.add(Route::Genome(0), Some(1), &[None]);
+    stats.add(Route::Genome(1), Some(2), &[None]);
+    stats.add_primary(false, true);
+    stats.add_primary(false, false);
+    stats.add_primary(true, false);
+    stats.name_contigs([&b"chr1"[..], b"chr2", b"dm6_chr2L"]);
+
+    let qc = stats.qc();
+    assert_eq!(qc.percent_spikein, Some(20.0));
+    assert_eq!(qc.endogenous_duplicate_rate, Some(0.5));
+    assert_eq!(qc.exogenous_duplicate_rate, Some(0.0));
+    assert_eq!(
+        qc.endogenous_exogenous_ratio_by_contig,
+        BTreeMap::from([("chr1".to_string(), 3.0), ("chr2".to_string(), 1.0)])
+    );
+    assert!(stats.spikein_fraction_warning(0.1, 0.3).is_none());
+    assert!(stats.spikein_fraction_warning(0.01, 0.1).is_some());
+}
+
+#[cfg(test)]
+#[test]
+fn test_sample_prefixes() {
+    let bams = [PathBuf::from("data/a.bam"), PathBuf::from("data/b.bam")];
+    assert_eq!(
+        sample_prefixes(&bams, None).unwrap(),
+        [Path::new("data/a"), Path::new("data/b")]
+    );
+    assert_eq!(
+        sample_prefixes(&bams, Some(Path::new("split"))).unwrap(),
+        [Path::new("split/a"), Path::new("split/b")]
+    );
+    assert_eq!(
+        sample_prefixes(&bams[..1], Some(Path::new("out"))).unwrap(),
+        [Path::new("out")]
+    );
+    let same_name = [PathBuf::from("rep1/a.bam"), PathBuf::from("rep2/a.bam")];
+    assert!(sample_prefixes(&same_name, Some(Path::new("split"))).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_follower_route_matches_primary() {
+    assert_eq!(follower_route(Route::Genome(1), true, true), Route::Genome(1));
+    assert_eq!(follower_route(Route::Genome(1), false, false), Route::BothGenomes);
+    assert_eq!(follower_route(Route::BothGenomes, true, false), Route::BothGenomes);
+    assert_eq!(follower_route(Route::LowMapq, true, true), Route::Secondary);
+    assert_eq!(follower_route(Route::Unmapped, true, false), Route::Supplementary);
+}
+
+#[cfg(test)]
+#[test]
+fn test_detect_exogenous_prefix_and_suffix() {
+    let chroms = |names: &[(&str, u64)]| -> Vec<(String, u64)> {
+        names.iter().map(|&(name, length)| (name.to_string(), length)).collect()
+    };
+    let labels = |detected: Vec<DetectedGenome>| -> Vec<String> {
+        detected.iter().map(|genome| genome.contigs.label()).collect()
+    };
+    let hg38_dm6 = chroms(&[
+        ("chr1", 248_956_422),
+        ("chr1_KI270706v1_random", 175_055),
+        ("chrUn_GL000220v1", 161_802),
+        ("chrX", 156_040_895),
+        ("dm6_chr2L", 23_513_712),
+        ("dm6_chrX", 23_542_271),
+        ("dm6_chrUn_DS483562v1", 1_001),
+    ]);
+    assert_eq!(labels(detect_exogenous(&hg38_dm6)), ["dm6"]);
+
+    let mm10_ecoli = chroms(&[("chr1", 195_471_971), ("chr2_ecoli", 4_641_652)]);
+    let detected = detect_exogenous(&mm10_ecoli);
+    assert!(matches!(&detected[..], [DetectedGenome { contigs: ExogenousContigs::Suffix(suffix), .. }] if suffix == "_ecoli"));
+
+    assert!(detect_exogenous(&chroms(&[("chr1", 1000), ("chr1_alt", 10)])).is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn test_route_class_names_exogenous_genomes() {
+    let one = genome_labels(&[ExogenousContigs::Prefix("dm6_".to_string())]);
+    assert_eq!(Route::Genome(1).class(&one), "exogenous");
+    let two = genome_labels(&[
+        ExogenousContigs::Prefix("dm6_".to_string()),
+        ExogenousContigs::Prefix("ecoli_".to_string()),
+    ]);
+    assert_eq!(Route::Genome(2).class(&two), "exogenous_ecoli");
+    assert_eq!(Route::Genome(0).class(&two), "endogenous");
+    assert_eq!(Route::LowMapq.class(&two), "low_mapq");
+}
+
+#[cfg(test)]
+#[test]
+fn test_pair_routes_keep_mates_together() {
+    let endogenous = Route::Genome(0);
+    assert_eq!(
+        pair_routes(endogenous, Route::LowMapq, PairPolicy::BothPass),
+        (Route::MateFiltered, Route::LowMapq)
+    );
+    assert_eq!(
+        pair_routes(Route::Unmapped, endogenous, PairPolicy::EitherPass),
+        (endogenous, endogenous)
+    );
+    assert_eq!(
+        pair_routes(Route::BothGenomes, Route::BothGenomes, PairPolicy::BothPass),
+        (Route::BothGenomes, Route::BothGenomes)
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_describe_options_lists_classification_settings() {
+    let exogenous = [
+        ExogenousContigs::Prefix("dm6_".to_string()),
+        ExogenousContigs::Suffix("_ecoli".to_string()),
+    ];
+    let options = SplitOptions {
+        keep_duplicates: true,
+        pair_policy: Some(PairPolicy::BothPass),
+        ..Default::default()
+    };
+    assert_eq!(
+        describe_options(&exogenous, &options),
+        "exogenous contigs by the dm6_ prefix or the _ecoli suffix, min MAPQ 30, \
+         keeping duplicate reads, pair policy both-pass"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_resolve_ambiguous_pairs() {
+    let r1 = MateInfo {
+        genome: 0,
+        is_first: true,
+        mapq: Some(40),
+        alignment_score: Some(-10),
+    };
+    let r2 = MateInfo {
+        genome: 1,
+        is_first: false,
+        mapq: Some(20),
+        alignment_score: Some(-2),
+    };
+    assert_eq!(resolve_ambiguous(AmbiguousPolicy::Both, &r1, &r2), None);
+    assert_eq!(resolve_ambiguous(AmbiguousPolicy::R1, &r2, &r1), Some(0));
+    assert_eq!(resolve_ambiguous(AmbiguousPolicy::HigherMapq, &r1, &r2), Some(0));
+    assert_eq!(resolve_ambiguous(AmbiguousPolicy::AlignmentScore, &r1, &r2), Some(1));
+
+    let tied = MateInfo { mapq: Some(40), ..r2 };
+    assert_eq!(resolve_ambiguous(AmbiguousPolicy::HigherMapq, &r1, &tied), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_pairs_assigned_to_one_genome_unmap_the_other_mate() {
+    use rust_htslib::bam::{self as hts, header::HeaderRecord, Read as _};
+
+    // Name, first segment, reference, position, MAPQ, mate reference and position
+    type Input<'a> = (&'a [u8], Option<bool>, i32, i64, u8, i32, i64);
+    // Name, unmapped, mate unmapped, reference, position, mate reference and position
+    type Output = (Vec<u8>, bool, bool, i32, i64, i32, i64);
+
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("input.bam");
+    {
+        let mut header = hts::Header::new();
+        header.push_record(HeaderRecord::new(b"HD").push_tag(b"VN", "1.6"));
+        for (name, length) in [("chr1", 10_000), ("dm6_chr2L", 10_000)] {
+            let mut sq = HeaderRecord::new(b"SQ");
+            sq.push_tag(b"SN", name).push_tag(b"LN", length);
+            header.push_record(&sq);
+        }
+        let mut writer = hts::Writer::from_path(&input, &header, hts::Format::Bam).unwrap();
+        let reads: [Input; 5] = [
+            (b"a", Some(true), 0, 100, 60, 1, 200),
+            (b"a", Some(false), 1, 200, 40, 0, 100),
+            (b"b", Some(true), 1, 300, 60, 0, 400),
+            (b"b", Some(false), 0, 400, 5, 1, 300),
+            (b"c", None, 0, 500, 60, -1, -1),
+        ];
+        let cigar = hts::record::CigarString(vec![hts::record::Cigar::Match(4)]);
+        for (name, first, tid, pos, mapq, mtid, mpos) in reads {
+            let mut record = hts::Record::new();
+            record.set(name, Some(&cigar), b"ACGT", b"IIII");
+            record.unset_unmapped();
+            record.set_tid(tid);
+            record.set_pos(pos);
+            record.set_mapq(mapq);
+            record.set_mtid(mtid);
+            record.set_mpos(mpos);
+            match first {
+                Some(true) => record.set_flags(0x1 | 0x40),
+                Some(false) => record.set_flags(0x1 | 0x80),
+                None => {}
+            }
+            writer.write(&record).unwrap();
+        }
+    }
+
+    let prefix = dir.path().join("out");
+    let options = SplitOptions {
+        threads: 1,
+        quiet: true,
+        pair_policy: Some(PairPolicy::EitherPass),
+        ambiguous_policy: AmbiguousPolicy::R1,
+        ..Default::default()
+    };
+    let exogenous = vec![ExogenousContigs::Prefix("dm6_".to_string())];
+    SplitBam::new(input, prefix.clone(), exogenous, options)
+        .unwrap()
+        .split()
+        .unwrap();
+
+    let read_back = |ext: &str| -> Vec<Output> {
+        let mut reader = hts::Reader::from_path(output_path(&prefix, ext, AlignmentFormat::Bam)).unwrap();
+        reader
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                (
+                    record.qname().to_vec(),
+                    record.is_unmapped(),
+                    record.is_mate_unmapped(),
+                    record.tid(),
+                    record.pos(),
+                    record.mtid(),
+                    record.mpos(),
+                )
+            })
+            .collect()
+    };
+
+    // Pair a goes with its first read to chr1; pair b with its first read to dm6_chr2L,
+    // which is the only reference in the exogenous output
+    assert_eq!(
+        read_back("endogenous.bam"),
+        vec![
+            (b"a".to_vec(), false, true, 0, 100, 0, 100),
+            (b"a".to_vec(), true, false, 0, 100, -1, -1),
+            (b"c".to_vec(), false, false, 0, 500, -1, -1),
+        ]
+    );
+    assert_eq!(
+        read_back("exogenous.bam"),
+        vec![
+            (b"b".to_vec(), false, true, 0, 300, 0, 300),
+            (b"b".to_vec(), true, false, 0, 300, -1, -1),
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_outputs_are_unsorted_when_records_are_held_back() {
+    use rust_htslib::bam::{self as hts, header::HeaderRecord, Read as _};
+
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("input.bam");
+    {
+        let mut header = hts::Header::new();
+        header.push_record(HeaderRecord::new(b"HD").push_tag(b"VN", "1.6").push_tag(b"SO", "coordinate"));
+        for name in ["chr1", "dm6_chr2L"] {
+            let mut sq = HeaderRecord::new(b"SQ");
+            sq.push_tag(b"SN", name).push_tag(b"LN", 10_000);
+            header.push_record(&sq);
+        }
+        let mut writer = hts::Writer::from_path(&input, &header, hts::Format::Bam).unwrap();
+        let mut record = hts::Record::new();
+        let cigar = hts::record::CigarString(vec![hts::record::Cigar::Match(4)]);
+        record.set(b"a", Some(&cigar), b"ACGT", b"IIII");
+        record.unset_unmapped();
+        record.set_tid(0);
+        record.set_pos(100);
+        record.set_mapq(60);
+        writer.write(&record).unwrap();
+    }
+
+    let sort_order = |options: SplitOptions| -> String {
+        let prefix = dir.path().join("out");
+        let exogenous = vec![ExogenousContigs::Prefix("dm6_".to_string())];
+        let options = SplitOptions { threads: 1, quiet: true, ..options };
+        SplitBam::new(input.clone(), prefix.clone(), exogenous, options)
+            .unwrap()
+            .split()
+            .unwrap();
+        let reader = hts::Reader::from_path(output_path(&prefix, "endogenous.bam", AlignmentFormat::Bam)).unwrap();
+        let text = String::from_utf8_lossy(reader.header().as_bytes()).into_owned();
+        let hd = text.lines().find(|line| line.starts_with("@HD")).unwrap().to_string();
+        hd.split('\t').find_map(|field| field.strip_prefix("SO:")).unwrap().to_string()
+    };
+    assert_eq!(sort_order(SplitOptions::default()), "coordinate");
+    let buffering = [
+        SplitOptions { pair_policy: Some(PairPolicy::BothPass), ..Default::default() },
+        SplitOptions { ambiguous_policy: AmbiguousPolicy::R1, ..Default::default() },
+        SplitOptions { follow_primary: true, ..Default::default() },
+    ];
+    for options in buffering {
+        assert_eq!(sort_order(options), "unsorted");
+    }
+}
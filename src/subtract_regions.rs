@@ -1,33 +1,278 @@
-use anyhow::Ok;
+use anyhow::{Context, Ok};
 use bio::io::bed;
-use log::{info, warn};
+use bio::io::gff::{self, GffType};
+use clap::ValueEnum;
+use flate2::read::MultiGzDecoder;
 use rust_htslib::bam::ext::BamRecordExtensions;
-use rust_htslib::bam::{Format, Header, IndexedReader, Read};
+use rust_htslib::bam::record::{Cigar, CigarString, Record};
+use rust_htslib::bam::{
+    FetchDefinition, Format, Header, HeaderView, IndexedReader, Read, Reader, Writer,
+};
 use rust_lapper::{Interval, Lapper};
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 type Iv = Interval<u64, u64>;
 use std::str;
 use std::sync::Arc;
 use std::thread;
 
-fn get_intervals(bed: &PathBuf) -> Result<HashMap<String, Vec<Iv>>, anyhow::Error> {
-    let mut bed_intervals = HashMap::new();
-    let mut reader = bed::Reader::from_file(Path::new(&bed)).expect("Could not open BED file");
+use crate::genome::{self, Assembly, ASSEMBLIES};
+use crate::provenance;
+use crate::shuffle::reg2bin;
+use crate::signal;
+use crate::sort;
 
-    for record in reader.records() {
-        let record = record.expect("Error reading BED record");
-        let interval = Iv {
-            start: record.start(),
-            stop: record.end(),
-            val: 0,
+/// Region file formats, detected from the extension (under any `.gz`). narrowPeak and
+/// broadPeak files are BED6 with extra columns, so are read as BED.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RegionFormat {
+    Bed,
+    Gff(GffType),
+}
+
+impl RegionFormat {
+    fn from_path(path: &Path) -> Self {
+        let path = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => path.file_stem().map(Path::new).unwrap_or(path),
+            _ => path,
         };
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gtf") => RegionFormat::Gff(GffType::GTF2),
+            Some("gff" | "gff3") => RegionFormat::Gff(GffType::GFF3),
+            _ => RegionFormat::Bed,
+        }
+    }
+}
+
+/// Open a region file, decompressing gzip (including bgzip) files ending `.gz`.
+fn open_regions(path: &Path) -> Result<Box<dyn io::Read>, anyhow::Error> {
+    let file =
+        File::open(path).with_context(|| format!("Could not open regions `{}`", path.display()))?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => Ok(Box::new(MultiGzDecoder::new(file))),
+        _ => Ok(Box::new(file)),
+    }
+}
+
+/// A region as given in its file, for reporting.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Region {
+    name: String,
+    chrom: String,
+    start: u64,
+    end: u64,
+}
+
+impl Region {
+    fn new(name: Option<&str>, chrom: &str, start: u64, end: u64) -> Self {
+        Region {
+            name: name
+                .map(str::to_owned)
+                .unwrap_or_else(|| format!("{}:{}-{}", chrom, start, end)),
+            chrom: chrom.to_owned(),
+            start,
+            end,
+        }
+    }
+}
+
+/// Intervals per chromosome, with the regions their values index.
+type Regions = (HashMap<String, Vec<Iv>>, Vec<Region>);
+
+/// Intervals per chromosome from a BED, narrowPeak, broadPeak, GFF3 or GTF file; GFF
+/// features are limited to `feature_type` if given. Each interval's value is the index
+/// of its region in the returned list. BED12 regions give one interval per block.
+fn get_intervals(bed: &Path, feature_type: Option<&str>) -> Result<Regions, anyhow::Error> {
+    let mut bed_intervals = HashMap::new();
+    let mut regions = Vec::new();
+
+    match RegionFormat::from_path(bed) {
+        RegionFormat::Bed => {
+            if feature_type.is_some() {
+                anyhow::bail!("--feature-type only applies to GTF or GFF regions");
+            }
+            let mut reader = bed::Reader::new(open_regions(bed)?);
+
+            for record in reader.records() {
+                let record = record.expect("Error reading BED record");
+                let interval = Iv {
+                    start: record.start(),
+                    stop: record.end(),
+                    val: regions.len() as u64,
+                };
+                regions.push(Region::new(
+                    record
+                        .name()
+                        .filter(|name| !name.is_empty() && *name != "."),
+                    record.chrom(),
+                    interval.start,
+                    interval.stop,
+                ));
+
+                let chrom = record.chrom().to_owned();
+                let chrom_intervals = bed_intervals.entry(chrom).or_insert(vec![]);
+                match bed12_blocks(&record)? {
+                    Some(blocks) => {
+                        chrom_intervals.extend(blocks.into_iter().map(|(start, stop)| Iv {
+                            start,
+                            stop,
+                            ..interval
+                        }))
+                    }
+                    None => chrom_intervals.push(interval),
+                }
+            }
+        }
+        RegionFormat::Gff(gff_type) => {
+            let mut reader = gff::Reader::new(open_regions(bed)?, gff_type);
+
+            for record in reader.records() {
+                let record = record.expect("Error reading GTF/GFF record");
+                if feature_type.is_some_and(|feature_type| record.feature_type() != feature_type) {
+                    continue;
+                }
+                // GFF coordinates are 1-based and inclusive
+                let interval = Iv {
+                    start: record.start().saturating_sub(1),
+                    stop: *record.end(),
+                    val: regions.len() as u64,
+                };
+                let attributes = record.attributes();
+                let name = ["gene_name", "Name", "ID", "gene_id"]
+                    .iter()
+                    .find_map(|key| attributes.get(*key));
+                regions.push(Region::new(
+                    name.map(String::as_str),
+                    record.seqname(),
+                    interval.start,
+                    interval.stop,
+                ));
+
+                let chrom = record.seqname().to_owned();
+                bed_intervals.entry(chrom).or_insert(vec![]).push(interval);
+            }
+        }
+    }
+
+    Ok((bed_intervals, regions))
+}
+
+/// The blocks (e.g. exons) of a BED12 record, or None for BED with fewer columns.
+fn bed12_blocks(record: &bed::Record) -> Result<Option<Vec<(u64, u64)>>, anyhow::Error> {
+    let (count, sizes, starts) = match (record.aux(9), record.aux(10), record.aux(11)) {
+        (Some(count), Some(sizes), Some(starts)) => (count, sizes, starts),
+        _ => return Ok(None),
+    };
+    let parse = |list: &str| -> Option<Vec<u64>> {
+        list.split(',')
+            .filter(|value| !value.is_empty())
+            .map(|value| value.trim().parse().ok())
+            .collect()
+    };
+    match (count.parse::<usize>().ok(), parse(sizes), parse(starts)) {
+        (Some(count), Some(sizes), Some(starts))
+            if sizes.len() == count && starts.len() == count =>
+        {
+            Ok(Some(
+                starts
+                    .iter()
+                    .zip(&sizes)
+                    .map(|(start, size)| (record.start() + start, record.start() + start + size))
+                    .collect(),
+            ))
+        }
+        _ => anyhow::bail!(
+            "Malformed BED12 blocks for {}:{}-{}",
+            record.chrom(),
+            record.start(),
+            record.end()
+        ),
+    }
+}
+
+/// Replace the blocks of each BED12 region with a single interval over its whole span.
+fn join_blocks(intervals: &mut HashMap<String, Vec<Iv>>, regions: &[Region]) {
+    for chrom_intervals in intervals.values_mut() {
+        chrom_intervals.dedup_by_key(|iv| iv.val);
+        for iv in chrom_intervals.iter_mut() {
+            let region = &regions[iv.val as usize];
+            (iv.start, iv.stop) = (region.start, region.end);
+        }
+    }
+}
 
-        let chrom = record.chrom().to_owned();
-        bed_intervals.entry(chrom).or_insert(vec![]).push(interval);
+/// Intervals from all of the region files merged into one set. The GTF/GFF feature type
+/// filter applies to the GTF/GFF files among them.
+fn get_all_intervals(
+    beds: &[PathBuf],
+    feature_type: Option<&str>,
+) -> Result<Regions, anyhow::Error> {
+    let is_gff = |bed: &PathBuf| matches!(RegionFormat::from_path(bed), RegionFormat::Gff(_));
+    if feature_type.is_some() && !beds.iter().any(is_gff) {
+        anyhow::bail!("--feature-type only applies to GTF or GFF regions");
     }
 
-    Ok(bed_intervals)
+    let mut all_intervals: HashMap<String, Vec<Iv>> = HashMap::new();
+    let mut all_regions = Vec::new();
+    for bed in beds {
+        let feature_type = feature_type.filter(|_| is_gff(bed));
+        let (intervals, regions) = get_intervals(bed, feature_type)?;
+        let offset = all_regions.len() as u64;
+        for (chrom, intervals) in intervals {
+            all_intervals
+                .entry(chrom)
+                .or_default()
+                .extend(intervals.into_iter().map(|iv| Iv {
+                    val: iv.val + offset,
+                    ..iv
+                }));
+        }
+        all_regions.extend(regions);
+    }
+    Ok((all_intervals, all_regions))
+}
+
+/// Rename chromosomes missing from the BAM to their name with or without a `chr` prefix
+/// where that is in the BAM, so UCSC-style regions match Ensembl-style references and
+/// vice versa.
+fn match_chrom_names(
+    intervals: HashMap<String, Vec<Iv>>,
+    header: &rust_htslib::bam::HeaderView,
+) -> HashMap<String, Vec<Iv>> {
+    let mut matched: HashMap<String, Vec<Iv>> = HashMap::new();
+    for (chrom, chrom_intervals) in intervals {
+        let alternative = match chrom.strip_prefix("chr") {
+            Some(stripped) => stripped.to_string(),
+            None => format!("chr{}", chrom),
+        };
+        let chrom = match header.tid(chrom.as_bytes()) {
+            None if header.tid(alternative.as_bytes()).is_some() => alternative,
+            _ => chrom,
+        };
+        matched.entry(chrom).or_default().extend(chrom_intervals);
+    }
+    matched
+}
+
+/// Widen every interval by `slop` on both sides, within the bounds of its chromosome.
+fn pad_intervals(
+    intervals: &mut HashMap<String, Vec<Iv>>,
+    slop: u64,
+    header: &rust_htslib::bam::HeaderView,
+) {
+    for (chrom, chrom_intervals) in intervals.iter_mut() {
+        let chrom_len = header
+            .tid(chrom.as_bytes())
+            .and_then(|tid| header.target_len(tid))
+            .unwrap_or(u64::MAX);
+        for interval in chrom_intervals.iter_mut() {
+            interval.start = interval.start.saturating_sub(slop);
+            interval.stop = interval.stop.saturating_add(slop).min(chrom_len);
+        }
+    }
 }
 
 fn get_chrom_names(header: &rust_htslib::bam::HeaderView) -> Result<Vec<String>, anyhow::Error> {
@@ -45,131 +290,1120 @@ fn get_chrom_names(header: &rust_htslib::bam::HeaderView) -> Result<Vec<String>,
     Ok(tids)
 }
 
-pub fn remove_regions_from_bam(
-    bed: PathBuf,
-    bam: PathBuf,
-    output: PathBuf,
-    n_threads: usize,
+/// Assemblies with a built-in ENCODE blacklist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BlacklistPreset {
+    Hg38,
+    Hg19,
+    Mm10,
+    Dm6,
+}
+
+impl BlacklistPreset {
+    fn assembly(self) -> Assembly {
+        let name = match self {
+            BlacklistPreset::Hg38 => "hg38",
+            BlacklistPreset::Hg19 => "hg19",
+            BlacklistPreset::Mm10 => "mm10",
+            BlacklistPreset::Dm6 => "dm6",
+        };
+        *ASSEMBLIES
+            .iter()
+            .find(|assembly| assembly.name == name)
+            .expect("Blacklist preset without a known assembly")
+    }
+}
+
+/// Directory blacklists are downloaded to: `$XDG_CACHE_HOME/rsbamtk`, falling back to
+/// `~/.cache/rsbamtk`.
+fn cache_dir() -> PathBuf {
+    let base = match (std::env::var_os("XDG_CACHE_HOME"), std::env::var_os("HOME")) {
+        (Some(cache), _) if !cache.is_empty() => PathBuf::from(cache),
+        (_, Some(home)) => PathBuf::from(home).join(".cache"),
+        _ => std::env::temp_dir(),
+    };
+    base.join("rsbamtk")
+}
+
+/// Local copy of the ENCODE blacklist for `preset`, downloaded on first use.
+fn blacklist_file(preset: BlacklistPreset) -> Result<PathBuf, anyhow::Error> {
+    let assembly = preset.assembly();
+    let url = match assembly.blacklist_url {
+        Some(url) => url,
+        None => anyhow::bail!(
+            "No ENCODE blacklist is published for {}; lift over another build's list and pass it with --regions",
+            assembly.name
+        ),
+    };
+    let dir = cache_dir();
+    let path = dir.join(format!("{}-blacklist.v2.bed", assembly.name));
+    if path.exists() {
+        return Ok(path);
+    }
+
+    eprintln!("Downloading the {} blacklist from {}", assembly.name, url);
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Could not create cache directory `{}`", dir.display()))?;
+    let failed = || {
+        format!(
+            "Could not download the {} blacklist from {}; download it yourself and pass it with --regions",
+            assembly.name, url
+        )
+    };
+    let response = ureq::get(url).call().with_context(failed)?;
+
+    // Decompressed beside the final path first so an interrupted download is never cached
+    let mut bed = tempfile::NamedTempFile::new_in(&dir)?;
+    io::copy(&mut MultiGzDecoder::new(response.into_reader()), &mut bed).with_context(failed)?;
+    bed.persist(&path)?;
+    Ok(path)
+}
+
+/// Which reads `subtract` keeps.
+#[derive(Debug, Clone)]
+pub struct SubtractOptions {
+    /// Keep only the reads overlapping the regions rather than removing them
+    pub invert: bool,
+    /// Bases of a read that must fall in regions for it to count as overlapping
+    pub min_overlap_bp: u64,
+    /// Fraction of a read's aligned span that must fall in regions
+    pub min_overlap_frac: f64,
+    /// Treat all records of a read name together, so if either mate overlaps a region
+    /// both are removed (or kept, with `invert`) and no mate is left widowed
+    pub pairs: bool,
+    /// Index the output (.bai, or .csi for very long references)
+    pub write_index: bool,
+    /// Only use GTF/GFF features of this type (e.g. `exon`)
+    pub feature_type: Option<String>,
+    /// Widen each region by this many bases on both sides
+    pub slop: u64,
+    /// Also subtract the ENCODE blacklist of this assembly
+    pub blacklist_preset: Option<BlacklistPreset>,
+    /// Write the reads left out to this BAM instead of discarding them
+    pub removed_output: Option<PathBuf>,
+    /// Pass unmapped reads without coordinates through to the output
+    pub keep_unmapped: bool,
+    /// Output format; taken from the output extension if not given
+    pub output_format: Option<AlignmentFormat>,
+    /// Reference FASTA for CRAM input or output
+    pub reference: Option<PathBuf>,
+    /// Read the input in a single pass, in any order and without an index (implied when
+    /// reading stdin)
+    pub stream: bool,
+    /// Mask the overlapping part of reads instead of removing them
+    pub mask: Option<MaskMode>,
+    /// Write the regions that captured the most reads to this TSV, or JSON for a `.json`
+    /// path
+    pub region_report: Option<PathBuf>,
+    /// Number of regions in the region report
+    pub top_regions: usize,
+    /// Bases of a chromosome filtered by one thread at a time
+    pub window_size: u64,
+    /// Measure overlap against the whole span of BED12 regions rather than their blocks
+    pub bed12_span: bool,
+    /// Discard reads below this mapping quality
+    pub min_mapq: u8,
+    /// Discard reads without all of these SAM flags set
+    pub include_flags: u16,
+    /// Discard reads with any of these SAM flags set
+    pub exclude_flags: u16,
+    pub threads: usize,
+}
+
+impl Default for SubtractOptions {
+    fn default() -> Self {
+        Self {
+            invert: false,
+            min_overlap_bp: 1,
+            min_overlap_frac: 0.0,
+            pairs: false,
+            write_index: false,
+            feature_type: None,
+            slop: 0,
+            blacklist_preset: None,
+            removed_output: None,
+            keep_unmapped: false,
+            output_format: None,
+            reference: None,
+            stream: false,
+            mask: None,
+            region_report: None,
+            top_regions: 10,
+            window_size: 10_000_000,
+            bed12_span: false,
+            min_mapq: 0,
+            include_flags: 0,
+            exclude_flags: 0,
+            threads: 1,
+        }
+    }
+}
+
+/// Whether `record` passes the MAPQ and flag filters. Reads that fail are discarded
+/// before any overlap is considered, so are not written to the removed output either.
+fn passes_filters(record: &Record, options: &SubtractOptions) -> bool {
+    let flags = record.flags();
+    record.mapq() >= options.min_mapq
+        && flags & options.include_flags == options.include_flags
+        && flags & options.exclude_flags == 0
+}
+
+/// Whether `record` is written out, given the regions on its chromosome and, in pairs
+/// mode, the names of reads with any record overlapping a region.
+fn keep_record(
+    record: &Record,
+    lapper: Option<&Lapper<u64, u64>>,
+    names: Option<&HashSet<Vec<u8>>>,
+    options: &SubtractOptions,
+) -> bool {
+    let overlaps = match names {
+        Some(names) => names.contains(record.qname()),
+        None => overlaps_regions(record, lapper, options),
+    };
+    overlaps == options.invert
+}
+
+/// Whether `record` overlaps the regions by at least the minimum overlap.
+fn overlaps_regions(
+    record: &Record,
+    lapper: Option<&Lapper<u64, u64>>,
+    options: &SubtractOptions,
+) -> bool {
+    match lapper {
+        Some(lapper) => {
+            let start = record.reference_start() as u64;
+            let end = record.reference_end() as u64;
+            let overlap = overlap_length(lapper, start, end);
+            overlap > 0
+                && overlap >= options.min_overlap_bp
+                && overlap as f64 >= options.min_overlap_frac * (end - start) as f64
+        }
+        None => false,
+    }
+}
+
+/// Bases of `[start, end)` covered by the regions, counting overlapping regions once.
+fn overlap_length(lapper: &Lapper<u64, u64>, start: u64, end: u64) -> u64 {
+    covered_parts(lapper, start, end)
+        .iter()
+        .map(|(part_start, part_end)| part_end - part_start)
+        .sum()
+}
+
+/// The parts of `[start, end)` covered by the regions, merged and in order.
+fn covered_parts(lapper: &Lapper<u64, u64>, start: u64, end: u64) -> Vec<(u64, u64)> {
+    let mut overlaps: Vec<(u64, u64)> = lapper
+        .find(start, end)
+        .map(|iv| (iv.start.max(start), iv.stop.min(end)))
+        .collect();
+    overlaps.sort_unstable();
+
+    let mut parts: Vec<(u64, u64)> = Vec::new();
+    for (overlap_start, overlap_end) in overlaps {
+        match parts.last_mut() {
+            Some(last) if overlap_start <= last.1 => last.1 = last.1.max(overlap_end),
+            _ if overlap_end > overlap_start => parts.push((overlap_start, overlap_end)),
+            _ => {}
+        }
+    }
+    parts
+}
+
+/// How `--mask` hides the part of a read overlapping the regions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MaskMode {
+    /// Soft-clip overlaps at either end of the alignment; overlaps inside it (which
+    /// cannot be clipped) have their base qualities set to 0
+    SoftClip,
+    /// Set the base qualities of the overlapping bases to 0, leaving the alignment as is
+    Quality,
+}
+
+/// Mask the part of `record` overlapping the regions. Returns false, leaving the record
+/// untouched, when nothing usable would be left: every aligned base is in a region, or
+/// bases need masking but the record has no base qualities. Mate fields (PNEXT, TLEN)
+/// are not updated for soft-clipped mates.
+fn mask_record(record: &mut Record, lapper: &Lapper<u64, u64>, mode: MaskMode) -> bool {
+    let start = record.reference_start();
+    let end = record.reference_end();
+    let mut parts = covered_parts(lapper, start as u64, end as u64);
+
+    let (mut clip_start, mut clip_end) = (start, end);
+    if mode == MaskMode::SoftClip {
+        if parts.first().is_some_and(|part| part.0 as i64 <= start) {
+            clip_start = parts.remove(0).1 as i64;
+        }
+        if parts.last().is_some_and(|part| part.1 as i64 >= end) {
+            clip_end = parts.pop().map_or(end, |part| part.0 as i64);
+        }
+    }
+    let clipped = match (clip_start, clip_end) == (start, end) {
+        true => None,
+        false => match clip_alignment(&record.cigar(), start, clip_start, clip_end) {
+            Some(clipped) => Some(clipped),
+            None => return false,
+        },
+    };
+
+    let mut qual = record.qual().to_vec();
+    if !parts.is_empty() {
+        if qual.first().is_none_or(|q| *q == 0xff) {
+            return false;
+        }
+        let mut aligned = 0;
+        let mut masked = 0;
+        for [qpos, rpos] in record.aligned_pairs() {
+            let rpos = rpos as u64;
+            if rpos < clip_start as u64 || rpos >= clip_end as u64 {
+                continue;
+            }
+            aligned += 1;
+            if parts.iter().any(|(s, e)| (*s..*e).contains(&rpos)) {
+                qual[qpos as usize] = 0;
+                masked += 1;
+            }
+        }
+        if masked == aligned {
+            return false;
+        }
+    }
+
+    let qname = record.qname().to_owned();
+    let seq = record.seq().as_bytes();
+    match clipped {
+        Some((pos, cigar)) => {
+            record.set(&qname, Some(&cigar), &seq, &qual);
+            record.set_pos(pos);
+            record.set_bin(reg2bin(pos, record.reference_end()));
+        }
+        None => {
+            let cigar = record.cigar().take();
+            record.set(&qname, Some(&cigar), &seq, &qual);
+        }
+    }
+    true
+}
+
+/// Soft-clip an alignment at `pos` to the aligned bases within `[clip_start, clip_end)`,
+/// returning its new position and CIGAR, or None if no aligned base is left.
+fn clip_alignment(
+    cigar: &[Cigar],
+    pos: i64,
+    clip_start: i64,
+    clip_end: i64,
+) -> Option<(i64, CigarString)> {
+    let (mut leading_hard, mut leading_soft) = (0, 0);
+    let (mut trailing_soft, mut trailing_hard) = (0, 0);
+    let mut kept: Vec<Cigar> = Vec::new();
+    let mut new_pos = None;
+    let mut past_end = false;
+    let mut rpos = pos;
+    for op in cigar {
+        match *op {
+            Cigar::HardClip(n) if new_pos.is_none() => leading_hard += n,
+            Cigar::HardClip(n) => trailing_hard += n,
+            Cigar::SoftClip(n) if new_pos.is_none() => leading_soft += n,
+            Cigar::SoftClip(n) => trailing_soft += n,
+            Cigar::Ins(n) if new_pos.is_none() => leading_soft += n,
+            Cigar::Ins(n) if past_end => trailing_soft += n,
+            Cigar::Ins(_) | Cigar::Pad(_) => {
+                if new_pos.is_some() && !past_end {
+                    kept.push(*op);
+                }
+            }
+            Cigar::Del(n) | Cigar::RefSkip(n) => {
+                if new_pos.is_some() && !past_end {
+                    kept.push(*op);
+                }
+                rpos += n as i64;
+            }
+            Cigar::Match(n) | Cigar::Equal(n) | Cigar::Diff(n) => {
+                let len = n as i64;
+                let before = (clip_start - rpos).clamp(0, len);
+                let after = (rpos + len - clip_end).clamp(0, len);
+                let middle = len - before - after;
+                if middle > 0 {
+                    leading_soft += before as u32;
+                    new_pos.get_or_insert(rpos + before);
+                    kept.push(match *op {
+                        Cigar::Match(_) => Cigar::Match(middle as u32),
+                        Cigar::Equal(_) => Cigar::Equal(middle as u32),
+                        _ => Cigar::Diff(middle as u32),
+                    });
+                    trailing_soft += after as u32;
+                } else if rpos + len <= clip_start {
+                    leading_soft += n;
+                } else {
+                    trailing_soft += n;
+                }
+                rpos += len;
+            }
+        }
+        if new_pos.is_some() && rpos >= clip_end {
+            past_end = true;
+        }
+    }
+
+    // An alignment can't end on an insertion or deletion
+    while let Some(last) = kept.last() {
+        match *last {
+            Cigar::Ins(n) => trailing_soft += n,
+            Cigar::Del(_) | Cigar::RefSkip(_) | Cigar::Pad(_) => {}
+            _ => break,
+        }
+        kept.pop();
+    }
+
+    let new_pos = new_pos?;
+    let mut ops = Vec::new();
+    for op in [Cigar::HardClip(leading_hard), Cigar::SoftClip(leading_soft)] {
+        if !op.is_empty() {
+            ops.push(op);
+        }
+    }
+    ops.extend(kept);
+    for op in [
+        Cigar::SoftClip(trailing_soft),
+        Cigar::HardClip(trailing_hard),
+    ] {
+        if !op.is_empty() {
+            ops.push(op);
+        }
+    }
+    Some((new_pos, CigarString(ops)))
+}
+
+/// Restores coordinate order after soft-clipping moves reads forward: records are held
+/// until the input has passed their (new) position. Unplaced reads sort last, as in BAM.
+#[derive(Default)]
+struct ClipReorder {
+    pending: BTreeMap<(u32, i64, u64), Record>,
+    added: u64,
+}
+
+impl ClipReorder {
+    fn push(&mut self, record: Record) {
+        self.pending
+            .insert((record.tid() as u32, record.pos(), self.added), record);
+        self.added += 1;
+    }
+
+    /// Records at or before `pos` on `tid`, which no later input can precede.
+    fn release(&mut self, tid: i32, pos: i64) -> Vec<Record> {
+        let later = self.pending.split_off(&(tid as u32, pos, u64::MAX));
+        std::mem::replace(&mut self.pending, later)
+            .into_values()
+            .collect()
+    }
+
+    fn release_all(&mut self) -> Vec<Record> {
+        std::mem::take(&mut self.pending).into_values().collect()
+    }
+}
+
+/// Reads overlapping each region (by interval value), out of the reads placed on the
+/// reference, for the region report.
+#[derive(Debug, Default)]
+struct RegionCounts {
+    placed: u64,
+    reads: HashMap<u64, u64>,
+}
+
+impl RegionCounts {
+    fn add(
+        &mut self,
+        record: &Record,
+        lapper: Option<&Lapper<u64, u64>>,
+        options: &SubtractOptions,
+    ) {
+        self.placed += 1;
+        if let Some(lapper) = lapper.filter(|_| overlaps_regions(record, lapper, options)) {
+            let start = record.reference_start() as u64;
+            let end = record.reference_end() as u64;
+            // A read over several blocks of a BED12 region counts once
+            let regions: HashSet<u64> = lapper.find(start, end).map(|iv| iv.val).collect();
+            for region in regions {
+                *self.reads.entry(region).or_default() += 1;
+            }
+        }
+    }
+
+    fn merge(&mut self, other: RegionCounts) {
+        self.placed += other.placed;
+        for (region, reads) in other.reads {
+            *self.reads.entry(region).or_default() += reads;
+        }
+    }
+}
+
+/// One line of the region report.
+#[derive(Debug, Serialize)]
+struct RegionReportRow<'a> {
+    #[serde(flatten)]
+    region: &'a Region,
+    reads: u64,
+    fraction: f64,
+}
+
+/// Write the `top` regions by overlapping reads, with the fraction of placed reads each
+/// captured, as JSON for a `.json` path and TSV otherwise.
+fn write_region_report(
+    path: &Path,
+    regions: &[Region],
+    counts: &RegionCounts,
+    top: usize,
 ) -> Result<(), anyhow::Error> {
-    let intervals_for_subtraction =
-        Arc::new(get_intervals(&bed).expect("Could not get intervals from BED file"));
+    let mut ranked: Vec<(u64, u64)> = counts.reads.iter().map(|(&i, &n)| (i, n)).collect();
+    ranked.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let rows: Vec<RegionReportRow> = ranked
+        .into_iter()
+        .take(top)
+        .map(|(i, reads)| RegionReportRow {
+            region: &regions[i as usize],
+            reads,
+            fraction: reads as f64 / counts.placed.max(1) as f64,
+        })
+        .collect();
 
-    let bam_reader = rust_htslib::bam::Reader::from_path(&bam).expect("Could not open BAM file");
-    let header_view = bam_reader.header().to_owned();
-    let header = Header::from_template(&header_view);
-    let chrom_names = get_chrom_names(&header_view).expect("Could not get chrom names");
+    let file = File::create(path)
+        .with_context(|| format!("Could not create region report `{}`", path.display()))?;
+    let mut out = io::BufWriter::new(file);
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::to_writer_pretty(&mut out, &rows)?,
+        _ => {
+            writeln!(out, "name\tchrom\tstart\tend\treads\tfraction")?;
+            for row in &rows {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}\t{:.6}",
+                    row.region.name,
+                    row.region.chrom,
+                    row.region.start,
+                    row.region.end,
+                    row.reads,
+                    row.fraction
+                )?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
 
-    let (chrom_sender, chrom_recv) = crossbeam::channel::unbounded::<String>();
-    let (filt_sender, filt_recv) = crossbeam::channel::unbounded();
+/// Records of one part of a window; removed records are only collected when they are
+/// written out. `carried` holds kept records soft-clipped past the end of the window.
+#[derive(Default)]
+struct RecordBatch {
+    kept: Vec<Record>,
+    removed: Vec<Record>,
+    carried: Vec<Record>,
+    counts: RegionCounts,
+}
 
-    let mut filter_handles = Vec::new();
+impl RecordBatch {
+    fn len(&self) -> usize {
+        self.kept.len() + self.removed.len() + self.carried.len()
+    }
+}
 
-    // Spawn filtering threads
-    for _ in 0..n_threads {
+/// Batches of records tagged with the index of their window; `None` marks the end of a
+/// window.
+type WindowBatch = (usize, Option<RecordBatch>);
+
+/// A stretch `[start, end)` of a chromosome, filtered as one unit of work.
+type Window = (String, u64, u64);
+
+/// Split each chromosome into windows of at most `size` bases, in header order.
+fn windows(header_view: &HeaderView, chrom_names: &[String], size: u64) -> Vec<Window> {
+    let size = size.max(1);
+    let mut windows = Vec::new();
+    for (tid, chrom) in chrom_names.iter().enumerate() {
+        let length = header_view.target_len(tid as u32).unwrap_or(0).max(1);
+        for start in (0..length).step_by(size as usize) {
+            windows.push((chrom.clone(), start, (start + size).min(length)));
+        }
+    }
+    windows
+}
+
+/// Send the reads starting in window `i` to the writer in batches, followed by the end
+/// marker; reads overlapping the window from the one before are left to that window.
+/// Without a window, the unmapped reads are passed through (or in pairs mode, follow
+/// their mates).
+fn filter_window(
+    reader: &mut IndexedReader,
+    i: usize,
+    window: Option<&Window>,
+    lapper: Option<&Lapper<u64, u64>>,
+    names: Option<&HashSet<Vec<u8>>>,
+    options: &SubtractOptions,
+    writer_sender: &crossbeam::channel::Sender<WindowBatch>,
+) {
+    let send = |message: WindowBatch| {
+        writer_sender.send(message).expect("Failed to send records");
+    };
+    // Nothing on a chromosome without regions overlaps one (though its mate might)
+    if window.is_some()
+        && lapper.is_none()
+        && names.is_none()
+        && options.invert
+        && options.removed_output.is_none()
+        && options.region_report.is_none()
+    {
+        send((i, None));
+        return;
+    }
+
+    match window {
+        Some((chrom, start, end)) => reader.fetch((chrom.as_str(), *start as i64, *end as i64)),
+        None => reader.fetch(FetchDefinition::Unmapped),
+    }
+    .expect("Failed to fetch window");
+
+    let mut record_batch = RecordBatch::default();
+    let mut reorder = ClipReorder::default();
+    for result in reader.records() {
+        if record_batch.len() >= 1e5 as usize {
+            send((i, Some(std::mem::take(&mut record_batch))));
+        }
+
+        let mut record = result.expect("Could not read BAM record");
+        if window.is_some_and(|(_, start, _)| record.pos() < *start as i64)
+            || !passes_filters(&record, options)
+        {
+            continue;
+        }
+        if window.is_some() && options.region_report.is_some() {
+            record_batch.counts.add(&record, lapper, options);
+        }
+        let mut keep = match (window, names) {
+            (None, None) => true,
+            _ => keep_record(&record, lapper, names, options),
+        };
+        if let (false, Some(mode), Some(lapper)) = (keep, options.mask, lapper) {
+            keep = mask_record(&mut record, lapper, mode);
+        }
+        if keep && options.mask == Some(MaskMode::SoftClip) {
+            let (tid, pos) = (record.tid(), record.pos());
+            reorder.push(record);
+            record_batch.kept.extend(reorder.release(tid, pos));
+        } else if keep {
+            record_batch.kept.push(record);
+        } else if options.removed_output.is_some() {
+            record_batch.removed.push(record);
+        }
+    }
+    // Reads clipped past the end of the window are placed among the following windows'
+    // reads by the writer
+    let end = window.map_or(i64::MAX, |(_, _, end)| *end as i64);
+    let (kept, carried): (Vec<Record>, Vec<Record>) = reorder
+        .release_all()
+        .into_iter()
+        .partition(|record| record.pos() < end);
+    record_batch.kept.extend(kept);
+    record_batch.carried = carried;
+
+    // Send any remaining records
+    if record_batch.len() > 0 || record_batch.counts.placed > 0 {
+        send((i, Some(record_batch)));
+    }
+    send((i, None));
+}
+
+/// Names of the reads with a record overlapping the regions, found in a first pass over
+/// the chromosomes that have regions.
+fn overlapping_names(
+    bam: &Path,
+    chrom_names: &[String],
+    intervals_for_subtraction: &Arc<HashMap<String, Vec<Iv>>>,
+    options: &SubtractOptions,
+) -> HashSet<Vec<u8>> {
+    let (chrom_sender, chrom_recv) = crossbeam::channel::unbounded::<String>();
+    for chrom in chrom_names {
+        if intervals_for_subtraction.contains_key(chrom) {
+            chrom_sender
+                .send(chrom.clone())
+                .expect("Failed to send chromosome");
+        }
+    }
+    drop(chrom_sender);
+
+    let mut handles = Vec::new();
+    for _ in 0..options.threads {
         let chrom_recv = chrom_recv.clone();
-        let writer_sender = filt_sender.clone();
         let intervals_for_subtraction = intervals_for_subtraction.clone();
-        let bam = bam.clone();
+        let bam = bam.to_path_buf();
+        let options = options.clone();
 
-        filter_handles.push(thread::spawn(move || {
+        handles.push(thread::spawn(move || {
+            let mut names = HashSet::new();
             for chrom in chrom_recv {
-                let mut record_batch = Vec::with_capacity(1e5 as usize);
-                let mut batch_counter = 0;
-
-                match intervals_for_subtraction.get(&chrom) {
-                    Some(intervals) => {
-                        let mut reader =
-                            IndexedReader::from_path(&bam).expect("Could not open BAM file");
-                        reader.fetch(&chrom).expect("Failed to fetch chromosome");
-
-                        let lapper = Lapper::new(intervals.clone());
-
-                        for result in reader.records() {
-                            if batch_counter == 1e5 as usize {
-                                writer_sender
-                                    .send(record_batch)
-                                    .expect("Failed to send records");
-                                record_batch = Vec::with_capacity(1e5 as usize);
-                                batch_counter = 0;
-                            }
-
-                            let record = result.expect("Could not read BAM record");
-                            let start = record.reference_start() as u64;
-                            let end = record.reference_end() as u64;
-
-                            let overlap_count = lapper.count(start, end);
-                            if overlap_count == 0 {
-                                record_batch.push(record);
-                                batch_counter += 1;
-                            }
-                        }
-
-                        // Send any remaining records
-                        if record_batch.len() > 0 {
-                            writer_sender
-                                .send(record_batch)
-                                .expect("Failed to send records");
-                        }
-                    }
-                    None => {
-                        let mut reader =
-                            IndexedReader::from_path(&bam).expect("Could not open BAM file");
-                        reader.fetch(&chrom).expect("Failed to fetch chromosome");
-
-                        for result in reader.records() {
-                            if batch_counter == 1e5 as usize {
-                                writer_sender
-                                    .send(record_batch)
-                                    .expect("Failed to send records");
-                                record_batch = Vec::with_capacity(1e5 as usize);
-                                batch_counter = 0;
-                            }
-
-                            let record = result.expect("Could not read BAM record");
-                            record_batch.push(record);
-                            batch_counter += 1;
-                        }
-
-                        // Send any remaining records
-                        if record_batch.len() > 0 {
-                            writer_sender
-                                .send(record_batch)
-                                .expect("Failed to send records");
-                        }
+                let lapper = Lapper::new(intervals_for_subtraction[&chrom].clone());
+                let mut reader = open_indexed(&bam, &options);
+                reader.fetch(&chrom).expect("Failed to fetch chromosome");
+                for result in reader.records() {
+                    let record = result.expect("Could not read BAM record");
+                    if passes_filters(&record, &options)
+                        && overlaps_regions(&record, Some(&lapper), &options)
+                    {
+                        names.insert(record.qname().to_vec());
                     }
                 }
             }
+            names
+        }));
+    }
+
+    let mut names = HashSet::new();
+    for handle in handles {
+        names.extend(handle.join().expect("Failed to join filter thread"));
+    }
+    names
+}
+
+/// Output formats of `subtract` and `split`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum AlignmentFormat {
+    #[default]
+    Bam,
+    Cram,
+}
+
+impl AlignmentFormat {
+    /// Format implied by the extension of `path`, BAM if it is not `.cram`.
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("cram") => AlignmentFormat::Cram,
+            _ => AlignmentFormat::Bam,
+        }
+    }
+}
+
+/// Open the indexed BAM or CRAM input, with the reference if one was given.
+fn open_indexed(bam: &Path, options: &SubtractOptions) -> IndexedReader {
+    let mut reader = IndexedReader::from_path(bam).expect("Could not open BAM file");
+    if let Some(reference) = &options.reference {
+        reader
+            .set_reference(reference)
+            .expect("Could not set the reference");
+    }
+    reader
+}
+
+/// Open an output BAM or CRAM (`-` for stdout), in the chosen format or the one implied
+/// by its extension.
+fn open_writer(path: &Path, header: &Header, options: &SubtractOptions) -> Writer {
+    let format = options
+        .output_format
+        .unwrap_or_else(|| AlignmentFormat::from_path(path));
+    let format = match format {
+        AlignmentFormat::Bam => Format::Bam,
+        AlignmentFormat::Cram => Format::Cram,
+    };
+    let mut writer = match path.as_os_str() == "-" {
+        true => Writer::from_stdout(header, format),
+        false => Writer::from_path(path, header, format),
+    }
+    .expect("Could not open output file for writing");
+    if let Some(reference) = &options.reference {
+        writer
+            .set_reference(reference)
+            .expect("Could not set the reference");
+    }
+    writer
+}
+
+/// Whether `bam` has an index beside it (`x.bam.bai`, `x.bai`, `x.bam.csi` or `x.cram.crai`).
+fn has_index(bam: &Path) -> bool {
+    let with_suffix = |suffix: &str| {
+        let mut path = bam.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path).exists()
+    };
+    with_suffix(".bai")
+        || with_suffix(".csi")
+        || with_suffix(".crai")
+        || bam.with_extension("bai").exists()
+}
+
+/// Subtract window by window from indexed input on `options.threads` workers.
+fn subtract_indexed(
+    bam: &Path,
+    header_view: &HeaderView,
+    intervals_for_subtraction: Arc<HashMap<String, Vec<Iv>>>,
+    header: Header,
+    removed_header: Header,
+    output: &Path,
+    options: &SubtractOptions,
+) -> Result<RegionCounts, anyhow::Error> {
+    let chrom_names = get_chrom_names(header_view).expect("Could not get chrom names");
+    let names = match options.pairs {
+        true => Some(Arc::new(overlapping_names(
+            bam,
+            &chrom_names,
+            &intervals_for_subtraction,
+            options,
+        ))),
+        false => None,
+    };
+
+    // Regions are indexed once per chromosome and shared by the windows
+    let lappers: Arc<HashMap<String, Lapper<u64, u64>>> = Arc::new(
+        intervals_for_subtraction
+            .iter()
+            .map(|(chrom, intervals)| (chrom.clone(), Lapper::new(intervals.clone())))
+            .collect(),
+    );
+
+    let (window_sender, window_recv) = crossbeam::channel::unbounded::<(usize, Option<Window>)>();
+    let (filt_sender, filt_recv) = crossbeam::channel::unbounded::<WindowBatch>();
+
+    let mut filter_handles = Vec::new();
+
+    // Spawn filtering threads
+    for _ in 0..options.threads {
+        let window_recv = window_recv.clone();
+        let writer_sender = filt_sender.clone();
+        let lappers = lappers.clone();
+        let bam = bam.to_path_buf();
+        let names = names.clone();
+        let options = options.clone();
+
+        filter_handles.push(thread::spawn(move || {
+            // One reader per thread, so the index is loaded once rather than per window
+            let mut reader = open_indexed(&bam, &options);
+            for (i, window) in window_recv {
+                let lapper = window.as_ref().and_then(|(chrom, _, _)| lappers.get(chrom));
+                filter_window(
+                    &mut reader,
+                    i,
+                    window.as_ref(),
+                    lapper,
+                    names.as_deref(),
+                    &options,
+                    &writer_sender,
+                );
+            }
             // Drop the sender so the receiver will know we're done
             drop(writer_sender);
         }));
     }
 
-    // Spawn writing thread
+    // Spawn writing thread. Windows are written in genome order, so the output stays
+    // coordinate sorted; batches of windows finished ahead of the one being written are
+    // held in memory until their turn.
+    let output_path = output.to_path_buf();
+    let removed_path = options.removed_output.clone();
+    let writer_options = options.clone();
     let writer_handle = thread::spawn(move || {
-        let mut bam_writer = rust_htslib::bam::Writer::from_path(output, &header, Format::Bam)
-            .expect("Could not open BAM file for writing");
+        let mut bam_writer = open_writer(&output_path, &header, &writer_options);
+        let mut removed_writer =
+            removed_path.map(|path| open_writer(&path, &removed_header, &writer_options));
 
-        for record_batch in filt_recv {
-            for read in record_batch {
-                bam_writer.write(&read).expect("Failed to write record");
+        let mut counts = RegionCounts::default();
+        let mut carried = ClipReorder::default();
+        let mut next = 0;
+        let mut pending: HashMap<usize, Vec<RecordBatch>> = HashMap::new();
+        let mut finished: HashSet<usize> = HashSet::new();
+        for (i, record_batch) in filt_recv {
+            match record_batch {
+                Some(record_batch) => pending.entry(i).or_default().push(record_batch),
+                None => {
+                    finished.insert(i);
+                }
+            }
+            loop {
+                for record_batch in pending.remove(&next).unwrap_or_default() {
+                    for read in record_batch.kept {
+                        for earlier in carried.release(read.tid(), read.pos()) {
+                            bam_writer.write(&earlier).expect("Failed to write record");
+                        }
+                        bam_writer.write(&read).expect("Failed to write record");
+                    }
+                    for read in record_batch.carried {
+                        carried.push(read);
+                    }
+                    if let Some(removed_writer) = removed_writer.as_mut() {
+                        for read in record_batch.removed {
+                            removed_writer.write(&read).expect("Failed to write record");
+                        }
+                    }
+                    counts.merge(record_batch.counts);
+                }
+                match finished.remove(&next) {
+                    true => next += 1,
+                    false => break,
+                }
             }
         }
+        for read in carried.release_all() {
+            bam_writer.write(&read).expect("Failed to write record");
+        }
+        counts
     });
 
-    // Send chromosomes to threads
-    for chrom in chrom_names {
-        chrom_sender.send(chrom)?;
+    // Send windows to threads, then the unmapped reads so they are written last. Windows
+    // rather than whole chromosomes keep the threads busy when one chromosome dominates.
+    let unmapped = options.keep_unmapped.then_some(None);
+    let windows = windows(header_view, &chrom_names, options.window_size)
+        .into_iter()
+        .map(Some)
+        .chain(unmapped);
+    for window in windows.enumerate() {
+        window_sender.send(window)?;
     }
 
     // Drop the sender so the receiver will know we're done
-    drop(chrom_sender);
+    drop(window_sender);
     drop(filt_sender); // Drop the ref to the sender so the threads will know we're done
 
     // Join threads
     for handle in filter_handles {
         handle.join().expect("Failed to join filter thread");
     }
-    writer_handle.join().expect("Failed to join writer thread");
+    let counts = writer_handle.join().expect("Failed to join writer thread");
+
+    Ok(counts)
+}
+
+/// Subtract in a single pass over input in any order, from a file or stdin.
+fn subtract_stream(
+    mut reader: Reader,
+    intervals_for_subtraction: &HashMap<String, Vec<Iv>>,
+    header: &Header,
+    removed_header: &Header,
+    output: &Path,
+    options: &SubtractOptions,
+) -> Result<RegionCounts, anyhow::Error> {
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+    let header_view = reader.header().to_owned();
+    let lappers: Vec<Option<Lapper<u64, u64>>> = get_chrom_names(&header_view)?
+        .iter()
+        .map(|chrom| {
+            intervals_for_subtraction
+                .get(chrom)
+                .map(|intervals| Lapper::new(intervals.clone()))
+        })
+        .collect();
+
+    let mut writer = open_writer(output, header, options);
+    let mut removed_writer = options
+        .removed_output
+        .as_ref()
+        .map(|path| open_writer(path, removed_header, options));
+    let mut counts = RegionCounts::default();
+    let mut reorder = ClipReorder::default();
+    let mut reorder_tid = -1;
+    for result in reader.records() {
+        let mut record = result?;
+        if !passes_filters(&record, options) {
+            continue;
+        }
+        let lapper = match record.tid() {
+            tid if tid >= 0 => lappers[tid as usize].as_ref(),
+            _ => None,
+        };
+        if record.tid() >= 0 && options.region_report.is_some() {
+            counts.add(&record, lapper, options);
+        }
+        let mut keep = match record.tid() {
+            tid if tid >= 0 => keep_record(&record, lapper, None, options),
+            // As with indexed input, unplaced reads are only written with --keep-unmapped
+            _ if options.keep_unmapped => true,
+            _ => continue,
+        };
+        if let (false, Some(mode), Some(lapper)) = (keep, options.mask, lapper) {
+            keep = mask_record(&mut record, lapper, mode);
+        }
+        if keep && options.mask == Some(MaskMode::SoftClip) {
+            // Only sorted input stays sorted, so reads are reordered within a chromosome
+            if record.tid() != reorder_tid {
+                for record in reorder.release_all() {
+                    writer.write(&record)?;
+                }
+                reorder_tid = record.tid();
+            }
+            let (tid, pos) = (record.tid(), record.pos());
+            reorder.push(record);
+            for record in reorder.release(tid, pos) {
+                writer.write(&record)?;
+            }
+        } else if keep {
+            writer.write(&record)?;
+        } else if let Some(removed_writer) = removed_writer.as_mut() {
+            removed_writer.write(&record)?;
+        }
+    }
+    for record in reorder.release_all() {
+        writer.write(&record)?;
+    }
+    Ok(counts)
+}
+
+pub fn remove_regions_from_bam(
+    beds: &[PathBuf],
+    bam: PathBuf,
+    output: PathBuf,
+    options: &SubtractOptions,
+) -> Result<(), anyhow::Error> {
+    if !(0.0..=1.0).contains(&options.min_overlap_frac) {
+        anyhow::bail!("--min-overlap-frac must be between 0 and 1");
+    }
+    let writes_cram = |path: &Path| {
+        options
+            .output_format
+            .unwrap_or_else(|| AlignmentFormat::from_path(path))
+            == AlignmentFormat::Cram
+    };
+    let cram_output =
+        writes_cram(&output) || options.removed_output.as_deref().is_some_and(writes_cram);
+    if cram_output && options.reference.is_none() {
+        anyhow::bail!("CRAM output requires a reference FASTA (--reference)");
+    }
+
+    let from_stdin = bam.as_os_str() == "-";
+    let stream = options.stream || from_stdin;
+    if stream && options.pairs {
+        anyhow::bail!("--pairs needs two passes over indexed input, so cannot stream");
+    }
+    if options.mask.is_some() && (options.invert || options.pairs) {
+        anyhow::bail!("--mask keeps the reads overlapping regions, so cannot be used with --invert or --pairs");
+    }
+    if options.write_index && output.as_os_str() == "-" {
+        anyhow::bail!("--write-index needs the output written to a file");
+    }
+
+    if !stream && !has_index(&bam) {
+        eprintln!("No index found for {}, building one", bam.display());
+        if let Err(error) = sort::index_bam(&bam, options.threads) {
+            // Don't leave a partial index to be picked up by the next run
+            for suffix in [".bai", ".csi", ".crai"] {
+                let mut index = bam.as_os_str().to_owned();
+                index.push(suffix);
+                let _ = std::fs::remove_file(index);
+            }
+            return Err(error.context(
+                "Could not index the input; subtract needs coordinate-sorted, indexed input",
+            ));
+        }
+    }
+
+    let mut bam_reader = match from_stdin {
+        true => Reader::from_stdin(),
+        false => Reader::from_path(&bam),
+    }
+    .context("Could not open BAM file")?;
+    if let Some(reference) = &options.reference {
+        bam_reader.set_reference(reference)?;
+    }
+    let header_view = bam_reader.header().to_owned();
+
+    let mut beds = beds.to_vec();
+    if let Some(preset) = options.blacklist_preset {
+        let expected = preset.assembly().name;
+        if let Some(found) = genome::detect_build(&signal::chrom_sizes(&header_view)) {
+            if found.assembly.name != expected {
+                eprintln!(
+                    "Warning: the {} blacklist was requested but the BAM looks like {}",
+                    expected, found.assembly.name
+                );
+            }
+        }
+        beds.push(blacklist_file(preset)?);
+    }
+    if beds.is_empty() {
+        anyhow::bail!("No regions to subtract (--regions or --blacklist-preset)");
+    }
+    let (mut intervals, regions) = get_all_intervals(&beds, options.feature_type.as_deref())?;
+    if options.bed12_span {
+        join_blocks(&mut intervals, &regions);
+    }
+    let mut intervals = match_chrom_names(intervals, &header_view);
+    if options.slop > 0 {
+        pad_intervals(&mut intervals, options.slop, &header_view);
+    }
+    let intervals_for_subtraction = Arc::new(intervals);
+    let mut header = Header::from_template(&header_view);
+    let bed_names: Vec<String> = beds.iter().map(|bed| bed.display().to_string()).collect();
+    let mut description = match (options.mask, options.invert) {
+        (Some(MaskMode::SoftClip), _) => format!(
+            "subtract: soft-clipped the parts of reads overlapping {}",
+            bed_names.join(", ")
+        ),
+        (Some(MaskMode::Quality), _) => format!(
+            "subtract: zeroed base qualities of the parts of reads overlapping {}",
+            bed_names.join(", ")
+        ),
+        (None, true) => format!(
+            "subtract: kept only reads overlapping {}",
+            bed_names.join(", ")
+        ),
+        (None, false) => format!(
+            "subtract: removed reads overlapping {}",
+            bed_names.join(", ")
+        ),
+    };
+    if options.min_overlap_bp > 1 {
+        description.push_str(&format!(" by at least {} bp", options.min_overlap_bp));
+    }
+    if options.min_overlap_frac > 0.0 {
+        description.push_str(&format!(
+            " by at least {} of their length",
+            options.min_overlap_frac
+        ));
+    }
+    if options.slop > 0 {
+        description.push_str(&format!(" (padded by {} bp)", options.slop));
+    }
+    if options.pairs {
+        description.push_str(", with their mates");
+    }
+    if options.min_mapq > 0 {
+        description.push_str(&format!(", min MAPQ {}", options.min_mapq));
+    }
+    if options.include_flags > 0 {
+        description.push_str(&format!(", required flags {}", options.include_flags));
+    }
+    if options.exclude_flags > 0 {
+        description.push_str(&format!(", excluded flags {}", options.exclude_flags));
+    }
+    provenance::add_program_record(&mut header, &description);
+    let mut removed_header = Header::from_template(&header_view);
+    provenance::add_program_record(
+        &mut removed_header,
+        &format!("{}; this file holds the reads left out", description),
+    );
+    let counts = match stream {
+        true => subtract_stream(
+            bam_reader,
+            &intervals_for_subtraction,
+            &header,
+            &removed_header,
+            &output,
+            options,
+        )?,
+        false => subtract_indexed(
+            &bam,
+            &header_view,
+            intervals_for_subtraction,
+            header,
+            removed_header,
+            &output,
+            options,
+        )?,
+    };
+    if let Some(report) = &options.region_report {
+        write_region_report(report, &regions, &counts, options.top_regions)?;
+    }
+
+    if options.write_index {
+        sort::index_bam(&output, options.threads)?;
+        if let Some(removed_output) = &options.removed_output {
+            sort::index_bam(removed_output, options.threads)?;
+        }
+    }
 
     Ok(())
 }
@@ -181,8 +1415,391 @@ fn test_remove_regions_from_bam() {
     let bed = PathBuf::from("test/test_subtraction.bed");
     let bam = PathBuf::from("test/iALL-863388_H3K27ac-1_subsample.bam");
     let output = PathBuf::from("test/test_no_regions.bam");
-    let n_threads = 4;
+    let options = SubtractOptions {
+        threads: 4,
+        ..Default::default()
+    };
 
-    remove_regions_from_bam(bed, bam, output, n_threads)
+    remove_regions_from_bam(&[bed], bam, output, &options)
         .expect("Could not remove regions from BAM file");
 }
+
+#[cfg(test)]
+#[test]
+fn test_invert_keeps_overlapping_reads() {
+    let lapper = Lapper::new(vec![Iv {
+        start: 100,
+        stop: 200,
+        val: 0,
+    }]);
+    let cigar = CigarString(vec![Cigar::Match(50)]);
+    let mut inside = Record::new();
+    inside.set(b"inside", Some(&cigar), &[b'A'; 50], &[30; 50]);
+    inside.unset_unmapped();
+    inside.set_pos(180);
+    let mut outside = inside.clone();
+    outside.set_pos(300);
+
+    let subtract = SubtractOptions::default();
+    let invert = SubtractOptions {
+        invert: true,
+        ..Default::default()
+    };
+    assert!(!keep_record(&inside, Some(&lapper), None, &subtract));
+    assert!(keep_record(&outside, Some(&lapper), None, &subtract));
+    assert!(keep_record(&inside, Some(&lapper), None, &invert));
+    assert!(!keep_record(&outside, Some(&lapper), None, &invert));
+    assert!(!keep_record(&outside, None, None, &invert));
+
+    // `inside` has 20 of its 50 bases in the region
+    let min_bp = SubtractOptions {
+        min_overlap_bp: 30,
+        ..Default::default()
+    };
+    let min_frac = SubtractOptions {
+        min_overlap_frac: 0.4,
+        ..Default::default()
+    };
+    assert!(keep_record(&inside, Some(&lapper), None, &min_bp));
+    assert!(!keep_record(&inside, Some(&lapper), None, &min_frac));
+
+    // In pairs mode a read is judged by its name, whatever its own position
+    let names: HashSet<Vec<u8>> = [b"inside".to_vec()].into_iter().collect();
+    let pairs = SubtractOptions {
+        pairs: true,
+        ..Default::default()
+    };
+    assert!(!keep_record(&outside, None, Some(&names), &pairs));
+}
+
+#[cfg(test)]
+#[test]
+fn test_passes_filters_on_mapq_and_flags() {
+    let mut record = Record::new();
+    record.set(b"read", None, b"ACGT", b"IIII");
+    record.set_mapq(20);
+    record.set_flags(0x1 | 0x2 | 0x40);
+
+    let filters = |min_mapq, include_flags, exclude_flags| SubtractOptions {
+        min_mapq,
+        include_flags,
+        exclude_flags,
+        ..Default::default()
+    };
+    assert!(passes_filters(&record, &SubtractOptions::default()));
+    assert!(passes_filters(&record, &filters(20, 0x3, 0x400)));
+    assert!(!passes_filters(&record, &filters(30, 0, 0)));
+    assert!(!passes_filters(&record, &filters(0, 0x2 | 0x80, 0)));
+    assert!(!passes_filters(&record, &filters(0, 0, 0x40)));
+}
+
+#[cfg(test)]
+#[test]
+fn test_overlap_length_merges_regions() {
+    let regions = [(100, 150), (120, 180), (190, 200)];
+    let lapper = Lapper::new(
+        regions
+            .iter()
+            .map(|&(start, stop)| Iv {
+                start,
+                stop,
+                val: 0,
+            })
+            .collect(),
+    );
+    assert_eq!(overlap_length(&lapper, 130, 195), 55);
+    assert_eq!(overlap_length(&lapper, 0, 100), 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_mask_clips_ends_and_zeroes_inner_qualities() {
+    let lapper = Lapper::new(vec![
+        Iv {
+            start: 90,
+            stop: 110,
+            val: 0,
+        },
+        Iv {
+            start: 130,
+            stop: 135,
+            val: 0,
+        },
+    ]);
+    let cigar = CigarString(vec![Cigar::Match(50)]);
+    let mut read = Record::new();
+    read.set(b"read", Some(&cigar), &[b'A'; 50], &[30; 50]);
+    read.unset_unmapped();
+    read.set_pos(100);
+
+    let mut clipped = read.clone();
+    assert!(mask_record(&mut clipped, &lapper, MaskMode::SoftClip));
+    assert_eq!(
+        (clipped.pos(), clipped.cigar().to_string()),
+        (110, "10S40M".to_string())
+    );
+    assert_eq!(clipped.qual()[..30], [30; 30]);
+    assert_eq!(clipped.qual()[30..35], [0; 5]);
+
+    let mut masked = read.clone();
+    assert!(mask_record(&mut masked, &lapper, MaskMode::Quality));
+    assert_eq!(
+        (masked.pos(), masked.cigar().to_string()),
+        (100, "50M".to_string())
+    );
+    assert_eq!(masked.qual()[..10], [0; 10]);
+    assert_eq!(masked.qual()[10..30], [30; 20]);
+
+    // Reads entirely within the regions have nothing left to keep
+    let mut covered = read.clone();
+    covered.set_pos(90);
+    covered.set(
+        b"read",
+        Some(&CigarString(vec![Cigar::Match(20)])),
+        &[b'A'; 20],
+        &[30; 20],
+    );
+    assert!(!mask_record(&mut covered, &lapper, MaskMode::SoftClip));
+    assert_eq!(covered.cigar().to_string(), "20M");
+
+    // Clipping keeps indels inside the window and folds the cut parts into soft clips
+    let cigar = CigarString(vec![
+        Cigar::SoftClip(10),
+        Cigar::Match(20),
+        Cigar::Del(5),
+        Cigar::Match(20),
+    ]);
+    let (pos, cigar) = clip_alignment(&cigar, 100, 110, 130).unwrap();
+    assert_eq!((pos, cigar.to_string()), (110, "20S10M5D5M15S".to_string()));
+    assert!(clip_alignment(&cigar, 110, 200, 300).is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn test_get_intervals_from_peaks_and_gtf() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let peaks = dir.path().join("peaks.narrowPeak");
+    std::fs::write(
+        &peaks,
+        "chr1\t100\t200\tpeak_1\t50\t.\t4.2\t8.1\t6.3\t40\n\
+         chr2\t300\t400\tpeak_2\t60\t.\t5.0\t9.0\t7.0\t55\n",
+    )
+    .unwrap();
+    let intervals = get_intervals(&peaks, None).unwrap().0;
+    assert_eq!(
+        (intervals["chr1"][0].start, intervals["chr1"][0].stop),
+        (100, 200)
+    );
+    assert!(get_intervals(&peaks, Some("exon")).is_err());
+
+    let gtf = dir.path().join("genes.gtf");
+    std::fs::write(
+        &gtf,
+        "chr1\ttest\tgene\t1001\t3000\t.\t+\t.\tgene_id \"g1\";\n\
+         chr1\ttest\texon\t1001\t1200\t.\t+\t.\tgene_id \"g1\";\n",
+    )
+    .unwrap();
+    assert_eq!(get_intervals(&gtf, None).unwrap().0["chr1"].len(), 2);
+    let exons = get_intervals(&gtf, Some("exon")).unwrap().0;
+    assert_eq!(
+        (exons["chr1"][0].start, exons["chr1"][0].stop),
+        (1000, 1200)
+    );
+
+    let gzipped = dir.path().join("genes.gtf.gz");
+    let mut encoder = flate2::write::GzEncoder::new(
+        File::create(&gzipped).unwrap(),
+        flate2::Compression::default(),
+    );
+    io::Write::write_all(&mut encoder, &std::fs::read(&gtf).unwrap()).unwrap();
+    encoder.finish().unwrap();
+    assert_eq!(
+        get_intervals(&gzipped, Some("exon")).unwrap().0["chr1"].len(),
+        1
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_bed12_overlap_uses_blocks_or_span() {
+    let dir = tempfile::tempdir().unwrap();
+    let bed = dir.path().join("transcripts.bed");
+    std::fs::write(
+        &bed,
+        "chr1\t1000\t2000\ttx1\t0\t+\t1000\t2000\t0\t2\t100,200,\t0,800,\n",
+    )
+    .unwrap();
+    let (mut intervals, regions) = get_all_intervals(&[bed], None).unwrap();
+    let blocks: Vec<(u64, u64)> = intervals["chr1"]
+        .iter()
+        .map(|iv| (iv.start, iv.stop))
+        .collect();
+    assert_eq!(blocks, vec![(1000, 1100), (1800, 2000)]);
+    assert_eq!(regions.len(), 1);
+
+    // A read in the intron only overlaps the span
+    let intron = |intervals: &HashMap<String, Vec<Iv>>| {
+        overlap_length(&Lapper::new(intervals["chr1"].clone()), 1200, 1300)
+    };
+    assert_eq!(intron(&intervals), 0);
+    join_blocks(&mut intervals, &regions);
+    assert_eq!(intron(&intervals), 100);
+
+    let malformed = dir.path().join("malformed.bed");
+    std::fs::write(
+        &malformed,
+        "chr1\t1000\t2000\ttx1\t0\t+\t1000\t2000\t0\t3\t100,200,\t0,800,\n",
+    )
+    .unwrap();
+    assert!(get_all_intervals(&[malformed], None).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_region_report_ranks_regions() {
+    let dir = tempfile::tempdir().unwrap();
+    let bed = dir.path().join("blacklist.bed");
+    std::fs::write(
+        &bed,
+        "chr1\t100\t200\tsatellite\nchr1\t500\t600\t.\nchr2\t0\t50\tunused\n",
+    )
+    .unwrap();
+    let (_, regions) = get_all_intervals(&[bed], None).unwrap();
+    assert_eq!(regions[1].name, "chr1:500-600");
+
+    let counts = RegionCounts {
+        placed: 100,
+        reads: HashMap::from([(0, 5), (1, 20)]),
+    };
+    let tsv = dir.path().join("report.tsv");
+    write_region_report(&tsv, &regions, &counts, 1).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&tsv).unwrap(),
+        "name\tchrom\tstart\tend\treads\tfraction\nchr1:500-600\tchr1\t500\t600\t20\t0.200000\n"
+    );
+
+    let json = dir.path().join("report.json");
+    write_region_report(&json, &regions, &counts, 10).unwrap();
+    let rows: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+    assert_eq!(rows.as_array().unwrap().len(), 2);
+    assert_eq!(rows[1]["name"], "satellite");
+    assert_eq!(rows[1]["reads"], 5);
+}
+
+#[cfg(test)]
+#[test]
+fn test_pad_intervals_clamps_to_chromosome() {
+    let header = rust_htslib::bam::HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:1000\n");
+    let mut intervals = HashMap::new();
+    intervals.insert(
+        "chr1".to_string(),
+        vec![
+            Iv {
+                start: 50,
+                stop: 200,
+                val: 0,
+            },
+            Iv {
+                start: 900,
+                stop: 950,
+                val: 0,
+            },
+        ],
+    );
+    pad_intervals(&mut intervals, 100, &header);
+    let padded: Vec<(u64, u64)> = intervals["chr1"]
+        .iter()
+        .map(|iv| (iv.start, iv.stop))
+        .collect();
+    assert_eq!(padded, vec![(0, 300), (800, 1000)]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_match_chrom_names_adds_or_strips_prefix() {
+    let header =
+        rust_htslib::bam::HeaderView::from_bytes(b"@SQ\tSN:1\tLN:1000\n@SQ\tSN:chrX\tLN:1000\n");
+    let interval = Iv {
+        start: 10,
+        stop: 20,
+        val: 0,
+    };
+    let intervals: HashMap<String, Vec<Iv>> = ["chr1", "X", "chrUn"]
+        .iter()
+        .map(|chrom| (chrom.to_string(), vec![interval.clone()]))
+        .collect();
+    let matched = match_chrom_names(intervals, &header);
+    let mut chroms: Vec<&String> = matched.keys().collect();
+    chroms.sort();
+    assert_eq!(chroms, vec!["1", "chrUn", "chrX"]);
+    for preset in BlacklistPreset::value_variants() {
+        assert!(preset.assembly().blacklist_url.is_some());
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_has_index_finds_either_naming() {
+    let dir = tempfile::tempdir().unwrap();
+    let bam = dir.path().join("reads.bam");
+    File::create(&bam).unwrap();
+    assert!(!has_index(&bam));
+    File::create(dir.path().join("reads.bai")).unwrap();
+    assert!(has_index(&bam));
+}
+
+#[cfg(test)]
+#[test]
+fn test_stream_partitions_reads() {
+    let dir = tempfile::tempdir().unwrap();
+    let bed = dir.path().join("regions.bed");
+    std::fs::write(&bed, "chr1\t816000\t828000\n").unwrap();
+    let output = dir.path().join("kept.bam");
+    let removed = dir.path().join("removed.bam");
+    let options = SubtractOptions {
+        stream: true,
+        removed_output: Some(removed.clone()),
+        keep_unmapped: true,
+        ..Default::default()
+    };
+    remove_regions_from_bam(
+        &[bed],
+        PathBuf::from("test/test.bam"),
+        output.clone(),
+        &options,
+    )
+    .unwrap();
+
+    let count = |path: &Path| Reader::from_path(path).unwrap().records().count();
+    let removed_count = count(&removed);
+    assert!(removed_count > 0);
+    assert_eq!(
+        count(&output) + removed_count,
+        count(Path::new("test/test.bam"))
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_windows_write_each_read_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let bam = dir.path().join("test.bam");
+    std::fs::copy("test/test.bam", &bam).unwrap();
+    let bed = dir.path().join("regions.bed");
+    std::fs::write(&bed, "chr1\t816000\t828000\n").unwrap();
+    let output = dir.path().join("kept.bam");
+    let removed = dir.path().join("removed.bam");
+    let options = SubtractOptions {
+        removed_output: Some(removed.clone()),
+        keep_unmapped: true,
+        window_size: 1_000_000,
+        threads: 4,
+        ..Default::default()
+    };
+    remove_regions_from_bam(&[bed], bam.clone(), output.clone(), &options).unwrap();
+
+    let count = |path: &Path| Reader::from_path(path).unwrap().records().count();
+    assert_eq!(count(&output) + count(&removed), count(&bam));
+}
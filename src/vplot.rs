@@ -0,0 +1,331 @@
+//! V-plot matrices: fragment midpoint position against fragment length around anchors.
+
+use anyhow::{bail, Context, Result};
+use bio::io::bed;
+use flate2::Crc;
+use rust_htslib::bam::{IndexedReader, Read};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::fragments::{self, FragmentFilter};
+
+#[derive(Debug, Clone)]
+pub struct VplotOptions {
+    /// Distance either side of the anchor centre to include
+    pub flank: i64,
+    pub min_length: i64,
+    pub max_length: i64,
+    pub min_mapq: u8,
+    pub threads: usize,
+}
+
+impl Default for VplotOptions {
+    fn default() -> Self {
+        Self {
+            flank: 500,
+            min_length: 1,
+            max_length: 500,
+            min_mapq: 0,
+            threads: 1,
+        }
+    }
+}
+
+/// Counts indexed by fragment length (rows) and midpoint offset from the anchor (columns).
+struct VplotMatrix {
+    counts: Vec<u32>,
+    min_length: i64,
+    rows: usize,
+    flank: i64,
+    columns: usize,
+}
+
+impl VplotMatrix {
+    fn new(options: &VplotOptions) -> Self {
+        let rows = (options.max_length - options.min_length + 1) as usize;
+        let columns = (2 * options.flank + 1) as usize;
+        Self {
+            counts: vec![0; rows * columns],
+            min_length: options.min_length,
+            rows,
+            flank: options.flank,
+            columns,
+        }
+    }
+
+    /// Count a fragment whose midpoint is `offset` bp from the anchor.
+    fn add(&mut self, length: i64, offset: i64) {
+        if offset.abs() > self.flank {
+            return;
+        }
+        let row = length - self.min_length;
+        if row < 0 || row as usize >= self.rows {
+            return;
+        }
+        let column = (offset + self.flank) as usize;
+        self.counts[row as usize * self.columns + column] += 1;
+    }
+
+    fn write_tsv<W: Write>(&self, writer: &mut W) -> Result<()> {
+        write!(writer, "length")?;
+        for offset in -self.flank..=self.flank {
+            write!(writer, "\t{}", offset)?;
+        }
+        writeln!(writer)?;
+
+        for (row, counts) in self.counts.chunks(self.columns).enumerate() {
+            write!(writer, "{}", self.min_length + row as i64)?;
+            for count in counts {
+                write!(writer, "\t{}", count)?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Write an uncompressed `.npz` holding `matrix`, `lengths` and `positions` arrays.
+    fn write_npz<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let lengths: Vec<i64> = (0..self.rows as i64).map(|r| self.min_length + r).collect();
+        let positions: Vec<i64> = (-self.flank..=self.flank).collect();
+
+        let matrix = npy(
+            "<u4",
+            &[self.rows, self.columns],
+            &self
+                .counts
+                .iter()
+                .flat_map(|c| c.to_le_bytes())
+                .collect::<Vec<u8>>(),
+        );
+        let lengths = npy(
+            "<i8",
+            &[lengths.len()],
+            &lengths
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<u8>>(),
+        );
+        let positions = npy(
+            "<i8",
+            &[positions.len()],
+            &positions
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<u8>>(),
+        );
+
+        write_stored_zip(
+            writer,
+            &[
+                ("matrix.npy", &matrix),
+                ("lengths.npy", &lengths),
+                ("positions.npy", &positions),
+            ],
+        )
+    }
+}
+
+/// Serialise a C-ordered array in the `.npy` (version 1.0) format.
+fn npy(dtype: &str, shape: &[usize], data: &[u8]) -> Vec<u8> {
+    let shape = match shape {
+        [n] => format!("({},)", n),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        dtype, shape
+    );
+    // Magic (6) + version (2) + header length (2) + header must be a multiple of 64
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    let mut out = Vec::with_capacity(10 + header.len() + data.len());
+    out.extend_from_slice(b"\x93NUMPY\x01\x00");
+    out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    out.extend_from_slice(header.as_bytes());
+    out.extend_from_slice(data);
+    out
+}
+
+/// Write a zip archive of uncompressed ("stored") entries, which is all numpy needs.
+fn write_stored_zip<W: Write>(writer: &mut W, entries: &[(&str, &[u8])]) -> Result<()> {
+    let mut offset = 0u32;
+    let mut central = Vec::new();
+
+    for (name, data) in entries {
+        let mut crc = Crc::new();
+        crc.update(data);
+        let size = data.len() as u32;
+
+        let mut local = Vec::new();
+        local.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        local.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        local.extend_from_slice(&0u16.to_le_bytes()); // flags
+        local.extend_from_slice(&0u16.to_le_bytes()); // stored
+        local.extend_from_slice(&0u32.to_le_bytes()); // time and date
+        local.extend_from_slice(&crc.sum().to_le_bytes());
+        local.extend_from_slice(&size.to_le_bytes());
+        local.extend_from_slice(&size.to_le_bytes());
+        local.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        local.extend_from_slice(&0u16.to_le_bytes()); // extra length
+        local.extend_from_slice(name.as_bytes());
+        writer.write_all(&local)?;
+        writer.write_all(data)?;
+
+        central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        central.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        central.extend_from_slice(&local[4..30]);
+        central.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        central.extend_from_slice(&0u16.to_le_bytes()); // disk number
+        central.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+        central.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+        central.extend_from_slice(&offset.to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+
+        offset += local.len() as u32 + size;
+    }
+
+    writer.write_all(&central)?;
+    let mut end = Vec::new();
+    end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    end.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    end.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    end.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    end.extend_from_slice(&offset.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    writer.write_all(&end)?;
+    Ok(())
+}
+
+/// Build the V-plot of fragments around the centres of the anchors in `regions`.
+///
+/// Anchors on the minus strand (BED column 6) are flipped so that all anchors are
+/// oriented the same way. Outputs ending in `.npz` are written as numpy archives,
+/// anything else as TSV.
+pub fn vplot<P>(bam: P, regions: P, output: P, options: &VplotOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    if options.flank < 0 {
+        bail!("The flank can't be negative, got {}", options.flank);
+    }
+    if options.min_length < 1 || options.max_length < options.min_length {
+        bail!(
+            "Fragment lengths from {} to {} are not a valid range",
+            options.min_length,
+            options.max_length
+        );
+    }
+    let mut reader = IndexedReader::from_path(&bam).context("Could not open indexed BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+    let mut anchors = bed::Reader::from_file(regions.as_ref())?;
+
+    let filter = FragmentFilter {
+        min_mapq: options.min_mapq,
+        min_length: options.min_length,
+        max_length: options.max_length,
+    };
+    let mut matrix = VplotMatrix::new(options);
+    let mut n_anchors = 0;
+
+    for record in anchors.records() {
+        let record = record.context("Error reading BED record")?;
+        let tid = match reader.header().tid(record.chrom().as_bytes()) {
+            Some(tid) => tid,
+            None => continue,
+        };
+        let centre = ((record.start() + record.end()) / 2) as i64;
+        let reverse = record.aux(5) == Some("-");
+
+        reader.fetch((
+            tid,
+            (centre - options.flank - options.max_length).max(0),
+            centre + options.flank + options.max_length,
+        ))?;
+        for result in reader.records() {
+            let record = result?;
+            if let Some(fragment) = fragments::from_record(&record, &filter) {
+                let offset = match reverse {
+                    true => centre - fragment.midpoint(),
+                    false => fragment.midpoint() - centre,
+                };
+                matrix.add(fragment.len(), offset);
+            }
+        }
+        n_anchors += 1;
+    }
+    println!("Anchors used: {}", n_anchors);
+
+    let output = output.as_ref();
+    let mut writer = BufWriter::new(
+        File::create(output).with_context(|| format!("Could not create `{}`", output.display()))?,
+    );
+    match output.extension().and_then(|ext| ext.to_str()) {
+        Some("npz") => matrix.write_npz(&mut writer)?,
+        _ => matrix.write_tsv(&mut writer)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matrix_counts_and_npy_header() {
+        let options = VplotOptions {
+            flank: 10,
+            min_length: 100,
+            max_length: 109,
+            ..Default::default()
+        };
+        let mut matrix = VplotMatrix::new(&options);
+        matrix.add(100, -10);
+        matrix.add(109, 10);
+        matrix.add(109, 11); // outside the flank
+        matrix.add(99, 0); // too short
+
+        assert_eq!(matrix.counts.iter().sum::<u32>(), 2);
+        assert_eq!(matrix.counts[0], 1);
+        assert_eq!(matrix.counts[matrix.counts.len() - 1], 1);
+
+        let array = npy("<u4", &[2, 3], &[0; 24]);
+        let header_len = u16::from_le_bytes([array[8], array[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        assert_eq!(array.len(), 10 + header_len + 24);
+    }
+
+    #[test]
+    fn invalid_lengths_and_flank_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (bam, output) = (Path::new("test/test.bam"), dir.path().join("vplot.tsv"));
+        for options in [
+            VplotOptions {
+                min_length: 200,
+                max_length: 100,
+                ..Default::default()
+            },
+            VplotOptions {
+                flank: -1,
+                ..Default::default()
+            },
+        ] {
+            assert!(vplot(bam, bam, output.as_path(), &options).is_err());
+        }
+    }
+}
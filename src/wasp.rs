@@ -0,0 +1,481 @@
+//! WASP-style filtering of allele-dependent alignments.
+//!
+//! Allele-specific analyses are biased if reads carrying one allele map better than reads
+//! carrying the other. Following WASP (van de Geijn et al. 2015) this runs in two steps
+//! around a remapping with the original aligner:
+//!
+//! 1. With `--snps`, templates whose aligned bases overlap heterozygous SNVs are set
+//!    aside in `<prefix>.to_remap.bam`, and every allele-swapped version of them is
+//!    written to FASTQ (`<prefix>.remap.fq.gz`, or `<prefix>.remap.1/2.fq.gz` for pairs).
+//!    Everything else goes to `<prefix>.keep.bam`.
+//! 2. With `--remapped`, the remapped swaps are checked: a template is kept (in
+//!    `<prefix>.remap.keep.bam`) only if every swapped version maps back to the original
+//!    positions. Single-end reads and mates without a partner in the BAM are remapped
+//!    as single-end reads.
+//!
+//! Merging `keep.bam` and `remap.keep.bam` gives the filtered alignments. Both outputs
+//! are unsorted.
+
+use anyhow::{bail, Context, Result};
+use bio::alphabets::dna;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{Format, Header, HeaderView, Read, Reader, Writer};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::provenance;
+
+#[derive(Debug, Clone)]
+pub struct WaspOptions {
+    /// Templates overlapping more SNVs than this are discarded rather than remapped
+    pub max_snvs: usize,
+    /// Minimum mapping quality of remapped reads
+    pub min_mapq: u8,
+    pub threads: usize,
+}
+
+impl Default for WaspOptions {
+    fn default() -> Self {
+        Self {
+            max_snvs: 6,
+            min_mapq: 0,
+            threads: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Snv {
+    /// 0-based reference position
+    pos: i64,
+    reference: u8,
+    alternate: u8,
+}
+
+fn open_vcf(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file =
+        File::open(path).with_context(|| format!("Could not open VCF `{}`", path.display()))?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") | Some("bgz") => Ok(Box::new(BufReader::new(MultiGzDecoder::new(file)))),
+        _ => Ok(Box::new(BufReader::new(file))),
+    }
+}
+
+/// Chromosome and SNV of a VCF line, if it is a biallelic SNV that is heterozygous in the
+/// first sample (or any biallelic SNV if there are no genotypes).
+fn parse_vcf_line(line: &str) -> Option<(&str, Snv)> {
+    if line.starts_with('#') {
+        return None;
+    }
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 5 || fields[3].len() != 1 || fields[4].len() != 1 {
+        return None;
+    }
+    if let (Some(format), Some(sample)) = (fields.get(8), fields.get(9)) {
+        let gt_index = format.split(':').position(|key| key == "GT")?;
+        let gt = sample.split(':').nth(gt_index)?;
+        let alleles: Vec<&str> = gt.split(['/', '|']).collect();
+        if alleles.len() != 2 || alleles[0] == alleles[1] || alleles.contains(&".") {
+            return None;
+        }
+    }
+    let pos: i64 = fields[1].parse().ok()?;
+    Some((
+        fields[0],
+        Snv {
+            pos: pos - 1,
+            reference: fields[3].as_bytes()[0].to_ascii_uppercase(),
+            alternate: fields[4].as_bytes()[0].to_ascii_uppercase(),
+        },
+    ))
+}
+
+/// Sorted SNVs per reference id.
+fn read_snvs(path: &Path, header: &HeaderView) -> Result<HashMap<u32, Vec<Snv>>> {
+    let mut snvs: HashMap<u32, Vec<Snv>> = HashMap::new();
+    for line in open_vcf(path)?.lines() {
+        let line = line?;
+        if let Some((chrom, snv)) = parse_vcf_line(&line) {
+            if let Some(tid) = header.tid(chrom.as_bytes()) {
+                snvs.entry(tid).or_default().push(snv);
+            }
+        }
+    }
+    for sites in snvs.values_mut() {
+        sites.sort_unstable_by_key(|snv| snv.pos);
+    }
+    Ok(snvs)
+}
+
+/// (query position, other allele) for every SNV in an aligned base of `record` that
+/// carries one of the two alleles.
+fn read_sites(record: &Record, snvs: &HashMap<u32, Vec<Snv>>) -> Vec<(usize, u8)> {
+    if record.is_unmapped() || record.tid() < 0 {
+        return Vec::new();
+    }
+    let sites = match snvs.get(&(record.tid() as u32)) {
+        Some(sites) => sites,
+        None => return Vec::new(),
+    };
+    let first = sites.partition_point(|snv| snv.pos < record.pos());
+    let last = sites.partition_point(|snv| snv.pos < record.reference_end());
+    if first == last {
+        return Vec::new();
+    }
+    let overlapping = &sites[first..last];
+
+    let seq = record.seq().as_bytes();
+    record
+        .aligned_pairs()
+        .filter_map(|[qpos, rpos]| {
+            let i = overlapping
+                .binary_search_by_key(&rpos, |snv| snv.pos)
+                .ok()?;
+            let snv = overlapping[i];
+            let base = seq[qpos as usize].to_ascii_uppercase();
+            match base {
+                b if b == snv.reference => Some((qpos as usize, snv.alternate)),
+                b if b == snv.alternate => Some((qpos as usize, snv.reference)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Every combination of swapped alleles except the original, as stored sequences for
+/// each segment. Sites are numbered across segments so a template is swapped jointly.
+fn allele_swaps(sequences: &[Vec<u8>], sites: &[Vec<(usize, u8)>]) -> Vec<Vec<Vec<u8>>> {
+    let all: Vec<(usize, usize, u8)> = sites
+        .iter()
+        .enumerate()
+        .flat_map(|(segment, sites)| sites.iter().map(move |&(q, b)| (segment, q, b)))
+        .collect();
+    (1..1usize << all.len())
+        .map(|mask| {
+            let mut swapped = sequences.to_vec();
+            for (bit, &(segment, q, base)) in all.iter().enumerate() {
+                if mask & (1 << bit) != 0 {
+                    swapped[segment][q] = base;
+                }
+            }
+            swapped
+        })
+        .collect()
+}
+
+/// Phred+33 character of a base quality, capped at `~` (missing qualities are 0xFF in BAM).
+fn phred_char(quality: u8) -> u8 {
+    quality.saturating_add(33).min(b'~')
+}
+
+fn write_fastq<W: Write>(writer: &mut W, name: &str, record: &Record, seq: &[u8]) -> Result<()> {
+    // FASTQ is in sequencing orientation
+    let (seq, qual): (Vec<u8>, Vec<u8>) = match record.is_reverse() {
+        true => (
+            dna::revcomp(seq),
+            record.qual().iter().rev().map(|&q| phred_char(q)).collect(),
+        ),
+        false => (
+            seq.to_vec(),
+            record.qual().iter().map(|&q| phred_char(q)).collect(),
+        ),
+    };
+    writer.write_all(b"@")?;
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(b"\n")?;
+    writer.write_all(&seq)?;
+    writer.write_all(b"\n+\n")?;
+    writer.write_all(&qual)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+fn fastq_writer(path: String) -> Result<GzEncoder<BufWriter<File>>> {
+    Ok(GzEncoder::new(
+        BufWriter::new(File::create(path)?),
+        Compression::default(),
+    ))
+}
+
+fn unsorted_header(header: &HeaderView, description: &str) -> Header {
+    let text = String::from_utf8_lossy(header.as_bytes())
+        .replace("SO:coordinate", "SO:unsorted")
+        .replace("SO:queryname", "SO:unsorted");
+    let mut header = Header::from_template(&HeaderView::from_bytes(text.as_bytes()));
+    provenance::add_program_record(&mut header, description);
+    header
+}
+
+/// Step 1: split `bam` into templates that can be kept as they are and templates to
+/// remap, writing the allele-swapped reads to remap as FASTQ.
+pub fn find_intersecting_snvs<P>(
+    bam: P,
+    snps: P,
+    output_prefix: P,
+    options: &WaspOptions,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let prefix = output_prefix.as_ref().display().to_string();
+    let mut reader = Reader::from_path(&bam).context("Could not open BAM file")?;
+    let header_view = reader.header().clone();
+    let snvs = read_snvs(snps.as_ref(), &header_view)?;
+
+    let header = unsorted_header(&header_view, "wasp-filter: reads without SNVs");
+    let mut keep = Writer::from_path(format!("{}.keep.bam", prefix), &header, Format::Bam)?;
+    let header = unsorted_header(&header_view, "wasp-filter: reads overlapping SNVs");
+    let mut to_remap = Writer::from_path(format!("{}.to_remap.bam", prefix), &header, Format::Bam)?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+        keep.set_threads(options.threads)?;
+        to_remap.set_threads(options.threads)?;
+    }
+    let mut single_fastq = fastq_writer(format!("{}.remap.fq.gz", prefix))?;
+    let mut read1_fastq = fastq_writer(format!("{}.remap.1.fq.gz", prefix))?;
+    let mut read2_fastq = fastq_writer(format!("{}.remap.2.fq.gz", prefix))?;
+
+    let mut pending: HashMap<Vec<u8>, Record> = HashMap::new();
+    let (mut n_kept, mut n_remap, mut n_discarded) = (0u64, 0u64, 0u64);
+
+    let mut handle_template = |template: Vec<Record>| -> Result<()> {
+        let sites: Vec<Vec<(usize, u8)>> = template.iter().map(|r| read_sites(r, &snvs)).collect();
+        let n_sites: usize = sites.iter().map(|s| s.len()).sum();
+        if n_sites == 0 {
+            for record in &template {
+                keep.write(record)?;
+            }
+            n_kept += 1;
+            return Ok(());
+        }
+        if n_sites > options.max_snvs {
+            n_discarded += 1;
+            return Ok(());
+        }
+
+        for record in &template {
+            to_remap.write(record)?;
+        }
+        n_remap += 1;
+        let sequences: Vec<Vec<u8>> = template.iter().map(|r| r.seq().as_bytes()).collect();
+        let swaps = allele_swaps(&sequences, &sites);
+        let qname = String::from_utf8_lossy(template[0].qname()).to_string();
+        for (i, swapped) in swaps.iter().enumerate() {
+            let name = format!("{}.{}.{}", qname, i + 1, swaps.len());
+            match template.len() {
+                1 => write_fastq(&mut single_fastq, &name, &template[0], &swapped[0])?,
+                _ => {
+                    for (record, seq) in template.iter().zip(swapped) {
+                        match record.is_first_in_template() {
+                            true => write_fastq(&mut read1_fastq, &name, record, seq)?,
+                            false => write_fastq(&mut read2_fastq, &name, record, seq)?,
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    };
+
+    for result in reader.records() {
+        let record = result?;
+        if record.is_secondary() || record.is_supplementary() {
+            continue;
+        }
+        if !record.is_paired() {
+            handle_template(vec![record])?;
+            continue;
+        }
+        match pending.remove(record.qname()) {
+            Some(mate) => handle_template(vec![mate, record])?,
+            None => {
+                pending.insert(record.qname().to_vec(), record);
+            }
+        }
+    }
+    // Mates that never turned up are handled as single reads
+    for (_, record) in pending.drain() {
+        handle_template(vec![record])?;
+    }
+
+    single_fastq.finish()?;
+    read1_fastq.finish()?;
+    read2_fastq.finish()?;
+    println!(
+        "Kept {} templates, {} to remap, {} discarded with more than {} SNVs",
+        n_kept, n_remap, n_discarded, options.max_snvs
+    );
+    Ok(())
+}
+
+/// Split a remapped read name `<qname>.<i>.<n>` into the original name and `n`.
+fn parse_swap_name(name: &[u8]) -> Option<(&[u8], usize)> {
+    let name = std::str::from_utf8(name).ok()?;
+    let mut parts = name.rsplitn(3, '.');
+    let n = parts.next()?.parse().ok()?;
+    let _i: usize = parts.next()?.parse().ok()?;
+    Some((parts.next()?.as_bytes(), n))
+}
+
+#[derive(Debug, Default)]
+struct RemapCheck {
+    /// (is read 2, tid, pos) of the original segments
+    original: Vec<(bool, i32, i64)>,
+    expected: usize,
+    seen: usize,
+    failed: bool,
+}
+
+/// Step 2: keep the templates of `<prefix>.to_remap.bam` whose allele-swapped versions in
+/// `remapped` all map back to the original positions, in `<prefix>.remap.keep.bam`.
+pub fn filter_remapped<P>(remapped: P, output_prefix: P, options: &WaspOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let prefix = output_prefix.as_ref().display().to_string();
+    let to_remap_path = PathBuf::from(format!("{}.to_remap.bam", prefix));
+
+    let mut checks: HashMap<Vec<u8>, RemapCheck> = HashMap::new();
+    let mut reader = Reader::from_path(&to_remap_path)
+        .with_context(|| format!("Could not open `{}`", to_remap_path.display()))?;
+    for result in reader.records() {
+        let record = result?;
+        checks
+            .entry(record.qname().to_vec())
+            .or_default()
+            .original
+            .push((record.is_last_in_template(), record.tid(), record.pos()));
+    }
+    // Templates with one segment in the BAM, including mates whose partner was missing,
+    // were remapped as single-end reads
+    for check in checks.values_mut() {
+        if let [segment] = check.original.as_mut_slice() {
+            segment.0 = false;
+        }
+    }
+
+    let mut remapped_reader =
+        Reader::from_path(&remapped).context("Could not open remapped BAM")?;
+    if options.threads > 1 {
+        remapped_reader.set_threads(options.threads)?;
+    }
+    for result in remapped_reader.records() {
+        let record = result?;
+        if record.is_secondary() || record.is_supplementary() {
+            continue;
+        }
+        let (qname, n) = match parse_swap_name(record.qname()) {
+            Some(parsed) => parsed,
+            None => bail!(
+                "Unexpected read name `{}` in remapped BAM",
+                String::from_utf8_lossy(record.qname())
+            ),
+        };
+        let check = match checks.get_mut(qname) {
+            Some(check) => check,
+            None => continue,
+        };
+        check.expected = n * check.original.len();
+        check.seen += 1;
+        let is_read2 = record.is_paired() && record.is_last_in_template();
+        let matches = !record.is_unmapped()
+            && record.mapq() >= options.min_mapq
+            && check.original.iter().any(|&(r2, tid, pos)| {
+                r2 == is_read2 && tid == record.tid() && pos == record.pos()
+            });
+        check.failed |= !matches;
+    }
+
+    let mut reader = Reader::from_path(&to_remap_path)?;
+    let header = unsorted_header(
+        reader.header(),
+        "wasp-filter: remapped to the same position",
+    );
+    let mut writer = Writer::from_path(format!("{}.remap.keep.bam", prefix), &header, Format::Bam)?;
+    for result in reader.records() {
+        let record = result?;
+        if let Some(check) = checks.get(record.qname()) {
+            if !check.failed && check.expected > 0 && check.seen == check.expected {
+                writer.write(&record)?;
+            }
+        }
+    }
+
+    let n_kept = checks
+        .values()
+        .filter(|c| !c.failed && c.expected > 0 && c.seen == c.expected)
+        .count();
+    println!("Kept {} of {} remapped templates", n_kept, checks.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vcf_parsing_and_allele_swaps() {
+        let (chrom, snv) = parse_vcf_line("chr1\t101\trs1\tA\tG\t.\tPASS\t.\tGT\t0|1").unwrap();
+        assert_eq!(chrom, "chr1");
+        assert_eq!((snv.pos, snv.reference, snv.alternate), (100, b'A', b'G'));
+        // Homozygous, multi-allelic and indels are skipped
+        assert!(parse_vcf_line("chr1\t101\t.\tA\tG\t.\t.\t.\tGT\t1/1").is_none());
+        assert!(parse_vcf_line("chr1\t101\t.\tA\tG,T\t.\t.\t.").is_none());
+        assert!(parse_vcf_line("chr1\t101\t.\tAT\tA\t.\t.\t.").is_none());
+
+        let sequences = vec![b"ACGT".to_vec(), b"TTTT".to_vec()];
+        let sites = vec![vec![(0, b'G')], vec![(3, b'C')]];
+        let swaps = allele_swaps(&sequences, &sites);
+        assert_eq!(swaps.len(), 3);
+        assert_eq!(swaps[0], vec![b"GCGT".to_vec(), b"TTTT".to_vec()]);
+        assert_eq!(swaps[2], vec![b"GCGT".to_vec(), b"TTTC".to_vec()]);
+
+        assert_eq!(
+            parse_swap_name(b"read.with.dots.2.3"),
+            Some((&b"read.with.dots"[..], 3))
+        );
+
+        assert_eq!(phred_char(40), b'I');
+        assert_eq!(phred_char(0xFF), b'~');
+    }
+
+    #[test]
+    fn orphan_read2_remapped_as_single_end_is_kept() {
+        use rust_htslib::bam::header::HeaderRecord;
+
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("sample.v1");
+        let mut header = Header::new();
+        let mut sq = HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chr1").push_tag(b"LN", 1000);
+        header.push_record(&sq);
+
+        let write_bam = |path: String, name: &[u8], paired: bool| {
+            let mut writer = Writer::from_path(path, &header, Format::Bam).unwrap();
+            let mut record = Record::new();
+            record.set(name, None, b"ACGT", b"IIII");
+            record.set_tid(0);
+            record.set_pos(100);
+            record.unset_unmapped();
+            if paired {
+                record.set_paired();
+                record.set_last_in_template();
+            }
+            writer.write(&record).unwrap();
+        };
+        let prefix_str = prefix.display().to_string();
+        write_bam(format!("{}.to_remap.bam", prefix_str), b"orphan", true);
+        let remapped = dir.path().join("remapped.bam");
+        write_bam(remapped.display().to_string(), b"orphan.1.1", false);
+
+        filter_remapped(remapped, prefix, &WaspOptions::default()).unwrap();
+        let mut reader = Reader::from_path(format!("{}.remap.keep.bam", prefix_str)).unwrap();
+        assert_eq!(reader.records().count(), 1);
+    }
+}
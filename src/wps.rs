@@ -0,0 +1,160 @@
+//! Windowed protection score (WPS) for cell-free DNA.
+//!
+//! For every position k the WPS is the number of fragments spanning the whole window
+//! centred on k minus the number of fragment endpoints falling inside it (Snyder et al.
+//! 2016). Positive scores mark positions protected by a nucleosome.
+
+use anyhow::{Context, Result};
+use log::info;
+use rust_htslib::bam::{IndexedReader, Read};
+use std::path::Path;
+
+use crate::fragments::{self, Fragment, FragmentFilter};
+use crate::signal::{self, TrackWriter};
+
+/// Size of the chunks each chromosome is processed in.
+const CHUNK_SIZE: i64 = 1_000_000;
+
+#[derive(Debug, Clone)]
+pub struct WpsOptions {
+    /// Window size; the window around k covers `k - window / 2 ..= k + window / 2`.
+    pub window: i64,
+    pub filter: FragmentFilter,
+    pub threads: usize,
+}
+
+impl Default for WpsOptions {
+    fn default() -> Self {
+        Self {
+            window: 120,
+            filter: FragmentFilter {
+                min_mapq: 0,
+                min_length: 120,
+                max_length: 180,
+            },
+            threads: 1,
+        }
+    }
+}
+
+/// Add `value` to `scores[start..=end]` (positions relative to `offset`), via a
+/// difference array.
+fn add_range(diff: &mut [i32], offset: i64, start: i64, end: i64, value: i32) {
+    let first = (start - offset).max(0);
+    let last = (end - offset + 1).min(diff.len() as i64 - 1);
+    if first < last {
+        diff[first as usize] += value;
+        diff[last as usize] -= value;
+    }
+}
+
+/// Add the contribution of `fragment` to the difference array of a chunk starting at
+/// `offset`.
+fn add_fragment(diff: &mut [i32], offset: i64, fragment: &Fragment, half_window: i64) {
+    let last_base = fragment.end - 1;
+
+    // Fragment spans the whole window, with both ends outside it
+    add_range(
+        diff,
+        offset,
+        fragment.start + half_window + 1,
+        last_base - half_window - 1,
+        1,
+    );
+    // Fragment ends inside the window
+    add_range(
+        diff,
+        offset,
+        fragment.start - half_window,
+        fragment.start + half_window,
+        -1,
+    );
+    add_range(
+        diff,
+        offset,
+        last_base - half_window,
+        last_base + half_window,
+        -1,
+    );
+}
+
+/// Convert a difference array into per-base scores.
+fn scores_from_diff(diff: &[i32]) -> Vec<f32> {
+    diff[..diff.len() - 1]
+        .iter()
+        .scan(0, |total, d| {
+            *total += d;
+            Some(*total as f32)
+        })
+        .collect()
+}
+
+pub fn windowed_protection_scores<P>(bam: P, output: P, options: &WpsOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut reader = IndexedReader::from_path(&bam).context("Could not open indexed BAM file")?;
+    if options.threads > 1 {
+        reader.set_threads(options.threads)?;
+    }
+    let chroms = signal::chrom_sizes(reader.header());
+    let mut writer = TrackWriter::create(&output, chroms.clone())?;
+
+    let half_window = options.window / 2;
+    let padding = options.filter.max_length + half_window;
+
+    for (tid, (chrom, length)) in chroms.iter().enumerate() {
+        let length = *length as i64;
+        info!("Computing WPS for {}", chrom);
+
+        let mut chunk_start = 0;
+        while chunk_start < length {
+            let chunk_end = (chunk_start + CHUNK_SIZE).min(length);
+            let mut diff = vec![0i32; (chunk_end - chunk_start + 1) as usize];
+
+            reader.fetch((
+                tid as i32,
+                (chunk_start - padding).max(0),
+                (chunk_end + padding).min(length),
+            ))?;
+            for result in reader.records() {
+                let record = result?;
+                if let Some(fragment) = fragments::from_record(&record, &options.filter) {
+                    add_fragment(&mut diff, chunk_start, &fragment, half_window);
+                }
+            }
+
+            writer.write_values(chrom, chunk_start as u64, &scores_from_diff(&diff))?;
+            chunk_start = chunk_end;
+        }
+    }
+
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_fragment_scores() {
+        // A 20 bp fragment with a 4 bp window (+/- 2)
+        let fragment = Fragment {
+            tid: 0,
+            start: 10,
+            end: 30,
+        };
+        let mut diff = vec![0; 41];
+        add_fragment(&mut diff, 0, &fragment, 2);
+        let scores = scores_from_diff(&diff);
+
+        assert_eq!(scores[7], 0.0);
+        assert_eq!(scores[8], -1.0); // window 6..=10 holds the start
+        assert_eq!(scores[12], -1.0);
+        assert_eq!(scores[13], 1.0); // window 11..=15 is spanned
+        assert_eq!(scores[26], 1.0);
+        assert_eq!(scores[27], -1.0); // window 25..=29 holds the end
+        assert_eq!(scores[31], -1.0);
+        assert_eq!(scores[32], 0.0);
+    }
+}
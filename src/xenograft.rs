@@ -0,0 +1,470 @@
+//! Separate host and graft reads of patient-derived xenograft (PDX) samples, in the
+//! manner of Disambiguate and XenofilteR.
+//!
+//! The reads are aligned separately to the graft (e.g. human) and host (e.g. mouse)
+//! genomes, and the alignments of each read name in the two BAMs are compared: the genome
+//! where the read aligns with the higher alignment score (AS), or with fewer edits (NM),
+//! keeps it, and a tie goes to the genome with the lower suboptimal alignment score (XS),
+//! where the read is placed more uniquely. A pair is only compared when the same mates
+//! are mapped to both genomes, since its scores are summed over the mapped mates. Reads
+//! scoring the same on both, or with different mates mapped, go to the ambiguous outputs
+//! and reads mapped to neither to the unmapped output, as with the both genomes and
+//! unmapped outputs of `split`. Both BAMs must list the reads in the same order, as given
+//! by the aligner or by name sorting each of them.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use rust_htslib::bam::record::{Aux, Record};
+use rust_htslib::bam::{Format, Header, Read, Reader, Writer};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+
+use crate::provenance;
+
+/// What decides which genome a read belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum XenograftScore {
+    /// Higher total alignment score (AS tag), then fewer edits
+    AlignmentScore,
+    /// Fewer edits (NM tag), then higher alignment score
+    EditDistance,
+}
+
+#[derive(Debug, Clone)]
+pub struct XenograftOptions {
+    pub score: XenograftScore,
+    pub threads: usize,
+}
+
+impl Default for XenograftOptions {
+    fn default() -> Self {
+        Self {
+            score: XenograftScore::AlignmentScore,
+            threads: 1,
+        }
+    }
+}
+
+/// Where a read goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Graft,
+    Host,
+    Ambiguous,
+    Unmapped,
+}
+
+/// Alignment scores and edit distance summed over the primary alignments of a read (both
+/// mates of a pair). Missing AS and NM tags count as 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ReadScore {
+    /// Which of the first and second mates (or the single read, as first) are mapped
+    mates: [bool; 2],
+    alignment_score: i64,
+    /// Summed XS, None if a mapped mate has no suboptimal alignment
+    suboptimal_score: Option<i64>,
+    edit_distance: i64,
+}
+
+/// Value of an integer tag, None if it is missing.
+fn integer_tag(record: &Record, tag: &[u8]) -> Option<i64> {
+    match record.aux(tag) {
+        Ok(Aux::I8(value)) => Some(value as i64),
+        Ok(Aux::U8(value)) => Some(value as i64),
+        Ok(Aux::I16(value)) => Some(value as i64),
+        Ok(Aux::U16(value)) => Some(value as i64),
+        Ok(Aux::I32(value)) => Some(value as i64),
+        Ok(Aux::U32(value)) => Some(value as i64),
+        _ => None,
+    }
+}
+
+impl ReadScore {
+    /// Score of the mapped primary alignments in `records`, None if there are none.
+    fn of(records: &[Record]) -> Option<Self> {
+        let primary: Vec<&Record> = records
+            .iter()
+            .filter(|record| {
+                !record.is_unmapped() && !record.is_secondary() && !record.is_supplementary()
+            })
+            .collect();
+        if primary.is_empty() {
+            return None;
+        }
+        let mut mates = [false; 2];
+        for record in &primary {
+            mates[record.is_last_in_template() as usize] = true;
+        }
+        let sum = |tag: &[u8]| {
+            primary
+                .iter()
+                .map(|record| integer_tag(record, tag).unwrap_or(0))
+                .sum()
+        };
+        Some(ReadScore {
+            mates,
+            alignment_score: sum(b"AS"),
+            suboptimal_score: primary
+                .iter()
+                .map(|record| integer_tag(record, b"XS"))
+                .sum(),
+            edit_distance: sum(b"NM"),
+        })
+    }
+
+    /// Ordering of the two scores, greater being the better alignment.
+    fn compare(&self, other: &Self, score: XenograftScore) -> Ordering {
+        let by_alignment_score = self.alignment_score.cmp(&other.alignment_score);
+        let by_edit_distance = other.edit_distance.cmp(&self.edit_distance);
+        // No suboptimal alignment places the read better than any
+        let by_suboptimal_score = match (self.suboptimal_score, other.suboptimal_score) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (Some(suboptimal), Some(other)) => other.cmp(&suboptimal),
+        };
+        match score {
+            XenograftScore::AlignmentScore => by_alignment_score.then(by_edit_distance),
+            XenograftScore::EditDistance => by_edit_distance.then(by_alignment_score),
+        }
+        .then(by_suboptimal_score)
+    }
+}
+
+fn classify(graft: Option<ReadScore>, host: Option<ReadScore>, score: XenograftScore) -> Class {
+    match (graft, host) {
+        (None, None) => Class::Unmapped,
+        (Some(_), None) => Class::Graft,
+        (None, Some(_)) => Class::Host,
+        (Some(graft), Some(host)) if graft.mates != host.mates => Class::Ambiguous,
+        (Some(graft), Some(host)) => match graft.compare(&host, score) {
+            Ordering::Greater => Class::Graft,
+            Ordering::Less => Class::Host,
+            Ordering::Equal => Class::Ambiguous,
+        },
+    }
+}
+
+/// The records of a BAM, a read name at a time.
+struct Reads {
+    reader: Reader,
+    next: Option<Record>,
+}
+
+impl Reads {
+    fn new(reader: Reader) -> Self {
+        Self { reader, next: None }
+    }
+
+    /// All consecutive records with the next read name.
+    fn next_read(&mut self) -> Result<Option<Vec<Record>>> {
+        let mut records = Vec::new();
+        if let Some(record) = self.next.take() {
+            records.push(record);
+        }
+        loop {
+            let mut record = Record::new();
+            match self.reader.read(&mut record) {
+                None => break,
+                Some(result) => result.context("Error reading BAM record")?,
+            }
+            if records
+                .first()
+                .is_some_and(|first| first.qname() != record.qname())
+            {
+                self.next = Some(record);
+                break;
+            }
+            records.push(record);
+        }
+        Ok((!records.is_empty()).then_some(records))
+    }
+}
+
+/// Reads in each class, counted once per read name.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct XenograftCounts {
+    pub graft: u64,
+    pub host: u64,
+    pub ambiguous: u64,
+    pub unmapped: u64,
+}
+
+impl XenograftCounts {
+    pub fn print(&self) {
+        let total = (self.graft + self.host + self.ambiguous + self.unmapped).max(1) as f64;
+        for (name, count) in [
+            ("Graft", self.graft),
+            ("Host", self.host),
+            ("Ambiguous", self.ambiguous),
+            ("Unmapped", self.unmapped),
+        ] {
+            println!(
+                "{} reads: {} ({:.2}%)",
+                name,
+                count,
+                100.0 * count as f64 / total
+            );
+        }
+    }
+}
+
+fn open_writer(path: &Path, reader: &Reader, description: &str, threads: usize) -> Result<Writer> {
+    let mut header = Header::from_template(reader.header());
+    provenance::add_program_record(&mut header, description);
+    let mut writer = Writer::from_path(path, &header, Format::Bam)
+        .with_context(|| format!("Could not create `{}`", path.display()))?;
+    if threads > 1 {
+        writer.set_threads(threads)?;
+    }
+    Ok(writer)
+}
+
+/// Compare the alignments of the same reads to the graft and host genomes, writing graft
+/// reads to `<prefix>.graft.bam`, host reads to `<prefix>.host.bam`, reads that align as
+/// well to both to `<prefix>.ambiguous_graft.bam` and `<prefix>.ambiguous_host.bam`, and
+/// reads mapped to neither to `<prefix>.unmapped.bam`.
+pub fn xenograft<P>(
+    graft: P,
+    host: P,
+    output_prefix: P,
+    options: &XenograftOptions,
+) -> Result<XenograftCounts>
+where
+    P: AsRef<Path>,
+{
+    let open = |path: &Path| -> Result<Reader> {
+        let mut reader = Reader::from_path(path)
+            .with_context(|| format!("Could not open `{}`", path.display()))?;
+        if options.threads > 1 {
+            reader.set_threads(options.threads)?;
+        }
+        Ok(reader)
+    };
+    let graft_reader = open(graft.as_ref())?;
+    let host_reader = open(host.as_ref())?;
+
+    let prefix = output_prefix.as_ref().display().to_string();
+    let path = |name: &str| PathBuf::from(format!("{}.{}.bam", prefix, name));
+    let threads = options.threads;
+    let mut graft_writer = open_writer(
+        &path("graft"),
+        &graft_reader,
+        "xenograft: graft reads",
+        threads,
+    )?;
+    let mut host_writer = open_writer(
+        &path("host"),
+        &host_reader,
+        "xenograft: host reads",
+        threads,
+    )?;
+    let mut ambiguous_graft = open_writer(
+        &path("ambiguous_graft"),
+        &graft_reader,
+        "xenograft: reads aligning as well to the host, as aligned to the graft",
+        threads,
+    )?;
+    let mut ambiguous_host = open_writer(
+        &path("ambiguous_host"),
+        &host_reader,
+        "xenograft: reads aligning as well to the graft, as aligned to the host",
+        threads,
+    )?;
+    let mut unmapped = open_writer(
+        &path("unmapped"),
+        &graft_reader,
+        "xenograft: reads mapped to neither genome",
+        threads,
+    )?;
+
+    let mut graft_reads = Reads::new(graft_reader);
+    let mut host_reads = Reads::new(host_reader);
+    let mut counts = XenograftCounts::default();
+    loop {
+        let (graft_records, host_records) =
+            match (graft_reads.next_read()?, host_reads.next_read()?) {
+                (None, None) => break,
+                (Some(graft_records), Some(host_records))
+                    if graft_records[0].qname() == host_records[0].qname() =>
+                {
+                    (graft_records, host_records)
+                }
+                (graft_records, _) => bail!(
+                "The graft and host BAMs list different reads (at {}); they need the same reads \
+                 in the same order, e.g. name sorted",
+                graft_records.map_or("the end of the graft BAM".to_string(), |records| {
+                    String::from_utf8_lossy(records[0].qname()).into_owned()
+                })
+            ),
+            };
+
+        let class = classify(
+            ReadScore::of(&graft_records),
+            ReadScore::of(&host_records),
+            options.score,
+        );
+        let writes: Vec<(&mut Writer, &[Record])> = match class {
+            Class::Graft => {
+                counts.graft += 1;
+                vec![(&mut graft_writer, graft_records.as_slice())]
+            }
+            Class::Host => {
+                counts.host += 1;
+                vec![(&mut host_writer, host_records.as_slice())]
+            }
+            Class::Ambiguous => {
+                counts.ambiguous += 1;
+                vec![
+                    (&mut ambiguous_graft, graft_records.as_slice()),
+                    (&mut ambiguous_host, host_records.as_slice()),
+                ]
+            }
+            Class::Unmapped => {
+                counts.unmapped += 1;
+                vec![(&mut unmapped, graft_records.as_slice())]
+            }
+        };
+        for (writer, records) in writes {
+            for record in records {
+                writer.write(record)?;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+#[test]
+fn test_classify_by_score() {
+    let score = |alignment_score, edit_distance| {
+        Some(ReadScore {
+            mates: [true, false],
+            alignment_score,
+            suboptimal_score: None,
+            edit_distance,
+        })
+    };
+    let by_as = XenograftScore::AlignmentScore;
+    assert_eq!(classify(score(150, 0), score(140, 2), by_as), Class::Graft);
+    assert_eq!(classify(score(150, 3), score(150, 1), by_as), Class::Host);
+    assert_eq!(
+        classify(score(150, 1), score(150, 1), by_as),
+        Class::Ambiguous
+    );
+    assert_eq!(classify(None, score(10, 9), by_as), Class::Host);
+    assert_eq!(classify(None, None, by_as), Class::Unmapped);
+    // Fewer edits win over a higher score when comparing by edit distance
+    let by_nm = XenograftScore::EditDistance;
+    assert_eq!(classify(score(150, 2), score(140, 1), by_nm), Class::Host);
+
+    // Ties go to the genome with the lower suboptimal score, or with none
+    let suboptimal = |suboptimal_score| {
+        score(150, 1).map(|score| ReadScore {
+            suboptimal_score,
+            ..score
+        })
+    };
+    assert_eq!(
+        classify(suboptimal(Some(120)), suboptimal(Some(140)), by_as),
+        Class::Graft
+    );
+    assert_eq!(
+        classify(suboptimal(Some(120)), suboptimal(None), by_as),
+        Class::Host
+    );
+    assert_eq!(
+        classify(suboptimal(Some(150)), suboptimal(Some(150)), by_as),
+        Class::Ambiguous
+    );
+
+    // A pair with one mate mapped to the host isn't compared with both mapped to the graft
+    let pair = |mates, alignment_score, edit_distance| {
+        score(alignment_score, edit_distance).map(|score| ReadScore { mates, ..score })
+    };
+    assert_eq!(
+        classify(
+            pair([true, true], 280, 4),
+            pair([true, false], 150, 0),
+            by_nm
+        ),
+        Class::Ambiguous
+    );
+    assert_eq!(
+        classify(
+            pair([true, true], 280, 4),
+            pair([true, true], 270, 6),
+            by_nm
+        ),
+        Class::Graft
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_xenograft_splits_reads_between_genomes() {
+    use rust_htslib::bam::header::HeaderRecord;
+
+    let dir = tempfile::tempdir().expect("Create temp dir");
+    // Read name, then alignment scores to the graft and host (None for unmapped)
+    let reads: [(&str, Option<i64>, Option<i64>); 4] = [
+        ("a", Some(60), Some(40)),
+        ("b", Some(40), Some(60)),
+        ("c", Some(50), Some(50)),
+        ("d", None, None),
+    ];
+    let write_bam = |path: &Path, graft: bool| {
+        let mut header = Header::new();
+        let mut sq = HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chr1").push_tag(b"LN", 1000);
+        header.push_record(&sq);
+        let mut writer = Writer::from_path(path, &header, Format::Bam).expect("Create BAM");
+        for &(name, graft_score, host_score) in &reads {
+            let mut record = Record::new();
+            record.set(name.as_bytes(), None, b"ACGT", b"IIII");
+            match if graft { graft_score } else { host_score } {
+                Some(alignment_score) => {
+                    record.set_tid(0);
+                    record.set_pos(100);
+                    record.unset_unmapped();
+                    record
+                        .push_aux(b"AS", Aux::I32(alignment_score as i32))
+                        .expect("Add AS");
+                }
+                None => {
+                    record.set_tid(-1);
+                    record.set_pos(-1);
+                    record.set_unmapped();
+                }
+            }
+            writer.write(&record).expect("Write record");
+        }
+    };
+    let (graft, host) = (dir.path().join("graft.bam"), dir.path().join("host.bam"));
+    write_bam(&graft, true);
+    write_bam(&host, false);
+
+    let prefix = dir.path().join("out");
+    let counts = xenograft(
+        graft.as_path(),
+        host.as_path(),
+        prefix.as_path(),
+        &XenograftOptions::default(),
+    )
+    .expect("Xenograft");
+    assert_eq!(
+        counts,
+        XenograftCounts {
+            graft: 1,
+            host: 1,
+            ambiguous: 1,
+            unmapped: 1
+        }
+    );
+    let mut reader = Reader::from_path(dir.path().join("out.graft.bam")).expect("Open output");
+    let names: Vec<Vec<u8>> = reader
+        .records()
+        .map(|record| record.expect("Read record").qname().to_vec())
+        .collect();
+    assert_eq!(names, [b"a".to_vec()]);
+}